@@ -0,0 +1,584 @@
+//! sBPF assembler
+//!
+//! Assembles the syntax printed by the disassembler into an [`ElfProgram`],
+//! so listings round-trip and hot paths can be tuned by hand. Besides
+//! instructions and `name:` labels, the following directives are accepted:
+//!
+//! - `.text` / `.rodata` (or `.section .text`) switch sections
+//! - `.globl name` marks the entrypoint; by default it is `entrypoint`
+//! - `.ascii "str"`, `.asciz "str"`, `.byte 1, 2`, `.quad 0x..`, `.zero n`
+//!   emit data; `.quad` in `.text` emits a raw instruction slot
+//!
+//! Labels in `.rodata` and functions can be loaded with `lddw r1, label`,
+//! and `.quad label` in `.rodata` holds the address of a function or of
+//! another `.rodata` label.
+use crate::codegen::BpfOpcode;
+use crate::disasm::{ALU_NAMES, JUMP_NAMES};
+use crate::elf::ElfProgram;
+use crate::lexer;
+use crate::syscalls;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SectionKind {
+    Text,
+    Rodata,
+}
+
+/// How labels and data are referenced by the encoded instructions
+#[derive(Default)]
+struct References {
+    called: Vec<String>,
+    jumped: Vec<String>,
+    rodata: Vec<(usize, usize)>,
+    functions: Vec<(usize, usize)>,
+}
+
+/// An instruction waiting for labels to be resolved
+struct Pending {
+    line: usize,
+    pc: usize,
+    mnemonic: String,
+    operands: Vec<String>,
+}
+
+#[derive(Default)]
+struct Assembler {
+    text_labels: HashMap<String, usize>,
+    /// Text labels in definition order
+    text_order: Vec<String>,
+    rodata_labels: HashMap<String, usize>,
+    rodata_symbols: Vec<(String, usize)>,
+    rodata: Vec<u8>,
+    /// `.rodata` offset, line and label of each `.quad` of an address
+    rodata_addresses: Vec<(usize, usize, String)>,
+    pending: Vec<Pending>,
+    globals: Vec<String>,
+    slots: usize,
+}
+
+/// Assemble a source file into a program ready to be written as ELF
+pub fn assemble(source: &str) -> Result<ElfProgram> {
+    let mut asm = Assembler::default();
+    let mut section = SectionKind::Text;
+
+    for (idx, raw_line) in source.lines().enumerate() {
+        let line = idx + 1;
+        asm.first_pass(line, strip_comment(raw_line), &mut section)
+            .map_err(|err| anyhow!("line {}: {}", line, err))?;
+    }
+
+    let mut text = vec![0u8; asm.slots * 8];
+    let mut refs = References::default();
+    for pending in &asm.pending {
+        let encoded = asm.encode(pending, &mut refs)
+            .map_err(|err| anyhow!("line {}: {}", pending.line, err))?;
+        for (slot, bytes) in encoded.iter().enumerate() {
+            let at = (pending.pc + slot) * 8;
+            text[at..at + 8].copy_from_slice(bytes);
+        }
+    }
+
+    let entry_name = match asm.globals.first() {
+        Some(name) if asm.text_labels.contains_key(name) => Some(name.clone()),
+        Some(name) => return Err(anyhow!("Entrypoint {} is not a label in .text", name)),
+        None => asm.text_labels.contains_key("entrypoint").then(|| "entrypoint".to_string()),
+    };
+    let entry = entry_name.as_ref().map_or(0, |name| asm.text_labels[name]);
+
+    let mut rodata_function_refs = Vec::new();
+    let mut rodata_data_refs = Vec::new();
+    for (offset, line, label) in &asm.rodata_addresses {
+        if let Some(pc) = asm.text_labels.get(label) {
+            if !refs.called.contains(label) {
+                refs.called.push(label.clone());
+            }
+            rodata_function_refs.push((*offset, *pc));
+        } else if let Some(target) = asm.rodata_labels.get(label) {
+            rodata_data_refs.push((*offset, *target));
+        } else {
+            return Err(anyhow!("line {}: undefined label {}", line, label));
+        }
+    }
+
+    // Labels only ever jumped to are local; every other label starts a function
+    let functions = asm.text_order.iter()
+        .filter(|name| {
+            refs.called.contains(name) || !refs.jumped.contains(name)
+                || asm.globals.contains(name) || entry_name.as_ref() == Some(name)
+        })
+        .map(|name| (name.clone(), asm.text_labels[name]))
+        .collect();
+
+    Ok(ElfProgram {
+        text,
+        rodata: asm.rodata,
+        entry,
+        functions,
+        rodata_symbols: asm.rodata_symbols,
+        rodata_refs: refs.rodata,
+        function_refs: refs.functions,
+        rodata_function_refs,
+        rodata_data_refs,
+    })
+}
+
+/// Assemble the body of an inline `asm` block. Labels are local to the
+/// block, and data can not be declared.
+pub fn assemble_fragment(source: &str) -> Result<Vec<u8>> {
+    let program = assemble(source)?;
+    if !program.rodata.is_empty() || !program.rodata_symbols.is_empty() {
+        return Err(anyhow!(".rodata is not allowed in inline asm"));
+    }
+    Ok(program.text)
+}
+
+impl Assembler {
+    /// Record labels and data, and queue instructions for encoding
+    fn first_pass(&mut self, line: usize, mut rest: &str, section: &mut SectionKind) -> Result<()> {
+        // Any number of labels may precede a statement
+        while let Some((label, tail)) = split_label(rest) {
+            self.define_label(label, *section)?;
+            rest = tail;
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, args) = match rest.split_once(char::is_whitespace) {
+            Some((mnemonic, args)) => (mnemonic, args.trim()),
+            None => (rest, ""),
+        };
+
+        match mnemonic {
+            ".text" => *section = SectionKind::Text,
+            ".rodata" => *section = SectionKind::Rodata,
+            ".section" => {
+                *section = match args {
+                    ".text" => SectionKind::Text,
+                    ".rodata" => SectionKind::Rodata,
+                    other => return Err(anyhow!("unsupported section {}", other)),
+                }
+            }
+            ".globl" | ".global" => self.globals.push(args.to_string()),
+
+            ".quad" if *section == SectionKind::Text => {
+                self.pending.push(Pending { line, pc: self.slots, mnemonic: mnemonic.to_string(), operands: vec![args.to_string()] });
+                self.slots += 1;
+            }
+            ".ascii" | ".asciz" | ".byte" | ".quad" | ".zero" => {
+                if *section != SectionKind::Rodata {
+                    return Err(anyhow!("{} is only allowed in .rodata", mnemonic));
+                }
+                self.data(line, mnemonic, args)?;
+            }
+            directive if directive.starts_with('.') => return Err(anyhow!("unknown directive {}", directive)),
+
+            _ if *section == SectionKind::Rodata => return Err(anyhow!("instruction {} in .rodata", mnemonic)),
+            _ => {
+                let mnemonic = mnemonic.to_ascii_lowercase();
+                let slots = if mnemonic == "lddw" { 2 } else { 1 };
+                self.pending.push(Pending { line, pc: self.slots, mnemonic, operands: split_operands(args) });
+                self.slots += slots;
+            }
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, label: &str, section: SectionKind) -> Result<()> {
+        if self.text_labels.contains_key(label) || self.rodata_labels.contains_key(label) {
+            return Err(anyhow!("label {} is defined twice", label));
+        }
+        match section {
+            SectionKind::Text => {
+                self.text_labels.insert(label.to_string(), self.slots);
+                self.text_order.push(label.to_string());
+            }
+            SectionKind::Rodata => {
+                self.rodata_labels.insert(label.to_string(), self.rodata.len());
+                self.rodata_symbols.push((label.to_string(), self.rodata.len()));
+            }
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, line: usize, directive: &str, args: &str) -> Result<()> {
+        match directive {
+            ".ascii" | ".asciz" => {
+                self.rodata.extend(parse_string(args)?);
+                if directive == ".asciz" {
+                    self.rodata.push(0);
+                }
+            }
+            ".byte" => {
+                for value in split_operands(args) {
+                    let value = parse_int(&value)?;
+                    let byte = u8::try_from(value).or_else(|_| i8::try_from(value).map(|v| v as u8))
+                        .map_err(|_| anyhow!("byte value {} out of range", value))?;
+                    self.rodata.push(byte);
+                }
+            }
+            ".quad" => {
+                for value in split_operands(args) {
+                    if is_label(&value) {
+                        // Filled in by the loader, once the label is resolved
+                        self.rodata_addresses.push((self.rodata.len(), line, value));
+                        self.rodata.extend_from_slice(&[0; 8]);
+                    } else {
+                        self.rodata.extend_from_slice(&parse_u64(&value)?.to_le_bytes());
+                    }
+                }
+            }
+            _ => {
+                let count = usize::try_from(parse_int(args)?).map_err(|_| anyhow!("invalid .zero count {}", args))?;
+                self.rodata.resize(self.rodata.len() + count, 0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode one queued instruction into its slots
+    fn encode(&self, inst: &Pending, refs: &mut References) -> Result<Vec<[u8; 8]>> {
+        let ops = &inst.operands;
+        let mnemonic = inst.mnemonic.as_str();
+        let expect = |count: usize| -> Result<()> {
+            if ops.len() == count {
+                Ok(())
+            } else {
+                Err(anyhow!("{} takes {} operand(s), got {}", mnemonic, count, ops.len()))
+            }
+        };
+
+        if mnemonic == ".quad" {
+            return Ok(vec![parse_u64(&ops[0])?.to_le_bytes()]);
+        }
+
+        match mnemonic {
+            "exit" => {
+                expect(0)?;
+                return Ok(vec![slot(BpfOpcode::Exit as u8, 0, 0, 0, 0)]);
+            }
+            "lddw" => {
+                expect(2)?;
+                let dst = parse_reg(&ops[0])?;
+                let value = if let Some(offset) = self.rodata_labels.get(&ops[1]) {
+                    refs.rodata.push((inst.pc, *offset));
+                    *offset as u64
+                } else if let Some(pc) = self.text_labels.get(&ops[1]) {
+                    // A function address; the label starts a function
+                    if !refs.called.contains(&ops[1]) {
+                        refs.called.push(ops[1].clone());
+                    }
+                    refs.functions.push((inst.pc, *pc));
+                    0
+                } else {
+                    parse_u64(&ops[1])?
+                };
+                return Ok(vec![
+                    slot(BpfOpcode::Lddw as u8, dst, 0, 0, value as u32 as i32),
+                    slot(0, 0, 0, 0, (value >> 32) as u32 as i32),
+                ]);
+            }
+            "call" => {
+                expect(1)?;
+                let target = &ops[0];
+                if let Some(pc) = self.text_labels.get(target) {
+                    if !refs.called.contains(target) {
+                        refs.called.push(target.clone());
+                    }
+                    let offset = *pc as i64 - inst.pc as i64 - 1;
+                    return Ok(vec![slot(BpfOpcode::Call as u8, 0, 1, 0, offset as i32)]);
+                }
+                let imm = if syscalls::is_syscall(target) {
+                    syscalls::hash(target)
+                } else if target.starts_with(|c: char| c.is_ascii_digit()) {
+                    parse_imm(target)? as u32
+                } else {
+                    return Err(anyhow!("unknown call target {}", target));
+                };
+                return Ok(vec![slot(BpfOpcode::Call as u8, 0, 0, 0, imm as i32)]);
+            }
+            "callx" => {
+                expect(1)?;
+                return Ok(vec![slot(BpfOpcode::Callx as u8, 0, 0, 0, parse_reg(&ops[0])? as i32)]);
+            }
+            "ja" => {
+                expect(1)?;
+                let offset = self.jump_offset(inst.pc, &ops[0], refs)?;
+                return Ok(vec![slot(BpfOpcode::Ja as u8, 0, 0, offset, 0)]);
+            }
+            _ => {}
+        }
+
+        // Loads and stores: ldx{size} rD, [rS+off] / st{size} [rD+off], imm / stx{size} [rD+off], rS
+        for (prefix, class) in [("ldx", 0x61u8), ("stx", 0x63), ("st", 0x62)] {
+            let Some(size) = mnemonic.strip_prefix(prefix).and_then(size_bits) else {
+                continue;
+            };
+            expect(2)?;
+            let opcode = class | size;
+            return Ok(vec![match prefix {
+                "ldx" => {
+                    let (src, offset) = parse_mem(&ops[1])?;
+                    slot(opcode, parse_reg(&ops[0])?, src, offset, 0)
+                }
+                "stx" => {
+                    let (dst, offset) = parse_mem(&ops[0])?;
+                    slot(opcode, dst, parse_reg(&ops[1])?, offset, 0)
+                }
+                _ => {
+                    let (dst, offset) = parse_mem(&ops[0])?;
+                    slot(opcode, dst, 0, offset, parse_imm(&ops[1])?)
+                }
+            }]);
+        }
+
+        // Byte swaps: le16 r1 / be64 r2
+        for (prefix, opcode) in [("le", BpfOpcode::Le), ("be", BpfOpcode::Be)] {
+            if let Some(width) = mnemonic.strip_prefix(prefix).and_then(|w| w.parse::<i32>().ok()) {
+                expect(1)?;
+                return Ok(vec![slot(opcode as u8, parse_reg(&ops[0])?, 0, 0, width)]);
+            }
+        }
+
+        // Conditional jumps: jeq rD, imm|rS, target
+        if let Some(code) = JUMP_NAMES.iter().position(|name| !name.is_empty() && *name == mnemonic) {
+            expect(3)?;
+            let dst = parse_reg(&ops[0])?;
+            let offset = self.jump_offset(inst.pc, &ops[2], refs)?;
+            let base = ((code as u8) << 4) | 0x05;
+            return Ok(vec![match parse_reg(&ops[1]) {
+                Ok(src) => slot(base | 0x08, dst, src, offset, 0),
+                Err(_) => slot(base, dst, 0, offset, parse_imm(&ops[1])?),
+            }]);
+        }
+
+        // ALU: add64 rD, imm|rS, with a missing width meaning 64 bits
+        let (name, class) = if let Some(name) = mnemonic.strip_suffix("64") {
+            (name, 0x07u8)
+        } else if let Some(name) = mnemonic.strip_suffix("32") {
+            (name, 0x04)
+        } else {
+            (mnemonic, 0x07)
+        };
+        let code = ALU_NAMES.iter().position(|alu| !alu.is_empty() && *alu == name)
+            .ok_or_else(|| anyhow!("unknown instruction {}", mnemonic))?;
+        let base = ((code as u8) << 4) | class;
+        if name == "neg" {
+            expect(1)?;
+            return Ok(vec![slot(base, parse_reg(&ops[0])?, 0, 0, 0)]);
+        }
+        expect(2)?;
+        let dst = parse_reg(&ops[0])?;
+        let encoded = match parse_reg(&ops[1]) {
+            Ok(src) => slot(base | 0x08, dst, src, 0, 0),
+            Err(_) => slot(base, dst, 0, 0, parse_imm(&ops[1])?),
+        };
+        if BpfOpcode::from_u8(encoded[0]).is_none() {
+            return Err(anyhow!("invalid operands for {}", mnemonic));
+        }
+        Ok(vec![encoded])
+    }
+
+    /// Offset from the instruction after `pc` to a label or `+n` / `-n`
+    fn jump_offset(&self, pc: usize, target: &str, refs: &mut References) -> Result<i16> {
+        let offset = match self.text_labels.get(target) {
+            Some(label_pc) => {
+                if !refs.jumped.iter().any(|name| name == target) {
+                    refs.jumped.push(target.to_string());
+                }
+                *label_pc as i64 - pc as i64 - 1
+            }
+            None if target.starts_with(['+', '-']) => parse_int(target)?,
+            None => return Err(anyhow!("undefined label {}", target)),
+        };
+        i16::try_from(offset).map_err(|_| anyhow!("jump to {} is out of range", target))
+    }
+}
+
+fn slot(opcode: u8, dst: u8, src: u8, offset: i16, imm: i32) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    bytes[0] = opcode;
+    bytes[1] = (src << 4) | dst;
+    bytes[2..4].copy_from_slice(&offset.to_le_bytes());
+    bytes[4..8].copy_from_slice(&imm.to_le_bytes());
+    bytes
+}
+
+/// Size bits of a load or store suffix
+fn size_bits(suffix: &str) -> Option<u8> {
+    match suffix {
+        "w" => Some(0x00),
+        "h" => Some(0x08),
+        "b" => Some(0x10),
+        "dw" => Some(0x18),
+        _ => None,
+    }
+}
+
+/// Remove a `;` or `//` comment, leaving string literals intact
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return line[..idx].trim(),
+            '/' if !in_string && line[idx..].starts_with("//") => return line[..idx].trim(),
+            _ => {}
+        }
+    }
+    line.trim()
+}
+
+/// Split a leading `name:` label off a statement
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    is_label(label).then(|| (label, rest.trim()))
+}
+
+fn is_label(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn split_operands(args: &str) -> Vec<String> {
+    if args.is_empty() {
+        return Vec::new();
+    }
+    args.split(',').map(|op| op.trim().to_string()).collect()
+}
+
+fn parse_reg(operand: &str) -> Result<u8> {
+    operand.strip_prefix('r')
+        .and_then(|n| n.parse::<u8>().ok())
+        .filter(|n| *n <= 10)
+        .ok_or_else(|| anyhow!("expected a register, got {}", operand))
+}
+
+/// `[rN]`, `[rN+off]` or `[rN-off]`
+fn parse_mem(operand: &str) -> Result<(u8, i16)> {
+    let inner = operand.strip_prefix('[').and_then(|op| op.strip_suffix(']'))
+        .ok_or_else(|| anyhow!("expected a memory operand, got {}", operand))?
+        .trim();
+    let (reg, offset) = match inner.find(['+', '-']) {
+        Some(idx) => (&inner[..idx], parse_int(&inner[idx..])?),
+        None => (inner, 0),
+    };
+    let offset = i16::try_from(offset).map_err(|_| anyhow!("offset {} out of range", offset))?;
+    Ok((parse_reg(reg.trim())?, offset))
+}
+
+/// A 32-bit immediate, accepting both signed and unsigned spellings
+fn parse_imm(operand: &str) -> Result<i32> {
+    let value = parse_int(operand)?;
+    i32::try_from(value)
+        .or_else(|_| u32::try_from(value).map(|v| v as i32))
+        .map_err(|_| anyhow!("immediate {} does not fit in 32 bits", operand))
+}
+
+fn parse_u64(operand: &str) -> Result<u64> {
+    match operand.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).map_err(|_| anyhow!("invalid number {}", operand)),
+        None => parse_int(operand).map(|value| value as u64),
+    }
+}
+
+fn parse_int(operand: &str) -> Result<i64> {
+    let operand = operand.trim();
+    let (negative, digits) = match operand.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, operand.strip_prefix('+').unwrap_or(operand)),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    }
+    .map_err(|_| anyhow!("invalid number {}", operand))?;
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+/// A double-quoted string with C escapes
+fn parse_string(operand: &str) -> Result<Vec<u8>> {
+    let inner = operand.strip_prefix('"').and_then(|op| op.strip_suffix('"'))
+        .ok_or_else(|| anyhow!("expected a quoted string, got {}", operand))?;
+    lexer::unescape(inner).ok_or_else(|| anyhow!("invalid escape in {}", operand))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+
+    const PROGRAM: &str = r#"
+.globl entrypoint
+entrypoint:
+    lddw r1, greeting
+    mov64 r2, 7
+    call sol_log_
+    call helper
+    jeq r0, 0, lbb_done
+    mov r0, 1            ; missing width means 64 bits
+lbb_done:
+    exit
+
+helper:
+    stxdw [r10-8], r1
+    ldxdw r0, [r10-8]
+    exit
+
+.rodata
+greeting:
+    .ascii "swap ok"
+"#;
+
+    #[test]
+    fn test_assembles_labels_calls_and_rodata() {
+        let program = assemble(PROGRAM).unwrap();
+        assert_eq!(program.text.len(), 11 * 8);
+        assert_eq!(program.rodata, b"swap ok");
+        assert_eq!(program.entry, 0);
+        assert_eq!(program.functions, [("entrypoint".to_string(), 0), ("helper".to_string(), 8)]);
+        assert_eq!(program.rodata_refs, [(0, 0)]);
+
+        let call = &program.text[4 * 8..5 * 8];
+        assert_eq!(call[0], BpfOpcode::Call as u8);
+        assert_eq!(call[1] >> 4, 1);
+        assert_eq!(i32::from_le_bytes(call[4..8].try_into().unwrap()), 3);
+    }
+
+    #[test]
+    fn test_elf_round_trip() {
+        let elf = assemble(PROGRAM).unwrap().to_bytes().unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        assert!(listing.contains("lddw r1, greeting"));
+        assert!(listing.contains("call sol_log_"));
+        assert!(listing.contains(".ascii \"swap ok\""));
+
+        let again = assemble(&listing).unwrap().to_bytes().unwrap();
+        assert_eq!(elf, again);
+    }
+
+    #[test]
+    fn test_raw_listing_round_trip() {
+        let source = "U64 Double(U64 x) { return x * 2; }\n\
+                      U0 entrypoint() { U64 v = Double(21); if (v == 42) { sol_log_64_(v, 0, 0, 0, 0); } }";
+        let bytecode = crate::compile_source(source, crate::CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble(&bytecode, &disasm::Symbols::default(), true).unwrap();
+        assert_eq!(assemble(&listing).unwrap().text, bytecode);
+    }
+
+    #[test]
+    fn test_reports_errors_with_line_numbers() {
+        let err = assemble("entrypoint:\n    mov64 r11, 1\n").unwrap_err().to_string();
+        assert_eq!(err, "line 2: expected a register, got r11");
+        let err = assemble("    ja nowhere\n").unwrap_err().to_string();
+        assert!(err.contains("undefined label nowhere"));
+        let err = assemble("entrypoint:\n    exit\n.rodata\ntable:\n    .quad missing\n").unwrap_err().to_string();
+        assert_eq!(err, "line 5: undefined label missing");
+    }
+}
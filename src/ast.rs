@@ -11,6 +11,7 @@ pub struct Program {
 pub enum Item {
     FunctionDef(FunctionDef),
     ClassDef(ClassDef),
+    EnumDef(EnumDef),
     GlobalVar(VarDecl),
     Define(Define),
     Include(String),
@@ -28,7 +29,36 @@ pub struct FunctionDef {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ClassDef {
     pub name: String,
-    pub fields: Vec<VarDecl>,
+    /// `class CDerived : CBase`: the base's fields come first
+    pub base: Option<String>,
+    pub fields: Vec<ClassField>,
+    /// Declared with `union`: every field starts at offset 0
+    pub is_union: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ClassField {
+    Named(VarDecl),
+    /// `union { ... };` inside a class. Its members share one offset and
+    /// are accessed as if they were fields of the class.
+    AnonymousUnion(Vec<VarDecl>),
+}
+
+/// `enum Name : U8 { A = 1, B, C = 0x10 };`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnumDef {
+    /// None for `enum { ... };`, which only declares constants
+    pub name: Option<String>,
+    /// Integer type the enum is stored as
+    pub underlying: Type,
+    pub enumerators: Vec<Enumerator>,
+}
+
+/// An enum constant; without a value it is one more than the previous one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Enumerator {
+    pub name: String,
+    pub value: Option<Expr>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -42,6 +72,8 @@ pub struct VarDecl {
 pub struct Param {
     pub name: String,
     pub param_type: Type,
+    /// Value passed when a call omits the argument, as in `I64 b = 5`
+    pub default: Option<Expr>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -66,6 +98,11 @@ pub enum Type {
     Pointer(Box<Type>),
     Array(Box<Type>, Option<usize>),
     Custom(String),
+    /// Function signature; only ever stored behind a `Pointer`
+    Function {
+        return_type: Box<Type>,
+        params: Vec<Type>,
+    },
 }
 
 impl Type {
@@ -79,6 +116,7 @@ impl Type {
             Type::Array(inner, Some(len)) => inner.size_bytes() * len,
             Type::Array(_, None) => 8, // Pointer to array
             Type::Custom(_) => 8, // Assume pointer
+            Type::Function { .. } => 0,
         }
     }
 
@@ -109,16 +147,97 @@ impl fmt::Display for Type {
             Type::F64 => write!(f, "F64"),
             Type::Bool => write!(f, "Bool"),
             Type::Void => write!(f, "Void"),
-            Type::Pointer(inner) => write!(f, "{}*", inner),
-            Type::Array(inner, Some(len)) => write!(f, "{}[{}]", inner, len),
-            Type::Array(inner, None) => write!(f, "{}[]", inner),
+            Type::Pointer(inner) => match inner.as_ref() {
+                Type::Function { return_type, params } => {
+                    write!(f, "{} (*)(", return_type)?;
+                    write_params(f, params)
+                }
+                inner => write!(f, "{}*", inner),
+            },
+            Type::Function { return_type, params } => {
+                write!(f, "{} (", return_type)?;
+                write_params(f, params)
+            }
+            Type::Array(..) => {
+                // The outermost length comes first, as declared: U8[3][5]
+                let mut element = self;
+                let mut lens = String::new();
+                while let Type::Array(inner, len) = element {
+                    match len {
+                        Some(len) => lens.push_str(&format!("[{}]", len)),
+                        None => lens.push_str("[]"),
+                    }
+                    element = inner;
+                }
+                write!(f, "{}{}", element, lens)
+            }
             Type::Custom(name) => write!(f, "{}", name),
         }
     }
 }
 
+fn write_params(f: &mut fmt::Formatter, params: &[Type]) -> fmt::Result {
+    for (i, param) in params.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", param)?;
+    }
+    write!(f, ")")
+}
+
 pub type Block = Vec<Stmt>;
 
+/// Inline assembly: `asm (in r1 = a, out r0 = b, clobber r3) { ... }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InlineAsm {
+    /// Variables loaded into registers before the block
+    pub inputs: Vec<AsmBinding>,
+    /// Variables stored from registers after the block
+    pub outputs: Vec<AsmBinding>,
+    /// Other registers the block may write
+    pub clobbers: Vec<u8>,
+    /// Assembly text, in the syntax accepted by the assembler
+    pub body: String,
+}
+
+/// A register bound to a HolyC variable
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AsmBinding {
+    pub reg: u8,
+    pub var: String,
+}
+
+impl InlineAsm {
+    /// Registers the block is allowed to write. Inputs are copied in from
+    /// their variables, so overwriting them is harmless.
+    pub fn writable_regs(&self) -> Vec<u8> {
+        let mut regs: Vec<u8> = self.inputs.iter().chain(&self.outputs).map(|b| b.reg)
+            .chain(self.clobbers.iter().copied())
+            .collect();
+        regs.sort_unstable();
+        regs.dedup();
+        regs
+    }
+}
+
+/// One `case` or `default` label of a switch and the statements after it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwitchCase {
+    pub label: CaseLabel,
+    pub body: Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CaseLabel {
+    /// `case 3:`
+    Value(Expr),
+    /// HolyC range case `case 'A'...'Z':`, inclusive on both ends
+    Range(Expr, Expr),
+    /// `default:`
+    Default,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Stmt {
     VarDecl(VarDecl),
@@ -132,23 +251,42 @@ pub enum Stmt {
         condition: Expr,
         body: Block,
     },
+    /// `do { body } while (condition);` runs the body at least once
+    DoWhile {
+        body: Block,
+        condition: Expr,
+    },
     For {
         init: Option<Box<Stmt>>,
         condition: Option<Expr>,
         increment: Option<Expr>,
         body: Block,
     },
+    /// Cases fall through into the next one unless they end in `break`
+    Switch {
+        value: Expr,
+        cases: Vec<SwitchCase>,
+    },
     Return(Option<Expr>),
     Break,
     Continue,
+    Goto(String),
+    /// `name:`, a target for `goto`
+    Label(String),
     Block(Block),
+    Asm(InlineAsm),
+    /// HolyC print statement: `"Balance: %d\n", bal;`
+    Print {
+        format: Vec<u8>,
+        args: Vec<Expr>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Expr {
     IntLiteral(u64),
     FloatLiteral(f64),
-    StringLiteral(String),
+    StringLiteral(Vec<u8>),
     CharLiteral(u8),
     BoolLiteral(bool),
     Null,
@@ -197,6 +335,218 @@ pub enum Expr {
     },
 
     Sizeof(Type),
+
+    /// `condition ? then_expr : else_expr`; only the chosen branch is evaluated
+    Ternary {
+        condition: Box<Expr>,
+        then_expr: Box<Expr>,
+        else_expr: Box<Expr>,
+    },
+
+    /// `a, b, ...`: evaluated left to right, yielding the last value
+    Comma(Vec<Expr>),
+
+    /// An argument left out of a call, as in `Foo(, 2)`; sema replaces it
+    /// with the parameter's default
+    DefaultArg,
+
+    /// `{a, b, ...}`: initializer of an array or class, element by element.
+    /// Elements it leaves out are zero.
+    InitList(Vec<Expr>),
+}
+
+impl Expr {
+    /// Direct subexpressions, in evaluation order
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Binary { left, right, .. } => vec![left, right],
+            Expr::Unary { expr, .. } => vec![expr],
+            Expr::Assign { target, value } => vec![target, value],
+            Expr::Call { func, args } => {
+                let mut children = vec![&**func];
+                children.extend(args.iter());
+                children
+            }
+            Expr::Index { expr, index } => vec![expr, index],
+            Expr::Member { expr, .. } | Expr::Arrow { expr, .. } | Expr::Cast { expr, .. } => {
+                vec![expr]
+            }
+            Expr::Ternary { condition, then_expr, else_expr } => vec![condition, then_expr, else_expr],
+            Expr::Comma(exprs) | Expr::InitList(exprs) => exprs.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Mutable access to the direct subexpressions, in evaluation order
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Expr::Binary { left, right, .. } => vec![left, right],
+            Expr::Unary { expr, .. } => vec![expr],
+            Expr::Assign { target, value } => vec![target, value],
+            Expr::Call { func, args } => {
+                let mut children = vec![&mut **func];
+                children.extend(args.iter_mut());
+                children
+            }
+            Expr::Index { expr, index } => vec![expr, index],
+            Expr::Member { expr, .. } | Expr::Arrow { expr, .. } | Expr::Cast { expr, .. } => {
+                vec![expr]
+            }
+            Expr::Ternary { condition, then_expr, else_expr } => vec![condition, then_expr, else_expr],
+            Expr::Comma(exprs) | Expr::InitList(exprs) => exprs.iter_mut().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns true if this expression or any subexpression matches `pred`
+    pub fn any(&self, pred: &dyn Fn(&Expr) -> bool) -> bool {
+        pred(self) || self.children().into_iter().any(|child| child.any(pred))
+    }
+
+    pub fn contains_call(&self) -> bool {
+        self.any(&|e| matches!(e, Expr::Call { .. }))
+    }
+
+    /// Visit this expression and all subexpressions, parents first
+    pub fn walk(&self, f: &mut dyn FnMut(&Expr)) {
+        f(self);
+        for child in self.children() {
+            child.walk(f);
+        }
+    }
+}
+
+impl Stmt {
+    /// Statements nested directly inside this one, in source order
+    pub fn children(&self) -> Vec<&Stmt> {
+        match self {
+            Stmt::If { then_block, else_block, .. } => then_block.iter().chain(else_block.iter().flatten()).collect(),
+            Stmt::While { body, .. } | Stmt::DoWhile { body, .. } | Stmt::Block(body) => body.iter().collect(),
+            Stmt::For { init, body, .. } => init.iter().map(|init| &**init).chain(body).collect(),
+            Stmt::Switch { cases, .. } => cases.iter().flat_map(|case| &case.body).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Mutable access to the statements nested directly inside this one
+    pub fn children_mut(&mut self) -> Vec<&mut Stmt> {
+        match self {
+            Stmt::If { then_block, else_block, .. } => {
+                then_block.iter_mut().chain(else_block.iter_mut().flatten()).collect()
+            }
+            Stmt::While { body, .. } | Stmt::DoWhile { body, .. } | Stmt::Block(body) => body.iter_mut().collect(),
+            Stmt::For { init, body, .. } => init.iter_mut().map(|init| &mut **init).chain(body).collect(),
+            Stmt::Switch { cases, .. } => cases.iter_mut().flat_map(|case| &mut case.body).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Mutable access to the expressions of this statement itself, leaving
+    /// out those of nested statements
+    pub fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Stmt::VarDecl(VarDecl { init: Some(init), .. }) => vec![init],
+            Stmt::Expr(expr) | Stmt::Return(Some(expr)) => vec![expr],
+            Stmt::Print { args, .. } => args.iter_mut().collect(),
+            Stmt::If { condition, .. } | Stmt::While { condition, .. } | Stmt::DoWhile { condition, .. } => {
+                vec![condition]
+            }
+            Stmt::For { condition, increment, .. } => condition.iter_mut().chain(increment.iter_mut()).collect(),
+            Stmt::Switch { value, cases } => {
+                let mut exprs = vec![value];
+                for case in cases {
+                    match &mut case.label {
+                        CaseLabel::Value(expr) => exprs.push(expr),
+                        CaseLabel::Range(low, high) => {
+                            exprs.push(low);
+                            exprs.push(high);
+                        }
+                        CaseLabel::Default => {}
+                    }
+                }
+                exprs
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Visit this statement and every nested statement, parents first
+    pub fn walk(&self, f: &mut dyn FnMut(&Stmt)) {
+        f(self);
+        for child in self.children() {
+            child.walk(f);
+        }
+    }
+
+    /// Visit this statement and every nested statement mutably, parents first
+    pub fn walk_mut(&mut self, f: &mut dyn FnMut(&mut Stmt)) {
+        f(self);
+        for child in self.children_mut() {
+            child.walk_mut(f);
+        }
+    }
+
+    /// Visit the top-level expressions of this statement and of every
+    /// nested statement, in source order
+    pub fn for_each_expr(&self, f: &mut dyn FnMut(&Expr)) {
+        match self {
+            Stmt::VarDecl(VarDecl { init: Some(init), .. }) => f(init),
+            Stmt::VarDecl(_) | Stmt::Return(None) | Stmt::Break | Stmt::Continue | Stmt::Asm(_)
+            | Stmt::Goto(_) | Stmt::Label(_) => {}
+            Stmt::Expr(expr) | Stmt::Return(Some(expr)) => f(expr),
+            Stmt::Print { args, .. } => args.iter().for_each(f),
+            Stmt::If { condition, then_block, else_block } => {
+                f(condition);
+                for stmt in then_block.iter().chain(else_block.iter().flatten()) {
+                    stmt.for_each_expr(f);
+                }
+            }
+            Stmt::While { condition, body } => {
+                f(condition);
+                for stmt in body {
+                    stmt.for_each_expr(f);
+                }
+            }
+            Stmt::DoWhile { body, condition } => {
+                for stmt in body {
+                    stmt.for_each_expr(f);
+                }
+                f(condition);
+            }
+            Stmt::For { init, condition, increment, body } => {
+                if let Some(init) = init {
+                    init.for_each_expr(f);
+                }
+                for expr in condition.iter().chain(increment.iter()) {
+                    f(expr);
+                }
+                for stmt in body {
+                    stmt.for_each_expr(f);
+                }
+            }
+            Stmt::Switch { value, cases } => {
+                f(value);
+                for case in cases {
+                    match &case.label {
+                        CaseLabel::Value(expr) => f(expr),
+                        CaseLabel::Range(low, high) => {
+                            f(low);
+                            f(high);
+                        }
+                        CaseLabel::Default => {}
+                    }
+                    for stmt in &case.body {
+                        stmt.for_each_expr(f);
+                    }
+                }
+            }
+            Stmt::Block(block) => {
+                for stmt in block {
+                    stmt.for_each_expr(f);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -345,6 +695,102 @@ impl fmt::Display for UnaryOp {
     }
 }
 
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Nested binary operands are parenthesized so the output is unambiguous
+        fn operand(f: &mut fmt::Formatter, expr: &Expr) -> fmt::Result {
+            match expr {
+                Expr::Binary { .. } | Expr::Assign { .. } | Expr::Ternary { .. } | Expr::Comma(_) => {
+                    write!(f, "({})", expr)
+                }
+                _ => write!(f, "{}", expr),
+            }
+        }
+
+        match self {
+            Expr::IntLiteral(n) => write!(f, "{}", n),
+            Expr::FloatLiteral(fl) => write!(f, "{}", fl),
+            Expr::StringLiteral(s) => write!(f, "\"{}\"", crate::lexer::escape(s)),
+            Expr::CharLiteral(c) => write!(f, "'{}'", *c as char),
+            Expr::BoolLiteral(true) => write!(f, "TRUE"),
+            Expr::BoolLiteral(false) => write!(f, "FALSE"),
+            Expr::Null => write!(f, "NULL"),
+            Expr::Ident(name) => write!(f, "{}", name),
+            Expr::Binary { op, left, right } => {
+                operand(f, left)?;
+                write!(f, " {} ", op)?;
+                operand(f, right)
+            }
+            Expr::Unary { op, expr } => match op {
+                UnaryOp::PostIncrement | UnaryOp::PostDecrement => {
+                    operand(f, expr)?;
+                    write!(f, "{}", op)
+                }
+                _ => {
+                    write!(f, "{}", op)?;
+                    operand(f, expr)
+                }
+            },
+            Expr::Assign { target, value } => write!(f, "{} = {}", target, value),
+            Expr::Call { func, args } => {
+                write!(f, "{}(", func)?;
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match arg {
+                        Expr::Comma(_) => write!(f, "({})", arg)?,
+                        _ => write!(f, "{}", arg)?,
+                    }
+                }
+                write!(f, ")")
+            }
+            Expr::Index { expr, index } => {
+                operand(f, expr)?;
+                write!(f, "[{}]", index)
+            }
+            Expr::Member { expr, member } => {
+                operand(f, expr)?;
+                write!(f, ".{}", member)
+            }
+            Expr::Arrow { expr, member } => {
+                operand(f, expr)?;
+                write!(f, "->{}", member)
+            }
+            Expr::Cast { expr, target_type } => {
+                write!(f, "({})", target_type)?;
+                operand(f, expr)
+            }
+            Expr::Sizeof(typ) => write!(f, "sizeof({})", typ),
+            Expr::Ternary { condition, then_expr, else_expr } => {
+                operand(f, condition)?;
+                write!(f, " ? {} : ", then_expr)?;
+                operand(f, else_expr)
+            }
+            Expr::Comma(exprs) => {
+                for (idx, expr) in exprs.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", expr)?;
+                }
+                Ok(())
+            }
+            Expr::DefaultArg => Ok(()),
+            Expr::InitList(exprs) => {
+                write!(f, "{{")?;
+                for (idx, expr) in exprs.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", expr)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
 // AST visitors for analysis and transformation
 pub trait Visitor {
     fn visit_program(&mut self, program: &Program) {
@@ -357,6 +803,7 @@ pub trait Visitor {
         match item {
             Item::FunctionDef(f) => self.visit_function(f),
             Item::ClassDef(c) => self.visit_class(c),
+            Item::EnumDef(e) => self.visit_enum(e),
             Item::GlobalVar(v) => self.visit_var_decl(v),
             Item::Define(_) => {}
             Item::Include(_) => {}
@@ -365,6 +812,7 @@ pub trait Visitor {
 
     fn visit_function(&mut self, _func: &FunctionDef) {}
     fn visit_class(&mut self, _class: &ClassDef) {}
+    fn visit_enum(&mut self, _enum_def: &EnumDef) {}
     fn visit_var_decl(&mut self, _var: &VarDecl) {}
     fn visit_stmt(&mut self, _stmt: &Stmt) {}
     fn visit_expr(&mut self, _expr: &Expr) {}
@@ -0,0 +1,255 @@
+//! Whole-program call graph and call-depth analysis
+//!
+//! Only calls between functions defined in the program are tracked; syscalls
+//! run natively and do not consume a VM frame. A call through a function
+//! pointer may reach any function whose address is taken.
+use crate::ast::*;
+use crate::syscalls;
+use crate::target::Target;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Name of the function the loader jumps to, when the program defines one
+pub const ENTRYPOINT: &str = "entrypoint";
+
+#[derive(Debug, Clone, Default)]
+pub struct CallGraph {
+    calls: BTreeMap<String, BTreeSet<String>>,
+    address_taken: BTreeSet<String>,
+}
+
+/// Result of the call-depth analysis
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CallDepth {
+    /// Frames on the deepest call chain, counting the root itself
+    pub max_depth: usize,
+    pub deepest_path: Vec<String>,
+    /// Recursive cycles, e.g. `[f, g, f]`; these make the depth unbounded
+    pub cycles: Vec<Vec<String>>,
+}
+
+impl CallGraph {
+    pub fn from_program(program: &Program) -> Self {
+        let mut graph = Self::default();
+        for item in &program.items {
+            if let Item::FunctionDef(func) = item {
+                graph.calls.insert(func.name.clone(), BTreeSet::new());
+            }
+        }
+
+        // A function named anywhere but as a direct callee has its address taken
+        let mut mentions: HashMap<String, usize> = HashMap::new();
+        let mut direct_calls: HashMap<String, usize> = HashMap::new();
+        let mut indirect_callers = BTreeSet::new();
+        for item in &program.items {
+            if let Item::FunctionDef(func) = item {
+                let mut callees = BTreeSet::new();
+                for stmt in &func.body {
+                    stmt.for_each_expr(&mut |expr| {
+                        expr.walk(&mut |e| match e {
+                            Expr::Call { func: callee, .. } => match &**callee {
+                                Expr::Ident(name) if graph.calls.contains_key(name) => {
+                                    *direct_calls.entry(name.clone()).or_default() += 1;
+                                    callees.insert(name.clone());
+                                }
+                                Expr::Ident(name) if syscalls::is_syscall(name) => {}
+                                _ => {
+                                    indirect_callers.insert(func.name.clone());
+                                }
+                            },
+                            Expr::Ident(name) if graph.calls.contains_key(name) => {
+                                *mentions.entry(name.clone()).or_default() += 1;
+                            }
+                            _ => {}
+                        })
+                    });
+                }
+                graph.calls.insert(func.name.clone(), callees);
+            }
+        }
+        for item in &program.items {
+            if let Item::GlobalVar(VarDecl { init: Some(init), .. }) = item {
+                init.walk(&mut |e| if let Expr::Ident(name) = e {
+                    if graph.calls.contains_key(name) {
+                        *mentions.entry(name.clone()).or_default() += 1;
+                    }
+                });
+            }
+        }
+
+        graph.address_taken = mentions.into_iter()
+            .filter(|(name, count)| *count > direct_calls.get(name).copied().unwrap_or(0))
+            .map(|(name, _)| name)
+            .collect();
+        for caller in indirect_callers {
+            graph.calls.entry(caller).or_default().extend(graph.address_taken.iter().cloned());
+        }
+        graph
+    }
+
+    /// Functions that may be called through a function pointer
+    pub fn address_taken(&self) -> &BTreeSet<String> {
+        &self.address_taken
+    }
+
+    pub fn callees(&self, function: &str) -> impl Iterator<Item = &String> {
+        self.calls.get(function).into_iter().flatten()
+    }
+
+    /// The entrypoint if defined, otherwise every function nobody calls
+    pub fn roots(&self) -> Vec<String> {
+        if self.calls.contains_key(ENTRYPOINT) {
+            return vec![ENTRYPOINT.to_string()];
+        }
+        let called: BTreeSet<&String> = self.calls.values().flatten().collect();
+        let roots: Vec<String> = self.calls.keys().filter(|f| !called.contains(f)).cloned().collect();
+        if roots.is_empty() {
+            self.calls.keys().cloned().collect()
+        } else {
+            roots
+        }
+    }
+
+    /// Deepest call chain from any root, ignoring recursive back edges
+    pub fn call_depth(&self) -> CallDepth {
+        let mut memo = HashMap::new();
+        let mut stack = Vec::new();
+        let mut result = CallDepth::default();
+
+        for root in self.roots() {
+            let path = self.deepest_from(&root, &mut stack, &mut memo, &mut result.cycles);
+            if path.len() > result.max_depth {
+                result.max_depth = path.len();
+                result.deepest_path = path;
+            }
+        }
+        result
+    }
+
+    /// Fail when the deepest call chain needs more frames than the target has
+    pub fn check(&self, target: &Target) -> Result<CallDepth> {
+        let depth = self.call_depth();
+        if depth.max_depth > target.max_call_depth {
+            return Err(anyhow!(
+                "Call depth {} exceeds the target limit of {} frames: {}",
+                depth.max_depth,
+                target.max_call_depth,
+                depth.deepest_path.join(" -> ")
+            ));
+        }
+        Ok(depth)
+    }
+
+    fn deepest_from(
+        &self,
+        function: &str,
+        stack: &mut Vec<String>,
+        memo: &mut HashMap<String, Vec<String>>,
+        cycles: &mut Vec<Vec<String>>,
+    ) -> Vec<String> {
+        if let Some(start) = stack.iter().position(|f| f == function) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(function.to_string());
+            if !cycles.contains(&cycle) {
+                cycles.push(cycle);
+            }
+            return Vec::new();
+        }
+        if let Some(path) = memo.get(function) {
+            return path.clone();
+        }
+
+        stack.push(function.to_string());
+        let mut deepest = Vec::new();
+        for callee in self.callees(function) {
+            let path = self.deepest_from(callee, stack, memo, cycles);
+            if path.len() > deepest.len() {
+                deepest = path;
+            }
+        }
+        stack.pop();
+
+        let mut path = vec![function.to_string()];
+        path.extend(deepest);
+        memo.insert(function.to_string(), path.clone());
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+    use crate::parser::Parser;
+
+    fn graph(source: &str) -> CallGraph {
+        let tokens: Vec<Token> = Lexer::collect_tokens(source).unwrap()
+            .into_iter().map(|(t, _)| t).collect();
+        CallGraph::from_program(&Parser::new(tokens).parse().unwrap())
+    }
+
+    #[test]
+    fn test_deepest_chain() {
+        let graph = graph(
+            "U64 leaf() { return 1; }
+             U64 mid() { return leaf() + 1; }
+             U64 entrypoint(U64 input) { U64 a = leaf(); return mid() + a; }",
+        );
+        let depth = graph.call_depth();
+        assert_eq!(depth.max_depth, 3);
+        assert_eq!(depth.deepest_path, vec!["entrypoint", "mid", "leaf"]);
+        assert!(depth.cycles.is_empty());
+    }
+
+    #[test]
+    fn test_indirect_calls_reach_address_taken_functions() {
+        let graph = graph(
+            "U64 on_init(U64 x) { return x; }
+             U64 on_close(U64 x) { return on_init(x); }
+             U64 unused(U64 x) { return x; }
+             U64 entrypoint(U64 input) {
+                 U64 (*handlers[2])(U64);
+                 handlers[0] = on_init;
+                 handlers[1] = &on_close;
+                 return handlers[input](input);
+             }",
+        );
+        let callees: Vec<&String> = graph.callees(ENTRYPOINT).collect();
+        assert_eq!(callees, ["on_close", "on_init"]);
+        assert_eq!(graph.call_depth().max_depth, 3);
+    }
+
+    #[test]
+    fn test_global_initializers_take_addresses() {
+        let graph = graph(
+            "U64 add(U64 x) { return x; }
+             U64 unused(U64 x) { return x; }
+             U64 (*ops[1])(U64) = {add};
+             U64 entrypoint(U64 input) { return ops[0](input); }",
+        );
+        let callees: Vec<&String> = graph.callees(ENTRYPOINT).collect();
+        assert_eq!(callees, ["add"]);
+    }
+
+    #[test]
+    fn test_recursion_is_reported() {
+        let graph = graph(
+            "U64 even(U64 n) { if (n == 0) { return 1; } return odd(n - 1); }
+             U64 odd(U64 n) { if (n == 0) { return 0; } return even(n - 1); }
+             U64 entrypoint(U64 input) { return even(input); }",
+        );
+        let depth = graph.call_depth();
+        assert_eq!(depth.cycles, vec![vec!["even", "odd", "even"]]);
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let mut source = String::from("U64 f0() { return 0; }\n");
+        for i in 1..70 {
+            source.push_str(&format!("U64 f{}() {{ return f{}(); }}\n", i, i - 1));
+        }
+        let err = graph(&source).check(&Target::sbf()).unwrap_err().to_string();
+        assert!(err.contains("Call depth 70 exceeds the target limit of 64"));
+        assert!(err.contains("f69 -> f68"));
+    }
+}
@@ -0,0 +1,493 @@
+//! Control-flow graph over HolyC statements
+//!
+//! Structured statements are lowered into basic blocks of straight-line
+//! statements (`VarDecl` and `Expr`) joined by explicit terminators. The
+//! graph is used for analyses such as dominance and natural-loop detection.
+use crate::ast::*;
+use std::collections::{BTreeSet, HashMap};
+
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        condition: Expr,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+    /// Jump to the target of the case matching `value`; the last target is
+    /// taken when no case matches
+    Switch {
+        value: Expr,
+        targets: Vec<BlockId>,
+    },
+    Return(Option<Expr>),
+    /// Control leaves the lowered statements
+    Exit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub stmts: Vec<Stmt>,
+    pub terminator: Terminator,
+}
+
+/// A loop found through a back edge `latch -> header`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NaturalLoop {
+    pub header: BlockId,
+    pub latches: Vec<BlockId>,
+    pub blocks: BTreeSet<BlockId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub entry: BlockId,
+    /// Header block of each lowered `while`/`do`/`for`, in source order
+    pub loop_headers: Vec<BlockId>,
+    /// Block starting at each label
+    pub labels: HashMap<String, BlockId>,
+}
+
+/// Targets of `break` and `continue` inside the loop or switch being
+/// lowered. A switch has no `continue` target of its own.
+struct LoopTargets {
+    break_target: BlockId,
+    continue_target: Option<BlockId>,
+}
+
+struct Builder {
+    blocks: Vec<BasicBlock>,
+    loops: Vec<LoopTargets>,
+    loop_headers: Vec<BlockId>,
+    /// Block starting at each label, created by its first `goto` or the label
+    labels: HashMap<String, BlockId>,
+}
+
+impl Cfg {
+    pub fn build(stmts: &[Stmt]) -> Self {
+        let mut builder = Builder {
+            blocks: Vec::new(),
+            loops: Vec::new(),
+            loop_headers: Vec::new(),
+            labels: HashMap::new(),
+        };
+        let entry = builder.new_block();
+        let exit = builder.lower_block(stmts, entry);
+        builder.terminate(exit, Terminator::Exit);
+
+        Cfg {
+            blocks: builder.blocks,
+            entry,
+            loop_headers: builder.loop_headers,
+            labels: builder.labels,
+        }
+    }
+
+    pub fn successors(&self, block: BlockId) -> Vec<BlockId> {
+        match &self.blocks[block].terminator {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { then_block, else_block, .. } => vec![*then_block, *else_block],
+            Terminator::Switch { targets, .. } => targets.clone(),
+            Terminator::Return(_) | Terminator::Exit => Vec::new(),
+        }
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for block in 0..self.blocks.len() {
+            for succ in self.successors(block) {
+                preds[succ].push(block);
+            }
+        }
+        preds
+    }
+
+    /// Blocks reachable from the entry block
+    pub fn reachable(&self) -> BTreeSet<BlockId> {
+        let mut seen = BTreeSet::new();
+        let mut worklist = vec![self.entry];
+        while let Some(block) = worklist.pop() {
+            if seen.insert(block) {
+                worklist.extend(self.successors(block));
+            }
+        }
+        seen
+    }
+
+    /// Dominator sets: `dom[b]` holds every block that dominates `b`.
+    /// Unreachable blocks get an empty set.
+    pub fn dominators(&self) -> Vec<BTreeSet<BlockId>> {
+        let reachable = self.reachable();
+        let preds = self.predecessors();
+        let mut dom: Vec<BTreeSet<BlockId>> = (0..self.blocks.len())
+            .map(|b| {
+                if b == self.entry {
+                    BTreeSet::from([b])
+                } else if reachable.contains(&b) {
+                    reachable.clone()
+                } else {
+                    BTreeSet::new()
+                }
+            })
+            .collect();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &reachable {
+                if block == self.entry {
+                    continue;
+                }
+                let mut new_dom: Option<BTreeSet<BlockId>> = None;
+                for pred in preds[block].iter().filter(|p| reachable.contains(p)) {
+                    new_dom = Some(match new_dom {
+                        None => dom[*pred].clone(),
+                        Some(acc) => acc.intersection(&dom[*pred]).copied().collect(),
+                    });
+                }
+                let mut new_dom = new_dom.unwrap_or_default();
+                new_dom.insert(block);
+                if new_dom != dom[block] {
+                    dom[block] = new_dom;
+                    changed = true;
+                }
+            }
+        }
+
+        dom
+    }
+
+    /// Natural loops keyed by header; back edges sharing a header are merged
+    pub fn natural_loops(&self) -> Vec<NaturalLoop> {
+        let dom = self.dominators();
+        let preds = self.predecessors();
+        let mut loops: Vec<NaturalLoop> = Vec::new();
+
+        for (latch, latch_dom) in dom.iter().enumerate() {
+            for header in self.successors(latch) {
+                if !latch_dom.contains(&header) {
+                    continue;
+                }
+
+                let mut blocks = BTreeSet::from([header]);
+                let mut worklist = vec![latch];
+                while let Some(block) = worklist.pop() {
+                    if blocks.insert(block) {
+                        worklist.extend(preds[block].iter().copied());
+                    }
+                }
+
+                match loops.iter_mut().find(|l| l.header == header) {
+                    Some(existing) => {
+                        existing.latches.push(latch);
+                        existing.blocks.extend(blocks);
+                    }
+                    None => loops.push(NaturalLoop {
+                        header,
+                        latches: vec![latch],
+                        blocks,
+                    }),
+                }
+            }
+        }
+
+        loops
+    }
+
+    /// Statements and terminator conditions of the given blocks
+    pub fn block_exprs<'a>(&'a self, blocks: &BTreeSet<BlockId>) -> Vec<&'a Expr> {
+        let mut exprs = Vec::new();
+        for &block in blocks {
+            for stmt in &self.blocks[block].stmts {
+                match stmt {
+                    Stmt::Expr(expr) => exprs.push(expr),
+                    Stmt::VarDecl(VarDecl { init: Some(init), .. }) => exprs.push(init),
+                    _ => {}
+                }
+            }
+            match &self.blocks[block].terminator {
+                Terminator::Branch { condition, .. } | Terminator::Switch { value: condition, .. } => exprs.push(condition),
+                Terminator::Return(Some(expr)) => exprs.push(expr),
+                _ => {}
+            }
+        }
+        exprs
+    }
+}
+
+impl Builder {
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push(BasicBlock {
+            stmts: Vec::new(),
+            terminator: Terminator::Exit,
+        });
+        self.blocks.len() - 1
+    }
+
+    fn terminate(&mut self, block: BlockId, terminator: Terminator) {
+        self.blocks[block].terminator = terminator;
+    }
+
+    fn label_block(&mut self, label: &str) -> BlockId {
+        if let Some(&block) = self.labels.get(label) {
+            return block;
+        }
+        let block = self.new_block();
+        self.labels.insert(label.to_string(), block);
+        block
+    }
+
+    /// Lower `stmts` starting in `current`, returning the block where
+    /// control continues afterwards
+    fn lower_block(&mut self, stmts: &[Stmt], mut current: BlockId) -> BlockId {
+        for stmt in stmts {
+            current = self.lower_stmt(stmt, current);
+        }
+        current
+    }
+
+    fn lower_stmt(&mut self, stmt: &Stmt, current: BlockId) -> BlockId {
+        match stmt {
+            Stmt::VarDecl(_) | Stmt::Expr(_) | Stmt::Asm(_) | Stmt::Print { .. } => {
+                self.blocks[current].stmts.push(stmt.clone());
+                current
+            }
+
+            Stmt::Block(block) => self.lower_block(block, current),
+
+            Stmt::Return(value) => {
+                self.terminate(current, Terminator::Return(value.clone()));
+                // Anything after a return is unreachable
+                self.new_block()
+            }
+
+            Stmt::Break | Stmt::Continue => {
+                let target = if matches!(stmt, Stmt::Break) {
+                    self.loops.last().map(|targets| targets.break_target)
+                } else {
+                    self.loops.iter().rev().find_map(|targets| targets.continue_target)
+                };
+                let Some(target) = target else {
+                    return current;
+                };
+                self.terminate(current, Terminator::Jump(target));
+                self.new_block()
+            }
+
+            Stmt::If { condition, then_block, else_block } => {
+                let then_start = self.new_block();
+                let join = self.new_block();
+                let else_start = if else_block.is_some() { self.new_block() } else { join };
+
+                self.terminate(current, Terminator::Branch {
+                    condition: condition.clone(),
+                    then_block: then_start,
+                    else_block: else_start,
+                });
+
+                let then_end = self.lower_block(then_block, then_start);
+                self.terminate(then_end, Terminator::Jump(join));
+
+                if let Some(else_block) = else_block {
+                    let else_end = self.lower_block(else_block, else_start);
+                    self.terminate(else_end, Terminator::Jump(join));
+                }
+
+                join
+            }
+
+            Stmt::While { condition, body } => {
+                let header = self.new_block();
+                let body_start = self.new_block();
+                let exit = self.new_block();
+                self.loop_headers.push(header);
+
+                self.terminate(current, Terminator::Jump(header));
+                self.terminate(header, Terminator::Branch {
+                    condition: condition.clone(),
+                    then_block: body_start,
+                    else_block: exit,
+                });
+
+                self.loops.push(LoopTargets { break_target: exit, continue_target: Some(header) });
+                let body_end = self.lower_block(body, body_start);
+                self.loops.pop();
+                self.terminate(body_end, Terminator::Jump(header));
+
+                exit
+            }
+
+            Stmt::DoWhile { body, condition } => {
+                let body_start = self.new_block();
+                let latch = self.new_block();
+                let exit = self.new_block();
+                self.loop_headers.push(body_start);
+
+                self.terminate(current, Terminator::Jump(body_start));
+                self.loops.push(LoopTargets { break_target: exit, continue_target: Some(latch) });
+                let body_end = self.lower_block(body, body_start);
+                self.loops.pop();
+                self.terminate(body_end, Terminator::Jump(latch));
+                self.terminate(latch, Terminator::Branch {
+                    condition: condition.clone(),
+                    then_block: body_start,
+                    else_block: exit,
+                });
+
+                exit
+            }
+
+            Stmt::Goto(label) => {
+                let target = self.label_block(label);
+                self.terminate(current, Terminator::Jump(target));
+                self.new_block()
+            }
+
+            Stmt::Label(label) => {
+                let block = self.label_block(label);
+                self.terminate(current, Terminator::Jump(block));
+                block
+            }
+
+            Stmt::For { init, condition, increment, body } => {
+                let current = match init {
+                    Some(init) => self.lower_stmt(init, current),
+                    None => current,
+                };
+
+                let header = self.new_block();
+                let body_start = self.new_block();
+                let latch = self.new_block();
+                let exit = self.new_block();
+                self.loop_headers.push(header);
+
+                self.terminate(current, Terminator::Jump(header));
+                match condition {
+                    Some(condition) => self.terminate(header, Terminator::Branch {
+                        condition: condition.clone(),
+                        then_block: body_start,
+                        else_block: exit,
+                    }),
+                    None => self.terminate(header, Terminator::Jump(body_start)),
+                }
+
+                self.loops.push(LoopTargets { break_target: exit, continue_target: Some(latch) });
+                let body_end = self.lower_block(body, body_start);
+                self.loops.pop();
+                self.terminate(body_end, Terminator::Jump(latch));
+
+                if let Some(increment) = increment {
+                    self.blocks[latch].stmts.push(Stmt::Expr(increment.clone()));
+                }
+                self.terminate(latch, Terminator::Jump(header));
+
+                exit
+            }
+
+            Stmt::Switch { value, cases } => {
+                let starts: Vec<BlockId> = cases.iter().map(|_| self.new_block()).collect();
+                let exit = self.new_block();
+                let default = cases.iter().position(|case| case.label == CaseLabel::Default)
+                    .map_or(exit, |idx| starts[idx]);
+
+                let mut targets = starts.clone();
+                targets.push(default);
+                self.terminate(current, Terminator::Switch { value: value.clone(), targets });
+
+                // Each case falls through into the next
+                self.loops.push(LoopTargets { break_target: exit, continue_target: None });
+                for (idx, case) in cases.iter().enumerate() {
+                    let end = self.lower_block(&case.body, starts[idx]);
+                    self.terminate(end, Terminator::Jump(starts.get(idx + 1).copied().unwrap_or(exit)));
+                }
+                self.loops.pop();
+
+                exit
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+    use crate::parser::Parser;
+
+    fn function_body(source: &str) -> Block {
+        let tokens: Vec<Token> = Lexer::collect_tokens(source).unwrap()
+            .into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+        match &program.items[0] {
+            Item::FunctionDef(func) => func.body.clone(),
+            _ => panic!("Expected function definition"),
+        }
+    }
+
+    #[test]
+    fn test_while_loop_is_natural_loop() {
+        let body = function_body("U64 f(U64 n) { U64 i = 0; while (i < n) { i = i + 1; } return i; }");
+        let cfg = Cfg::build(&body);
+        let loops = cfg.natural_loops();
+
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].header, cfg.loop_headers[0]);
+        assert!(cfg.dominators()[loops[0].latches[0]].contains(&loops[0].header));
+    }
+
+    #[test]
+    fn test_continue_targets_for_latch() {
+        let body = function_body(
+            "U64 f(U64 n) { U64 s = 0; for (U64 i = 0; i < n; i++) { if (i == 3) { continue; } s += i; } return s; }",
+        );
+        let cfg = Cfg::build(&body);
+        let loops = cfg.natural_loops();
+        assert_eq!(loops.len(), 1);
+
+        // The latch holds the increment and is reached both from the end of
+        // the body and from the `continue`
+        let latch = loops[0].latches[0];
+        assert!(matches!(cfg.blocks[latch].stmts[0], Stmt::Expr(Expr::Unary { op: UnaryOp::PostIncrement, .. })));
+        assert_eq!(cfg.predecessors()[latch].len(), 2);
+    }
+
+    #[test]
+    fn test_code_after_return_is_unreachable() {
+        let body = function_body("U64 f() { return 1; U64 x = 2; }");
+        let cfg = Cfg::build(&body);
+        let reachable = cfg.reachable();
+        assert!(cfg.blocks.iter().enumerate()
+            .any(|(id, block)| !block.stmts.is_empty() && !reachable.contains(&id)));
+    }
+
+    #[test]
+    fn test_switch_cases_fall_through() {
+        let body = function_body(
+            "U64 f(U64 n) { U64 s = 0; switch (n) { case 1: s = 1; case 2: s++; break; default: s = 9; } return s; }",
+        );
+        let cfg = Cfg::build(&body);
+        let Terminator::Switch { targets, .. } = &cfg.blocks[cfg.entry].terminator else {
+            panic!("Expected a switch terminator");
+        };
+        // One target per case plus the default, which is the last case
+        assert_eq!(targets.len(), 4);
+        assert_eq!(targets[3], targets[2]);
+        assert_eq!(cfg.successors(targets[0]), [targets[1]]);
+        // `break` leaves the switch and skips the default case
+        assert_ne!(cfg.successors(targets[1]), [targets[2]]);
+    }
+
+    #[test]
+    fn test_backward_goto_forms_loop() {
+        let body = function_body("U64 f(U64 n) { again: n--; if (n > 0) { goto again; } do { n++; } while (n < 9); return n; }");
+        let cfg = Cfg::build(&body);
+        let loops = cfg.natural_loops();
+        // The goto loop has no `loop_headers` entry; the do-while does
+        assert_eq!(loops.len(), 2);
+        assert_eq!(cfg.loop_headers.len(), 1);
+        assert!(loops.iter().any(|l| l.header == cfg.loop_headers[0] && l.blocks.len() == 2));
+    }
+}
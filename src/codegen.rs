@@ -1,6 +1,19 @@
+use crate::asm;
 use crate::ast::*;
+use crate::callgraph::{CallDepth, CallGraph, ENTRYPOINT};
+use crate::consteval::{self, Sign};
+use crate::frame::{FrameLayout, SlotKind};
+use crate::disasm;
+use crate::elf::ElfProgram;
+use crate::format::{self, Conversion, Piece};
+use crate::layout::{self, LayoutEngine};
+use crate::loop_opt::CanonicalLoop;
+use crate::rodata::Rodata;
+use crate::switch::{Dispatch, SwitchPlan};
+use crate::syscalls;
+use crate::target::{CallxEncoding, Target};
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 /// BPF register allocation
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -18,11 +31,20 @@ pub enum BpfReg {
     R10 = 10, // Stack pointer (read-only)
 }
 
+impl BpfReg {
+    /// Registers indexed by number
+    pub const ALL: [BpfReg; 11] = [
+        BpfReg::R0, BpfReg::R1, BpfReg::R2, BpfReg::R3, BpfReg::R4, BpfReg::R5,
+        BpfReg::R6, BpfReg::R7, BpfReg::R8, BpfReg::R9, BpfReg::R10,
+    ];
+}
+
 /// BPF instruction opcodes
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum BpfOpcode {
     // Load/Store
+    Lddw = 0x18,     // Load 64-bit immediate (two instruction slots)
     LdXDW = 0x79,    // Load double word
     StXDW = 0x7b,    // Store double word
     LdXW = 0x61,     // Load word
@@ -31,6 +53,10 @@ pub enum BpfOpcode {
     StXH = 0x6b,     // Store half word
     LdXB = 0x71,     // Load byte
     StXB = 0x73,     // Store byte
+    StDW = 0x7a,     // Store immediate double word
+    StW = 0x62,      // Store immediate word
+    StH = 0x6a,      // Store immediate half word
+    StB = 0x72,      // Store immediate byte
 
     // ALU64
     Add64Imm = 0x07,  // Add immediate
@@ -45,19 +71,19 @@ pub enum BpfOpcode {
     Or64Reg = 0x4f,   // OR register
     And64Imm = 0x57,  // AND immediate
     And64Reg = 0x5f,  // AND register
-    Lsh64Imm = 0x77,  // Left shift immediate
-    Lsh64Reg = 0x7f,  // Left shift register
-    Rsh64Imm = 0x87,  // Right shift immediate (logical)
-    Rsh64Reg = 0x8f,  // Right shift register (logical)
-    Neg64 = 0x97,     // Negate
-    Mod64Imm = 0xa7,  // Modulo immediate
-    Mod64Reg = 0xaf,  // Modulo register
-    Xor64Imm = 0xb7,  // XOR immediate
-    Xor64Reg = 0xbf,  // XOR register
-    Mov64Imm = 0xd7,  // Move immediate
-    Mov64Reg = 0xdf,  // Move register
-    Arsh64Imm = 0xe7, // Arithmetic right shift immediate
-    Arsh64Reg = 0xef, // Arithmetic right shift register
+    Lsh64Imm = 0x67,  // Left shift immediate
+    Lsh64Reg = 0x6f,  // Left shift register
+    Rsh64Imm = 0x77,  // Right shift immediate (logical)
+    Rsh64Reg = 0x7f,  // Right shift register (logical)
+    Neg64 = 0x87,     // Negate
+    Mod64Imm = 0x97,  // Modulo immediate
+    Mod64Reg = 0x9f,  // Modulo register
+    Xor64Imm = 0xa7,  // XOR immediate
+    Xor64Reg = 0xaf,  // XOR register
+    Mov64Imm = 0xb7,  // Move immediate
+    Mov64Reg = 0xbf,  // Move register
+    Arsh64Imm = 0xc7, // Arithmetic right shift immediate
+    Arsh64Reg = 0xcf, // Arithmetic right shift register
 
     // ALU32 (same pattern with 0x04 base instead of 0x07)
     Add32Imm = 0x04,
@@ -68,6 +94,25 @@ pub enum BpfOpcode {
     Mul32Reg = 0x2c,
     Div32Imm = 0x34,
     Div32Reg = 0x3c,
+    Or32Imm = 0x44,
+    Or32Reg = 0x4c,
+    And32Imm = 0x54,
+    And32Reg = 0x5c,
+    Lsh32Imm = 0x64,
+    Lsh32Reg = 0x6c,
+    Rsh32Imm = 0x74,
+    Rsh32Reg = 0x7c,
+    Neg32 = 0x84,
+    Mod32Imm = 0x94,
+    Mod32Reg = 0x9c,
+    Xor32Imm = 0xa4,
+    Xor32Reg = 0xac,
+    Mov32Imm = 0xb4,
+    Mov32Reg = 0xbc,
+    Arsh32Imm = 0xc4,
+    Arsh32Reg = 0xcc,
+    Le = 0xd4,        // Convert to little endian (imm = 16, 32 or 64)
+    Be = 0xdc,        // Convert to big endian (imm = 16, 32 or 64)
 
     // Jumps
     Ja = 0x05,        // Jump always
@@ -77,6 +122,8 @@ pub enum BpfOpcode {
     JgtReg = 0x2d,    // Jump if greater than register
     JgeImm = 0x35,    // Jump if greater or equal immediate
     JgeReg = 0x3d,    // Jump if greater or equal register
+    JsetImm = 0x45,   // Jump if any bit set immediate
+    JsetReg = 0x4d,   // Jump if any bit set register
     JneImm = 0x55,    // Jump if not equal immediate
     JneReg = 0x5d,    // Jump if not equal register
     JsgtImm = 0x65,   // Jump if signed greater than immediate
@@ -93,10 +140,49 @@ pub enum BpfOpcode {
     JsleReg = 0xdd,   // Jump if signed less or equal register
 
     // Call/Exit
-    Call = 0x85,      // Function call
+    Call = 0x85,      // Function call (src = 0: syscall, src = 1: pc-relative)
+    Callx = 0x8d,     // Call the address in a register
     Exit = 0x95,      // Exit program
 }
 
+impl BpfOpcode {
+    /// Every opcode of the SBF v1 instruction set
+    pub const ALL: [BpfOpcode; 91] = {
+        use BpfOpcode::*;
+        [
+            Lddw, LdXDW, StXDW, LdXW, StXW, LdXH, StXH, LdXB, StXB, StDW, StW, StH, StB,
+            Add64Imm, Add64Reg, Sub64Imm, Sub64Reg, Mul64Imm, Mul64Reg, Div64Imm, Div64Reg,
+            Or64Imm, Or64Reg, And64Imm, And64Reg, Lsh64Imm, Lsh64Reg, Rsh64Imm, Rsh64Reg,
+            Neg64, Mod64Imm, Mod64Reg, Xor64Imm, Xor64Reg, Mov64Imm, Mov64Reg, Arsh64Imm, Arsh64Reg,
+            Add32Imm, Add32Reg, Sub32Imm, Sub32Reg, Mul32Imm, Mul32Reg, Div32Imm, Div32Reg,
+            Or32Imm, Or32Reg, And32Imm, And32Reg, Lsh32Imm, Lsh32Reg, Rsh32Imm, Rsh32Reg,
+            Neg32, Mod32Imm, Mod32Reg, Xor32Imm, Xor32Reg, Mov32Imm, Mov32Reg, Arsh32Imm, Arsh32Reg,
+            Le, Be,
+            Ja, JeqImm, JeqReg, JgtImm, JgtReg, JgeImm, JgeReg, JsetImm, JsetReg, JneImm, JneReg,
+            JsgtImm, JsgtReg, JsgeImm, JsgeReg, JltImm, JltReg, JleImm, JleReg,
+            JsltImm, JsltReg, JsleImm, JsleReg,
+            Call, Callx, Exit,
+        ]
+    };
+
+    pub fn from_u8(opcode: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|op| *op as u8 == opcode)
+    }
+
+    /// Conditional and unconditional jumps, which carry a pc-relative offset
+    pub fn is_jump(self) -> bool {
+        let code = self as u8;
+        code & 0x07 == 0x05 && !matches!(self, BpfOpcode::Call | BpfOpcode::Callx | BpfOpcode::Exit)
+    }
+
+    /// Instructions that write their destination register
+    pub fn writes_dst(self) -> bool {
+        let class = self as u8 & 0x07;
+        class == 0x04 || class == 0x07 || matches!(self, BpfOpcode::Lddw | BpfOpcode::LdXDW
+            | BpfOpcode::LdXW | BpfOpcode::LdXH | BpfOpcode::LdXB)
+    }
+}
+
 /// BPF instruction (8 bytes)
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
@@ -177,6 +263,39 @@ impl BpfInstruction {
         Self::new(BpfOpcode::StXDW, dst, src, offset, 0)
     }
 
+    /// Load `size` bytes (1, 2, 4 or 8) zero-extended into `dst`
+    pub fn ldx(size: usize, dst: BpfReg, src: BpfReg, offset: i16) -> Self {
+        let opcode = match size {
+            1 => BpfOpcode::LdXB,
+            2 => BpfOpcode::LdXH,
+            4 => BpfOpcode::LdXW,
+            _ => BpfOpcode::LdXDW,
+        };
+        Self::new(opcode, dst, src, offset, 0)
+    }
+
+    /// Store the low `size` bytes (1, 2, 4 or 8) of `src`
+    pub fn stx(size: usize, dst: BpfReg, src: BpfReg, offset: i16) -> Self {
+        let opcode = match size {
+            1 => BpfOpcode::StXB,
+            2 => BpfOpcode::StXH,
+            4 => BpfOpcode::StXW,
+            _ => BpfOpcode::StXDW,
+        };
+        Self::new(opcode, dst, src, offset, 0)
+    }
+
+    /// Store the low `size` bytes (1, 2, 4 or 8) of `imm`, sign-extended
+    pub fn st(size: usize, dst: BpfReg, offset: i16, imm: i32) -> Self {
+        let opcode = match size {
+            1 => BpfOpcode::StB,
+            2 => BpfOpcode::StH,
+            4 => BpfOpcode::StW,
+            _ => BpfOpcode::StDW,
+        };
+        Self::new(opcode, dst, BpfReg::R0, offset, imm)
+    }
+
     pub fn jeq_imm(dst: BpfReg, imm: i32, offset: i16) -> Self {
         Self::new(BpfOpcode::JeqImm, dst, BpfReg::R0, offset, imm)
     }
@@ -185,6 +304,14 @@ impl BpfInstruction {
         Self::new(BpfOpcode::JneImm, dst, BpfReg::R0, offset, imm)
     }
 
+    pub fn jeq_reg(dst: BpfReg, src: BpfReg, offset: i16) -> Self {
+        Self::new(BpfOpcode::JeqReg, dst, src, offset, 0)
+    }
+
+    pub fn jne_reg(dst: BpfReg, src: BpfReg, offset: i16) -> Self {
+        Self::new(BpfOpcode::JneReg, dst, src, offset, 0)
+    }
+
     pub fn jgt_reg(dst: BpfReg, src: BpfReg, offset: i16) -> Self {
         Self::new(BpfOpcode::JgtReg, dst, src, offset, 0)
     }
@@ -205,14 +332,45 @@ impl BpfInstruction {
         Self::new(BpfOpcode::Ja, BpfReg::R0, BpfReg::R0, offset, 0)
     }
 
-    pub fn call(func_id: i32) -> Self {
-        Self::new(BpfOpcode::Call, BpfReg::R0, BpfReg::R0, 0, func_id)
+    /// Load a 64-bit immediate; occupies two instruction slots
+    pub fn lddw(dst: BpfReg, imm: u64) -> [Self; 2] {
+        [
+            Self::new(BpfOpcode::Lddw, dst, BpfReg::R0, 0, imm as u32 as i32),
+            Self { opcode: 0, dst_src: 0, offset: 0, imm: (imm >> 32) as u32 as i32 },
+        ]
+    }
+
+    /// Call a function `offset` instructions after the next one
+    pub fn call_internal(offset: i32) -> Self {
+        Self::new(BpfOpcode::Call, BpfReg::R0, BpfReg::R1, 0, offset)
+    }
+
+    /// Call the address held in `reg`
+    pub fn callx(reg: BpfReg, encoding: CallxEncoding) -> Self {
+        match encoding {
+            CallxEncoding::Imm => Self::new(BpfOpcode::Callx, BpfReg::R0, BpfReg::R0, 0, reg as i32),
+            CallxEncoding::Src => Self::new(BpfOpcode::Callx, BpfReg::R0, reg, 0, 0),
+        }
+    }
+
+    /// Call the syscall with the given name hash
+    pub fn syscall(hash: u32) -> Self {
+        Self::new(BpfOpcode::Call, BpfReg::R0, BpfReg::R0, 0, hash as i32)
     }
 
     pub fn exit() -> Self {
         Self::new(BpfOpcode::Exit, BpfReg::R0, BpfReg::R0, 0, 0)
     }
 
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self {
+            opcode: bytes[0],
+            dst_src: bytes[1],
+            offset: i16::from_le_bytes([bytes[2], bytes[3]]),
+            imm: i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+
     pub fn to_bytes(&self) -> [u8; 8] {
         let mut bytes = [0u8; 8];
         bytes[0] = self.opcode;
@@ -223,41 +381,244 @@ impl BpfInstruction {
     }
 }
 
+/// Scratch registers used for expression evaluation. The VM preserves
+/// R6-R9 across calls, so temporaries survive nested calls.
+const SCRATCH_REGS: [BpfReg; 4] = [BpfReg::R6, BpfReg::R7, BpfReg::R8, BpfReg::R9];
+
+/// Argument registers in calling-convention order
+const ARG_REGS: [BpfReg; 5] = [BpfReg::R1, BpfReg::R2, BpfReg::R3, BpfReg::R4, BpfReg::R5];
+
+/// Where a call argument is passed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArgSlot {
+    Reg(BpfReg),
+    /// Index among the arguments passed on the stack
+    Stack(usize),
+}
+
+/// LLVM's SBF v1 convention: up to five arguments go in R1-R5. With more,
+/// the first four go in R1-R4 and the rest at the far end of the caller's
+/// frame, 8 bytes each, with R5 pointing at the caller's frame.
+fn arg_slot(idx: usize, count: usize) -> ArgSlot {
+    if count <= ARG_REGS.len() || idx < ARG_REGS.len() - 1 {
+        ArgSlot::Reg(ARG_REGS[idx])
+    } else {
+        ArgSlot::Stack(idx + 1 - ARG_REGS.len())
+    }
+}
+
+/// How a value is passed to a function, following the C ABI for BPF
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PassMode {
+    Scalar,
+    /// A class of up to 16 bytes, split into this many 8-byte words
+    Words(usize),
+    /// A larger class, as a pointer to a copy the caller makes
+    Indirect,
+}
+
+impl PassMode {
+    fn words(self) -> usize {
+        match self {
+            PassMode::Words(n) => n,
+            PassMode::Scalar | PassMode::Indirect => 1,
+        }
+    }
+}
+
+/// Largest class passed in registers
+const MAX_WORDS_BYTES: usize = 16;
+
+/// An addressable memory location: `[base + offset]`
+#[derive(Debug, Clone)]
+struct Place {
+    base: BpfReg,
+    offset: i16,
+    place_type: Type,
+}
+
+/// Jumps that still need patching once the enclosing loop or switch is
+/// generated
+#[derive(Debug, Default)]
+struct LoopContext {
+    break_jumps: Vec<usize>,
+    continue_jumps: Vec<usize>,
+    /// A switch takes `break` but leaves `continue` to the enclosing loop
+    switch: bool,
+}
+
+/// What the compute-unit estimate needs to know about the generated code
+/// that its instructions don't tell
+#[derive(Debug, Clone, Default)]
+pub struct CostHints {
+    /// Loop header instruction -> most times the loop jumps back to it,
+    /// when known, and a description of its iterations
+    pub loops: HashMap<usize, (Option<u64>, String)>,
+    /// Syscall instruction -> most bytes it is passed
+    pub syscall_lens: HashMap<usize, u64>,
+}
+
 /// Code generator state
 pub struct CodeGen {
     instructions: Vec<BpfInstruction>,
     variables: HashMap<String, (usize, Type)>, // name -> (stack_offset, type)
-    stack_offset: usize,
-    next_reg: usize,
-    label_counter: usize,
-    functions: HashMap<String, usize>, // function name -> func_id
+    globals: HashMap<String, (usize, Type)>,   // name -> (rodata_offset, type)
+    rodata: Rodata,
+    rodata_refs: Vec<(usize, usize)>,         // lddw instruction -> rodata offset
+    frame: FrameLayout,
+    frames: Vec<FrameLayout>,
+    call_depth: CallDepth,
+    target: Target,
+    free_regs: Vec<BpfReg>,
+    functions: HashMap<String, Type>,        // function name -> function pointer type
+    function_starts: HashMap<String, usize>, // function name -> first instruction
+    call_fixups: Vec<(usize, String)>,      // call instruction -> callee
+    function_refs: Vec<(usize, String)>,    // lddw instruction -> function
+    rodata_function_refs: Vec<(usize, String)>, // rodata offset -> function
+    rodata_data_refs: Vec<(usize, usize)>,  // rodata offset -> rodata offset
+    jump_targets: HashSet<usize>,
+    layouts: LayoutEngine,
+    loops: Vec<LoopContext>,
+    labels: HashMap<String, usize>,         // label -> instruction
+    goto_fixups: Vec<(usize, String)>,      // jump instruction -> label
+    cost_hints: CostHints,
+    /// Slot holding the hidden result pointer of a function returning a class
+    sret: Option<(usize, Type)>,
+    /// Abort on out-of-range indices into fixed-size arrays
+    bounds_checks: bool,
+    /// Compute 32-bit arithmetic with ALU32 instructions
+    alu32: bool,
+}
+
+impl Default for CodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CodeGen {
     pub fn new() -> Self {
+        Self::with_target(Target::default())
+    }
+
+    pub fn with_target(target: Target) -> Self {
         Self {
             instructions: Vec::new(),
             variables: HashMap::new(),
-            stack_offset: 0,
-            next_reg: 6, // R6-R9 are callee-saved
-            label_counter: 0,
+            globals: HashMap::new(),
+            rodata: Rodata::new(),
+            rodata_refs: Vec::new(),
+            frame: FrameLayout::new(""),
+            frames: Vec::new(),
+            call_depth: CallDepth::default(),
+            target,
+            free_regs: SCRATCH_REGS.iter().rev().copied().collect(),
             functions: HashMap::new(),
+            function_starts: HashMap::new(),
+            call_fixups: Vec::new(),
+            function_refs: Vec::new(),
+            rodata_function_refs: Vec::new(),
+            rodata_data_refs: Vec::new(),
+            jump_targets: HashSet::new(),
+            layouts: LayoutEngine::new(),
+            loops: Vec::new(),
+            labels: HashMap::new(),
+            goto_fixups: Vec::new(),
+            cost_hints: CostHints::default(),
+            sret: None,
+            bounds_checks: false,
+            alu32: false,
+        }
+    }
+
+    /// Check indices into fixed-size arrays at run time, calling `abort`
+    /// when one is out of range. Constant indices are always checked at
+    /// compile time.
+    pub fn with_bounds_checks(mut self, enabled: bool) -> Self {
+        self.bounds_checks = enabled;
+        self
+    }
+
+    /// Compute arithmetic on 32-bit and narrower operands with the ALU32
+    /// instructions, so it wraps at 32 bits instead of 64
+    pub fn with_alu32(mut self, enabled: bool) -> Self {
+        self.alu32 = enabled;
+        self
+    }
+
+    /// Stack frames of the generated functions, in program order
+    pub fn frames(&self) -> &[FrameLayout] {
+        &self.frames
+    }
+
+    /// First instruction of each generated function
+    pub fn function_starts(&self) -> &HashMap<String, usize> {
+        &self.function_starts
+    }
+
+    /// Loop bounds and syscall lengths for the compute-unit estimate
+    pub fn cost_hints(&self) -> &CostHints {
+        &self.cost_hints
+    }
+
+    /// Deepest call chain of the generated program
+    pub fn call_depth(&self) -> &CallDepth {
+        &self.call_depth
+    }
+
+    /// Package generated code with its `.rodata` for writing as ELF.
+    /// Execution starts at `entrypoint` if defined, else at the first function.
+    pub fn elf_program(&self, text: Vec<u8>) -> ElfProgram {
+        let mut functions: Vec<(String, usize)> = self.function_starts.iter()
+            .map(|(name, pc)| (name.clone(), *pc))
+            .collect();
+        functions.sort_by_key(|(_, pc)| *pc);
+
+        ElfProgram {
+            text,
+            rodata: self.rodata.data().to_vec(),
+            entry: self.function_starts.get(ENTRYPOINT).copied().unwrap_or(0),
+            functions,
+            rodata_symbols: self.rodata.symbols().to_vec(),
+            rodata_refs: self.rodata_refs.clone(),
+            function_refs: self.function_refs.iter()
+                .map(|(idx, name)| (*idx, self.function_starts[name]))
+                .collect(),
+            rodata_function_refs: self.rodata_function_refs.iter()
+                .map(|(offset, name)| (*offset, self.function_starts[name]))
+                .collect(),
+            rodata_data_refs: self.rodata_data_refs.clone(),
         }
     }
 
     pub fn generate(&mut self, program: &Program) -> Result<Vec<u8>> {
+        self.layouts = LayoutEngine::from_program(program)?;
+        self.call_depth = CallGraph::from_program(program).check(&self.target)?;
+
         // First pass: register all functions
-        for (idx, item) in program.items.iter().enumerate() {
+        for item in &program.items {
             if let Item::FunctionDef(func) = item {
-                self.functions.insert(func.name.clone(), idx);
+                let signature = Type::Function {
+                    return_type: Box::new(func.return_type.clone()),
+                    params: func.params.iter().map(|p| p.param_type.clone()).collect(),
+                };
+                self.functions.insert(func.name.clone(), Type::Pointer(Box::new(signature)));
             }
         }
+        self.generate_globals(program)?;
 
         // Generate entrypoint
         for item in &program.items {
             self.generate_item(item)?;
         }
 
+        // Resolve calls now that every function has an address
+        for (idx, callee) in std::mem::take(&mut self.call_fixups) {
+            let start = self.function_starts[&callee];
+            self.instructions[idx].imm = i32::try_from(start as i64 - idx as i64 - 1)
+                .map_err(|_| anyhow!("Call to {} out of range", callee))?;
+        }
+
         // Convert instructions to bytes
         let mut bytecode = Vec::new();
         for inst in &self.instructions {
@@ -267,11 +628,74 @@ impl CodeGen {
         Ok(bytecode)
     }
 
+    /// Lay out global variables in `.rodata`. Initializers must be constant,
+    /// and may refer to globals declared before them. A function name, with
+    /// or without `&`, is its address, relocated when the ELF is written.
+    fn generate_globals(&mut self, program: &Program) -> Result<()> {
+        let mut constants = self.layouts.constants().clone();
+        for item in &program.items {
+            let Item::GlobalVar(var) = item else {
+                continue;
+            };
+            if self.globals.contains_key(&var.name) {
+                return Err(anyhow!("Global {} is defined twice", var.name));
+            }
+
+            let var_type = self.layouts.resolve(&sized_by_init(&var.var_type, var.init.as_ref()));
+            let size = self.layouts.size_of(&var_type);
+            let align = self.layouts.align_of(&var_type);
+            let mut bytes = vec![0u8; size];
+            let mut addresses = Vec::new();
+            let mut strings = Vec::new();
+            if let Some(init) = &var.init {
+                let mut runtime = Vec::new();
+                self.lay_out_init(&var.name, &var_type, init, 0, &constants, &mut bytes, &mut runtime)?;
+                for (at, ty, expr) in runtime {
+                    let target = match expr {
+                        Expr::Unary { op: UnaryOp::AddressOf, expr } => &**expr,
+                        expr => expr,
+                    };
+                    let holds_address = !self.is_aggregate(&ty) && self.layouts.size_of(&ty) == 8;
+                    match target {
+                        Expr::Ident(name) if self.is_function_name(name) => {
+                            if !holds_address {
+                                return Err(anyhow!("Global {} can not hold the address of {} in a {}", var.name, name, ty));
+                            }
+                            addresses.push((at, name.clone()));
+                        }
+                        Expr::StringLiteral(text) if target == expr => {
+                            if !holds_address {
+                                return Err(anyhow!("Global {} can not hold the address of {} in a {}", var.name, expr, ty));
+                            }
+                            strings.push((at, text));
+                        }
+                        _ => return Err(anyhow!("Global {} must be initialized with a constant expression, got {}", var.name, expr)),
+                    }
+                }
+                if !self.is_aggregate(&var_type) && addresses.is_empty() && strings.is_empty() {
+                    let mut value = [0u8; 8];
+                    value[..size.min(8)].copy_from_slice(&bytes[..size.min(8)]);
+                    constants.insert(var.name.clone(), consteval::truncate(u64::from_le_bytes(value), &var_type));
+                }
+            }
+
+            let offset = self.rodata.push(Some(&var.name), &bytes, align);
+            self.rodata_function_refs.extend(addresses.into_iter().map(|(at, name)| (offset + at, name)));
+            for (at, text) in strings {
+                let target = self.rodata.intern(text);
+                self.rodata_data_refs.push((offset + at, target));
+            }
+            self.globals.insert(var.name.clone(), (offset, var_type));
+        }
+        Ok(())
+    }
+
     fn generate_item(&mut self, item: &Item) -> Result<()> {
         match item {
             Item::FunctionDef(func) => self.generate_function(func),
             Item::ClassDef(_) => Ok(()), // Classes are just type information
-            Item::GlobalVar(_) => Ok(()), // Global variables handled separately
+            Item::EnumDef(_) => Ok(()),  // Enumerators are folded to constants
+            Item::GlobalVar(_) => Ok(()), // Laid out in .rodata by generate_globals
             Item::Define(_) => Ok(()),    // Defines are preprocessor directives
             Item::Include(_) => Ok(()),   // Includes are preprocessor directives
         }
@@ -280,53 +704,120 @@ impl CodeGen {
     fn generate_function(&mut self, func: &FunctionDef) -> Result<()> {
         // Reset local state for new function
         self.variables.clear();
-        self.stack_offset = 0;
-
-        // Allocate stack space for parameters
-        for (idx, param) in func.params.iter().enumerate() {
-            let offset = self.stack_offset;
-            self.variables.insert(param.name.clone(), (offset, param.param_type.clone()));
-            self.stack_offset += param.param_type.size_bytes();
-
-            // Store parameter from register to stack
-            let reg = match idx {
-                0 => BpfReg::R1,
-                1 => BpfReg::R2,
-                2 => BpfReg::R3,
-                3 => BpfReg::R4,
-                4 => BpfReg::R5,
-                _ => return Err(anyhow!("Too many parameters (max 5)")),
+        self.frame = FrameLayout::new(&func.name);
+        self.function_starts.insert(func.name.clone(), self.instructions.len());
+        self.loops.clear();
+        self.labels.clear();
+        self.reset_regs();
+
+        // Allocate stack space for parameters and copy them in from their
+        // registers, or from the caller's frame through R5. A function
+        // returning a class takes the address to write it to first.
+        let modes: Vec<PassMode> = func.params.iter().map(|p| self.pass_mode(&p.param_type)).collect();
+        let returns_class = self.pass_mode(&func.return_type) != PassMode::Scalar;
+        let total = returns_class as usize + modes.iter().map(|m| m.words()).sum::<usize>();
+        let mut word = 0;
+        self.sret = None;
+        if returns_class {
+            let reg = self.incoming_word(word, total);
+            let offset = self.frame.alloc("<sret>", SlotKind::Param, Type::U64, 8, 8);
+            self.emit(BpfInstruction::stxdw(BpfReg::R10, reg, -(offset as i16)));
+            self.sret = Some((offset, self.layouts.resolve(&func.return_type)));
+            word += 1;
+        }
+        for (param, mode) in func.params.iter().zip(modes) {
+            let param_type = self.layouts.resolve(&param.param_type);
+            let size = self.layouts.size_of(&param_type);
+            let align = self.layouts.align_of(&param_type);
+            let width = match mode {
+                PassMode::Scalar => self.value_width(&param_type),
+                _ => size,
             };
-
-            self.emit(BpfInstruction::stxdw(BpfReg::R10, reg, -(offset as i16) - 8));
+            let offset = self.frame.alloc(&param.name, SlotKind::Param, param.param_type.clone(), width, align.min(width.max(1)));
+            self.variables.insert(param.name.clone(), (offset, param_type.clone()));
+            let slot = Place { base: BpfReg::R10, offset: -(offset as i16), place_type: param_type };
+
+            match mode {
+                PassMode::Scalar => {
+                    let reg = self.incoming_word(word, total);
+                    self.emit(BpfInstruction::stx(width, BpfReg::R10, reg, slot.offset));
+                }
+                PassMode::Words(n) => {
+                    for k in 0..n {
+                        let reg = self.incoming_word(word + k, total);
+                        self.store_bytes(&slot, 8 * k, (size - 8 * k).min(8), reg);
+                    }
+                }
+                PassMode::Indirect => {
+                    let reg = self.incoming_word(word, total);
+                    let base = self.alloc_reg()?;
+                    self.emit(BpfInstruction::mov_reg(base, reg));
+                    let source = Place { base, offset: 0, place_type: slot.place_type.clone() };
+                    self.copy_place(&slot, &source, size);
+                    self.free_reg(base);
+                }
+            }
+            word += mode.words();
         }
 
         // Generate function body
-        for stmt in &func.body {
-            self.generate_stmt(stmt)?;
+        self.generate_block(&func.body)?;
+
+        // Labels are known once the whole body is generated
+        for (idx, label) in std::mem::take(&mut self.goto_fixups) {
+            let target = *self.labels.get(&label)
+                .ok_or_else(|| anyhow!("goto to undefined label {} in {}", label, func.name))?;
+            self.patch_jump(idx, target)?;
+        }
+        for (label, pc) in &self.labels {
+            self.cost_hints.loops.entry(*pc)
+                .or_insert_with(|| (None, format!("iterations of the `goto {}` loop", label)));
         }
 
-        // Ensure function returns (even if no explicit return)
-        if !matches!(func.body.last(), Some(Stmt::Return(_))) {
+        // Ensure function returns (even if no explicit return). Control can
+        // reach the end unless the last instruction is an exit that nothing
+        // jumps past.
+        let end = self.instructions.len();
+        let ends_in_exit = self.instructions.last().is_some_and(|inst| inst.opcode == BpfOpcode::Exit as u8)
+            && end > self.function_starts[&func.name];
+        if !ends_in_exit || self.jump_targets.contains(&end) {
             if func.return_type != Type::Void {
                 self.emit(BpfInstruction::mov_imm(BpfReg::R0, 0));
             }
             self.emit(BpfInstruction::exit());
         }
 
+        self.frame.check(&self.target)?;
+        self.frames.push(self.frame.clone());
         Ok(())
     }
 
     fn generate_stmt(&mut self, stmt: &Stmt) -> Result<()> {
+        // Temporaries never outlive a statement
+        self.reset_regs();
+
         match stmt {
             Stmt::VarDecl(var) => {
-                let offset = self.stack_offset;
-                self.variables.insert(var.name.clone(), (offset, var.var_type.clone()));
-                self.stack_offset += var.var_type.size_bytes();
+                let var_type = sized_by_init(&var.var_type, var.init.as_ref());
+                let size = self.layouts.size_of(&var_type);
+                let align = self.layouts.align_of(&var_type);
+                let offset = self.frame.alloc(&var.name, SlotKind::Local, var_type.clone(), size, align);
+                self.variables.insert(var.name.clone(), (offset, self.layouts.resolve(&var_type)));
 
                 if let Some(init) = &var.init {
+                    let var_type = self.layouts.resolve(&var_type);
+                    if matches!(init, Expr::InitList(_)) || matches!(var_type, Type::Array(_, Some(_))) {
+                        return self.generate_initializer(&var.name, offset, &var_type, init);
+                    }
+                    if self.pass_mode(&var_type) != PassMode::Scalar {
+                        let source = self.generate_place(init)?;
+                        self.check_class_copy(&var_type, &source.place_type, &format!("Initializer of {}", var.name))?;
+                        let dest = Place { base: BpfReg::R10, offset: -(offset as i16), place_type: var_type };
+                        self.copy_place(&dest, &source, size);
+                        return Ok(());
+                    }
                     let reg = self.generate_expr(init)?;
-                    self.emit(BpfInstruction::stxdw(BpfReg::R10, reg, -(offset as i16) - 8));
+                    self.emit(BpfInstruction::stx(size, BpfReg::R10, reg, -(offset as i16)));
                 }
                 Ok(())
             }
@@ -336,9 +827,29 @@ impl CodeGen {
                 Ok(())
             }
 
+            Stmt::Return(Some(expr)) if self.sret.is_some() => {
+                // Write the class to the caller's result slot and hand its
+                // address back in R0
+                let (slot, return_type) = self.sret.clone().unwrap();
+                let source = self.generate_place(expr)?;
+                self.check_class_copy(&return_type, &source.place_type, "Return value")?;
+                let base = self.alloc_reg()?;
+                self.emit(BpfInstruction::ldxdw(base, BpfReg::R10, -(slot as i16)));
+                let dest = Place { base, offset: 0, place_type: return_type.clone() };
+                self.copy_place(&dest, &source, self.layouts.size_of(&return_type));
+                self.emit(BpfInstruction::mov_reg(BpfReg::R0, base));
+                self.emit(BpfInstruction::exit());
+                Ok(())
+            }
+
             Stmt::Return(expr) => {
                 if let Some(expr) = expr {
                     let reg = self.generate_expr(expr)?;
+                    if let Some(Type::Pointer(signature)) = self.functions.get(&self.frame.function).cloned() {
+                        if let Type::Function { return_type, .. } = *signature {
+                            self.convert(reg, expr, &return_type);
+                        }
+                    }
                     if reg != BpfReg::R0 {
                         self.emit(BpfInstruction::mov_reg(BpfReg::R0, reg));
                     }
@@ -348,42 +859,30 @@ impl CodeGen {
             }
 
             Stmt::If { condition, then_block, else_block } => {
-                let cond_reg = self.generate_expr(condition)?;
-
-                let else_label = self.new_label();
-                let end_label = self.new_label();
-
-                // Jump to else if condition is false (== 0)
-                self.emit(BpfInstruction::jeq_imm(cond_reg, 0, 0)); // Offset will be patched
-                let if_jump_idx = self.instructions.len() - 1;
+                // Jump to else/end if condition is false
+                let else_jumps = self.generate_cond_jump(condition, false)?;
 
                 // Then block
-                for stmt in then_block {
-                    self.generate_stmt(stmt)?;
-                }
+                self.generate_block(then_block)?;
 
-                let mut else_jump_idx = None;
+                let mut end_jump = None;
                 if else_block.is_some() {
                     // Jump to end after then block
                     self.emit(BpfInstruction::ja(0)); // Offset will be patched
-                    else_jump_idx = Some(self.instructions.len() - 1);
+                    end_jump = Some(self.instructions.len() - 1);
                 }
 
-                // Patch if jump to else/end
                 let else_target = self.instructions.len();
-                self.instructions[if_jump_idx].offset = (else_target - if_jump_idx - 1) as i16;
+                self.patch_jumps(&else_jumps, else_target)?;
 
                 // Else block
                 if let Some(else_block) = else_block {
-                    for stmt in else_block {
-                        self.generate_stmt(stmt)?;
-                    }
+                    self.generate_block(else_block)?;
                 }
 
-                // Patch else jump to end
-                if let Some(idx) = else_jump_idx {
+                if let Some(idx) = end_jump {
                     let end_target = self.instructions.len();
-                    self.instructions[idx].offset = (end_target - idx - 1) as i16;
+                    self.patch_jump(idx, end_target)?;
                 }
 
                 Ok(())
@@ -391,189 +890,1814 @@ impl CodeGen {
 
             Stmt::While { condition, body } => {
                 let start = self.instructions.len();
-
-                let cond_reg = self.generate_expr(condition)?;
+                self.cost_hints.loops.insert(start, (None, format!("iterations of `while ({})`", condition)));
 
                 // Jump to end if condition is false
-                self.emit(BpfInstruction::jeq_imm(cond_reg, 0, 0));
-                let exit_jump_idx = self.instructions.len() - 1;
+                let exit_jumps = self.generate_cond_jump(condition, false)?;
 
-                // Loop body
-                for stmt in body {
-                    self.generate_stmt(stmt)?;
-                }
+                self.loops.push(LoopContext::default());
+                self.generate_block(body)?;
+                let context = self.loops.pop().unwrap_or_default();
 
                 // Jump back to condition
-                let back_offset = -((self.instructions.len() - start) as i16) - 1;
-                self.emit(BpfInstruction::ja(back_offset));
+                self.emit(BpfInstruction::ja(0));
+                self.patch_jump(self.instructions.len() - 1, start)?;
 
-                // Patch exit jump
-                let end_target = self.instructions.len();
-                self.instructions[exit_jump_idx].offset = (end_target - exit_jump_idx - 1) as i16;
+                let end = self.instructions.len();
+                self.patch_jumps(&exit_jumps, end)?;
+                self.patch_jumps(&context.break_jumps, end)?;
+                self.patch_jumps(&context.continue_jumps, start)?;
 
                 Ok(())
             }
 
-            Stmt::Block(block) => {
-                for stmt in block {
-                    self.generate_stmt(stmt)?;
-                }
+            Stmt::DoWhile { body, condition } => {
+                let start = self.instructions.len();
+                let description = format!("iterations of `do {{ ... }} while ({})`", condition);
+                self.cost_hints.loops.insert(start, (None, description));
+
+                self.loops.push(LoopContext::default());
+                self.generate_block(body)?;
+                let context = self.loops.pop().unwrap_or_default();
+
+                // Jump back while the condition holds
+                let continue_target = self.instructions.len();
+                self.reset_regs();
+                let repeat_jumps = self.generate_cond_jump(condition, true)?;
+                self.patch_jumps(&repeat_jumps, start)?;
+
+                let end = self.instructions.len();
+                self.patch_jumps(&context.break_jumps, end)?;
+                self.patch_jumps(&context.continue_jumps, continue_target)?;
+
                 Ok(())
             }
 
-            _ => Err(anyhow!("Unsupported statement: {:?}", stmt)),
-        }
-    }
+            Stmt::For { init, condition, increment, body } => {
+                // The initializer is scoped to the loop
+                let outer = self.variables.clone();
+                if let Some(init) = init {
+                    self.generate_block_stmt(init)?;
+                }
 
-    fn generate_expr(&mut self, expr: &Expr) -> Result<BpfReg> {
-        match expr {
-            Expr::IntLiteral(n) => {
-                let reg = self.alloc_reg()?;
-                if *n <= i32::MAX as u64 {
-                    self.emit(BpfInstruction::mov_imm(reg, *n as i32));
-                } else {
-                    // For large numbers, need to load in two parts
-                    self.emit(BpfInstruction::mov_imm(reg, (*n >> 32) as i32));
-                    self.emit(BpfInstruction::new(
-                        BpfOpcode::Lsh64Imm,
-                        reg,
-                        BpfReg::R0,
-                        0,
-                        32,
-                    ));
-                    self.emit(BpfInstruction::new(
-                        BpfOpcode::Or64Imm,
-                        reg,
-                        BpfReg::R0,
-                        0,
-                        (*n & 0xFFFFFFFF) as i32,
-                    ));
+                let start = self.instructions.len();
+                let description = match condition {
+                    Some(condition) => format!("iterations of `for (...; {}; ...)`", condition),
+                    None => "iterations of `for (;;)`".to_string(),
+                };
+                self.cost_hints.loops.insert(start, (self.trip_count(stmt, start), description));
+                self.reset_regs();
+                let exit_jumps = match condition {
+                    Some(condition) => self.generate_cond_jump(condition, false)?,
+                    None => Vec::new(),
+                };
+
+                self.loops.push(LoopContext::default());
+                self.generate_block(body)?;
+                let context = self.loops.pop().unwrap_or_default();
+
+                let continue_target = self.instructions.len();
+                if let Some(increment) = increment {
+                    self.reset_regs();
+                    self.generate_expr(increment)?;
                 }
-                Ok(reg)
+
+                self.emit(BpfInstruction::ja(0));
+                self.patch_jump(self.instructions.len() - 1, start)?;
+
+                let end = self.instructions.len();
+                self.patch_jumps(&exit_jumps, end)?;
+                self.patch_jumps(&context.break_jumps, end)?;
+                self.patch_jumps(&context.continue_jumps, continue_target)?;
+                self.variables = outer;
+
+                Ok(())
             }
 
-            Expr::Ident(name) => {
-                if let Some(&(offset, _)) = self.variables.get(name) {
-                    let reg = self.alloc_reg()?;
-                    self.emit(BpfInstruction::ldxdw(reg, BpfReg::R10, -(offset as i16) - 8));
-                    Ok(reg)
-                } else {
-                    Err(anyhow!("Undefined variable: {}", name))
+            Stmt::Break => {
+                self.emit(BpfInstruction::ja(0));
+                let idx = self.instructions.len() - 1;
+                let context = self.loops.last_mut()
+                    .ok_or_else(|| anyhow!("break outside of a loop or switch"))?;
+                context.break_jumps.push(idx);
+                Ok(())
+            }
+
+            Stmt::Continue => {
+                self.emit(BpfInstruction::ja(0));
+                let idx = self.instructions.len() - 1;
+                let context = self.loops.iter_mut().rev().find(|context| !context.switch)
+                    .ok_or_else(|| anyhow!("continue outside of a loop"))?;
+                context.continue_jumps.push(idx);
+                Ok(())
+            }
+
+            Stmt::Switch { value, cases } => self.generate_switch(value, cases),
+
+            Stmt::Goto(label) => {
+                self.emit(BpfInstruction::ja(0));
+                self.goto_fixups.push((self.instructions.len() - 1, label.clone()));
+                Ok(())
+            }
+
+            Stmt::Label(label) => {
+                if self.labels.insert(label.clone(), self.instructions.len()).is_some() {
+                    return Err(anyhow!("Label {} is defined twice in {}", label, self.frame.function));
                 }
+                Ok(())
             }
 
-            Expr::Binary { op, left, right } => {
-                let left_reg = self.generate_expr(left)?;
-                let right_reg = self.generate_expr(right)?;
+            Stmt::Block(block) => self.generate_block(block),
 
-                let inst = match op {
-                    BinaryOp::Add => BpfInstruction::add_reg(left_reg, right_reg),
-                    BinaryOp::Sub => BpfInstruction::sub_reg(left_reg, right_reg),
-                    BinaryOp::Mul => BpfInstruction::mul_reg(left_reg, right_reg),
-                    BinaryOp::Div => BpfInstruction::div_reg(left_reg, right_reg),
-                    BinaryOp::Mod => BpfInstruction::mod_reg(left_reg, right_reg),
-                    BinaryOp::BitAnd => BpfInstruction::and_reg(left_reg, right_reg),
-                    BinaryOp::BitOr => BpfInstruction::or_reg(left_reg, right_reg),
-                    BinaryOp::BitXor => BpfInstruction::xor_reg(left_reg, right_reg),
-                    BinaryOp::Shl => BpfInstruction::lsh_reg(left_reg, right_reg),
-                    BinaryOp::Shr => BpfInstruction::rsh_reg(left_reg, right_reg),
-                    _ => return Err(anyhow!("Unsupported binary operator: {}", op)),
-                };
+            Stmt::Asm(asm) => self.generate_inline_asm(asm),
 
-                self.emit(inst);
-                Ok(left_reg)
+            Stmt::Print { format, args } => self.generate_print(format, args),
+        }
+    }
+
+    /// Generate a block. Locals declared in it go out of scope at its end,
+    /// and their stack slots are reused.
+    fn generate_block(&mut self, stmts: &[Stmt]) -> Result<()> {
+        let outer = self.variables.clone();
+        let mark = self.frame.mark();
+        for stmt in stmts {
+            self.generate_block_stmt(stmt)?;
+        }
+        self.frame.release(mark);
+        self.variables = outer;
+        Ok(())
+    }
+
+    /// Generate a statement of a block, releasing the stack slots of its
+    /// temporaries after it. A local it declares, whose slot comes first,
+    /// stays until the block ends.
+    fn generate_block_stmt(&mut self, stmt: &Stmt) -> Result<()> {
+        let mark = self.frame.mark();
+        self.generate_stmt(stmt)?;
+        self.frame.release(mark + matches!(stmt, Stmt::VarDecl(_)) as usize);
+        Ok(())
+    }
+
+    /// Lower a switch: the planned branch tree dispatches on the value,
+    /// then the case bodies follow in source order so each one falls into
+    /// the next. Dispatch makes no calls, so R1 holds wide constants.
+    fn generate_switch(&mut self, value: &Expr, cases: &[SwitchCase]) -> Result<()> {
+        let signed = self.sign_of(value) == Sign::Signed;
+        let plan = SwitchPlan::new(cases, signed, &self.layouts)
+            .map_err(|err| anyhow!("Switch in {}: {}", self.frame.function, err))?;
+
+        let reg = self.generate_expr(value)?;
+
+        // Jumps to case bodies; None jumps past the switch
+        let mut jumps = Vec::new();
+        if let Some((base, limit)) = plan.rebase {
+            if base != 0 {
+                self.emit_with_const(BpfOpcode::Sub64Imm, BpfOpcode::Sub64Reg, reg, base as u64);
+            }
+            let idx = self.emit_with_const(BpfOpcode::JgtImm, BpfOpcode::JgtReg, reg, limit);
+            jumps.push((idx, plan.default));
+        }
+        self.emit_dispatch(&plan.tree, reg, plan.signed, &mut jumps)?;
+        self.free_reg(reg);
+
+        self.loops.push(LoopContext { switch: true, ..LoopContext::default() });
+        let outer = self.variables.clone();
+        let mut starts = Vec::new();
+        for case in cases {
+            starts.push(self.instructions.len());
+            for stmt in &case.body {
+                self.generate_block_stmt(stmt)?;
             }
+        }
+        self.variables = outer;
+        let context = self.loops.pop().unwrap_or_default();
 
-            Expr::Assign { target, value } => {
-                if let Expr::Ident(name) = &**target {
-                    let value_reg = self.generate_expr(value)?;
+        let end = self.instructions.len();
+        for (idx, target) in jumps {
+            self.patch_jump(idx, target.map_or(end, |case| starts[case]))?;
+        }
+        self.patch_jumps(&context.break_jumps, end)
+    }
 
-                    if let Some((offset, _)) = self.variables.get(name) {
-                        self.emit(BpfInstruction::stxdw(BpfReg::R10, value_reg, -(*offset as i16) - 8));
-                        Ok(value_reg)
-                    } else {
-                        Err(anyhow!("Undefined variable: {}", name))
-                    }
+    /// Emit the comparisons of a dispatch tree. Every path ends in a jump
+    /// recorded in `jumps` with the case it goes to.
+    fn emit_dispatch(&mut self, tree: &Dispatch, reg: BpfReg, signed: bool, jumps: &mut Vec<(usize, Option<usize>)>) -> Result<()> {
+        match tree {
+            Dispatch::Goto(target) => {
+                self.emit(BpfInstruction::ja(0));
+                jumps.push((self.instructions.len() - 1, *target));
+            }
+            Dispatch::Equals { key, target, other } => {
+                let idx = self.emit_with_const(BpfOpcode::JeqImm, BpfOpcode::JeqReg, reg, *key as u64);
+                jumps.push((idx, *target));
+                self.emit_dispatch(other, reg, signed, jumps)?;
+            }
+            Dispatch::Split { pivot, below, above } => {
+                let (imm, src) = if signed {
+                    (BpfOpcode::JsgeImm, BpfOpcode::JsgeReg)
                 } else {
-                    Err(anyhow!("Invalid assignment target"))
-                }
-            }
-
-            Expr::Call { func, args } => {
-                if let Expr::Ident(func_name) = &**func {
-                    // Load arguments into R1-R5
-                    for (idx, arg) in args.iter().enumerate() {
-                        let arg_reg = self.generate_expr(arg)?;
-                        let param_reg = match idx {
-                            0 => BpfReg::R1,
-                            1 => BpfReg::R2,
-                            2 => BpfReg::R3,
-                            3 => BpfReg::R4,
-                            4 => BpfReg::R5,
-                            _ => return Err(anyhow!("Too many arguments (max 5)")),
-                        };
-                        if arg_reg != param_reg {
-                            self.emit(BpfInstruction::mov_reg(param_reg, arg_reg));
-                        }
+                    (BpfOpcode::JgeImm, BpfOpcode::JgeReg)
+                };
+                let idx = self.emit_with_const(imm, src, reg, *pivot as u64);
+                self.emit_dispatch(below, reg, signed, jumps)?;
+                match **above {
+                    // Jump straight to the case rather than to another jump
+                    Dispatch::Goto(target) => jumps.push((idx, target)),
+                    _ => {
+                        self.patch_jump(idx, self.instructions.len())?;
+                        self.emit_dispatch(above, reg, signed, jumps)?;
                     }
+                }
+            }
+        }
+        Ok(())
+    }
 
-                    // Call function
-                    if let Some(func_id) = self.functions.get(func_name) {
-                        self.emit(BpfInstruction::call(*func_id as i32));
-                        Ok(BpfReg::R0) // Result in R0
-                    } else {
-                        Err(anyhow!("Undefined function: {}", func_name))
+    /// Emit `imm_op` on `reg` with `constant`, or `reg_op` with the constant
+    /// loaded into R1 when it does not fit a sign-extended immediate.
+    /// Returns the index of the instruction.
+    fn emit_with_const(&mut self, imm_op: BpfOpcode, reg_op: BpfOpcode, reg: BpfReg, constant: u64) -> usize {
+        let imm = constant as i32;
+        if imm as i64 as u64 == constant {
+            self.emit(BpfInstruction::new(imm_op, reg, BpfReg::R0, 0, imm));
+        } else {
+            for inst in BpfInstruction::lddw(BpfReg::R1, constant) {
+                self.emit(inst);
+            }
+            self.emit(BpfInstruction::new(reg_op, reg, BpfReg::R1, 0, 0));
+        }
+        self.instructions.len() - 1
+    }
+
+    /// Lower a print statement. Plain text and the exact format
+    /// `sol_log_64_` logs become a single syscall; anything else is formatted into a stack buffer that is then
+    /// logged with `sol_log_`. Each argument is evaluated right before its
+    /// conversion, which uses R0-R5 as scratch since no call happens there.
+    fn generate_print(&mut self, format: &[u8], args: &[Expr]) -> Result<()> {
+        let function = self.frame.function.clone();
+        let pieces = format::parse(format)
+            .and_then(|pieces| format::check_arg_count(&pieces, args.len()).map(|_| pieces))
+            .map_err(|err| anyhow!("Print in {}: {}", function, err))?;
+        for (conversion, arg) in format::conversions(&pieces).zip(args) {
+            self.check_format_arg(conversion, arg)
+                .map_err(|err| anyhow!("Print in {}: {}", function, err))?;
+        }
+
+        if let Some(call) = format::as_syscall(&pieces, args) {
+            self.generate_expr(&call)?;
+            return Ok(());
+        }
+
+        let size = format::max_len(&pieces);
+        let buffer = self.frame.alloc("<print>", SlotKind::Spill, Type::Array(Box::new(Type::U8), Some(size)), size, 8);
+        let cursor = self.alloc_reg()?;
+        self.emit(BpfInstruction::mov_reg(cursor, BpfReg::R10));
+        self.emit(BpfInstruction::add_imm(cursor, -(buffer as i32)));
+
+        let mut args = args.iter();
+        for piece in &pieces {
+            match piece {
+                Piece::Text(text) => self.emit_store_text(cursor, text),
+                Piece::Arg(conversion) => {
+                    let arg = args.next().expect("argument count was checked");
+                    let value = self.generate_expr(arg)?;
+                    self.emit(BpfInstruction::mov_reg(BpfReg::R1, value));
+                    self.free_reg(value);
+                    match conversion {
+                        Conversion::Decimal => {
+                            if self.sign_of(arg) == Sign::Signed {
+                                self.emit_sign(cursor)?;
+                            }
+                            self.emit_digits(cursor, 10)?;
+                        }
+                        Conversion::Hex => self.emit_digits(cursor, 16)?,
+                        Conversion::Str => self.emit_copy_str(cursor)?,
+                        Conversion::Char => {
+                            self.emit(BpfInstruction::stx(1, cursor, BpfReg::R1, 0));
+                            self.emit(BpfInstruction::add_imm(cursor, 1));
+                        }
                     }
-                } else {
-                    Err(anyhow!("Invalid function call"))
                 }
             }
+        }
 
-            _ => Err(anyhow!("Unsupported expression: {:?}", expr)),
+        self.emit(BpfInstruction::mov_reg(BpfReg::R1, BpfReg::R10));
+        self.emit(BpfInstruction::add_imm(BpfReg::R1, -(buffer as i32)));
+        self.emit(BpfInstruction::mov_reg(BpfReg::R2, cursor));
+        self.emit(BpfInstruction::sub_reg(BpfReg::R2, BpfReg::R1));
+        self.cost_hints.syscall_lens.insert(self.instructions.len(), size as u64);
+        self.emit(BpfInstruction::syscall(syscalls::hash("sol_log_")));
+        Ok(())
+    }
+
+    /// `%s` takes a byte pointer; the other conversions take integers
+    fn check_format_arg(&self, conversion: Conversion, arg: &Expr) -> Result<()> {
+        let Some(ty) = self.expr_type(arg) else {
+            return Ok(());
+        };
+        let is_bytes = matches!(&ty, Type::Pointer(inner) | Type::Array(inner, _) if matches!(**inner, Type::U8 | Type::I8));
+        let is_integer = !matches!(ty, Type::Pointer(_) | Type::Array(..) | Type::Custom(_) | Type::F64 | Type::Void);
+        match conversion {
+            Conversion::Str if !is_bytes => Err(anyhow!("%s expects a U8* argument, got {} of type {}", arg, ty)),
+            Conversion::Decimal | Conversion::Hex | Conversion::Char if !is_integer => {
+                let spec = match conversion {
+                    Conversion::Decimal => "%d",
+                    Conversion::Hex => "%x",
+                    _ => "%c",
+                };
+                Err(anyhow!("{} expects an integer argument, got {} of type {}", spec, arg, ty))
+            }
+            _ => Ok(()),
         }
     }
 
-    fn emit(&mut self, inst: BpfInstruction) {
-        self.instructions.push(inst);
+    /// Store literal text at the cursor, four bytes at a time, and advance it
+    fn emit_store_text(&mut self, cursor: BpfReg, text: &[u8]) {
+        let words = text.chunks_exact(4);
+        let tail = words.remainder();
+        let mut offset = 0;
+        for word in words {
+            let imm = i32::from_le_bytes(word.try_into().unwrap());
+            self.emit(BpfInstruction::new(BpfOpcode::StW, cursor, BpfReg::R0, offset, imm));
+            offset += 4;
+        }
+        for &byte in tail {
+            self.emit(BpfInstruction::new(BpfOpcode::StB, cursor, BpfReg::R0, offset, byte as i32));
+            offset += 1;
+        }
+        self.emit(BpfInstruction::add_imm(cursor, text.len() as i32));
     }
 
-    fn alloc_reg(&mut self) -> Result<BpfReg> {
-        let reg = match self.next_reg {
-            6 => BpfReg::R6,
-            7 => BpfReg::R7,
-            8 => BpfReg::R8,
-            9 => BpfReg::R9,
-            _ => return Err(anyhow!("Out of registers")),
+    /// If the signed value in R1 is negative, write a minus sign and
+    /// negate it
+    fn emit_sign(&mut self, cursor: BpfReg) -> Result<()> {
+        self.emit(BpfInstruction::new(BpfOpcode::JsgeImm, BpfReg::R1, BpfReg::R0, 0, 0));
+        let positive = self.instructions.len() - 1;
+        self.emit(BpfInstruction::new(BpfOpcode::StB, cursor, BpfReg::R0, 0, b'-' as i32));
+        self.emit(BpfInstruction::add_imm(cursor, 1));
+        self.emit(BpfInstruction::new(BpfOpcode::Neg64, BpfReg::R1, BpfReg::R0, 0, 0));
+        self.patch_jump(positive, self.instructions.len())
+    }
+
+    /// Write the unsigned value in R1 in base 10 or 16. The digits are
+    /// counted first so they can be written backwards from the end.
+    fn emit_digits(&mut self, cursor: BpfReg, base: i32) -> Result<()> {
+        let next_digit = |value: BpfReg| match base {
+            16 => BpfInstruction::new(BpfOpcode::Rsh64Imm, value, BpfReg::R0, 0, 4),
+            _ => BpfInstruction::new(BpfOpcode::Div64Imm, value, BpfReg::R0, 0, base),
         };
-        self.next_reg = (self.next_reg % 4) + 6; // Rotate R6-R9
-        Ok(reg)
+
+        // Each loop runs once per digit
+        let digits = if base == 16 { 16 } else { 20 };
+        self.emit(BpfInstruction::mov_reg(BpfReg::R2, BpfReg::R1));
+        let count = self.instructions.len();
+        self.cost_hints.loops.insert(count, (Some(digits - 1), "digits".to_string()));
+        self.emit(BpfInstruction::add_imm(cursor, 1));
+        self.emit(next_digit(BpfReg::R2));
+        self.emit(BpfInstruction::jne_imm(BpfReg::R2, 0, 0));
+        self.patch_jump(self.instructions.len() - 1, count)?;
+
+        self.emit(BpfInstruction::mov_reg(BpfReg::R3, cursor));
+        let digit = self.instructions.len();
+        self.cost_hints.loops.insert(digit, (Some(digits - 1), "digits".to_string()));
+        self.emit(BpfInstruction::mov_reg(BpfReg::R2, BpfReg::R1));
+        match base {
+            16 => self.emit(BpfInstruction::new(BpfOpcode::And64Imm, BpfReg::R2, BpfReg::R0, 0, 15)),
+            _ => self.emit(BpfInstruction::new(BpfOpcode::Mod64Imm, BpfReg::R2, BpfReg::R0, 0, base)),
+        }
+        self.emit(BpfInstruction::add_imm(BpfReg::R2, b'0' as i32));
+        if base == 16 {
+            self.emit(BpfInstruction::new(BpfOpcode::JleImm, BpfReg::R2, BpfReg::R0, 1, b'9' as i32));
+            self.emit(BpfInstruction::add_imm(BpfReg::R2, (b'a' - b'9' - 1) as i32));
+            self.jump_targets.insert(self.instructions.len());
+        }
+        self.emit(BpfInstruction::add_imm(BpfReg::R3, -1));
+        self.emit(BpfInstruction::stx(1, BpfReg::R3, BpfReg::R2, 0));
+        self.emit(next_digit(BpfReg::R1));
+        self.emit(BpfInstruction::jne_imm(BpfReg::R1, 0, 0));
+        self.patch_jump(self.instructions.len() - 1, digit)
     }
 
-    fn new_label(&mut self) -> usize {
-        let label = self.label_counter;
-        self.label_counter += 1;
-        label
+    /// Copy the NUL-terminated string at R1, up to `format::STR_MAX` bytes
+    fn emit_copy_str(&mut self, cursor: BpfReg) -> Result<()> {
+        self.emit(BpfInstruction::mov_reg(BpfReg::R3, cursor));
+        self.emit(BpfInstruction::add_imm(BpfReg::R3, format::STR_MAX as i32));
+        let top = self.instructions.len();
+        self.cost_hints.loops.insert(top, (Some(format::STR_MAX as u64), "copied bytes".to_string()));
+        self.emit(BpfInstruction::jge_reg(cursor, BpfReg::R3, 0));
+        self.emit(BpfInstruction::ldx(1, BpfReg::R2, BpfReg::R1, 0));
+        self.emit(BpfInstruction::jeq_imm(BpfReg::R2, 0, 0));
+        self.emit(BpfInstruction::stx(1, cursor, BpfReg::R2, 0));
+        self.emit(BpfInstruction::add_imm(cursor, 1));
+        self.emit(BpfInstruction::add_imm(BpfReg::R1, 1));
+        self.emit(BpfInstruction::ja(0));
+        let end = self.instructions.len();
+        self.patch_jump(end - 1, top)?;
+        self.patch_jumps(&[top, top + 2], end)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Splice an inline asm block: inputs are loaded from their variables,
+    /// then the assembled body runs and outputs are stored back. Values live
+    /// on the stack between statements, so the block may use any register it
+    /// declares; calls inside it clobber R0-R5 as usual.
+    fn generate_inline_asm(&mut self, asm: &InlineAsm) -> Result<()> {
+        let function = self.frame.function.clone();
+        let code = asm::assemble_fragment(&asm.body)
+            .map_err(|err| anyhow!("Inline asm in {}: {}", function, err))?;
+        let body = disasm::decode(&code)?;
+        let slots = code.len() / 8;
+
+        let writable = asm.writable_regs();
+        for inst in &body {
+            if inst.opcode.is_some_and(|op| op.writes_dst()) && !writable.contains(&inst.dst) {
+                return Err(anyhow!(
+                    "Inline asm in {} writes r{} without declaring it as an output or clobber",
+                    function, inst.dst
+                ));
+            }
+            if inst.opcode.is_some_and(|op| op.is_jump()) && inst.jump_target().is_none_or(|target| target > slots) {
+                return Err(anyhow!("Inline asm in {} jumps outside the block", function));
+            }
+        }
 
-    #[test]
-    fn test_bpf_instruction_encoding() {
-        let inst = BpfInstruction::mov_imm(BpfReg::R0, 42);
-        let bytes = inst.to_bytes();
-        assert_eq!(bytes[0], BpfOpcode::Mov64Imm as u8);
-        assert_eq!(bytes[1], 0x00); // dst=R0, src=R0
-        assert_eq!(&bytes[4..8], &42i32.to_le_bytes());
+        for input in &asm.inputs {
+            let (offset, width) = self.asm_binding(&input.var)?;
+            self.emit(BpfInstruction::ldx(width, BpfReg::ALL[input.reg as usize], BpfReg::R10, -(offset as i16)));
+        }
+
+        let start = self.instructions.len();
+        for chunk in code.chunks_exact(8) {
+            self.emit(BpfInstruction::from_bytes(chunk.try_into().unwrap()));
+        }
+        for target in body.iter().filter_map(|inst| inst.jump_target()) {
+            self.jump_targets.insert(start + target);
+        }
+
+        for output in &asm.outputs {
+            self.check_writable(&Expr::Ident(output.var.clone()))?;
+            let (offset, width) = self.asm_binding(&output.var)?;
+            self.emit(BpfInstruction::stx(width, BpfReg::R10, BpfReg::ALL[output.reg as usize], -(offset as i16)));
+        }
+        Ok(())
     }
 
-    #[test]
-    fn test_xor_instruction() {
-        let inst = BpfInstruction::xor_reg(BpfReg::R6, BpfReg::R7);
-        let bytes = inst.to_bytes();
-        assert_eq!(bytes[0], BpfOpcode::Xor64Reg as u8);
-        assert_eq!(bytes[1], 0x76); // dst=R6, src=R7
+    /// Stack offset and width of a variable bound to an asm register
+    fn asm_binding(&self, name: &str) -> Result<(usize, usize)> {
+        let (offset, ty) = self.variables.get(name)
+            .ok_or_else(|| anyhow!("Undefined variable in asm binding: {}", name))?;
+        if self.is_aggregate(ty) {
+            return Err(anyhow!("Cannot bind aggregate variable {} to a register", name));
+        }
+        Ok((*offset, self.value_width(ty)))
+    }
+
+    fn generate_expr(&mut self, expr: &Expr) -> Result<BpfReg> {
+        match expr {
+            Expr::IntLiteral(n) => {
+                let reg = self.alloc_reg()?;
+                if *n <= i32::MAX as u64 {
+                    self.emit(BpfInstruction::mov_imm(reg, *n as i32));
+                } else {
+                    // mov sign-extends its immediate, so use a full 64-bit load
+                    for inst in BpfInstruction::lddw(reg, *n) {
+                        self.emit(inst);
+                    }
+                }
+                Ok(reg)
+            }
+
+            Expr::CharLiteral(c) => self.load_imm(*c as i32),
+            Expr::StringLiteral(bytes) => {
+                let reg = self.alloc_reg()?;
+                let offset = self.rodata.intern(bytes);
+                self.emit_rodata_addr(reg, offset);
+                Ok(reg)
+            }
+            Expr::BoolLiteral(b) => self.load_imm(*b as i32),
+            Expr::Null => self.load_imm(0),
+            Expr::Sizeof(typ) => {
+                let size = self.layouts.size_of(typ);
+                self.load_imm(size as i32)
+            }
+
+            // Enumerators are constants unless a local shadows them
+            Expr::Ident(name) if !self.variables.contains_key(name) && self.layouts.constants().contains_key(name) => {
+                let value = self.layouts.constants()[name];
+                self.generate_expr(&Expr::IntLiteral(value))
+            }
+
+            // A function name, with or without `&`, is its address
+            Expr::Ident(name) if self.is_function_name(name) => self.function_addr(name),
+            Expr::Unary { op: UnaryOp::AddressOf, expr: inner }
+                if matches!(&**inner, Expr::Ident(name) if self.is_function_name(name)) =>
+            {
+                self.generate_expr(inner)
+            }
+
+            Expr::Ident(_) | Expr::Member { .. } | Expr::Arrow { .. } | Expr::Index { .. } => {
+                let place = self.generate_place(expr)?;
+                self.load_place(place)
+            }
+
+            Expr::Binary { op, left, right } if op.is_assignment() => {
+                self.check_writable(left)?;
+                let base_op = compound_base_op(*op);
+                let place = self.generate_place(left)?;
+                let value_reg = self.load_place(place.clone())?;
+                let right_reg = self.generate_expr(right)?;
+                let sign = match base_op {
+                    BinaryOp::Shl | BinaryOp::Shr => self.type_sign(&place.place_type),
+                    _ => self.type_sign(&place.place_type).combine(self.sign_of(right)),
+                };
+                let alu32 = self.alu32_type(base_op, left, right);
+                self.emit_alu(base_op, value_reg, right_reg, sign, alu32.as_ref())?;
+                self.free_reg(right_reg);
+                self.store_place(&place, value_reg)?;
+                self.free_place(&place);
+                Ok(value_reg)
+            }
+
+            Expr::Binary { op, .. } if op.is_comparison() || matches!(op, BinaryOp::LogicalAnd | BinaryOp::LogicalOr) => {
+                // Materialize the condition as 0 or 1
+                let false_jumps = self.generate_cond_jump(expr, false)?;
+                let reg = self.alloc_reg()?;
+                self.emit(BpfInstruction::mov_imm(reg, 1));
+                self.emit(BpfInstruction::ja(1));
+                let false_target = self.instructions.len();
+                self.patch_jumps(&false_jumps, false_target)?;
+                self.emit(BpfInstruction::mov_imm(reg, 0));
+                Ok(reg)
+            }
+
+            Expr::Binary { op, left, right } => {
+                let left_reg = self.generate_expr(left)?;
+                let right_reg = self.generate_expr(right)?;
+                let sign = match op {
+                    BinaryOp::Shl | BinaryOp::Shr => self.sign_of(left),
+                    _ => self.sign_of(left).combine(self.sign_of(right)),
+                };
+                let alu32 = self.alu32_type(*op, left, right);
+                self.emit_alu(*op, left_reg, right_reg, sign, alu32.as_ref())?;
+                self.free_reg(right_reg);
+                Ok(left_reg)
+            }
+
+            Expr::Unary { op, expr: inner } => match op {
+                UnaryOp::Neg => {
+                    let reg = self.generate_expr(inner)?;
+                    self.emit(BpfInstruction::new(BpfOpcode::Neg64, reg, BpfReg::R0, 0, 0));
+                    Ok(reg)
+                }
+                UnaryOp::BitNot => {
+                    let reg = self.generate_expr(inner)?;
+                    self.emit(BpfInstruction::new(BpfOpcode::Xor64Imm, reg, BpfReg::R0, 0, -1));
+                    Ok(reg)
+                }
+                UnaryOp::Not => {
+                    let true_jumps = self.generate_cond_jump(inner, true)?;
+                    let reg = self.alloc_reg()?;
+                    self.emit(BpfInstruction::mov_imm(reg, 1));
+                    self.emit(BpfInstruction::ja(1));
+                    let true_target = self.instructions.len();
+                    self.patch_jumps(&true_jumps, true_target)?;
+                    self.emit(BpfInstruction::mov_imm(reg, 0));
+                    Ok(reg)
+                }
+                UnaryOp::Deref => {
+                    let place = self.generate_place(expr)?;
+                    self.load_place(place)
+                }
+                UnaryOp::AddressOf => {
+                    let place = self.generate_place(inner)?;
+                    self.place_address(place)
+                }
+                UnaryOp::PreIncrement | UnaryOp::PreDecrement
+                | UnaryOp::PostIncrement | UnaryOp::PostDecrement => {
+                    self.check_writable(inner)?;
+                    let step = if matches!(op, UnaryOp::PreIncrement | UnaryOp::PostIncrement) { 1 } else { -1 };
+                    let place = self.generate_place(inner)?;
+                    let value_reg = self.load_place(place.clone())?;
+
+                    let result_reg = if matches!(op, UnaryOp::PostIncrement | UnaryOp::PostDecrement) {
+                        let old = self.alloc_reg()?;
+                        self.emit(BpfInstruction::mov_reg(old, value_reg));
+                        Some(old)
+                    } else {
+                        None
+                    };
+
+                    self.emit(BpfInstruction::add_imm(value_reg, step));
+                    self.store_place(&place, value_reg)?;
+                    self.free_place(&place);
+
+                    match result_reg {
+                        Some(old) => {
+                            self.free_reg(value_reg);
+                            Ok(old)
+                        }
+                        None => Ok(value_reg),
+                    }
+                }
+            },
+
+            Expr::Assign { target, value }
+                if self.expr_type(target).is_some_and(|ty| self.pass_mode(&ty) != PassMode::Scalar) =>
+            {
+                self.check_writable(target)?;
+                let source = self.generate_place(value)?;
+                let dest = self.generate_place(target)?;
+                self.check_class_copy(&dest.place_type, &source.place_type, "Assignment")?;
+                let size = self.layouts.size_of(&dest.place_type);
+                self.copy_place(&dest, &source, size);
+                self.free_place(&source);
+                self.place_address(dest)
+            }
+
+            Expr::Assign { target, value } => {
+                self.check_writable(target)?;
+                let value_reg = self.generate_expr(value)?;
+                let place = self.generate_place(target)?;
+                if self.is_aggregate(&place.place_type) {
+                    return Err(anyhow!("Invalid assignment target"));
+                }
+                self.store_place(&place, value_reg)?;
+                self.free_place(&place);
+                Ok(value_reg)
+            }
+
+            Expr::Call { func, args } => self.generate_call(func, args),
+
+            Expr::Cast { expr: inner, target_type } => {
+                let target_type = self.layouts.resolve(target_type);
+                if self.is_aggregate(&target_type) {
+                    return Err(anyhow!("Cannot cast {} to {}", inner, target_type));
+                }
+                if let Some(value) = consteval::eval(expr, self.layouts.constants(), &self.layouts)
+                    .filter(|_| !inner.any(&|e| matches!(e, Expr::Ident(name) if self.variables.contains_key(name))))
+                {
+                    return self.generate_expr(&Expr::IntLiteral(value));
+                }
+                let reg = self.generate_expr(inner)?;
+                self.extend(reg, &target_type);
+                Ok(reg)
+            }
+
+            Expr::Ternary { condition, then_expr, else_expr } => {
+                // Both branches leave their value in the register the then
+                // branch picked, so the else branch starts from the same
+                // free registers
+                let else_jumps = self.generate_cond_jump(condition, false)?;
+                let free_regs = self.free_regs.clone();
+                let reg = self.generate_expr(then_expr)?;
+                let end_jump = self.instructions.len();
+                self.emit(BpfInstruction::ja(0));
+
+                let else_target = self.instructions.len();
+                self.patch_jumps(&else_jumps, else_target)?;
+                self.free_regs = free_regs;
+                let else_reg = self.generate_expr(else_expr)?;
+                if else_reg != reg {
+                    self.emit(BpfInstruction::mov_reg(reg, else_reg));
+                    self.free_reg(else_reg);
+                }
+                self.free_regs.retain(|free| *free != reg);
+
+                let end = self.instructions.len();
+                self.patch_jump(end_jump, end)?;
+                Ok(reg)
+            }
+
+            Expr::Comma(exprs) => {
+                let (last, rest) = exprs.split_last().ok_or_else(|| anyhow!("Empty comma expression"))?;
+                for expr in rest {
+                    let reg = self.generate_expr(expr)?;
+                    self.free_reg(reg);
+                }
+                self.generate_expr(last)
+            }
+
+            _ => Err(anyhow!("Unsupported expression: {:?}", expr)),
+        }
+    }
+
+    /// Emit jumps taken when `cond` evaluates to `jump_if`, falling through
+    /// otherwise. Returns the indices of the jumps to patch.
+    fn generate_cond_jump(&mut self, cond: &Expr, jump_if: bool) -> Result<Vec<usize>> {
+        match cond {
+            Expr::Binary { op, left, right } if op.is_comparison() => {
+                let op = if jump_if { *op } else { negate_comparison(*op) };
+                let signed = self.sign_of(left).combine(self.sign_of(right)) == Sign::Signed;
+                let left_reg = self.generate_expr(left)?;
+                let right_reg = self.generate_expr(right)?;
+                self.emit(BpfInstruction::new(comparison_jump(op, signed), left_reg, right_reg, 0, 0));
+                self.free_reg(right_reg);
+                self.free_reg(left_reg);
+                Ok(vec![self.instructions.len() - 1])
+            }
+
+            // a && b jumps when false as soon as either side is false;
+            // a || b jumps when true as soon as either side is true.
+            Expr::Binary { op: op @ (BinaryOp::LogicalAnd | BinaryOp::LogicalOr), left, right } => {
+                let short_circuit_on = matches!(op, BinaryOp::LogicalOr);
+                if jump_if == short_circuit_on {
+                    let mut jumps = self.generate_cond_jump(left, jump_if)?;
+                    jumps.extend(self.generate_cond_jump(right, jump_if)?);
+                    Ok(jumps)
+                } else {
+                    let skip = self.generate_cond_jump(left, !jump_if)?;
+                    let jumps = self.generate_cond_jump(right, jump_if)?;
+                    let target = self.instructions.len();
+                    self.patch_jumps(&skip, target)?;
+                    Ok(jumps)
+                }
+            }
+
+            Expr::Unary { op: UnaryOp::Not, expr } => self.generate_cond_jump(expr, !jump_if),
+
+            Expr::Comma(exprs) => {
+                let (last, rest) = exprs.split_last().ok_or_else(|| anyhow!("Empty comma expression"))?;
+                for expr in rest {
+                    let reg = self.generate_expr(expr)?;
+                    self.free_reg(reg);
+                }
+                self.generate_cond_jump(last, jump_if)
+            }
+
+            _ => {
+                let reg = self.generate_expr(cond)?;
+                if jump_if {
+                    self.emit(BpfInstruction::jne_imm(reg, 0, 0));
+                } else {
+                    self.emit(BpfInstruction::jeq_imm(reg, 0, 0));
+                }
+                self.free_reg(reg);
+                Ok(vec![self.instructions.len() - 1])
+            }
+        }
+    }
+
+    /// Resolve an lvalue expression to a memory location
+    fn generate_place(&mut self, expr: &Expr) -> Result<Place> {
+        match expr {
+            Expr::Ident(name) => {
+                if let Some((offset, var_type)) = self.variables.get(name) {
+                    Ok(Place {
+                        base: BpfReg::R10,
+                        offset: -(*offset as i16),
+                        place_type: var_type.clone(),
+                    })
+                } else if let Some((offset, var_type)) = self.globals.get(name).cloned() {
+                    let base = self.alloc_reg()?;
+                    self.emit_rodata_addr(base, offset);
+                    Ok(Place { base, offset: 0, place_type: var_type })
+                } else {
+                    Err(anyhow!("Undefined variable: {}", name))
+                }
+            }
+
+            // A class returned by value lives in this frame
+            Expr::Call { .. } if self.expr_type(expr).is_some_and(|ty| self.is_aggregate(&self.layouts.resolve(&ty))) => {
+                let place_type = self.layouts.resolve(&self.expr_type(expr).unwrap());
+                let base = self.generate_expr(expr)?;
+                Ok(Place { base, offset: 0, place_type })
+            }
+
+            Expr::Unary { op: UnaryOp::Deref, expr: inner } => {
+                let pointee = match self.expr_type(inner) {
+                    Some(Type::Pointer(pointee)) | Some(Type::Array(pointee, _)) => *pointee,
+                    _ => Type::U64,
+                };
+                let base = self.generate_expr(inner)?;
+                Ok(Place { base, offset: 0, place_type: pointee })
+            }
+
+            Expr::Arrow { expr: inner, member } => {
+                let class_name = match self.expr_type(inner) {
+                    Some(Type::Pointer(pointee)) => match *pointee {
+                        Type::Custom(name) => name,
+                        other => return Err(anyhow!("'->' applied to non-class pointer type {}*", other)),
+                    },
+                    Some(other) => return Err(anyhow!("'->' applied to non-pointer type {}", other)),
+                    None => return Err(anyhow!("Cannot determine type of {}", inner)),
+                };
+                let (field_offset, field_type) = self.field(&class_name, member)?;
+                let base = self.generate_expr(inner)?;
+                Ok(Place { base, offset: field_offset, place_type: field_type })
+            }
+
+            Expr::Member { expr: inner, member } => {
+                let class_name = match self.expr_type(inner) {
+                    Some(Type::Custom(name)) => name,
+                    Some(ty) if ty.is_integer() => {
+                        let (element, count) = layout::integer_view(&ty, member)
+                            .ok_or_else(|| anyhow!("{} has no sub-integer view {}", ty, member))?;
+                        let place = self.generate_place(inner)?;
+                        return Ok(Place { place_type: Type::Array(Box::new(element), Some(count)), ..place });
+                    }
+                    Some(other) => return Err(anyhow!("'.' applied to non-class type {}", other)),
+                    None => return Err(anyhow!("Cannot determine type of {}", inner)),
+                };
+                let (field_offset, field_type) = self.field(&class_name, member)?;
+                let place = self.generate_place(inner)?;
+                let offset = place.offset.checked_add(field_offset)
+                    .ok_or_else(|| anyhow!("Field offset out of range: {}", expr))?;
+                Ok(Place { base: place.base, offset, place_type: field_type })
+            }
+
+            Expr::Index { expr: inner, index } => {
+                // A pointer, like an unsized array parameter, holds the
+                // address of the first element
+                let place = match self.expr_type(inner) {
+                    Some(Type::Pointer(element) | Type::Array(element, None)) => {
+                        let base = self.generate_expr(inner)?;
+                        Place { base, offset: 0, place_type: Type::Array(element, None) }
+                    }
+                    _ => self.generate_place(inner)?,
+                };
+                let (element, len) = match &place.place_type {
+                    Type::Array(element, len) => ((**element).clone(), *len),
+                    _ => return Err(anyhow!("Cannot index {}: only arrays and pointers can be indexed", inner)),
+                };
+                let size = self.layouts.size_of(&element);
+
+                // A constant index folds into the offset
+                if let Some(idx) = consteval::eval(index, &HashMap::new(), &self.layouts) {
+                    if let Some(len) = len.filter(|len| idx >= *len as u64) {
+                        return Err(anyhow!("Index {} is out of bounds for {} (length {})", idx, inner, len));
+                    }
+                    let offset = (idx as i64).checked_mul(size as i64)
+                        .and_then(|delta| delta.checked_add(place.offset as i64))
+                        .and_then(|offset| i16::try_from(offset).ok());
+                    match offset {
+                        Some(offset) => return Ok(Place { offset, place_type: element, ..place }),
+                        None if len.is_some() => return Err(anyhow!("Element offset out of range: {}", expr)),
+                        // Too far from the pointer for an offset; add it instead
+                        None => {}
+                    }
+                }
+
+                let base = self.place_address(place)?;
+                let index_reg = self.generate_expr(index)?;
+                if let Some(len) = len.filter(|_| self.bounds_checks) {
+                    // Negative indices wrap around and fail the unsigned check
+                    let check = self.emit_with_const(BpfOpcode::JltImm, BpfOpcode::JltReg, index_reg, len as u64);
+                    self.emit(BpfInstruction::syscall(syscalls::hash("abort")));
+                    self.patch_jump(check, self.instructions.len())?;
+                }
+                // Scale the index to a byte offset
+                match size {
+                    1 => {}
+                    _ if size.is_power_of_two() => {
+                        let shift = size.trailing_zeros() as i32;
+                        self.emit(BpfInstruction::new(BpfOpcode::Lsh64Imm, index_reg, BpfReg::R0, 0, shift));
+                    }
+                    _ => {
+                        self.emit_with_const(BpfOpcode::Mul64Imm, BpfOpcode::Mul64Reg, index_reg, size as u64);
+                    }
+                }
+                self.emit(BpfInstruction::add_reg(base, index_reg));
+                self.free_reg(index_reg);
+                Ok(Place { base, offset: 0, place_type: element })
+            }
+
+            _ => Err(anyhow!("Invalid assignment target")),
+        }
+    }
+
+    /// Load the value stored at `place`; aggregates evaluate to their address
+    fn load_place(&mut self, place: Place) -> Result<BpfReg> {
+        if self.is_aggregate(&place.place_type) {
+            return self.place_address(place);
+        }
+
+        let width = self.value_width(&place.place_type);
+        let reg = if place.base == BpfReg::R10 { self.alloc_reg()? } else { place.base };
+        self.emit(BpfInstruction::ldx(width, reg, place.base, place.offset));
+        // Loads zero-extend; signed values are sign-extended
+        let place_type = self.layouts.resolve(&place.place_type);
+        if matches!(place_type, Type::I8 | Type::I16 | Type::I32) {
+            self.extend(reg, &place_type);
+        }
+        Ok(reg)
+    }
+
+    fn store_place(&mut self, place: &Place, value: BpfReg) -> Result<()> {
+        let width = self.value_width(&place.place_type);
+        self.emit(BpfInstruction::stx(width, place.base, value, place.offset));
+        Ok(())
+    }
+
+    fn place_address(&mut self, place: Place) -> Result<BpfReg> {
+        let reg = if place.base == BpfReg::R10 {
+            let reg = self.alloc_reg()?;
+            self.emit(BpfInstruction::mov_reg(reg, BpfReg::R10));
+            reg
+        } else {
+            place.base
+        };
+        if place.offset != 0 {
+            self.emit(BpfInstruction::add_imm(reg, place.offset as i32));
+        }
+        Ok(reg)
+    }
+
+    fn free_place(&mut self, place: &Place) {
+        if place.base != BpfReg::R10 {
+            self.free_reg(place.base);
+        }
+    }
+
+    fn field(&self, class_name: &str, member: &str) -> Result<(i16, Type)> {
+        let class = self.layouts.class(class_name)
+            .ok_or_else(|| anyhow!("Unknown class: {}", class_name))?;
+        let field = class.field(member)
+            .ok_or_else(|| anyhow!("Class {} has no field {}", class_name, member))?;
+        let offset = i16::try_from(field.offset)
+            .map_err(|_| anyhow!("Field {}.{} is out of addressable range", class_name, member))?;
+        Ok((offset, self.layouts.resolve(&field.field_type)))
+    }
+
+    /// Static type of an expression, where it can be determined
+    fn expr_type(&self, expr: &Expr) -> Option<Type> {
+        match expr {
+            Expr::IntLiteral(_) | Expr::Sizeof(_) => Some(Type::I64),
+            Expr::CharLiteral(_) => Some(Type::U8),
+            Expr::StringLiteral(_) => Some(Type::Pointer(Box::new(Type::U8))),
+            Expr::BoolLiteral(_) => Some(Type::Bool),
+            Expr::Ident(name) if self.is_function_name(name) => self.functions.get(name).cloned(),
+            Expr::Ident(name) => self.variables.get(name).or_else(|| self.globals.get(name)).map(|(_, ty)| ty.clone()),
+            Expr::Unary { op: UnaryOp::AddressOf, expr: inner }
+                if matches!(&**inner, Expr::Ident(name) if self.is_function_name(name)) =>
+            {
+                self.expr_type(inner)
+            }
+            Expr::Call { func, .. } => match self.function_type(func)? {
+                Type::Function { return_type, .. } => Some(*return_type),
+                _ => None,
+            },
+            Expr::Arrow { expr, member } => match self.expr_type(expr)? {
+                Type::Pointer(pointee) => match *pointee {
+                    Type::Custom(name) => self.field(&name, member).ok().map(|(_, ty)| ty),
+                    _ => None,
+                },
+                _ => None,
+            },
+            Expr::Member { expr, member } => match self.expr_type(expr)? {
+                Type::Custom(name) => self.field(&name, member).ok().map(|(_, ty)| ty),
+                ty => layout::integer_view(&ty, member)
+                    .map(|(element, count)| Type::Array(Box::new(element), Some(count))),
+            },
+            Expr::Index { expr, .. } => match self.expr_type(expr)? {
+                Type::Pointer(element) | Type::Array(element, _) => Some(*element),
+                _ => None,
+            },
+            Expr::Unary { op: UnaryOp::Deref, expr } => match self.expr_type(expr)? {
+                Type::Pointer(pointee) | Type::Array(pointee, _) => Some(*pointee),
+                _ => None,
+            },
+            Expr::Unary { op: UnaryOp::AddressOf, expr } => {
+                Some(Type::Pointer(Box::new(self.expr_type(expr)?)))
+            }
+            Expr::Unary { op: UnaryOp::Neg | UnaryOp::BitNot, expr: inner } => self.arithmetic_type(expr, inner, None),
+            Expr::Unary { expr, .. } => self.expr_type(expr),
+            Expr::Binary { op, left, right } if op.is_assignment() => {
+                self.arithmetic_type(expr, left, Some((compound_base_op(*op), left, right)))
+            }
+            Expr::Binary { op, left, right } if !op.is_comparison() => {
+                self.arithmetic_type(expr, left, Some((*op, left, right)))
+            }
+            Expr::Assign { target, .. } => self.expr_type(target),
+            Expr::Cast { target_type, .. } => Some(target_type.clone()),
+            Expr::Ternary { then_expr, else_expr, .. } => self.expr_type(then_expr).or_else(|| self.expr_type(else_expr)),
+            Expr::Comma(exprs) => self.expr_type(exprs.last()?),
+            _ => None,
+        }
+    }
+
+    /// Type of the result of an arithmetic `expr` whose first operand is
+    /// `left`. Pointer arithmetic keeps the pointer type. Integer results
+    /// are not truncated, so they are 64-bit unless `op` runs in ALU32.
+    fn arithmetic_type(&self, expr: &Expr, left: &Expr, op: Option<(BinaryOp, &Expr, &Expr)>) -> Option<Type> {
+        let ty = self.expr_type(left)?;
+        if int_range(&self.layouts.resolve(&ty)).is_none() {
+            return Some(ty);
+        }
+        if let Some(ty) = op.and_then(|(op, left, right)| self.alu32_type(op, left, right)) {
+            return Some(ty);
+        }
+        let sign = match op {
+            Some((BinaryOp::Shl | BinaryOp::Shr, left, _)) => self.sign_of(left),
+            Some((_, left, right)) if matches!(expr, Expr::Binary { op, .. } if op.is_assignment()) => {
+                self.sign_of(left).combine(self.sign_of(right))
+            }
+            _ => self.sign_of(expr),
+        };
+        match sign {
+            Sign::Signed => Some(Type::I64),
+            _ => Some(Type::U64),
+        }
+    }
+
+    /// Classes and fixed-size arrays live in memory and are handled by address
+    fn is_aggregate(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Array(_, Some(_)) => true,
+            Type::Custom(name) => self.layouts.class(name).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Width in bytes of a scalar value of this type when stored in memory
+    fn value_width(&self, ty: &Type) -> usize {
+        match ty {
+            Type::U8 | Type::I8 | Type::Bool => 1,
+            Type::U16 | Type::I16 => 2,
+            Type::U32 | Type::I32 => 4,
+            _ => 8,
+        }
+    }
+
+    /// `dst = dst op src`. Signed division, remainder and right shift
+    /// differ from unsigned ones. With `alu32` set to a 32-bit type the
+    /// operation wraps at 32 bits, and the result is extended again, since
+    /// VM versions disagree on how 32-bit results fill the upper half.
+    fn emit_alu(&mut self, op: BinaryOp, dst: BpfReg, src: BpfReg, sign: Sign, alu32: Option<&Type>) -> Result<()> {
+        let signed = sign == Sign::Signed;
+        if signed && matches!(op, BinaryOp::Div | BinaryOp::Mod) {
+            return self.emit_signed_div(op, dst, src);
+        }
+        let mut inst = match op {
+            BinaryOp::Add => BpfInstruction::add_reg(dst, src),
+            BinaryOp::Sub => BpfInstruction::sub_reg(dst, src),
+            BinaryOp::Mul => BpfInstruction::mul_reg(dst, src),
+            BinaryOp::Div => BpfInstruction::div_reg(dst, src),
+            BinaryOp::Mod => BpfInstruction::mod_reg(dst, src),
+            BinaryOp::BitAnd => BpfInstruction::and_reg(dst, src),
+            BinaryOp::BitOr => BpfInstruction::or_reg(dst, src),
+            BinaryOp::BitXor => BpfInstruction::xor_reg(dst, src),
+            BinaryOp::Shl => BpfInstruction::lsh_reg(dst, src),
+            BinaryOp::Shr if signed => BpfInstruction::new(BpfOpcode::Arsh64Reg, dst, src, 0, 0),
+            BinaryOp::Shr => BpfInstruction::rsh_reg(dst, src),
+            _ => return Err(anyhow!("Unsupported binary operator: {}", op)),
+        };
+        if let Some(ty) = alu32 {
+            // ALU32 opcodes share the operation bits, in the 32-bit class
+            inst.opcode = (inst.opcode & 0xf8) | 0x04;
+            self.emit(inst);
+            self.extend(dst, ty);
+        } else {
+            self.emit(inst);
+        }
+        Ok(())
+    }
+
+    /// Signed `/` or `%` from the unsigned instructions: divide the
+    /// magnitudes, then negate the result if the quotient's signs differ,
+    /// or the remainder's dividend is negative
+    fn emit_signed_div(&mut self, op: BinaryOp, dst: BpfReg, src: BpfReg) -> Result<()> {
+        let mask = self.alloc_reg()?;
+        self.emit(BpfInstruction::mov_reg(mask, dst));
+        if op == BinaryOp::Div {
+            self.emit(BpfInstruction::xor_reg(mask, src));
+        }
+        self.emit(BpfInstruction::new(BpfOpcode::Arsh64Imm, mask, BpfReg::R0, 0, 63));
+        for reg in [dst, src] {
+            // |reg|, through its own sign mask in R0
+            self.emit(BpfInstruction::mov_reg(BpfReg::R0, reg));
+            self.emit(BpfInstruction::new(BpfOpcode::Arsh64Imm, BpfReg::R0, BpfReg::R0, 0, 63));
+            self.emit(BpfInstruction::xor_reg(reg, BpfReg::R0));
+            self.emit(BpfInstruction::sub_reg(reg, BpfReg::R0));
+        }
+        self.emit(match op {
+            BinaryOp::Div => BpfInstruction::div_reg(dst, src),
+            _ => BpfInstruction::mod_reg(dst, src),
+        });
+        self.emit(BpfInstruction::xor_reg(dst, mask));
+        self.emit(BpfInstruction::sub_reg(dst, mask));
+        self.free_reg(mask);
+        Ok(())
+    }
+
+    /// Convert the value of `from` in `reg` to `to`, truncating and
+    /// extending only where `to` cannot hold every value of `from`'s type
+    fn convert(&mut self, reg: BpfReg, from: &Expr, to: &Type) {
+        let to = self.layouts.resolve(to);
+        let Some(to_range) = int_range(&to) else { return };
+        let fits = self.expr_type(from)
+            .and_then(|ty| int_range(&self.layouts.resolve(&ty)))
+            .is_some_and(|(low, high)| to_range.0 <= low && high <= to_range.1);
+        if !fits {
+            self.extend(reg, &to);
+        }
+    }
+
+    /// Truncate `reg` to the width of the integer type `ty` and extend it
+    /// back to 64 bits, by sign for signed types. Bool becomes 0 or 1.
+    fn extend(&mut self, reg: BpfReg, ty: &Type) {
+        match ty {
+            Type::U8 => self.emit(BpfInstruction::new(BpfOpcode::And64Imm, reg, BpfReg::R0, 0, 0xff)),
+            Type::U16 => self.emit(BpfInstruction::new(BpfOpcode::And64Imm, reg, BpfReg::R0, 0, 0xffff)),
+            Type::U32 => {
+                self.emit(BpfInstruction::new(BpfOpcode::Lsh64Imm, reg, BpfReg::R0, 0, 32));
+                self.emit(BpfInstruction::new(BpfOpcode::Rsh64Imm, reg, BpfReg::R0, 0, 32));
+            }
+            Type::I8 | Type::I16 | Type::I32 => {
+                let shift = 64 - 8 * self.value_width(ty) as i32;
+                self.emit(BpfInstruction::new(BpfOpcode::Lsh64Imm, reg, BpfReg::R0, 0, shift));
+                self.emit(BpfInstruction::new(BpfOpcode::Arsh64Imm, reg, BpfReg::R0, 0, shift));
+            }
+            Type::Bool => {
+                self.emit(BpfInstruction::jeq_imm(reg, 0, 1));
+                self.emit(BpfInstruction::mov_imm(reg, 1));
+            }
+            _ => {}
+        }
+    }
+
+    /// What is known about the sign of `expr`'s value
+    fn sign_of(&self, expr: &Expr) -> Sign {
+        match expr {
+            Expr::IntLiteral(n) if *n > i64::MAX as u64 => Sign::Unsigned,
+            Expr::IntLiteral(_) | Expr::CharLiteral(_) | Expr::BoolLiteral(_) | Expr::Sizeof(_) => Sign::NonNegative,
+            Expr::Unary { op: UnaryOp::Neg | UnaryOp::BitNot, expr } => match self.sign_of(expr) {
+                Sign::Unsigned => Sign::Unsigned,
+                _ => Sign::Signed,
+            },
+            Expr::Unary { op: UnaryOp::Not, .. } => Sign::NonNegative,
+            Expr::Binary { op, .. } if op.is_comparison() || matches!(op, BinaryOp::LogicalAnd | BinaryOp::LogicalOr) => {
+                Sign::NonNegative
+            }
+            Expr::Binary { op: BinaryOp::Shl | BinaryOp::Shr, left, .. } => self.sign_of(left),
+            // The difference of two non-negative values may be negative
+            Expr::Binary { op: BinaryOp::Sub, left, right } => match self.sign_of(left).combine(self.sign_of(right)) {
+                Sign::NonNegative => Sign::Signed,
+                sign => sign,
+            },
+            Expr::Binary { op, left, right } if !op.is_assignment() => self.sign_of(left).combine(self.sign_of(right)),
+            Expr::Ternary { then_expr, else_expr, .. } => self.sign_of(then_expr).combine(self.sign_of(else_expr)),
+            Expr::Comma(exprs) => exprs.last().map_or(Sign::Unsigned, |last| self.sign_of(last)),
+            Expr::Ident(name) if !self.variables.contains_key(name) && self.layouts.constants().contains_key(name) => {
+                match self.layouts.constants()[name] {
+                    n if n > i64::MAX as u64 => Sign::Signed,
+                    _ => Sign::NonNegative,
+                }
+            }
+            _ => self.expr_type(expr).map_or(Sign::Unsigned, |ty| self.type_sign(&ty)),
+        }
+    }
+
+    fn type_sign(&self, ty: &Type) -> Sign {
+        match self.layouts.resolve(ty) {
+            Type::I8 | Type::I16 | Type::I32 | Type::I64 => Sign::Signed,
+            Type::U8 | Type::U16 | Type::U32 | Type::Bool => Sign::NonNegative,
+            _ => Sign::Unsigned,
+        }
+    }
+
+    /// With ALU32 enabled, the 32-bit type an operation on `left` and
+    /// `right` is computed in, if both fit in 32 bits. Signed division
+    /// stays 64-bit, as there is no signed divide instruction.
+    fn alu32_type(&self, op: BinaryOp, left: &Expr, right: &Expr) -> Option<Type> {
+        if !self.alu32 {
+            return None;
+        }
+        let narrow = |expr: &Expr| match expr {
+            Expr::IntLiteral(n) => *n <= i32::MAX as u64,
+            _ => self.expr_type(expr)
+                .is_some_and(|ty| self.layouts.resolve(&ty).is_integer() && self.value_width(&self.layouts.resolve(&ty)) <= 4),
+        };
+        if !narrow(left) || !narrow(right) {
+            return None;
+        }
+        // As in C, an unsigned 32-bit operand makes the operation unsigned
+        let unsigned = |expr: &Expr| self.expr_type(expr).is_some_and(|ty| self.layouts.resolve(&ty) == Type::U32);
+        // A shift has the type of its left operand
+        let right = if matches!(op, BinaryOp::Shl | BinaryOp::Shr) { left } else { right };
+        match self.sign_of(left).combine(self.sign_of(right)) {
+            Sign::Signed if !unsigned(left) && !unsigned(right) => Some(Type::I32),
+            _ => Some(Type::U32),
+        }
+    }
+
+    fn load_imm(&mut self, imm: i32) -> Result<BpfReg> {
+        let reg = self.alloc_reg()?;
+        self.emit(BpfInstruction::mov_imm(reg, imm));
+        Ok(reg)
+    }
+
+    /// Trip count of a `for` loop starting at instruction `start`, with the
+    /// counter's type known here. A label at the start may be jumped to
+    /// from anywhere, so such a loop has none.
+    fn trip_count(&self, stmt: &Stmt, start: usize) -> Option<u64> {
+        if self.labels.values().any(|pc| *pc == start) {
+            return None;
+        }
+        let mut canonical = CanonicalLoop::from_stmt(stmt)?;
+        if canonical.var_type.is_none() {
+            canonical.var_type = Some(self.variables.get(&canonical.var)?.1.clone());
+        }
+        canonical.trip_count()
+    }
+
+    /// Whether `name` refers to a function rather than a variable
+    fn is_function_name(&self, name: &str) -> bool {
+        self.functions.contains_key(name) && !self.variables.contains_key(name) && !self.globals.contains_key(name)
+    }
+
+    /// Signature of a callee: a function name, a function pointer, or a
+    /// dereferenced function pointer
+    fn function_type(&self, callee: &Expr) -> Option<Type> {
+        match self.expr_type(self.strip_deref(callee))? {
+            Type::Pointer(signature) if matches!(*signature, Type::Function { .. }) => Some(*signature),
+            _ => None,
+        }
+    }
+
+    /// Calling `(*handler)(..)` is the same as calling `handler(..)`
+    fn strip_deref<'a>(&self, callee: &'a Expr) -> &'a Expr {
+        match callee {
+            Expr::Unary { op: UnaryOp::Deref, expr } if matches!(
+                self.expr_type(expr),
+                Some(Type::Pointer(signature)) if matches!(*signature, Type::Function { .. })
+            ) => self.strip_deref(expr),
+            _ => callee,
+        }
+    }
+
+    /// Load the address of a function, relocated when the ELF is written
+    fn function_addr(&mut self, name: &str) -> Result<BpfReg> {
+        let reg = self.alloc_reg()?;
+        self.function_refs.push((self.instructions.len(), name.to_string()));
+        for inst in BpfInstruction::lddw(reg, 0) {
+            self.emit(inst);
+        }
+        Ok(reg)
+    }
+
+    /// Load the address of `.rodata` + `offset`, relocated when the ELF is written
+    fn emit_rodata_addr(&mut self, reg: BpfReg, offset: usize) {
+        self.rodata_refs.push((self.instructions.len(), offset));
+        for inst in BpfInstruction::lddw(reg, offset as u64) {
+            self.emit(inst);
+        }
+    }
+
+    /// Globals live in read-only memory, so writing one is an error
+    fn check_writable(&self, target: &Expr) -> Result<()> {
+        let mut root = target;
+        loop {
+            match root {
+                Expr::Member { expr, .. } => root = expr,
+                Expr::Index { expr, .. } if matches!(self.expr_type(expr), Some(Type::Array(..))) => root = expr,
+                _ => break,
+            }
+        }
+        match root {
+            Expr::Ident(name) if !self.variables.contains_key(name) && self.globals.contains_key(name) => Err(anyhow!(
+                "Cannot write to global {}: SBF programs have no writable static data, keep mutable state in locals or account data",
+                name
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn emit(&mut self, inst: BpfInstruction) {
+        self.instructions.push(inst);
+    }
+
+    /// Point the jump at `idx` to instruction `target`
+    fn patch_jump(&mut self, idx: usize, target: usize) -> Result<()> {
+        let offset = target as i64 - idx as i64 - 1;
+        self.instructions[idx].offset = i16::try_from(offset)
+            .map_err(|_| anyhow!("Jump offset {} out of range", offset))?;
+        self.jump_targets.insert(target);
+        Ok(())
+    }
+
+    fn patch_jumps(&mut self, jumps: &[usize], target: usize) -> Result<()> {
+        for &idx in jumps {
+            self.patch_jump(idx, target)?;
+        }
+        Ok(())
+    }
+
+    fn alloc_reg(&mut self) -> Result<BpfReg> {
+        self.free_regs.pop()
+            .ok_or_else(|| anyhow!("Out of registers: expression too complex"))
+    }
+
+    fn free_reg(&mut self, reg: BpfReg) {
+        if SCRATCH_REGS.contains(&reg) && !self.free_regs.contains(&reg) {
+            self.free_regs.push(reg);
+        }
+    }
+
+    fn reset_regs(&mut self) {
+        self.free_regs = SCRATCH_REGS.iter().rev().copied().collect();
+    }
+
+    /// Lower a call. Classes are passed by value in up to two words, or as
+    /// a pointer to a copy when larger. A call returning a class writes it
+    /// to a slot in this frame whose address is passed first, and evaluates
+    /// to that address.
+    fn generate_call(&mut self, func: &Expr, args: &[Expr]) -> Result<BpfReg> {
+        // Anything but a function or syscall name is called through
+        // a function pointer, evaluated first into a callee-saved
+        // register so the arguments cannot clobber it
+        let direct = match func {
+            Expr::Ident(name) if self.is_function_name(name) || syscalls::is_syscall(name) => Some(name),
+            Expr::Ident(name) if self.function_type(func).is_none() => {
+                return Err(anyhow!("Undefined function: {}", name));
+            }
+            _ => None,
+        };
+        let signature = self.function_type(func);
+        let target = match direct {
+            Some(_) => None,
+            None => {
+                let Some(Type::Function { params, .. }) = &signature else {
+                    return Err(anyhow!("Invalid function call: {} is not a function pointer", func));
+                };
+                if params.len() != args.len() {
+                    return Err(anyhow!(
+                        "Call through {} expects {} arguments, got {}", func, params.len(), args.len()
+                    ));
+                }
+                Some(self.generate_expr(self.strip_deref(func))?)
+            }
+        };
+
+        let (params, return_type) = match signature {
+            Some(Type::Function { params, return_type }) => (params, self.layouts.resolve(&return_type)),
+            _ => (Vec::new(), Type::U64),
+        };
+        let modes: Vec<PassMode> = args.iter().enumerate()
+            .map(|(idx, arg)| {
+                let ty = params.get(idx).map(|ty| self.layouts.resolve(ty)).or_else(|| self.expr_type(arg));
+                ty.map_or(PassMode::Scalar, |ty| self.pass_mode(&ty))
+            })
+            .collect();
+        let sret = match self.pass_mode(&return_type) {
+            PassMode::Scalar => None,
+            _ => {
+                let size = self.layouts.size_of(&return_type);
+                let align = self.layouts.align_of(&return_type);
+                Some(self.frame.alloc("<sret>", SlotKind::Spill, return_type.clone(), size, align))
+            }
+        };
+        let total = sret.is_some() as usize + modes.iter().map(|m| m.words()).sum::<usize>();
+        if let Some(name) = direct.filter(|name| !self.is_function_name(name)) {
+            if total > ARG_REGS.len() {
+                return Err(anyhow!("Too many arguments to syscall {} (max 5)", name));
+            }
+        }
+
+        // Large classes are copied before any argument is loaded
+        let mut copies = HashMap::new();
+        for (idx, (arg, mode)) in args.iter().zip(&modes).enumerate() {
+            if *mode != PassMode::Indirect {
+                continue;
+            }
+            let source = self.generate_place(arg)?;
+            let ty = params.get(idx).map_or_else(|| source.place_type.clone(), |ty| self.layouts.resolve(ty));
+            self.check_class_copy(&ty, &source.place_type, &format!("Argument {} of {}", idx + 1, func))?;
+            let size = self.layouts.size_of(&ty);
+            let offset = self.frame.alloc("<byval>", SlotKind::Spill, ty.clone(), size, self.layouts.align_of(&ty));
+            let dest = Place { base: BpfReg::R10, offset: -(offset as i16), place_type: ty };
+            self.copy_place(&dest, &source, size);
+            self.free_place(&source);
+            copies.insert(idx, offset);
+        }
+
+        // Load arguments into R1-R5 or the stack argument area.
+        // Arguments followed by another call are parked in a spill
+        // slot since calls clobber R1-R5 and the stack arguments.
+        let mut parked = Vec::new();
+        let mut word = 0;
+        if let Some(offset) = sret {
+            let reg = self.frame_address(offset)?;
+            let park = args.iter().any(|a| a.contains_call());
+            self.pass_word(arg_slot(word, total), reg, park, &mut parked);
+            word += 1;
+        }
+        for (idx, (arg, mode)) in args.iter().zip(&modes).enumerate() {
+            let park = args[idx + 1..].iter().any(|a| a.contains_call());
+            match mode {
+                PassMode::Scalar => {
+                    let reg = self.generate_expr(arg)?;
+                    self.pass_word(arg_slot(word, total), reg, park, &mut parked);
+                }
+                PassMode::Words(n) => {
+                    let place = self.generate_place(arg)?;
+                    if let Some(param) = params.get(idx) {
+                        let param = self.layouts.resolve(param);
+                        self.check_class_copy(&param, &place.place_type, &format!("Argument {} of {}", idx + 1, func))?;
+                    }
+                    let size = self.layouts.size_of(&place.place_type);
+                    for k in 0..*n {
+                        let reg = self.load_bytes(&place, 8 * k, (size - 8 * k).min(8))?;
+                        self.pass_word(arg_slot(word + k, total), reg, park, &mut parked);
+                    }
+                    self.free_place(&place);
+                }
+                PassMode::Indirect => {
+                    let reg = self.frame_address(copies[&idx])?;
+                    self.pass_word(arg_slot(word, total), reg, park, &mut parked);
+                }
+            }
+            word += mode.words();
+        }
+        for (slot, offset) in parked {
+            match slot {
+                ArgSlot::Reg(reg) => self.emit(BpfInstruction::ldxdw(reg, BpfReg::R10, offset)),
+                ArgSlot::Stack(_) => {
+                    self.emit(BpfInstruction::ldxdw(BpfReg::R0, BpfReg::R10, offset));
+                    self.pass_arg(slot, BpfReg::R0);
+                }
+            }
+        }
+        if total > ARG_REGS.len() {
+            self.frame.reserve_outgoing(8 * (total + 1 - ARG_REGS.len()));
+            self.emit(BpfInstruction::mov_reg(BpfReg::R5, BpfReg::R10));
+        }
+
+        // Call function
+        match (direct, target) {
+            (_, Some(target)) => {
+                self.emit(BpfInstruction::callx(target, self.target.callx));
+                self.free_reg(target);
+            }
+            (Some(func_name), None) if self.functions.contains_key(func_name) => {
+                self.call_fixups.push((self.instructions.len(), func_name.clone()));
+                self.emit(BpfInstruction::call_internal(-1));
+            }
+            (Some(func_name), None) => {
+                self.emit(BpfInstruction::syscall(syscalls::hash(func_name)));
+            }
+            (None, None) => unreachable!("indirect calls always have a target"),
+        }
+
+        // Result arrives in R0, which the next call clobbers
+        if let Some(offset) = sret {
+            return self.frame_address(offset);
+        }
+        let reg = self.alloc_reg()?;
+        self.emit(BpfInstruction::mov_reg(reg, BpfReg::R0));
+        Ok(reg)
+    }
+
+    /// Pass one argument word, or park it when a later argument makes a call
+    fn pass_word(&mut self, slot: ArgSlot, reg: BpfReg, park: bool, parked: &mut Vec<(ArgSlot, i16)>) {
+        if park {
+            let offset = self.alloc_temp_slot();
+            self.emit(BpfInstruction::stxdw(BpfReg::R10, reg, offset));
+            parked.push((slot, offset));
+        } else {
+            self.pass_arg(slot, reg);
+        }
+        self.free_reg(reg);
+    }
+
+    /// Register holding incoming argument word `word` of `total`, loading
+    /// it into R0 when it was passed on the stack
+    fn incoming_word(&mut self, word: usize, total: usize) -> BpfReg {
+        match arg_slot(word, total) {
+            ArgSlot::Reg(reg) => reg,
+            ArgSlot::Stack(slot) => {
+                self.emit(BpfInstruction::ldxdw(BpfReg::R0, BpfReg::R5, self.stack_arg_offset(slot)));
+                BpfReg::R0
+            }
+        }
+    }
+
+    fn pass_mode(&self, ty: &Type) -> PassMode {
+        match ty {
+            Type::Custom(name) if self.layouts.class(name).is_some() => {
+                let size = self.layouts.size_of(ty);
+                if size <= MAX_WORDS_BYTES {
+                    PassMode::Words(size.div_ceil(8))
+                } else {
+                    PassMode::Indirect
+                }
+            }
+            _ => PassMode::Scalar,
+        }
+    }
+
+    /// A class value may be copied into the same class or into a base class
+    fn check_class_copy(&self, to: &Type, from: &Type, what: &str) -> Result<()> {
+        match (to, from) {
+            (Type::Custom(to_name), Type::Custom(from_name)) if self.layouts.derives_from(from_name, to_name) => Ok(()),
+            _ => Err(anyhow!("{} has type {}, expected {}", what, from, to)),
+        }
+    }
+
+    /// Initialize the local `name` at `offset` below R10. Constant parts of
+    /// the initializer become immediate stores, zeros included; the rest is
+    /// computed and stored element by element.
+    fn generate_initializer(&mut self, name: &str, offset: usize, ty: &Type, init: &Expr) -> Result<()> {
+        // Locals hide enumerators of the same name
+        let mut constants = self.layouts.constants().clone();
+        constants.retain(|constant, _| !self.variables.contains_key(constant));
+
+        let size = self.layouts.size_of(ty);
+        let mut image = vec![0u8; size];
+        let mut runtime = Vec::new();
+        self.lay_out_init(name, ty, init, 0, &constants, &mut image, &mut runtime)?;
+
+        // Bytes written at run time are not stored twice
+        let mut skip = vec![false; size];
+        for (at, elem_type, _) in &runtime {
+            let end = at + self.layouts.size_of(elem_type);
+            skip[*at..end].fill(true);
+        }
+        let mut at = 0;
+        while at < size {
+            if skip[at] {
+                at += 1;
+                continue;
+            }
+            // R10 is 8-byte aligned, so the offset gives the address's alignment
+            let align = 1 << (offset - at).trailing_zeros().min(3);
+            let mut width = chunk_width(align, size - at);
+            while skip[at..at + width].contains(&true) {
+                width /= 2;
+            }
+            let mut value = [0u8; 8];
+            value[..width].copy_from_slice(&image[at..at + width]);
+            let mut value = u64::from_le_bytes(value);
+            // A doubleword store sign-extends its 32-bit immediate
+            if width == 8 && value as i64 != value as i32 as i64 {
+                width = 4;
+                value &= 0xffff_ffff;
+            }
+            self.emit(BpfInstruction::st(width, BpfReg::R10, at as i16 - offset as i16, value as i32));
+            at += width;
+        }
+
+        for (at, elem_type, expr) in runtime {
+            self.reset_regs();
+            let dest = Place { base: BpfReg::R10, offset: at as i16 - offset as i16, place_type: elem_type.clone() };
+            if self.pass_mode(&elem_type) != PassMode::Scalar {
+                let source = self.generate_place(expr)?;
+                self.check_class_copy(&elem_type, &source.place_type, &format!("Initializer of {}", name))?;
+                self.copy_place(&dest, &source, self.layouts.size_of(&elem_type));
+            } else {
+                let reg = self.generate_expr(expr)?;
+                self.store_place(&dest, reg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the constant parts of `init`, for a `ty` at `at` bytes into
+    /// `image`, and collect the elements only known at run time
+    #[allow(clippy::too_many_arguments)]
+    fn lay_out_init<'e>(
+        &self,
+        name: &str,
+        ty: &Type,
+        init: &'e Expr,
+        at: usize,
+        constants: &HashMap<String, u64>,
+        image: &mut [u8],
+        runtime: &mut Vec<(usize, Type, &'e Expr)>,
+    ) -> Result<()> {
+        let ty = self.layouts.resolve(ty);
+        match (&ty, init) {
+            (Type::Array(elem, Some(len)), Expr::StringLiteral(bytes)) if matches!(**elem, Type::U8 | Type::I8) => {
+                // The terminating NUL is dropped if only it does not fit
+                if bytes.len() > *len {
+                    return Err(anyhow!("String initializer of {} has {} bytes, but {} holds {}", name, bytes.len(), ty, len));
+                }
+                image[at..at + bytes.len()].copy_from_slice(bytes);
+            }
+            (Type::Array(elem, Some(len)), Expr::InitList(items)) => {
+                if items.len() > *len {
+                    return Err(anyhow!("Initializer of {} has {} elements, but {} holds {}", name, items.len(), ty, len));
+                }
+                let stride = self.layouts.size_of(elem);
+                for (idx, item) in items.iter().enumerate() {
+                    self.lay_out_init(name, elem, item, at + idx * stride, constants, image, runtime)?;
+                }
+            }
+            (Type::Custom(class_name), Expr::InitList(items)) if self.layouts.class(class_name).is_some() => {
+                // Fields are initialized in layout order, base class first.
+                // Of overlapping union members, only the first is.
+                let mut fields = Vec::new();
+                let mut end = 0;
+                for field in &self.layouts.class(class_name).unwrap().fields {
+                    if field.offset >= end || fields.is_empty() {
+                        end = field.offset + self.layouts.size_of(&field.field_type);
+                        fields.push(field);
+                    }
+                }
+                if items.len() > fields.len() {
+                    return Err(anyhow!("Initializer of {} has {} elements, but {} has {} fields", name, items.len(), ty, fields.len()));
+                }
+                for (field, item) in fields.into_iter().zip(items) {
+                    self.lay_out_init(name, &field.field_type, item, at + field.offset, constants, image, runtime)?;
+                }
+            }
+            (_, Expr::InitList(_)) => {
+                return Err(anyhow!("Cannot initialize {} of type {} with a list", name, ty));
+            }
+            (Type::Array(..), _) => {
+                return Err(anyhow!("Cannot initialize array {} with {}", name, init));
+            }
+            _ => match consteval::eval(init, constants, &self.layouts) {
+                Some(value) if !self.is_aggregate(&ty) => {
+                    let width = self.value_width(&ty).min(self.layouts.size_of(&ty));
+                    let value = consteval::truncate(value, &ty);
+                    image[at..at + width].copy_from_slice(&value.to_le_bytes()[..width]);
+                }
+                _ => runtime.push((at, ty.clone(), init)),
+            },
+        }
+        Ok(())
+    }
+
+    /// Copy `size` bytes between two places through R0, in the widest
+    /// chunks both alignments allow
+    fn copy_place(&mut self, dest: &Place, source: &Place, size: usize) {
+        let align = self.layouts.align_of(&dest.place_type)
+            .min(self.layouts.align_of(&source.place_type))
+            .clamp(1, 8);
+        let mut done = 0;
+        while done < size {
+            let width = chunk_width(align, size - done);
+            self.emit(BpfInstruction::ldx(width, BpfReg::R0, source.base, source.offset + done as i16));
+            self.emit(BpfInstruction::stx(width, dest.base, BpfReg::R0, dest.offset + done as i16));
+            done += width;
+        }
+    }
+
+    /// Load `len` (at most 8) bytes at `offset` into `place` as one
+    /// little-endian word
+    fn load_bytes(&mut self, place: &Place, offset: usize, len: usize) -> Result<BpfReg> {
+        let reg = self.alloc_reg()?;
+        let align = self.layouts.align_of(&place.place_type).clamp(1, 8);
+        let mut done = 0;
+        while done < len {
+            let width = chunk_width(align, len - done);
+            let at = place.offset + (offset + done) as i16;
+            if done == 0 {
+                self.emit(BpfInstruction::ldx(width, reg, place.base, at));
+            } else {
+                self.emit(BpfInstruction::ldx(width, BpfReg::R0, place.base, at));
+                self.emit(BpfInstruction::new(BpfOpcode::Lsh64Imm, BpfReg::R0, BpfReg::R0, 0, 8 * done as i32));
+                self.emit(BpfInstruction::or_reg(reg, BpfReg::R0));
+            }
+            done += width;
+        }
+        Ok(reg)
+    }
+
+    /// Store the low `len` bytes of `reg` at `offset` into `place`,
+    /// consuming the register
+    fn store_bytes(&mut self, place: &Place, offset: usize, len: usize, reg: BpfReg) {
+        let align = self.layouts.align_of(&place.place_type).clamp(1, 8);
+        let mut done = 0;
+        while done < len {
+            let width = chunk_width(align, len - done);
+            self.emit(BpfInstruction::stx(width, place.base, reg, place.offset + (offset + done) as i16));
+            done += width;
+            if done < len {
+                self.emit(BpfInstruction::new(BpfOpcode::Rsh64Imm, reg, BpfReg::R0, 0, 8 * width as i32));
+            }
+        }
+    }
+
+    /// Address of a frame slot at `offset` below R10
+    fn frame_address(&mut self, offset: usize) -> Result<BpfReg> {
+        self.place_address(Place { base: BpfReg::R10, offset: -(offset as i16), place_type: Type::U64 })
+    }
+
+    /// Offset from a caller's R10 of its `slot`th stack argument
+    fn stack_arg_offset(&self, slot: usize) -> i16 {
+        -((self.target.frame_size - 8 * slot) as i16)
+    }
+
+    fn pass_arg(&mut self, slot: ArgSlot, reg: BpfReg) {
+        match slot {
+            ArgSlot::Reg(arg_reg) => self.emit(BpfInstruction::mov_reg(arg_reg, reg)),
+            ArgSlot::Stack(slot) => {
+                let offset = self.stack_arg_offset(slot);
+                self.emit(BpfInstruction::stxdw(BpfReg::R10, reg, offset))
+            }
+        }
+    }
+
+    /// Reserve an 8-byte stack slot for a temporary, returning its R10 offset
+    fn alloc_temp_slot(&mut self) -> i16 {
+        -(self.frame.alloc_spill() as i16)
+    }
+}
+
+/// Smallest and largest value of an integer type
+fn int_range(ty: &Type) -> Option<(i128, i128)> {
+    Some(match ty {
+        Type::Bool => (0, 1),
+        Type::U8 => (0, u8::MAX as i128),
+        Type::U16 => (0, u16::MAX as i128),
+        Type::U32 => (0, u32::MAX as i128),
+        Type::U64 => (0, u64::MAX as i128),
+        Type::I8 => (i8::MIN as i128, i8::MAX as i128),
+        Type::I16 => (i16::MIN as i128, i16::MAX as i128),
+        Type::I32 => (i32::MIN as i128, i32::MAX as i128),
+        Type::I64 => (i64::MIN as i128, i64::MAX as i128),
+        _ => return None,
+    })
+}
+
+/// The type of a variable declared as `T x[]`, whose length its initializer
+/// gives; other declarations keep their type
+fn sized_by_init(ty: &Type, init: Option<&Expr>) -> Type {
+    match (ty, init) {
+        (Type::Array(elem, None), Some(Expr::InitList(items))) => Type::Array(elem.clone(), Some(items.len())),
+        (Type::Array(elem, None), Some(Expr::StringLiteral(bytes))) => Type::Array(elem.clone(), Some(bytes.len() + 1)),
+        _ => ty.clone(),
+    }
+}
+
+/// Widest power-of-two access of at most `align` bytes that fits in `remaining`
+fn chunk_width(align: usize, remaining: usize) -> usize {
+    let mut width = align.min(8);
+    while width > remaining {
+        width /= 2;
+    }
+    width
+}
+
+fn compound_base_op(op: BinaryOp) -> BinaryOp {
+    match op {
+        BinaryOp::AddAssign => BinaryOp::Add,
+        BinaryOp::SubAssign => BinaryOp::Sub,
+        BinaryOp::MulAssign => BinaryOp::Mul,
+        BinaryOp::DivAssign => BinaryOp::Div,
+        BinaryOp::ModAssign => BinaryOp::Mod,
+        BinaryOp::AndAssign => BinaryOp::BitAnd,
+        BinaryOp::OrAssign => BinaryOp::BitOr,
+        BinaryOp::XorAssign => BinaryOp::BitXor,
+        BinaryOp::ShlAssign => BinaryOp::Shl,
+        BinaryOp::ShrAssign => BinaryOp::Shr,
+        other => other,
+    }
+}
+
+fn negate_comparison(op: BinaryOp) -> BinaryOp {
+    match op {
+        BinaryOp::Eq => BinaryOp::Ne,
+        BinaryOp::Ne => BinaryOp::Eq,
+        BinaryOp::Lt => BinaryOp::Ge,
+        BinaryOp::Ge => BinaryOp::Lt,
+        BinaryOp::Le => BinaryOp::Gt,
+        BinaryOp::Gt => BinaryOp::Le,
+        other => other,
+    }
+}
+
+fn comparison_jump(op: BinaryOp, signed: bool) -> BpfOpcode {
+    match (op, signed) {
+        (BinaryOp::Eq, _) => BpfOpcode::JeqReg,
+        (BinaryOp::Ne, _) => BpfOpcode::JneReg,
+        (BinaryOp::Lt, false) => BpfOpcode::JltReg,
+        (BinaryOp::Le, false) => BpfOpcode::JleReg,
+        (BinaryOp::Gt, false) => BpfOpcode::JgtReg,
+        (_, false) => BpfOpcode::JgeReg,
+        (BinaryOp::Lt, true) => BpfOpcode::JsltReg,
+        (BinaryOp::Le, true) => BpfOpcode::JsleReg,
+        (BinaryOp::Gt, true) => BpfOpcode::JsgtReg,
+        (_, true) => BpfOpcode::JsgeReg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bpf_instruction_encoding() {
+        let inst = BpfInstruction::mov_imm(BpfReg::R0, 42);
+        let bytes = inst.to_bytes();
+        assert_eq!(bytes[0], BpfOpcode::Mov64Imm as u8);
+        assert_eq!(bytes[1], 0x00); // dst=R0, src=R0
+        assert_eq!(&bytes[4..8], &42i32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_xor_instruction() {
+        let inst = BpfInstruction::xor_reg(BpfReg::R6, BpfReg::R7);
+        let bytes = inst.to_bytes();
+        assert_eq!(bytes[0], BpfOpcode::Xor64Reg as u8);
+        assert_eq!(bytes[1], 0x76); // dst=R6, src=R7
+    }
+
+    #[test]
+    fn test_callx_encoding() {
+        let v1 = BpfInstruction::callx(BpfReg::R6, Target::sbf().callx).to_bytes();
+        assert_eq!((v1[1], &v1[4..8]), (0x00, &6i32.to_le_bytes()[..]));
+        let v2 = BpfInstruction::callx(BpfReg::R6, Target::sbf_v2().callx).to_bytes();
+        assert_eq!((v2[1], &v2[4..8]), (0x60, &0i32.to_le_bytes()[..]));
     }
 }
@@ -0,0 +1,204 @@
+//! Constant expression evaluation
+//!
+//! Folds integer expressions whose value is known at compile time, such as
+//! global initializers. Arithmetic wraps at 64 bits, and division, shifts
+//! right and ordering comparisons are signed when an operand is, matching
+//! the generated code.
+use crate::ast::*;
+use crate::layout::LayoutEngine;
+use std::collections::HashMap;
+
+/// What is known about the sign of a value. The code generator keeps
+/// every value extended to 64 bits: zero-extended for unsigned types and
+/// sign-extended for signed ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sign {
+    /// Below 2^63, so signed and unsigned operations agree; narrow
+    /// unsigned types, small constants and comparisons
+    NonNegative,
+    Signed,
+    /// U64 and pointers
+    Unsigned,
+}
+
+impl Sign {
+    /// Sign of an operation on two values: unsigned if either is, as in C
+    pub fn combine(self, other: Sign) -> Sign {
+        match (self, other) {
+            (Sign::Unsigned, _) | (_, Sign::Unsigned) => Sign::Unsigned,
+            (Sign::Signed, _) | (_, Sign::Signed) => Sign::Signed,
+            _ => Sign::NonNegative,
+        }
+    }
+}
+
+/// Value of `expr`, looking identifiers up in `constants`. Returns None if
+/// the expression is not a compile-time constant.
+pub fn eval(expr: &Expr, constants: &HashMap<String, u64>, layouts: &LayoutEngine) -> Option<u64> {
+    let eval = |expr: &Expr| eval(expr, constants, layouts);
+
+    match expr {
+        Expr::IntLiteral(n) => Some(*n),
+        Expr::CharLiteral(c) => Some(*c as u64),
+        Expr::BoolLiteral(b) => Some(*b as u64),
+        Expr::Null => Some(0),
+        Expr::Ident(name) => constants.get(name).copied(),
+        Expr::Sizeof(ty) => Some(layouts.size_of(ty) as u64),
+        Expr::Cast { expr, target_type } => Some(truncate(eval(expr)?, target_type)),
+        // Only the chosen branch has to be constant
+        Expr::Ternary { condition, then_expr, else_expr } => {
+            if eval(condition)? != 0 { eval(then_expr) } else { eval(else_expr) }
+        }
+
+        Expr::Unary { op, expr } => {
+            let value = eval(expr)?;
+            match op {
+                UnaryOp::Neg => Some(value.wrapping_neg()),
+                UnaryOp::BitNot => Some(!value),
+                UnaryOp::Not => Some((value == 0) as u64),
+                _ => None,
+            }
+        }
+
+        Expr::Binary { op, left, right } => {
+            let (l, r) = (eval(left)?, eval(right)?);
+            let signed = sign_of(left, constants).combine(sign_of(right, constants)) == Sign::Signed;
+            let (sl, sr) = (l as i64, r as i64);
+            match op {
+                BinaryOp::Div if signed => sl.checked_div(sr).map(|v| v as u64),
+                BinaryOp::Mod if signed => sl.checked_rem(sr).map(|v| v as u64),
+                BinaryOp::Shr if sign_of(left, constants) == Sign::Signed => {
+                    sl.checked_shr(u32::try_from(r).ok()?).map(|v| v as u64)
+                }
+                BinaryOp::Lt if signed => Some((sl < sr) as u64),
+                BinaryOp::Le if signed => Some((sl <= sr) as u64),
+                BinaryOp::Gt if signed => Some((sl > sr) as u64),
+                BinaryOp::Ge if signed => Some((sl >= sr) as u64),
+                BinaryOp::Add => Some(l.wrapping_add(r)),
+                BinaryOp::Sub => Some(l.wrapping_sub(r)),
+                BinaryOp::Mul => Some(l.wrapping_mul(r)),
+                BinaryOp::Div => l.checked_div(r),
+                BinaryOp::Mod => l.checked_rem(r),
+                BinaryOp::BitAnd => Some(l & r),
+                BinaryOp::BitOr => Some(l | r),
+                BinaryOp::BitXor => Some(l ^ r),
+                BinaryOp::Shl => l.checked_shl(u32::try_from(r).ok()?),
+                BinaryOp::Shr => l.checked_shr(u32::try_from(r).ok()?),
+                BinaryOp::LogicalAnd => Some((l != 0 && r != 0) as u64),
+                BinaryOp::LogicalOr => Some((l != 0 || r != 0) as u64),
+                BinaryOp::Eq => Some((l == r) as u64),
+                BinaryOp::Ne => Some((l != r) as u64),
+                BinaryOp::Lt => Some((l < r) as u64),
+                BinaryOp::Le => Some((l <= r) as u64),
+                BinaryOp::Gt => Some((l > r) as u64),
+                BinaryOp::Ge => Some((l >= r) as u64),
+                _ => None,
+            }
+        }
+
+        _ => None,
+    }
+}
+
+/// Sign of a constant expression, by the same rules as the generated code
+fn sign_of(expr: &Expr, constants: &HashMap<String, u64>) -> Sign {
+    let sign_of = |expr: &Expr| sign_of(expr, constants);
+
+    match expr {
+        Expr::IntLiteral(n) if *n > i64::MAX as u64 => Sign::Unsigned,
+        Expr::Ident(name) => match constants.get(name) {
+            Some(&n) if n > i64::MAX as u64 => Sign::Signed,
+            _ => Sign::NonNegative,
+        },
+        Expr::Cast { target_type, .. } => match target_type {
+            Type::I8 | Type::I16 | Type::I32 | Type::I64 => Sign::Signed,
+            Type::U8 | Type::U16 | Type::U32 | Type::Bool => Sign::NonNegative,
+            _ => Sign::Unsigned,
+        },
+        Expr::Unary { op: UnaryOp::Neg | UnaryOp::BitNot, expr } => match sign_of(expr) {
+            Sign::Unsigned => Sign::Unsigned,
+            _ => Sign::Signed,
+        },
+        Expr::Binary { op, .. } if op.is_comparison() || matches!(op, BinaryOp::LogicalAnd | BinaryOp::LogicalOr) => {
+            Sign::NonNegative
+        }
+        Expr::Binary { op: BinaryOp::Shl | BinaryOp::Shr, left, .. } => sign_of(left),
+        // The difference of two non-negative values may be negative
+        Expr::Binary { op: BinaryOp::Sub, left, right } => match sign_of(left).combine(sign_of(right)) {
+            Sign::NonNegative => Sign::Signed,
+            sign => sign,
+        },
+        Expr::Binary { left, right, .. } => sign_of(left).combine(sign_of(right)),
+        Expr::Ternary { then_expr, else_expr, .. } => sign_of(then_expr).combine(sign_of(else_expr)),
+        _ => Sign::NonNegative,
+    }
+}
+
+/// Narrow a value to the width of an integer type, sign-extending signed types
+pub fn truncate(value: u64, ty: &Type) -> u64 {
+    match ty {
+        Type::U8 => value as u8 as u64,
+        Type::U16 => value as u16 as u64,
+        Type::U32 => value as u32 as u64,
+        Type::I8 => value as i8 as u64,
+        Type::I16 => value as i16 as u64,
+        Type::I32 => value as i32 as u64,
+        Type::Bool => (value != 0) as u64,
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(n: u64) -> Box<Expr> {
+        Box::new(Expr::IntLiteral(n))
+    }
+
+    #[test]
+    fn test_folds_arithmetic_and_constants() {
+        let layouts = LayoutEngine::new();
+        let constants = HashMap::from([("FEE_BPS".to_string(), 30)]);
+        let expr = Expr::Binary {
+            op: BinaryOp::Sub,
+            left: int(10_000),
+            right: Box::new(Expr::Ident("FEE_BPS".to_string())),
+        };
+        assert_eq!(eval(&expr, &constants, &layouts), Some(9_970));
+
+        let cast = Expr::Cast { expr: int(0x1ff), target_type: Type::U8 };
+        assert_eq!(eval(&cast, &constants, &layouts), Some(0xff));
+
+        // The untaken branch may be anything
+        let div = Expr::Binary { op: BinaryOp::Div, left: int(1), right: int(0) };
+        let ternary = Expr::Ternary { condition: int(0), then_expr: Box::new(div), else_expr: int(7) };
+        assert_eq!(eval(&ternary, &constants, &layouts), Some(7));
+    }
+
+    #[test]
+    fn test_signed_operands_use_signed_operations() {
+        let layouts = LayoutEngine::new();
+        let constants = HashMap::new();
+        let neg = |n| Box::new(Expr::Unary { op: UnaryOp::Neg, expr: int(n) });
+        let div = Expr::Binary { op: BinaryOp::Div, left: neg(7), right: int(2) };
+        assert_eq!(eval(&div, &constants, &layouts), Some(-3i64 as u64));
+        let shr = Expr::Binary { op: BinaryOp::Shr, left: neg(8), right: int(1) };
+        assert_eq!(eval(&shr, &constants, &layouts), Some(-4i64 as u64));
+        let lt = Expr::Binary { op: BinaryOp::Lt, left: neg(1), right: int(0) };
+        assert_eq!(eval(&lt, &constants, &layouts), Some(1));
+
+        // U64 operands keep the comparison unsigned
+        let lt = Expr::Binary { op: BinaryOp::Lt, left: int(u64::MAX), right: int(0) };
+        assert_eq!(eval(&lt, &constants, &layouts), Some(0));
+    }
+
+    #[test]
+    fn test_rejects_non_constants() {
+        let layouts = LayoutEngine::new();
+        let constants = HashMap::new();
+        assert_eq!(eval(&Expr::Ident("x".to_string()), &constants, &layouts), None);
+        let div = Expr::Binary { op: BinaryOp::Div, left: int(1), right: int(0) };
+        assert_eq!(eval(&div, &constants, &layouts), None);
+    }
+}
@@ -0,0 +1,638 @@
+//! Static compute-unit estimation
+//!
+//! Every executed SBF instruction costs one compute unit and syscalls charge
+//! according to `SYSCALL_COSTS`. The program is compiled with the options it
+//! will be deployed with and the generated code is costed, so the worst case
+//! is an upper bound on what the runtime meters as long as loops run no more
+//! than their trip counts. Loops whose trip count is not known statically
+//! are reported as a per-iteration cost.
+use crate::callgraph::CallGraph;
+use crate::codegen::{BpfOpcode, CodeGen, CostHints};
+use crate::disasm::{self, Instruction};
+use crate::loop_opt::{self, LoopOptOptions};
+use crate::syscalls;
+use crate::CompilerOptions;
+use crate::ast::*;
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// Default per-instruction compute budget of the Solana runtime
+pub const DEFAULT_CU_BUDGET: u64 = 200_000;
+
+/// Metered cost of a syscall: `base + len / bytes_per_unit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallCost {
+    pub name: &'static str,
+    pub base: u64,
+    /// Argument holding the byte length, when the syscall charges per byte
+    pub len_arg: Option<usize>,
+    /// Bytes per extra compute unit; 0 when the cost is flat
+    pub bytes_per_unit: u64,
+}
+
+const fn syscall(name: &'static str, base: u64, len_arg: Option<usize>, bytes_per_unit: u64) -> SyscallCost {
+    SyscallCost { name, base, len_arg, bytes_per_unit }
+}
+
+/// Costs from the runtime's default compute budget. `sol_log_` really
+/// charges `max(base, len)`; `base + len` is used as an upper bound.
+pub const SYSCALL_COSTS: &[SyscallCost] = &[
+    syscall("sol_log_", 100, Some(1), 1),
+    syscall("sol_log_64_", 100, None, 0),
+    syscall("sol_log_pubkey", 100, None, 0),
+    syscall("sol_log_compute_units_", 100, None, 0),
+    syscall("sol_log_data", 100, None, 1),
+    syscall("sol_sha256", 85, None, 2),
+    syscall("sol_keccak256", 85, None, 2),
+    syscall("sol_blake3", 85, None, 2),
+    syscall("sol_secp256k1_recover", 25_000, None, 0),
+    syscall("sol_create_program_address", 1_500, None, 0),
+    syscall("sol_try_find_program_address", 1_500, None, 0),
+    syscall("sol_invoke_signed_c", 1_000, None, 0),
+    syscall("sol_invoke_signed_rust", 1_000, None, 0),
+    syscall("sol_memcpy_", 10, Some(2), 250),
+    syscall("sol_memmove_", 10, Some(2), 250),
+    syscall("sol_memset_", 10, Some(2), 250),
+    syscall("sol_memcmp_", 10, Some(2), 250),
+    syscall("sol_get_clock_sysvar", 100, None, 0),
+    syscall("sol_get_rent_sysvar", 100, None, 0),
+    syscall("sol_get_epoch_schedule_sysvar", 100, None, 0),
+    syscall("sol_get_fees_sysvar", 100, None, 0),
+    syscall("sol_get_last_restart_slot", 100, None, 0),
+    syscall("sol_get_epoch_rewards_sysvar", 100, None, 0),
+    syscall("sol_remaining_compute_units", 100, None, 0),
+    syscall("sol_set_return_data", 100, Some(1), 250),
+    syscall("sol_get_return_data", 100, None, 0),
+];
+
+pub fn syscall_cost(name: &str) -> Option<&'static SyscallCost> {
+    SYSCALL_COSTS.iter().find(|s| s.name == name)
+}
+
+/// A cost that grows with a quantity unknown at compile time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+    pub units: u64,
+    pub parameter: String,
+}
+
+/// Best and worst case compute units, plus parametric terms that are added
+/// on top of the worst case
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Cost {
+    pub best: u64,
+    pub worst: u64,
+    pub terms: Vec<Term>,
+}
+
+impl Cost {
+    pub fn fixed(units: u64) -> Self {
+        Self { best: units, worst: units, terms: Vec::new() }
+    }
+
+    /// True when the worst case does not depend on unknown quantities
+    pub fn is_bounded(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Both costs, one after the other
+    pub fn then(mut self, other: Cost) -> Self {
+        self.best = self.best.saturating_add(other.best);
+        self.worst = self.worst.saturating_add(other.worst);
+        self.add_terms(other.terms);
+        self
+    }
+
+    /// Either cost, whichever path is taken
+    pub fn either(mut self, other: Cost) -> Self {
+        self.best = self.best.min(other.best);
+        self.worst = self.worst.max(other.worst);
+        for term in other.terms {
+            match self.terms.iter_mut().find(|t| t.parameter == term.parameter) {
+                Some(existing) => existing.units = existing.units.max(term.units),
+                None => self.terms.push(term),
+            }
+        }
+        self
+    }
+
+    /// The cost repeated a known number of times
+    pub fn times(mut self, count: u64) -> Self {
+        self.best = self.best.saturating_mul(count);
+        self.worst = self.worst.saturating_mul(count);
+        for term in &mut self.terms {
+            term.units = term.units.saturating_mul(count);
+        }
+        self
+    }
+
+    fn add_terms(&mut self, terms: Vec<Term>) {
+        for term in terms {
+            match self.terms.iter_mut().find(|t| t.parameter == term.parameter) {
+                Some(existing) => existing.units = existing.units.saturating_add(term.units),
+                None => self.terms.push(term),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.best == self.worst {
+            write!(f, "{}", self.worst)?;
+        } else {
+            write!(f, "{}..{}", self.best, self.worst)?;
+        }
+        for term in &self.terms {
+            write!(f, " + {} x {}", term.units, term.parameter)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCost {
+    pub name: String,
+    pub cost: Cost,
+}
+
+/// Estimate the cost of every function in the program, in program order.
+/// The program is compiled with `options` and the generated code costed,
+/// so loop optimization, ALU32 and bounds checks are accounted for.
+pub fn estimate_program(program: &Program, options: &CompilerOptions) -> Result<Vec<FunctionCost>> {
+    let mut program = program.clone();
+    let loop_options = LoopOptOptions::new(options.opt_level, options.unroll_limit);
+    if loop_options.is_enabled() {
+        loop_opt::optimize_program(&mut program, &loop_options);
+    }
+    let mut codegen = CodeGen::new()
+        .with_bounds_checks(options.bounds_checks)
+        .with_alu32(options.alu32);
+    let bytecode = codegen.generate(&program)?;
+    let code: BTreeMap<usize, Instruction> = disasm::decode(&bytecode)?.into_iter()
+        .map(|inst| (inst.pc, inst))
+        .collect();
+
+    let mut starts: Vec<(usize, String)> = codegen.function_starts().iter()
+        .map(|(name, pc)| (*pc, name.clone()))
+        .collect();
+    starts.sort();
+    let slots = bytecode.len() / 8;
+    let functions = starts.iter().enumerate()
+        .map(|(idx, (start, name))| (name.clone(), (*start, starts.get(idx + 1).map_or(slots, |(pc, _)| *pc))))
+        .collect();
+
+    let mut estimator = Estimator {
+        code,
+        functions,
+        names: starts.into_iter().collect(),
+        hints: codegen.cost_hints(),
+        address_taken: CallGraph::from_program(&program).address_taken().iter().cloned().collect(),
+        costs: HashMap::new(),
+        in_progress: Vec::new(),
+    };
+    Ok(program.items.iter()
+        .filter_map(|item| match item {
+            Item::FunctionDef(func) => Some(FunctionCost {
+                name: func.name.clone(),
+                cost: estimator.function_cost(&func.name),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+struct Estimator<'a> {
+    code: BTreeMap<usize, Instruction>,
+    /// First and one past the last instruction of each function
+    functions: HashMap<String, (usize, usize)>,
+    /// Function name by first instruction
+    names: HashMap<usize, String>,
+    hints: &'a CostHints,
+    /// Possible targets of calls through function pointers
+    address_taken: Vec<String>,
+    costs: HashMap<String, Cost>,
+    in_progress: Vec<String>,
+}
+
+/// Control flow of one function. Loops are collapsed into their header
+/// one at a time, innermost first, so `rep` maps each block to the node
+/// that stands for it.
+struct Graph {
+    /// First instruction of each block
+    starts: Vec<usize>,
+    cost: Vec<Cost>,
+    succs: Vec<Vec<usize>>,
+    rep: Vec<usize>,
+}
+
+impl Estimator<'_> {
+    fn function_cost(&mut self, name: &str) -> Cost {
+        if let Some(cost) = self.costs.get(name) {
+            return cost.clone();
+        }
+        if self.in_progress.iter().any(|f| f == name) {
+            return Cost {
+                terms: vec![Term { units: 1, parameter: format!("recursive calls to {}", name) }],
+                ..Cost::default()
+            };
+        }
+        let Some(&(start, end)) = self.functions.get(name) else {
+            return Cost::default();
+        };
+
+        self.in_progress.push(name.to_string());
+        let mut graph = self.graph(start, end);
+        let cost = graph.cost_from(0, self.hints);
+        self.in_progress.pop();
+
+        self.costs.insert(name.to_string(), cost.clone());
+        cost
+    }
+
+    /// Split the instructions in `start..end` into basic blocks. Each
+    /// instruction costs one unit, plus whatever it calls.
+    fn graph(&mut self, start: usize, end: usize) -> Graph {
+        let insts: Vec<Instruction> = self.code.range(start..end).map(|(_, inst)| *inst).collect();
+        let mut leaders = BTreeSet::from([start]);
+        for inst in &insts {
+            let next = inst.pc + inst.slots();
+            if let Some(target) = inst.jump_target() {
+                leaders.insert(target);
+                leaders.insert(next);
+            }
+            if inst.opcode == Some(BpfOpcode::Exit) {
+                leaders.insert(next);
+            }
+        }
+        let starts: Vec<usize> = leaders.into_iter().filter(|pc| self.code.contains_key(pc) && (start..end).contains(pc)).collect();
+        let block_of = |pc: usize| starts.binary_search(&pc).ok();
+
+        let mut graph = Graph { starts: starts.clone(), cost: Vec::new(), succs: Vec::new(), rep: (0..starts.len()).collect() };
+        for (idx, &first) in starts.iter().enumerate() {
+            let last = starts.get(idx + 1).copied().unwrap_or(end);
+            let block: Vec<&Instruction> = insts.iter().filter(|inst| (first..last).contains(&inst.pc)).collect();
+            let mut cost = Cost::fixed(block.len() as u64);
+            for (at, inst) in block.iter().enumerate() {
+                cost = cost.then(self.call_cost(inst, &block[..at]));
+            }
+
+            let tail = block.last().expect("blocks are not empty");
+            let next = tail.pc + tail.slots();
+            let succs = match tail.opcode {
+                Some(BpfOpcode::Exit) => Vec::new(),
+                Some(BpfOpcode::Ja) => tail.jump_target().into_iter().collect(),
+                Some(op) if op.is_jump() => tail.jump_target().into_iter().chain([next]).collect(),
+                _ => vec![next],
+            };
+            graph.cost.push(cost);
+            graph.succs.push(succs.into_iter().filter_map(block_of).collect());
+        }
+        graph
+    }
+
+    /// Cost of what `inst` calls: a function, a syscall, or through a
+    /// pointer any function whose address is taken. `before` are the
+    /// instructions of its block before it.
+    fn call_cost(&mut self, inst: &Instruction, before: &[&Instruction]) -> Cost {
+        match inst.opcode {
+            Some(BpfOpcode::Call) if inst.src == 1 => {
+                match inst.call_target().and_then(|pc| self.names.get(&pc)).cloned() {
+                    Some(name) => self.function_cost(&name),
+                    None => Cost::default(),
+                }
+            }
+            Some(BpfOpcode::Call) => match syscalls::by_hash(inst.imm as u32) {
+                Some(name) => self.syscall_cost(name, inst.pc, before),
+                None => Cost::default(),
+            },
+            Some(BpfOpcode::Callx) => self.address_taken.clone().iter()
+                .map(|name| self.function_cost(name))
+                .reduce(Cost::either)
+                .unwrap_or_default(),
+            _ => Cost::default(),
+        }
+    }
+
+    /// A syscall's cost, charged per byte when its length is known
+    fn syscall_cost(&self, name: &str, pc: usize, before: &[&Instruction]) -> Cost {
+        let Some(syscall) = syscall_cost(name) else {
+            return Cost::default();
+        };
+        let base = Cost::fixed(syscall.base);
+        if syscall.bytes_per_unit == 0 {
+            return base;
+        }
+        let len = self.hints.syscall_lens.get(&pc).copied()
+            .or_else(|| syscall.len_arg.and_then(|arg| constant_in(before, arg as u8 + 1)));
+        match len {
+            Some(len) => base.then(Cost::fixed(len / syscall.bytes_per_unit)),
+            None => {
+                let parameter = if syscall.bytes_per_unit == 1 {
+                    format!("bytes passed to {}", name)
+                } else {
+                    format!("{}-byte chunks passed to {}", syscall.bytes_per_unit, name)
+                };
+                Cost { terms: vec![Term { units: 1, parameter }], ..base }
+            }
+        }
+    }
+}
+
+/// The constant register `reg` holds after `insts`, if they set it to one
+fn constant_in(insts: &[&Instruction], mut reg: u8) -> Option<u64> {
+    for inst in insts.iter().rev() {
+        let op = inst.opcode?;
+        if matches!(op, BpfOpcode::Call | BpfOpcode::Callx) && reg <= 5 {
+            return None;
+        }
+        if !op.writes_dst() || inst.dst != reg {
+            continue;
+        }
+        match op {
+            BpfOpcode::Mov64Imm | BpfOpcode::Lddw => return Some(inst.imm as u64),
+            BpfOpcode::Mov64Reg => reg = inst.src,
+            _ => return None,
+        }
+    }
+    None
+}
+
+impl Graph {
+    fn find(&self, mut node: usize) -> usize {
+        while self.rep[node] != node {
+            node = self.rep[node];
+        }
+        node
+    }
+
+    /// Nodes a node's edges lead to
+    fn out(&self, node: usize) -> Vec<usize> {
+        let mut out: Vec<usize> = self.succs[node].iter().map(|s| self.find(*s)).collect();
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+
+    /// Worst and best cost of running the function from block `entry`.
+    /// Each loop runs its iterations, each one its longest path back to
+    /// its header, then its longest path out.
+    fn cost_from(&mut self, entry: usize, hints: &CostHints) -> Cost {
+        for (header, body) in self.loops(entry) {
+            let header = self.find(header);
+            let members: BTreeSet<usize> = body.iter().map(|b| self.find(*b)).collect();
+            let (paths, mut extra) = self.paths(header, &members);
+
+            let mut iteration: Option<Cost> = None;
+            let mut exit: Option<Cost> = None;
+            let mut exits = BTreeSet::new();
+            for (node, cost) in &paths {
+                let out = self.out(*node);
+                if out.contains(&header) {
+                    iteration = Some(either(iteration, cost.clone()));
+                }
+                let leaving: Vec<usize> = out.iter().copied().filter(|s| !members.contains(s)).collect();
+                if out.is_empty() || !leaving.is_empty() {
+                    exit = Some(either(exit, cost.clone()));
+                }
+                exits.extend(leaving);
+            }
+
+            let pc = self.starts[header];
+            let (trips, description) = hints.loops.get(&pc).cloned()
+                .unwrap_or_else(|| (None, format!("iterations of the loop at instruction {}", pc)));
+            let iteration = iteration.unwrap_or_default();
+            let exit = exit.unwrap_or_default();
+            let mut cost = match trips {
+                Some(trips) => iteration.times(trips).then(exit),
+                None => {
+                    let mut terms = vec![Term { units: iteration.worst, parameter: description.clone() }];
+                    terms.extend(iteration.terms.into_iter().map(|term| Term {
+                        units: term.units,
+                        parameter: format!("{} per {}", term.parameter, description.trim_start_matches("iterations of ")),
+                    }));
+                    exit.then(Cost { best: 0, worst: 0, terms })
+                }
+            };
+            cost.add_terms(std::mem::take(&mut extra));
+
+            for member in &members {
+                self.rep[*member] = header;
+            }
+            self.rep[header] = header;
+            self.cost[header] = cost;
+            self.succs[header] = exits.into_iter().collect();
+        }
+
+        let entry = self.find(entry);
+        let everything: BTreeSet<usize> = (0..self.rep.len()).map(|b| self.find(b)).collect();
+        let (paths, extra) = self.paths(entry, &everything);
+        let mut cost = paths.iter()
+            .filter(|(node, _)| self.out(**node).is_empty())
+            .map(|(_, cost)| cost.clone())
+            .reduce(Cost::either)
+            .unwrap_or_default();
+        cost.add_terms(extra);
+        cost
+    }
+
+    /// Loops by header, innermost first. A jump to a block still on the
+    /// depth-first stack closes a loop, whose body is every block that
+    /// reaches the jump without passing the header.
+    fn loops(&self, entry: usize) -> Vec<(usize, BTreeSet<usize>)> {
+        let mut state = vec![0u8; self.succs.len()]; // unvisited, on the stack, done
+        let mut back_edges: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut stack = vec![(entry, 0)];
+        state[entry] = 1;
+        while let Some((node, next)) = stack.pop() {
+            let Some(&succ) = self.succs[node].get(next) else {
+                state[node] = 2;
+                continue;
+            };
+            stack.push((node, next + 1));
+            match state[succ] {
+                0 => {
+                    state[succ] = 1;
+                    stack.push((succ, 0));
+                }
+                1 => back_edges.entry(succ).or_default().push(node),
+                _ => {}
+            }
+        }
+
+        let mut preds = vec![Vec::new(); self.succs.len()];
+        for (node, succs) in self.succs.iter().enumerate() {
+            for succ in succs {
+                preds[*succ].push(node);
+            }
+        }
+        let mut loops: Vec<(usize, BTreeSet<usize>)> = back_edges.into_iter()
+            .map(|(header, tails)| {
+                let mut body = BTreeSet::from([header]);
+                let mut work = tails;
+                while let Some(node) = work.pop() {
+                    if body.insert(node) {
+                        work.extend(preds[node].iter().copied());
+                    }
+                }
+                (header, body)
+            })
+            .collect();
+        loops.sort_by_key(|(_, body)| body.len());
+        loops
+    }
+
+    /// Costliest and cheapest way to reach each node of `members` from
+    /// `from`, not coming back to it. A cycle that is not a collapsed loop
+    /// can only come from jumps into a loop's middle; it is charged as a
+    /// loop of unknown length.
+    fn paths(&self, from: usize, members: &BTreeSet<usize>) -> (BTreeMap<usize, Cost>, Vec<Term>) {
+        let edges = |node: usize| -> Vec<usize> {
+            self.out(node).into_iter().filter(|s| *s != from && members.contains(s)).collect()
+        };
+
+        let mut order = Vec::new();
+        let mut state: BTreeMap<usize, u8> = BTreeMap::from([(from, 1)]);
+        let mut cycles = BTreeSet::new();
+        let mut stack = vec![(from, edges(from), 0)];
+        while let Some((node, succs, next)) = stack.pop() {
+            let Some(&succ) = succs.get(next) else {
+                state.insert(node, 2);
+                order.push(node);
+                continue;
+            };
+            stack.push((node, succs, next + 1));
+            match state.get(&succ) {
+                None => {
+                    state.insert(succ, 1);
+                    stack.push((succ, edges(succ), 0));
+                }
+                Some(1) => {
+                    cycles.insert((node, succ));
+                }
+                _ => {}
+            }
+        }
+
+        let mut paths = BTreeMap::from([(from, self.cost[from].clone())]);
+        for &node in order.iter().rev() {
+            let Some(cost) = paths.get(&node).cloned() else {
+                continue;
+            };
+            for succ in edges(node).into_iter().filter(|succ| !cycles.contains(&(node, *succ))) {
+                let cost = cost.clone().then(self.cost[succ].clone());
+                let merged = either(paths.remove(&succ), cost);
+                paths.insert(succ, merged);
+            }
+        }
+
+        let terms = cycles.iter()
+            .map(|(_, target)| Term {
+                units: state.keys().map(|node| self.cost[*node].worst).sum(),
+                parameter: format!("iterations of the loop at instruction {}", self.starts[*target]),
+            })
+            .collect();
+        (paths, terms)
+    }
+}
+
+fn either(cost: Option<Cost>, other: Cost) -> Cost {
+    match cost {
+        Some(cost) => cost.either(other),
+        None => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+    use crate::parser::Parser;
+
+    fn estimate(source: &str) -> Vec<FunctionCost> {
+        let tokens: Vec<Token> = Lexer::collect_tokens(source).unwrap()
+            .into_iter().map(|(t, _)| t).collect();
+        estimate_program(&Parser::new(tokens).parse().unwrap(), &CompilerOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_straight_line_best_and_worst() {
+        let costs = estimate(
+            "U64 add(U64 a, U64 b) { return a + b; }
+             U64 pick(U64 a, U64 b) { if (a > b) { U64 t = a * 3 + b; return t; } return b; }",
+        );
+        // 2 param stores, a, b, add, mov, exit
+        assert_eq!(costs[0].cost, Cost::fixed(7));
+        let pick = &costs[1].cost;
+        assert!(pick.is_bounded());
+        assert!(pick.best < pick.worst);
+    }
+
+    #[test]
+    fn test_known_trip_count_is_multiplied() {
+        let costs = estimate(
+            "U64 f() { U64 s = 0; for (U64 i = 0; i < 10; i++) { s += i; } return s; }
+             U64 g() { U64 s = 0; for (U64 i = 0; i < 20; i++) { s += i; } return s; }",
+        );
+        assert!(costs[0].cost.is_bounded());
+        assert_eq!(costs[0].cost.best, costs[0].cost.worst);
+        let per_iteration = (costs[1].cost.worst - costs[0].cost.worst) / 10;
+        assert_eq!(costs[1].cost.worst, costs[0].cost.worst + 10 * per_iteration);
+    }
+
+    #[test]
+    fn test_unknown_trip_count_is_parametric() {
+        let costs = estimate("U64 f(U64 n) { U64 i = 0; while (i < n) { i++; } return i; }");
+        let cost = &costs[0].cost;
+        assert_eq!(cost.terms.len(), 1);
+        assert_eq!(cost.terms[0].parameter, "iterations of `while (i < n)`");
+        assert!(cost.to_string().contains(" x iterations of `while (i < n)`"));
+    }
+
+    #[test]
+    fn test_syscall_costs_from_table() {
+        let costs = estimate(
+            "U64 f() { sol_log_(0, 30); return 0; }
+             U64 g(U8* data, U64 len) { sol_memcpy_(data, data, len); return 0; }",
+        );
+        assert!(costs[0].cost.best >= 130);
+        assert_eq!(costs[1].cost.terms[0].parameter, "250-byte chunks passed to sol_memcpy_");
+    }
+
+    #[test]
+    fn test_callee_cost_is_included() {
+        let costs = estimate(
+            "U64 leaf(U64 x) { return x * 2; }
+             U64 caller() { return leaf(1) + leaf(2); }",
+        );
+        assert!(costs[1].cost.worst > 2 * costs[0].cost.worst);
+    }
+
+    #[test]
+    fn test_indirect_call_costs_any_address_taken_function() {
+        let costs = estimate(
+            "U64 cheap(U64 x) { return x; }
+             U64 costly(U64 x) { return x * x * x * x; }
+             U64 caller(U64 x) { U64 (*f)(U64) = cheap; f = costly; return f(x); }",
+        );
+        // The call may land in either function
+        let (cheap, costly, caller) = (&costs[0].cost, &costs[1].cost, &costs[2].cost);
+        assert_eq!(caller.worst - caller.best, costly.worst - cheap.worst);
+    }
+
+    #[test]
+    fn test_goto_loop_is_parametric() {
+        let costs = estimate(
+            "U64 f(U64 n) { U64 s = 0; top: s += n; n--; if (n > 0) { goto top; } return s; }
+             U64 g(U64 n) { goto done; n = n * n * n; done: return n; }",
+        );
+        let cost = &costs[0].cost;
+        assert!(!cost.is_bounded());
+        assert_eq!(cost.terms[0].parameter, "iterations of the `goto top` loop");
+        // The statement jumped over is not counted, the label after it is
+        let (g, skipped) = (&costs[1].cost, estimate("U64 g(U64 n) { goto done; done: return n; }"));
+        assert!(g.is_bounded());
+        assert_eq!(*g, skipped[0].cost);
+    }
+}
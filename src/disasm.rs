@@ -0,0 +1,496 @@
+//! sBPF disassembler
+//!
+//! Decodes raw instruction streams or the `.text` of an ELF shared object
+//! into assembly. Calls are printed with the callee's symbol or syscall
+//! name, jump targets as labels and `.rodata` as data directives, in the
+//! syntax accepted by the assembler.
+use crate::codegen::BpfOpcode;
+use crate::lexer;
+use crate::elf::{self, ElfFile, ElfProgram, R_BPF_64_32, R_BPF_64_64, R_BPF_64_RELATIVE};
+use crate::syscalls;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+const INSN_SIZE: usize = 8;
+
+/// A decoded instruction; `lddw` occupies two slots with its full immediate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instruction {
+    pub pc: usize,
+    /// None for bytes that are not a known opcode
+    pub opcode: Option<BpfOpcode>,
+    pub raw: [u8; INSN_SIZE],
+    pub dst: u8,
+    pub src: u8,
+    pub offset: i16,
+    pub imm: i64,
+}
+
+impl Instruction {
+    /// Number of 8-byte slots taken by the instruction
+    pub fn slots(&self) -> usize {
+        if self.opcode == Some(BpfOpcode::Lddw) { 2 } else { 1 }
+    }
+
+    /// Target of a jump, relative to the start of the program
+    pub fn jump_target(&self) -> Option<usize> {
+        match self.opcode {
+            Some(op) if op.is_jump() => relative(self.pc, self.offset as i64),
+            _ => None,
+        }
+    }
+
+    /// Target of a pc-relative call
+    pub fn call_target(&self) -> Option<usize> {
+        match self.opcode {
+            Some(BpfOpcode::Call) if self.src == 1 => relative(self.pc, self.imm),
+            _ => None,
+        }
+    }
+}
+
+fn relative(pc: usize, offset: i64) -> Option<usize> {
+    usize::try_from(pc as i64 + 1 + offset).ok()
+}
+
+/// Names for program locations, and the read-only data they refer to
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    /// Function name by starting pc
+    pub functions: BTreeMap<usize, String>,
+    /// Callee name by pc of a relocated `call`
+    pub calls: HashMap<usize, String>,
+    /// Entrypoint pc, when it is known
+    pub entry: Option<usize>,
+    /// Contents of `.rodata`
+    pub rodata: Vec<u8>,
+    /// `.rodata` label by offset
+    pub rodata_labels: BTreeMap<usize, String>,
+    /// `.rodata` offset by pc of a relocated `lddw`
+    pub rodata_refs: HashMap<usize, usize>,
+    /// Target pc by pc of a relocated `lddw` of a function address
+    pub function_refs: HashMap<usize, usize>,
+    /// Target pc by `.rodata` offset of a relocated function address
+    pub rodata_function_refs: BTreeMap<usize, usize>,
+    /// Target `.rodata` offset by `.rodata` offset of a relocated address
+    pub rodata_data_refs: BTreeMap<usize, usize>,
+}
+
+impl Symbols {
+    /// Names for a program that has not been written as ELF yet
+    pub fn from_program(program: &ElfProgram) -> Self {
+        Self {
+            functions: program.functions.iter().map(|(name, pc)| (*pc, name.clone())).collect(),
+            calls: HashMap::new(),
+            entry: Some(program.entry),
+            rodata: program.rodata.clone(),
+            rodata_labels: program.rodata_symbols.iter().map(|(name, offset)| (*offset, name.clone())).collect(),
+            rodata_refs: program.rodata_refs.iter().copied().collect(),
+            function_refs: program.function_refs.iter().copied().collect(),
+            rodata_function_refs: program.rodata_function_refs.iter().copied().collect(),
+            rodata_data_refs: program.rodata_data_refs.iter().copied().collect(),
+        }
+    }
+}
+
+/// Decode an instruction stream
+pub fn decode(bytecode: &[u8]) -> Result<Vec<Instruction>> {
+    if !bytecode.len().is_multiple_of(INSN_SIZE) {
+        return Err(anyhow!("Program length {} is not a multiple of {}", bytecode.len(), INSN_SIZE));
+    }
+
+    let slots: Vec<[u8; INSN_SIZE]> = bytecode.chunks_exact(INSN_SIZE)
+        .map(|chunk| chunk.try_into().unwrap())
+        .collect();
+    let mut instructions = Vec::new();
+    let mut pc = 0;
+    while pc < slots.len() {
+        let raw = slots[pc];
+        let mut inst = Instruction {
+            pc,
+            opcode: BpfOpcode::from_u8(raw[0]),
+            raw,
+            dst: raw[1] & 0x0f,
+            src: raw[1] >> 4,
+            offset: i16::from_le_bytes([raw[2], raw[3]]),
+            imm: i32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]) as i64,
+        };
+        if inst.opcode == Some(BpfOpcode::Lddw) {
+            let high = slots.get(pc + 1)
+                .ok_or_else(|| anyhow!("pc {}: lddw is missing its second half", pc))?;
+            let high = u32::from_le_bytes([high[4], high[5], high[6], high[7]]) as u64;
+            inst.imm = ((high << 32) | (inst.imm as u32 as u64)) as i64;
+        }
+        pc += inst.slots();
+        instructions.push(inst);
+    }
+    Ok(instructions)
+}
+
+/// Disassemble raw bytes or an ELF file, detected from the contents
+pub fn disassemble_file(data: &[u8], annotate: bool) -> Result<String> {
+    if !elf::is_elf(data) {
+        return disassemble(data, &Symbols::default(), annotate);
+    }
+
+    let elf = ElfFile::parse(data)?;
+    let (text, code) = elf.text()?;
+    let text_pc = |addr: u64| -> Option<usize> {
+        [text.addr, text.offset].into_iter()
+            .find(|base| addr >= *base && addr < base + text.size)
+            .map(|base| ((addr - base) / INSN_SIZE as u64) as usize)
+    };
+
+    let mut symbols = Symbols::default();
+    for symbol in elf.symbols()? {
+        if symbol.is_function && !symbol.name.is_empty() {
+            if let Some(pc) = text_pc(symbol.value) {
+                symbols.functions.insert(pc, symbol.name);
+            }
+        }
+    }
+    symbols.entry = text_pc(elf.entry);
+
+    let rodata = elf.section(".rodata");
+    let rodata_offset = |addr: u64| -> Option<usize> {
+        rodata.filter(|s| addr >= s.addr && addr < s.addr + s.size)
+            .map(|s| (addr - s.addr) as usize)
+    };
+    if let Some(section) = rodata {
+        symbols.rodata = elf.section_data(section)?.to_vec();
+        for symbol in elf.symbols()? {
+            if symbol.is_object && !symbol.name.is_empty() {
+                if let Some(offset) = rodata_offset(symbol.value) {
+                    symbols.rodata_labels.insert(offset, symbol.name);
+                }
+            }
+        }
+    }
+
+    let instructions = decode(code)?;
+    for reloc in elf.relocations()? {
+        if reloc.kind == R_BPF_64_RELATIVE {
+            // An address in `.rodata` is kept in the upper half of its slot
+            let Some(offset) = rodata_offset(reloc.offset) else {
+                continue;
+            };
+            let Some(high) = symbols.rodata.get(offset + 4..offset + 8) else {
+                continue;
+            };
+            let addr = u32::from_le_bytes([high[0], high[1], high[2], high[3]]) as u64;
+            if let Some(target) = text_pc(addr) {
+                symbols.rodata_function_refs.insert(offset, target);
+            } else if let Some(target) = rodata_offset(addr) {
+                symbols.rodata_labels.entry(target).or_insert_with(|| format!("rodata_{}", target));
+                symbols.rodata_data_refs.insert(offset, target);
+            }
+            continue;
+        }
+        let Some(pc) = text_pc(reloc.offset) else {
+            continue;
+        };
+        match (reloc.kind, reloc.symbol) {
+            (R_BPF_64_32, Some(symbol)) => {
+                symbols.calls.insert(pc, symbol.name);
+            }
+            (R_BPF_64_64, symbol) => {
+                // The address is the symbol's value plus the immediate
+                let Some(inst) = instructions.iter().find(|inst| inst.pc == pc) else {
+                    continue;
+                };
+                let addr = symbol.map_or(0, |s| s.value).wrapping_add(inst.imm as u64);
+                if let Some(offset) = rodata_offset(addr) {
+                    symbols.rodata_labels.entry(offset).or_insert_with(|| format!("rodata_{}", offset));
+                    symbols.rodata_refs.insert(pc, offset);
+                } else if let Some(target) = text_pc(addr) {
+                    symbols.function_refs.insert(pc, target);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    disassemble(code, &symbols, annotate)
+}
+
+/// Disassemble an instruction stream. With `annotate`, every line carries
+/// its address and raw bytes as a comment.
+pub fn disassemble(bytecode: &[u8], symbols: &Symbols, annotate: bool) -> Result<String> {
+    let instructions = decode(bytecode)?;
+    let slot_count = bytecode.len() / INSN_SIZE;
+
+    // Name every location that is jumped to, called or has its address taken
+    let mut labels: BTreeMap<usize, String> = symbols.functions.clone();
+    for inst in &instructions {
+        let address_taken = symbols.function_refs.get(&inst.pc).copied();
+        if let Some(target) = inst.call_target().or(address_taken).filter(|t| *t < slot_count) {
+            labels.entry(target).or_insert_with(|| format!("function_{}", target));
+        }
+    }
+    for target in symbols.rodata_function_refs.values().filter(|t| **t < slot_count) {
+        labels.entry(*target).or_insert_with(|| format!("function_{}", target));
+    }
+    for inst in &instructions {
+        if let Some(target) = inst.jump_target().filter(|t| *t < slot_count) {
+            labels.entry(target).or_insert_with(|| format!("lbb_{}", target));
+        }
+    }
+
+    let mut output = String::new();
+    if let Some(entry) = symbols.entry {
+        if let Some(label) = labels.get(&entry) {
+            writeln!(output, ".globl {}", label).unwrap();
+        }
+    }
+    if !symbols.rodata.is_empty() {
+        output.push_str(".text\n");
+    }
+    for inst in &instructions {
+        if let Some(label) = labels.get(&inst.pc) {
+            if inst.pc != 0 && (symbols.functions.contains_key(&inst.pc) || label.starts_with("function_")) {
+                output.push('\n');
+            }
+            writeln!(output, "{}:", label).unwrap();
+        }
+
+        let text = format_instruction(inst, &labels, symbols);
+        if annotate {
+            let bytes: Vec<String> = inst.raw.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(output, "    {:<32} ; {:04x}: {}", text, inst.pc * INSN_SIZE, bytes.join(" ")).unwrap();
+        } else {
+            writeln!(output, "    {}", text).unwrap();
+        }
+    }
+
+    if !symbols.rodata.is_empty() {
+        output.push_str("\n.rodata\n");
+        let addresses: BTreeMap<usize, String> = symbols.rodata_function_refs.iter()
+            .filter_map(|(offset, target)| Some((*offset, labels.get(target)?.clone())))
+            .chain(symbols.rodata_data_refs.iter()
+                .filter_map(|(offset, target)| Some((*offset, symbols.rodata_labels.get(target)?.clone()))))
+            .collect();
+        format_rodata(&mut output, &symbols.rodata, &symbols.rodata_labels, &addresses);
+    }
+    Ok(output)
+}
+
+/// Print `.rodata` as labelled runs of `.ascii` strings or `.byte` lists,
+/// with each relocated address as a `.quad` of its label
+fn format_rodata(output: &mut String, data: &[u8], labels: &BTreeMap<usize, String>, addresses: &BTreeMap<usize, String>) {
+    let mut bounds: Vec<usize> = labels.keys().copied()
+        .chain(addresses.keys().flat_map(|offset| [*offset, offset + 8]))
+        .filter(|offset| *offset < data.len())
+        .collect();
+    bounds.push(0);
+    bounds.push(data.len());
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    for run in bounds.windows(2) {
+        let (start, end) = (run[0], run[1]);
+        if let Some(label) = labels.get(&start) {
+            writeln!(output, "{}:", label).unwrap();
+        }
+        if let Some(target) = addresses.get(&start) {
+            writeln!(output, "    .quad {}", target).unwrap();
+            continue;
+        }
+        let bytes = &data[start..end];
+        let text_like = bytes.iter().filter(|b| b.is_ascii_graphic() || **b == b' ').count() * 4 >= bytes.len() * 3;
+        if text_like {
+            writeln!(output, "    .ascii \"{}\"", lexer::escape(bytes)).unwrap();
+        } else {
+            for chunk in bytes.chunks(16) {
+                let values: Vec<String> = chunk.iter().map(|b| format!("{:#04x}", b)).collect();
+                writeln!(output, "    .byte {}", values.join(", ")).unwrap();
+            }
+        }
+    }
+}
+
+/// Assembly text of a single instruction
+pub fn format_instruction(inst: &Instruction, labels: &BTreeMap<usize, String>, symbols: &Symbols) -> String {
+    use BpfOpcode::*;
+
+    let Some(opcode) = inst.opcode else {
+        return raw_quad(inst);
+    };
+    let (dst, src, imm) = (inst.dst, inst.src, inst.imm);
+    let label = |target: Option<usize>| target.and_then(|t| labels.get(&t)).cloned();
+
+    match opcode {
+        Lddw => match symbols.rodata_refs.get(&inst.pc).and_then(|offset| symbols.rodata_labels.get(offset))
+            .cloned()
+            .or_else(|| label(symbols.function_refs.get(&inst.pc).copied()))
+        {
+            Some(label) => format!("lddw r{}, {}", dst, label),
+            None => format!("lddw r{}, {:#x}", dst, imm as u64),
+        },
+        LdXB | LdXH | LdXW | LdXDW => {
+            format!("ldx{} r{}, [r{}{}]", size_suffix(opcode), dst, src, signed_offset(inst.offset))
+        }
+        StB | StH | StW | StDW => {
+            format!("st{} [r{}{}], {}", size_suffix(opcode), dst, signed_offset(inst.offset), imm)
+        }
+        StXB | StXH | StXW | StXDW => {
+            format!("stx{} [r{}{}], r{}", size_suffix(opcode), dst, signed_offset(inst.offset), src)
+        }
+
+        Le | Be => format!("{}{} r{}", if opcode == Le { "le" } else { "be" }, imm, dst),
+        Neg64 | Neg32 => format!("neg{} r{}", alu_width(opcode), dst),
+
+        Ja => match label(inst.jump_target()) {
+            Some(target) => format!("ja {}", target),
+            None => raw_quad(inst),
+        },
+        Call => {
+            if let Some(name) = symbols.calls.get(&inst.pc) {
+                format!("call {}", name)
+            } else if src == 1 {
+                match label(inst.call_target()) {
+                    Some(target) => format!("call {}", target),
+                    None => raw_quad(inst),
+                }
+            } else {
+                match syscalls::by_hash(imm as u32) {
+                    Some(name) => format!("call {}", name),
+                    None => format!("call {:#x}", imm as u32),
+                }
+            }
+        }
+        // SBF v1 names the register in the immediate, later versions in src
+        Callx if imm == 0 && src != 0 => format!("callx r{}", src),
+        Callx => format!("callx r{}", imm),
+        Exit => "exit".to_string(),
+
+        op if op.is_jump() => match label(inst.jump_target()) {
+            Some(target) if is_reg_source(op) => format!("{} r{}, r{}, {}", jump_name(op), dst, src, target),
+            Some(target) => format!("{} r{}, {}, {}", jump_name(op), dst, imm, target),
+            None => raw_quad(inst),
+        },
+
+        op => {
+            let name = format!("{}{}", alu_name(op), alu_width(op));
+            if is_reg_source(op) {
+                format!("{} r{}, r{}", name, dst, src)
+            } else {
+                format!("{} r{}, {}", name, dst, imm)
+            }
+        }
+    }
+}
+
+/// Bytes that cannot be expressed as an instruction, kept verbatim
+fn raw_quad(inst: &Instruction) -> String {
+    format!(".quad {:#018x}", u64::from_le_bytes(inst.raw))
+}
+
+fn signed_offset(offset: i16) -> String {
+    if offset < 0 { format!("-{}", -(offset as i32)) } else { format!("+{}", offset) }
+}
+
+fn size_suffix(opcode: BpfOpcode) -> &'static str {
+    match opcode as u8 & 0x18 {
+        0x10 => "b",
+        0x08 => "h",
+        0x00 => "w",
+        _ => "dw",
+    }
+}
+
+/// The BPF_X bit selects a register rather than the immediate
+fn is_reg_source(opcode: BpfOpcode) -> bool {
+    opcode as u8 & 0x08 != 0
+}
+
+fn alu_width(opcode: BpfOpcode) -> &'static str {
+    if opcode as u8 & 0x07 == 0x07 { "64" } else { "32" }
+}
+
+pub(crate) fn alu_name(opcode: BpfOpcode) -> &'static str {
+    ALU_NAMES[(opcode as u8 >> 4) as usize]
+}
+
+pub(crate) fn jump_name(opcode: BpfOpcode) -> &'static str {
+    JUMP_NAMES[(opcode as u8 >> 4) as usize]
+}
+
+/// Operation names indexed by the high nibble of the opcode
+pub(crate) const ALU_NAMES: [&str; 16] = [
+    "add", "sub", "mul", "div", "or", "and", "lsh", "rsh", "neg", "mod", "xor", "mov", "arsh", "", "", "",
+];
+pub(crate) const JUMP_NAMES: [&str; 16] = [
+    "ja", "jeq", "jgt", "jge", "jset", "jne", "jsgt", "jsge", "", "", "jlt", "jle", "jslt", "jsle", "", "",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::{BpfInstruction, BpfReg};
+
+    fn bytes(insts: &[BpfInstruction]) -> Vec<u8> {
+        insts.iter().flat_map(|inst| inst.to_bytes()).collect()
+    }
+
+    #[test]
+    fn test_decodes_lddw_as_one_instruction() {
+        let [lo, hi] = BpfInstruction::lddw(BpfReg::R3, 0x1122334455667788);
+        let decoded = decode(&bytes(&[lo, hi, BpfInstruction::exit()])).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].imm as u64, 0x1122334455667788);
+        assert_eq!(decoded[1].pc, 2);
+    }
+
+    #[test]
+    fn test_labels_and_symbols() {
+        let program = bytes(&[
+            BpfInstruction::jeq_imm(BpfReg::R1, 0, 2),
+            BpfInstruction::call_internal(2),
+            BpfInstruction::syscall(syscalls::hash("sol_log_64_")),
+            BpfInstruction::exit(),
+            BpfInstruction::mov_imm(BpfReg::R0, 7),
+            BpfInstruction::exit(),
+        ]);
+        let mut symbols = Symbols::default();
+        symbols.functions.insert(0, "entrypoint".to_string());
+
+        let text = disassemble(&program, &symbols, false).unwrap();
+        assert!(text.starts_with("entrypoint:\n    jeq r1, 0, lbb_3\n"));
+        assert!(text.contains("call function_4\n"));
+        assert!(text.contains("call sol_log_64_\n"));
+        assert!(text.contains("lbb_3:\n    exit\n"));
+        assert!(text.contains("function_4:\n    mov64 r0, 7\n"));
+    }
+
+    #[test]
+    fn test_memory_and_alu_syntax() {
+        let program = bytes(&[
+            BpfInstruction::stx(4, BpfReg::R10, BpfReg::R1, -8),
+            BpfInstruction::ldx(1, BpfReg::R2, BpfReg::R1, 16),
+            BpfInstruction::new(BpfOpcode::Arsh32Imm, BpfReg::R2, BpfReg::R0, 0, 3),
+            BpfInstruction::new(BpfOpcode::Be, BpfReg::R2, BpfReg::R0, 0, 16),
+            BpfInstruction::xor_reg(BpfReg::R2, BpfReg::R3),
+            BpfInstruction::exit(),
+        ]);
+        let text = disassemble(&program, &Symbols::default(), false).unwrap();
+        let lines: Vec<&str> = text.lines().map(str::trim).collect();
+        assert_eq!(lines, [
+            "stxw [r10-8], r1",
+            "ldxb r2, [r1+16]",
+            "arsh32 r2, 3",
+            "be16 r2",
+            "xor64 r2, r3",
+            "exit",
+        ]);
+    }
+
+    #[test]
+    fn test_unknown_bytes_are_kept() {
+        let mut program = bytes(&[BpfInstruction::exit()]);
+        program[0] = 0xff;
+        let text = disassemble(&program, &Symbols::default(), true).unwrap();
+        assert!(text.contains(".quad 0x00000000000000ff"));
+        assert!(text.contains("; 0000: ff 00"));
+    }
+}
@@ -0,0 +1,640 @@
+//! Minimal ELF64 support for SBF shared objects
+//!
+//! Only what the toolchain needs is understood: the section table, the
+//! `.text` and `.rodata` contents, symbols and the relocations the Solana
+//! loader applies. Files are written in the layout the loader expects for
+//! SBF v1 programs, with virtual addresses equal to file offsets.
+use crate::codegen::BpfOpcode;
+use crate::syscalls;
+use anyhow::{anyhow, Result};
+
+pub const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+/// Relocation type for `lddw` immediates holding an address
+pub const R_BPF_64_64: u32 = 1;
+/// Relocation type for addresses stored in `.rodata`
+pub const R_BPF_64_RELATIVE: u32 = 8;
+/// Relocation type for `call` immediates (syscalls and internal calls)
+pub const R_BPF_64_32: u32 = 10;
+
+const EM_BPF: u16 = 247;
+const ET_DYN: u16 = 3;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_DYNAMIC: u32 = 6;
+const SHT_REL: u32 = 9;
+const SHT_DYNSYM: u32 = 11;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STB_GLOBAL: u8 = 1;
+
+const DT_NULL: u64 = 0;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_STRSZ: u64 = 10;
+const DT_SYMENT: u64 = 11;
+const DT_REL: u64 = 17;
+const DT_RELSZ: u64 = 18;
+const DT_RELENT: u64 = 19;
+const DT_FLAGS: u64 = 30;
+const DF_TEXTREL: u64 = 4;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const REL_SIZE: usize = 16;
+const INSN_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub entsize: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub is_function: bool,
+    pub is_object: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: u64,
+    pub kind: u32,
+    pub symbol: Option<Symbol>,
+}
+
+/// A parsed ELF file borrowing its bytes
+#[derive(Debug)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    pub sections: Vec<Section>,
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if !is_elf(data) {
+            return Err(anyhow!("Not an ELF file"));
+        }
+        if data.get(4) != Some(&2) || data.get(5) != Some(&1) {
+            return Err(anyhow!("Only little-endian ELF64 files are supported"));
+        }
+
+        let entry = read_u64(data, 0x18)?;
+        let shoff = read_u64(data, 0x28)? as usize;
+        let shentsize = read_u16(data, 0x3a)? as usize;
+        let shnum = read_u16(data, 0x3c)? as usize;
+        let shstrndx = read_u16(data, 0x3e)? as usize;
+
+        let mut raw = Vec::with_capacity(shnum);
+        for idx in 0..shnum {
+            let base = shoff + idx * shentsize;
+            raw.push((
+                read_u32(data, base)?,
+                Section {
+                    name: String::new(),
+                    kind: read_u32(data, base + 4)?,
+                    addr: read_u64(data, base + 0x10)?,
+                    offset: read_u64(data, base + 0x18)?,
+                    size: read_u64(data, base + 0x20)?,
+                    link: read_u32(data, base + 0x28)?,
+                    entsize: read_u64(data, base + 0x38)?,
+                },
+            ));
+        }
+
+        let mut elf = Self { data, entry, sections: Vec::new() };
+        let names = raw.get(shstrndx).map(|(_, s)| s.clone());
+        for (name_offset, mut section) in raw {
+            if let Some(names) = &names {
+                section.name = elf.string_at(names, name_offset as u64)?;
+            }
+            elf.sections.push(section);
+        }
+        Ok(elf)
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    pub fn section_data(&self, section: &Section) -> Result<&'a [u8]> {
+        let start = section.offset as usize;
+        let end = start.checked_add(section.size as usize)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("Section {} extends past the end of the file", section.name))?;
+        Ok(&self.data[start..end])
+    }
+
+    /// Contents of `.text`, together with its section header
+    pub fn text(&self) -> Result<(&Section, &'a [u8])> {
+        let text = self.section(".text").ok_or_else(|| anyhow!("ELF file has no .text section"))?;
+        Ok((text, self.section_data(text)?))
+    }
+
+    /// Symbols from `.symtab`, falling back to `.dynsym` for stripped files
+    pub fn symbols(&self) -> Result<Vec<Symbol>> {
+        let table = self.sections.iter().find(|s| s.kind == SHT_SYMTAB)
+            .or_else(|| self.sections.iter().find(|s| s.kind == SHT_DYNSYM));
+        match table {
+            Some(table) => self.read_symbols(table),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Relocations from every `SHT_REL` section
+    pub fn relocations(&self) -> Result<Vec<Relocation>> {
+        let mut relocations = Vec::new();
+        for section in self.sections.iter().filter(|s| s.kind == SHT_REL) {
+            let symbols = match self.sections.get(section.link as usize) {
+                Some(table) if table.kind == SHT_DYNSYM || table.kind == SHT_SYMTAB => self.read_symbols(table)?,
+                _ => Vec::new(),
+            };
+            for entry in self.section_data(section)?.chunks_exact(16) {
+                let info = read_u64(entry, 8)?;
+                let symbol_idx = (info >> 32) as usize;
+                relocations.push(Relocation {
+                    offset: read_u64(entry, 0)?,
+                    kind: info as u32,
+                    // Index 0 is the null symbol
+                    symbol: symbol_idx.checked_sub(1).and_then(|idx| symbols.get(idx).cloned()),
+                });
+            }
+        }
+        Ok(relocations)
+    }
+
+    /// Symbols of a table, without the leading null entry
+    fn read_symbols(&self, table: &Section) -> Result<Vec<Symbol>> {
+        let strings = self.sections.get(table.link as usize)
+            .ok_or_else(|| anyhow!("Symbol table {} has no string table", table.name))?
+            .clone();
+        let mut symbols = Vec::new();
+        for entry in self.section_data(table)?.chunks_exact(24).skip(1) {
+            symbols.push(Symbol {
+                name: self.string_at(&strings, read_u32(entry, 0)? as u64)?,
+                value: read_u64(entry, 8)?,
+                is_function: entry[4] & 0x0f == STT_FUNC,
+                is_object: entry[4] & 0x0f == STT_OBJECT,
+            });
+        }
+        Ok(symbols)
+    }
+
+    fn string_at(&self, table: &Section, offset: u64) -> Result<String> {
+        let data = self.section_data(table)?;
+        let start = offset as usize;
+        let bytes = data.get(start..).ok_or_else(|| anyhow!("String offset {} out of range", offset))?;
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+/// Contents of an SBF program to be written as an ELF shared object
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ElfProgram {
+    pub text: Vec<u8>,
+    pub rodata: Vec<u8>,
+    /// Instruction index where execution starts
+    pub entry: usize,
+    /// Function name and first instruction index
+    pub functions: Vec<(String, usize)>,
+    /// Named `.rodata` objects and their offsets
+    pub rodata_symbols: Vec<(String, usize)>,
+    /// `lddw` instruction index and the `.rodata` offset it loads
+    pub rodata_refs: Vec<(usize, usize)>,
+    /// `lddw` instruction index and the instruction whose address it loads
+    pub function_refs: Vec<(usize, usize)>,
+    /// `.rodata` offset of an 8-byte slot and the instruction whose
+    /// address it holds
+    pub rodata_function_refs: Vec<(usize, usize)>,
+    /// `.rodata` offset of an 8-byte slot and the `.rodata` offset whose
+    /// address it holds
+    pub rodata_data_refs: Vec<(usize, usize)>,
+}
+
+/// Section being laid out by the writer
+struct OutSection {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+    offset: usize,
+}
+
+impl ElfProgram {
+    /// Serialize the program. Syscall calls are turned into `call -1` with
+    /// an `R_BPF_64_32` relocation against an undefined dynamic symbol, and
+    /// `.rodata` and function address loads into `R_BPF_64_64` relocations,
+    /// as the loader expects. Function and data addresses stored in `.rodata`
+    /// get an `R_BPF_64_RELATIVE` relocation; for SBF v1 the loader reads
+    /// them from the upper half of their slot.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if !self.text.len().is_multiple_of(INSN_SIZE) {
+            return Err(anyhow!("Text length {} is not a multiple of {}", self.text.len(), INSN_SIZE));
+        }
+        let offsets = self.rodata_refs.iter().map(|(_, offset)| *offset)
+            .chain(self.rodata_symbols.iter().map(|(_, offset)| *offset));
+        for offset in offsets {
+            if offset > self.rodata.len() {
+                return Err(anyhow!("Reference to .rodata offset {} past its end", offset));
+            }
+        }
+        for (offset, _) in &self.rodata_function_refs {
+            if offset + 8 > self.rodata.len() {
+                return Err(anyhow!("Function address at .rodata offset {} past its end", offset));
+            }
+        }
+        for (offset, target) in &self.rodata_data_refs {
+            if offset + 8 > self.rodata.len() || *target > self.rodata.len() {
+                return Err(anyhow!("Address of .rodata offset {} at {} past its end", target, offset));
+            }
+        }
+
+        // Sections are laid out in this order, each after the headers
+        let phnum = if self.rodata.is_empty() { 2 } else { 3 };
+        let text_offset = align_to(EHDR_SIZE + phnum * PHDR_SIZE, 8);
+        let rodata_offset = align_to(text_offset + self.text.len(), 8);
+        let text_addr = text_offset as u64;
+        let rodata_addr = rodata_offset as u64;
+
+        let mut text = self.text.clone();
+        let mut rodata = self.rodata.clone();
+        let mut dynstr = StringTable::new();
+        let mut dynsym = vec![0u8; SYM_SIZE];
+        let mut rel_dyn = Vec::new();
+        let mut syscall_syms: Vec<(String, u32)> = Vec::new();
+
+        // The entrypoint is the one exported function
+        let entry_name = self.functions.iter()
+            .find(|(_, pc)| *pc == self.entry)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| "entrypoint".to_string());
+        push_symbol(&mut dynsym, dynstr.add(&entry_name), STT_FUNC, 1, text_addr + (self.entry * INSN_SIZE) as u64, 0);
+
+        for (pc, chunk) in text.chunks_exact_mut(INSN_SIZE).enumerate() {
+            let imm = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            if chunk[0] != BpfOpcode::Call as u8 || chunk[1] >> 4 != 0 {
+                continue;
+            }
+            let Some(name) = syscalls::by_hash(imm) else {
+                continue;
+            };
+            let index = match syscall_syms.iter().find(|(n, _)| n == name) {
+                Some((_, index)) => *index,
+                None => {
+                    let index = (dynsym.len() / SYM_SIZE) as u32;
+                    push_symbol(&mut dynsym, dynstr.add(name), 0, 0, 0, 0);
+                    syscall_syms.push((name.to_string(), index));
+                    index
+                }
+            };
+            chunk[4..8].copy_from_slice(&(-1i32).to_le_bytes());
+            push_rel(&mut rel_dyn, text_addr + (pc * INSN_SIZE) as u64, R_BPF_64_32, index);
+        }
+
+        for (pc, offset) in &self.rodata_refs {
+            let at = pc * INSN_SIZE;
+            if text.get(at) != Some(&(BpfOpcode::Lddw as u8)) || at + 2 * INSN_SIZE > text.len() {
+                return Err(anyhow!("Instruction {} referencing .rodata is not an lddw", pc));
+            }
+            let addr = rodata_addr + *offset as u64;
+            text[at + 4..at + 8].copy_from_slice(&(addr as u32).to_le_bytes());
+            text[at + 12..at + 16].copy_from_slice(&((addr >> 32) as u32).to_le_bytes());
+            push_rel(&mut rel_dyn, text_addr + at as u64, R_BPF_64_64, 0);
+        }
+
+        for (pc, target) in &self.function_refs {
+            let at = pc * INSN_SIZE;
+            if text.get(at) != Some(&(BpfOpcode::Lddw as u8)) || at + 2 * INSN_SIZE > text.len() {
+                return Err(anyhow!("Instruction {} referencing a function is not an lddw", pc));
+            }
+            if target * INSN_SIZE >= text.len() {
+                return Err(anyhow!("Reference to instruction {} past the end of .text", target));
+            }
+            let addr = text_addr + (target * INSN_SIZE) as u64;
+            text[at + 4..at + 8].copy_from_slice(&(addr as u32).to_le_bytes());
+            text[at + 12..at + 16].copy_from_slice(&((addr >> 32) as u32).to_le_bytes());
+            push_rel(&mut rel_dyn, text_addr + at as u64, R_BPF_64_64, 0);
+        }
+
+        for (offset, target) in &self.rodata_function_refs {
+            if target * INSN_SIZE >= text.len() {
+                return Err(anyhow!("Reference to instruction {} past the end of .text", target));
+            }
+            let addr = text_addr + (target * INSN_SIZE) as u64;
+            rodata[*offset..offset + 8].copy_from_slice(&(addr << 32).to_le_bytes());
+            push_rel(&mut rel_dyn, rodata_addr + *offset as u64, R_BPF_64_RELATIVE, 0);
+        }
+        for (offset, target) in &self.rodata_data_refs {
+            let addr = rodata_addr + *target as u64;
+            rodata[*offset..offset + 8].copy_from_slice(&(addr << 32).to_le_bytes());
+            push_rel(&mut rel_dyn, rodata_addr + *offset as u64, R_BPF_64_RELATIVE, 0);
+        }
+
+        let mut strtab = StringTable::new();
+        let mut symtab = vec![0u8; SYM_SIZE];
+        for (name, pc) in &self.functions {
+            push_symbol(&mut symtab, strtab.add(name), STT_FUNC, 1, text_addr + (pc * INSN_SIZE) as u64, 0);
+        }
+        for (name, offset) in &self.rodata_symbols {
+            push_symbol(&mut symtab, strtab.add(name), STT_OBJECT, 2, rodata_addr + *offset as u64, 0);
+        }
+
+        let mut sections = vec![
+            OutSection::new(".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, text, 8),
+            OutSection::new(".rodata", SHT_PROGBITS, SHF_ALLOC, rodata, 8),
+            OutSection::new(".dynamic", SHT_DYNAMIC, SHF_ALLOC | SHF_WRITE, Vec::new(), 8).entsize(16).link(5),
+            OutSection::new(".dynsym", SHT_DYNSYM, SHF_ALLOC, dynsym, 8).entsize(SYM_SIZE as u64).link(5).info(1),
+            OutSection::new(".dynstr", SHT_STRTAB, SHF_ALLOC, dynstr.data, 1),
+            OutSection::new(".rel.dyn", SHT_REL, SHF_ALLOC, rel_dyn, 8).entsize(REL_SIZE as u64).link(4),
+            OutSection::new(".symtab", SHT_SYMTAB, 0, symtab, 8).entsize(SYM_SIZE as u64).link(8).info(1),
+            OutSection::new(".strtab", SHT_STRTAB, 0, strtab.data, 1),
+            OutSection::new(".shstrtab", SHT_STRTAB, 0, Vec::new(), 1),
+        ];
+        let mut shstrtab = StringTable::new();
+        let name_offsets: Vec<u32> = sections.iter().map(|s| shstrtab.add(s.name)).collect();
+        sections[8].data = shstrtab.data;
+
+        // The dynamic table needs the final offsets of what it points to
+        let dynamic_len = 9 * 16;
+        let mut offset = rodata_offset + self.rodata.len();
+        for (idx, section) in sections.iter_mut().enumerate().skip(2) {
+            offset = align_to(offset, section.align as usize);
+            section.offset = offset;
+            offset += if idx == 2 { dynamic_len } else { section.data.len() };
+        }
+        sections[0].offset = text_offset;
+        sections[1].offset = rodata_offset;
+        let shoff = align_to(offset, 8);
+
+        // The loader rejects a DT_REL with a zero DT_RELSZ, so without
+        // relocations the REL entries are left out and the table is padded
+        // with DT_NULL
+        let has_relocations = !sections[5].data.is_empty();
+        let mut dynamic = Vec::new();
+        for (tag, value) in [
+            (DT_FLAGS, DF_TEXTREL),
+            (DT_REL, sections[5].offset as u64),
+            (DT_RELSZ, sections[5].data.len() as u64),
+            (DT_RELENT, REL_SIZE as u64),
+            (DT_SYMTAB, sections[3].offset as u64),
+            (DT_SYMENT, SYM_SIZE as u64),
+            (DT_STRTAB, sections[4].offset as u64),
+            (DT_STRSZ, sections[4].data.len() as u64),
+        ] {
+            if has_relocations || !matches!(tag, DT_FLAGS | DT_REL | DT_RELSZ | DT_RELENT) {
+                dynamic.extend_from_slice(&tag.to_le_bytes());
+                dynamic.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        while dynamic.len() < dynamic_len {
+            dynamic.extend_from_slice(&DT_NULL.to_le_bytes());
+            dynamic.extend_from_slice(&0u64.to_le_bytes());
+        }
+        sections[2].data = dynamic;
+
+        // ELF header
+        let mut out = Vec::new();
+        out.extend_from_slice(ELF_MAGIC);
+        out.extend_from_slice(&[2, 1, 1, 0]); // ELF64, little endian, version 1, SysV ABI
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&ET_DYN.to_le_bytes());
+        out.extend_from_slice(&EM_BPF.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&(text_addr + (self.entry * INSN_SIZE) as u64).to_le_bytes());
+        out.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        out.extend_from_slice(&(shoff as u64).to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes()); // flags: SBF v1
+        out.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&(phnum as u16).to_le_bytes());
+        out.extend_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&((sections.len() + 1) as u16).to_le_bytes());
+        out.extend_from_slice(&((sections.len()) as u16).to_le_bytes()); // .shstrtab is last
+
+        // Program headers
+        let mut push_phdr = |kind: u32, flags: u32, offset: usize, size: usize| {
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&flags.to_le_bytes());
+            for value in [offset as u64, offset as u64, offset as u64, size as u64, size as u64, 8] {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+        };
+        push_phdr(PT_LOAD, PF_R | PF_X, text_offset, self.text.len());
+        if !self.rodata.is_empty() {
+            push_phdr(PT_LOAD, PF_R, rodata_offset, self.rodata.len());
+        }
+        push_phdr(PT_DYNAMIC, PF_R | PF_W, sections[2].offset, sections[2].data.len());
+
+        // Section contents
+        for section in &sections {
+            out.resize(section.offset, 0);
+            out.extend_from_slice(&section.data);
+        }
+        out.resize(shoff, 0);
+
+        // Section headers, starting with the null section
+        out.extend_from_slice(&[0u8; SHDR_SIZE]);
+        for (section, name) in sections.iter().zip(name_offsets) {
+            let addr = if section.flags & SHF_ALLOC != 0 { section.offset as u64 } else { 0 };
+            out.extend_from_slice(&name.to_le_bytes());
+            out.extend_from_slice(&section.kind.to_le_bytes());
+            for value in [section.flags, addr, section.offset as u64, section.data.len() as u64] {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            out.extend_from_slice(&section.link.to_le_bytes());
+            out.extend_from_slice(&section.info.to_le_bytes());
+            out.extend_from_slice(&section.align.to_le_bytes());
+            out.extend_from_slice(&section.entsize.to_le_bytes());
+        }
+
+        Ok(out)
+    }
+}
+
+impl OutSection {
+    fn new(name: &'static str, kind: u32, flags: u64, data: Vec<u8>, align: u64) -> Self {
+        Self { name, kind, flags, data, link: 0, info: 0, align, entsize: 0, offset: 0 }
+    }
+
+    fn entsize(mut self, entsize: u64) -> Self {
+        self.entsize = entsize;
+        self
+    }
+
+    /// Section header index (1-based, after the null section) of the linked section
+    fn link(mut self, link: u32) -> Self {
+        self.link = link;
+        self
+    }
+
+    fn info(mut self, info: u32) -> Self {
+        self.info = info;
+        self
+    }
+}
+
+/// A string table under construction; offset 0 is the empty string
+struct StringTable {
+    data: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        Self { data: vec![0] }
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        offset
+    }
+}
+
+fn push_symbol(table: &mut Vec<u8>, name: u32, kind: u8, section: u16, value: u64, size: u64) {
+    table.extend_from_slice(&name.to_le_bytes());
+    table.push((STB_GLOBAL << 4) | kind);
+    table.push(0);
+    table.extend_from_slice(&section.to_le_bytes());
+    table.extend_from_slice(&value.to_le_bytes());
+    table.extend_from_slice(&size.to_le_bytes());
+}
+
+fn push_rel(table: &mut Vec<u8>, offset: u64, kind: u32, symbol: u32) {
+    table.extend_from_slice(&offset.to_le_bytes());
+    table.extend_from_slice(&(((symbol as u64) << 32) | kind as u64).to_le_bytes());
+}
+
+fn align_to(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N]> {
+    data.get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Truncated ELF file"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(read_bytes(data, offset)?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(data, offset)?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(data, offset)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::{BpfInstruction, BpfReg};
+
+    fn program() -> ElfProgram {
+        let [lo, hi] = BpfInstruction::lddw(BpfReg::R1, 0);
+        let text = [
+            BpfInstruction::exit(),
+            lo,
+            hi,
+            BpfInstruction::syscall(syscalls::hash("sol_log_")),
+            BpfInstruction::exit(),
+        ];
+        ElfProgram {
+            text: text.iter().flat_map(|inst| inst.to_bytes()).collect(),
+            rodata: b"hello".to_vec(),
+            entry: 1,
+            functions: vec![("helper".to_string(), 0), ("entrypoint".to_string(), 1)],
+            rodata_symbols: vec![("greeting".to_string(), 0)],
+            rodata_refs: vec![(1, 0)],
+            function_refs: Vec::new(),
+            rodata_function_refs: Vec::new(),
+            rodata_data_refs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_written_file_parses() {
+        let data = program().to_bytes().unwrap();
+        let elf = ElfFile::parse(&data).unwrap();
+        let (text, code) = elf.text().unwrap();
+        assert_eq!(code.len(), 5 * 8);
+        assert_eq!(elf.entry, text.addr + 8);
+        assert_eq!(elf.section_data(elf.section(".rodata").unwrap()).unwrap(), b"hello");
+
+        let names: Vec<String> = elf.symbols().unwrap().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["helper", "entrypoint", "greeting"]);
+    }
+
+    #[test]
+    fn test_relocations() {
+        let data = program().to_bytes().unwrap();
+        let elf = ElfFile::parse(&data).unwrap();
+        let (text, code) = elf.text().unwrap();
+        let rodata = elf.section(".rodata").unwrap();
+
+        let relocations = elf.relocations().unwrap();
+        assert_eq!(relocations.len(), 2);
+        let call = relocations.iter().find(|r| r.kind == R_BPF_64_32).unwrap();
+        assert_eq!(call.offset, text.addr + 3 * 8);
+        assert_eq!(call.symbol.as_ref().unwrap().name, "sol_log_");
+        assert_eq!(read_u32(code, 3 * 8 + 4).unwrap(), u32::MAX);
+
+        let load = relocations.iter().find(|r| r.kind == R_BPF_64_64).unwrap();
+        assert_eq!(load.offset, text.addr + 8);
+        assert_eq!(read_u32(code, 8 + 4).unwrap() as u64, rodata.addr);
+    }
+
+    #[test]
+    fn test_file_without_relocations_loads() {
+        let text = [BpfInstruction::mov_imm(BpfReg::R0, 7), BpfInstruction::exit()];
+        let program = ElfProgram {
+            text: text.iter().flat_map(|inst| inst.to_bytes()).collect(),
+            rodata: Vec::new(),
+            entry: 0,
+            functions: vec![("entrypoint".to_string(), 0)],
+            rodata_symbols: Vec::new(),
+            rodata_refs: Vec::new(),
+            function_refs: Vec::new(),
+            rodata_function_refs: Vec::new(),
+            rodata_data_refs: Vec::new(),
+        };
+        let data = program.to_bytes().unwrap();
+        assert!(ElfFile::parse(&data).unwrap().relocations().unwrap().is_empty());
+        // The loader rejects an empty DT_REL table
+        assert_eq!(crate::test_vm::run(&data, &mut []).unwrap().result, 7);
+    }
+}
@@ -0,0 +1,182 @@
+//! Format strings of HolyC print statements
+//!
+//! `"Balance: %d\n", bal;` logs formatted output. The format string is split
+//! into text and conversions at compile time, so a malformed format or a
+//! wrong number of arguments is reported before any code is generated.
+use crate::ast::Expr;
+use anyhow::{anyhow, Result};
+
+/// Longest string copied for a `%s` conversion, in bytes
+pub const STR_MAX: usize = 64;
+
+/// Most values `sol_log_64_` logs in one call
+const LOG_64_ARGS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conversion {
+    /// `%d`: decimal, with a sign for signed types
+    Decimal,
+    /// `%x`: lowercase hex without a prefix
+    Hex,
+    /// `%s`: a NUL-terminated string
+    Str,
+    /// `%c`: a single byte
+    Char,
+}
+
+impl Conversion {
+    /// Most bytes the conversion writes
+    pub fn max_len(self) -> usize {
+        match self {
+            Conversion::Decimal => 20,
+            Conversion::Hex => 16,
+            Conversion::Str => STR_MAX,
+            Conversion::Char => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Piece {
+    Text(Vec<u8>),
+    Arg(Conversion),
+}
+
+/// Split a format string into pieces. `%%` is a literal percent sign, and a
+/// single trailing newline is dropped since every log call is its own line.
+pub fn parse(format: &[u8]) -> Result<Vec<Piece>> {
+    let format = format.strip_suffix(b"\n").unwrap_or(format);
+    let mut pieces = Vec::new();
+    let mut text = Vec::new();
+
+    let mut bytes = format.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'%' {
+            text.push(byte);
+            continue;
+        }
+        let conversion = match bytes.next() {
+            Some(b'%') => {
+                text.push(b'%');
+                continue;
+            }
+            Some(b'd') => Conversion::Decimal,
+            Some(b'x') => Conversion::Hex,
+            Some(b's') => Conversion::Str,
+            Some(b'c') => Conversion::Char,
+            Some(&other) => return Err(anyhow!("unsupported conversion %{}", other.escape_ascii())),
+            None => return Err(anyhow!("format ends with a lone %")),
+        };
+        if !text.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(&mut text)));
+        }
+        pieces.push(Piece::Arg(conversion));
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+/// Conversions in format order
+pub fn conversions(pieces: &[Piece]) -> impl Iterator<Item = Conversion> + '_ {
+    pieces.iter().filter_map(|piece| match piece {
+        Piece::Arg(conversion) => Some(*conversion),
+        Piece::Text(_) => None,
+    })
+}
+
+/// Fail unless there is exactly one argument per conversion
+pub fn check_arg_count(pieces: &[Piece], args: usize) -> Result<()> {
+    let expected = conversions(pieces).count();
+    if args != expected {
+        return Err(anyhow!(
+            "format has {} conversion{} but {} argument{} given",
+            expected, if expected == 1 { "" } else { "s" },
+            args, if args == 1 { " was" } else { "s were" }
+        ));
+    }
+    Ok(())
+}
+
+/// Most bytes the formatted message can take
+pub fn max_len(pieces: &[Piece]) -> usize {
+    pieces.iter().map(|piece| match piece {
+        Piece::Text(text) => text.len(),
+        Piece::Arg(conversion) => conversion.max_len(),
+    }).sum()
+}
+
+/// The syscall a print lowers to when it needs no formatting at run time:
+/// plain text is logged with `sol_log_`, and `0x%x, 0x%x, 0x%x, 0x%x, 0x%x`
+/// with `sol_log_64_`, which logs exactly that. Any other `%x` list is
+/// formatted in the program, since the syscall always logs five values.
+pub fn as_syscall(pieces: &[Piece], args: &[Expr]) -> Option<Expr> {
+    let call = |name: &str, args: Vec<Expr>| Expr::Call {
+        func: Box::new(Expr::Ident(name.to_string())),
+        args,
+    };
+
+    if args.is_empty() {
+        let text: Vec<u8> = pieces.iter()
+            .flat_map(|piece| match piece {
+                Piece::Text(text) => text.clone(),
+                Piece::Arg(_) => Vec::new(),
+            })
+            .collect();
+        let len = text.len() as u64;
+        return Some(call("sol_log_", vec![Expr::StringLiteral(text), Expr::IntLiteral(len)]));
+    }
+
+    let log_64: Vec<Piece> = (0..LOG_64_ARGS)
+        .flat_map(|i| [Piece::Text(if i == 0 { b"0x".to_vec() } else { b", 0x".to_vec() }), Piece::Arg(Conversion::Hex)])
+        .collect();
+    if args.len() != LOG_64_ARGS || pieces != log_64 {
+        return None;
+    }
+    Some(call("sol_log_64_", args.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_format() {
+        let pieces = parse(b"Balance: %d (%x%%)\n").unwrap();
+        assert_eq!(pieces, [
+            Piece::Text(b"Balance: ".to_vec()),
+            Piece::Arg(Conversion::Decimal),
+            Piece::Text(b" (".to_vec()),
+            Piece::Arg(Conversion::Hex),
+            Piece::Text(b"%)".to_vec()),
+        ]);
+        assert_eq!(max_len(&pieces), 9 + 20 + 2 + 16 + 2);
+
+        assert!(parse(b"%f").unwrap_err().to_string().contains("unsupported conversion %f"));
+        assert!(parse(b"100%").is_err());
+        let err = check_arg_count(&pieces, 1).unwrap_err();
+        assert_eq!(err.to_string(), "format has 2 conversions but 1 argument was given");
+    }
+
+    #[test]
+    fn test_simple_prints_become_syscalls() {
+        let text = parse(b"swap ok\n").unwrap();
+        let Some(Expr::Call { func, args }) = as_syscall(&text, &[]) else { panic!("Expected a call") };
+        assert_eq!(*func, Expr::Ident("sol_log_".to_string()));
+        assert_eq!(args, [Expr::StringLiteral(b"swap ok".to_vec()), Expr::IntLiteral(7)]);
+
+        let values: Vec<Expr> = ["a", "b", "c", "d", "e"].iter().map(|v| Expr::Ident(v.to_string())).collect();
+        let Some(Expr::Call { func, args }) = as_syscall(&parse(b"0x%x, 0x%x, 0x%x, 0x%x, 0x%x\n").unwrap(), &values) else {
+            panic!("Expected a call")
+        };
+        assert_eq!(*func, Expr::Ident("sol_log_64_".to_string()));
+        assert_eq!(args, values);
+
+        // sol_log_64_ would log other lists differently
+        assert!(as_syscall(&parse(b"%x, %x").unwrap(), &values[..2]).is_none());
+        assert!(as_syscall(&parse(b"%x %x %x %x %x").unwrap(), &values).is_none());
+        assert!(as_syscall(&parse(b"0x%x, 0x%x, 0x%x, 0x%x, 0x%x, ").unwrap(), &values).is_none());
+        assert!(as_syscall(&parse(b"a=%x").unwrap(), &values[..1]).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! Stack frame layout of a single function
+//!
+//! Slots are allocated downwards from R10: a slot at `offset` occupies
+//! `[r10 - offset, r10 - offset + size)`. Offsets are rounded so every slot
+//! is naturally aligned, and the frame as a whole is a multiple of 8 bytes.
+//! Arguments past the fifth of any call the function makes are written to
+//! the far end of its frame, below every slot.
+//!
+//! Slots are released in the reverse order they were allocated, back to a
+//! [`FrameLayout::mark`], when the scope or statement holding them ends, so
+//! later slots reuse the space. The frame is as large as it ever gets.
+use crate::ast::Type;
+use crate::layout::align_to;
+use crate::target::Target;
+use anyhow::{anyhow, Result};
+
+/// Number of slots listed when a frame is too large
+const BREAKDOWN_SLOTS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKind {
+    Param,
+    Local,
+    /// Temporary spilled by the code generator
+    Spill,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameSlot {
+    pub name: String,
+    pub kind: SlotKind,
+    pub offset: usize,
+    pub size: usize,
+    pub slot_type: Type,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameLayout {
+    pub function: String,
+    /// Every slot ever allocated, in allocation order
+    pub slots: Vec<FrameSlot>,
+    /// Indices of the slots not yet released
+    live: Vec<usize>,
+    /// Indices of the slots live when the frame was deepest
+    deepest: Vec<usize>,
+    used: usize,
+    peak: usize,
+    /// Bytes of stack arguments passed to callees
+    outgoing: usize,
+}
+
+impl FrameLayout {
+    pub fn new(function: &str) -> Self {
+        Self {
+            function: function.to_string(),
+            slots: Vec::new(),
+            live: Vec::new(),
+            deepest: Vec::new(),
+            used: 0,
+            peak: 0,
+            outgoing: 0,
+        }
+    }
+
+    /// Reserve an aligned slot, returning its offset below R10
+    pub fn alloc(&mut self, name: &str, kind: SlotKind, slot_type: Type, size: usize, align: usize) -> usize {
+        let offset = align_to(self.used + size, align.clamp(1, 8));
+        self.used = offset;
+        self.live.push(self.slots.len());
+        self.slots.push(FrameSlot {
+            name: name.to_string(),
+            kind,
+            offset,
+            size,
+            slot_type,
+        });
+        if offset > self.peak {
+            self.peak = offset;
+            self.deepest = self.live.clone();
+        }
+        offset
+    }
+
+    /// Point to release slots allocated after it back to
+    pub fn mark(&self) -> usize {
+        self.live.len()
+    }
+
+    /// Release every slot allocated since `mark`
+    pub fn release(&mut self, mark: usize) {
+        self.live.truncate(mark);
+        self.used = self.live.last().map_or(0, |idx| self.slots[*idx].offset);
+    }
+
+    /// Reserve an 8-byte slot for a spilled temporary
+    pub fn alloc_spill(&mut self) -> usize {
+        let name = format!("<spill{}>", self.slots.iter().filter(|s| s.kind == SlotKind::Spill).count());
+        self.alloc(&name, SlotKind::Spill, Type::U64, 8, 8)
+    }
+
+    /// Make room for `bytes` of stack arguments at the end of the frame
+    pub fn reserve_outgoing(&mut self, bytes: usize) {
+        self.outgoing = self.outgoing.max(bytes);
+    }
+
+    /// Total frame size, including alignment padding and stack arguments
+    pub fn size(&self) -> usize {
+        align_to(self.peak, 8) + self.outgoing
+    }
+
+    /// Slots live when the frame was deepest
+    fn deepest_slots(&self) -> impl Iterator<Item = &FrameSlot> {
+        self.deepest.iter().map(|idx| &self.slots[*idx])
+    }
+
+    /// Bytes of `kind` slots live when the frame was deepest
+    pub fn bytes_of(&self, kind: SlotKind) -> usize {
+        self.deepest_slots().filter(|s| s.kind == kind).map(|s| s.size).sum()
+    }
+
+    /// Slots live when the frame was deepest, from largest to smallest
+    pub fn largest_slots(&self) -> Vec<&FrameSlot> {
+        let mut slots: Vec<&FrameSlot> = self.deepest_slots().collect();
+        slots.sort_by(|a, b| b.size.cmp(&a.size).then(a.offset.cmp(&b.offset)));
+        slots
+    }
+
+    /// Fail when the frame does not fit in the target's stack frame
+    pub fn check(&self, target: &Target) -> Result<()> {
+        if self.size() <= target.frame_size {
+            return Ok(());
+        }
+
+        let mut message = format!(
+            "Function {} needs a {}-byte stack frame, but the target allows {} bytes\n  largest stack slots:",
+            self.function,
+            self.size(),
+            target.frame_size
+        );
+        for slot in self.largest_slots().into_iter().take(BREAKDOWN_SLOTS) {
+            message.push_str(&format!("\n    {:<16} {:<12} {:>6} bytes", slot.name, slot.slot_type.to_string(), slot.size));
+        }
+        message.push_str(&format!(
+            "\n  params {} bytes, locals {} bytes, spills {} bytes, stack args {} bytes, padding {} bytes",
+            self.bytes_of(SlotKind::Param),
+            self.bytes_of(SlotKind::Local),
+            self.bytes_of(SlotKind::Spill),
+            self.outgoing,
+            self.size() - self.outgoing - self.deepest_slots().map(|s| s.size).sum::<usize>()
+        ));
+        Err(anyhow!(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_aligned() {
+        let mut frame = FrameLayout::new("f");
+        assert_eq!(frame.alloc("flag", SlotKind::Local, Type::U8, 1, 1), 1);
+        assert_eq!(frame.alloc("amount", SlotKind::Local, Type::U64, 8, 8), 16);
+        assert_eq!(frame.alloc("small", SlotKind::Local, Type::U16, 2, 2), 18);
+        assert_eq!(frame.alloc_spill(), 32);
+        assert_eq!(frame.size(), 32);
+    }
+
+    #[test]
+    fn test_oversized_frame_lists_largest_slots() {
+        let mut frame = FrameLayout::new("process");
+        frame.alloc("n", SlotKind::Param, Type::U64, 8, 8);
+        frame.alloc("buf", SlotKind::Local, Type::Array(Box::new(Type::U8), Some(8192)), 8192, 1);
+        assert!(FrameLayout::new("empty").check(&Target::sbf()).is_ok());
+
+        let err = frame.check(&Target::sbf()).unwrap_err().to_string();
+        assert!(err.contains("8200-byte stack frame"));
+        assert!(err.contains("4096 bytes"));
+        let buf_line = err.lines().position(|l| l.trim_start().starts_with("buf")).unwrap();
+        let n_line = err.lines().position(|l| l.trim_start().starts_with("n ")).unwrap();
+        assert!(buf_line < n_line);
+    }
+
+    #[test]
+    fn test_released_slots_are_reused() {
+        let mut frame = FrameLayout::new("f");
+        frame.alloc("n", SlotKind::Param, Type::U64, 8, 8);
+        let mark = frame.mark();
+        assert_eq!(frame.alloc("a", SlotKind::Local, Type::Array(Box::new(Type::U8), Some(3000)), 3000, 1), 3008);
+        frame.release(mark);
+        assert_eq!(frame.alloc("b", SlotKind::Local, Type::Array(Box::new(Type::U8), Some(3000)), 3000, 1), 3008);
+        assert_eq!(frame.alloc_spill(), 3016);
+        frame.release(mark);
+        assert_eq!(frame.alloc_spill(), 16);
+        assert_eq!(frame.size(), 3016);
+        assert!(frame.check(&Target::sbf()).is_ok());
+    }
+
+    #[test]
+    fn test_stack_arguments_count_towards_the_frame() {
+        let mut frame = FrameLayout::new("caller");
+        frame.alloc("buf", SlotKind::Local, Type::Array(Box::new(Type::U8), Some(4080)), 4080, 1);
+        frame.reserve_outgoing(8);
+        frame.reserve_outgoing(16);
+        assert!(frame.check(&Target::sbf()).is_ok());
+
+        frame.reserve_outgoing(24);
+        let err = frame.check(&Target::sbf()).unwrap_err().to_string();
+        assert!(err.contains("4104-byte stack frame"));
+        assert!(err.contains("stack args 24 bytes, padding 0 bytes"));
+    }
+}
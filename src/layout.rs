@@ -0,0 +1,447 @@
+//! Memory layout of HolyC classes and enums
+//!
+//! Fields are laid out in declaration order at their natural alignment,
+//! matching the C ABI used by Rust-compiled SBF programs. Union members,
+//! and the members of an anonymous union inside a class, overlap at one
+//! offset and the union is as large as its largest member. A derived class
+//! starts with the complete layout of its base. An enum is stored as its
+//! underlying integer type.
+use crate::ast::*;
+use crate::consteval;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldLayout {
+    pub name: String,
+    pub offset: usize,
+    pub field_type: Type,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassLayout {
+    pub name: String,
+    pub base: Option<String>,
+    pub size: usize,
+    pub align: usize,
+    pub fields: Vec<FieldLayout>,
+}
+
+impl ClassLayout {
+    pub fn field(&self, name: &str) -> Option<&FieldLayout> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+}
+
+/// An enum's storage type and the values of its enumerators, in
+/// declaration order
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnumLayout {
+    pub name: String,
+    pub underlying: Type,
+    pub values: Vec<(String, u64)>,
+}
+
+/// Computes sizes, alignments and field offsets for all classes of a
+/// program, and the values of its enum constants
+#[derive(Debug, Clone, Default)]
+pub struct LayoutEngine {
+    classes: HashMap<String, ClassLayout>,
+    enums: HashMap<String, EnumLayout>,
+    constants: HashMap<String, u64>,
+}
+
+impl LayoutEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_program(program: &Program) -> Result<Self> {
+        let mut engine = Self::new();
+        for item in &program.items {
+            match item {
+                Item::ClassDef(class) => engine.add_class(class)?,
+                Item::EnumDef(enum_def) => engine.add_enum(enum_def)?,
+                _ => {}
+            }
+        }
+        Ok(engine)
+    }
+
+    pub fn add_class(&mut self, class: &ClassDef) -> Result<()> {
+        if self.classes.contains_key(&class.name) {
+            return Err(anyhow!("Duplicate class definition: {}", class.name));
+        }
+
+        let mut offset = 0;
+        let mut size = 0;
+        let mut align = 1;
+        let mut fields = Vec::new();
+
+        // The base class is laid out first, unchanged, so a pointer to the
+        // derived class is also a valid pointer to the base
+        if let Some(base_name) = &class.base {
+            if class.is_union {
+                return Err(anyhow!("Union {} cannot have a base class", class.name));
+            }
+            let base = self.classes.get(base_name).ok_or_else(|| {
+                anyhow!("Base class {} of {} must be defined before it", base_name, class.name)
+            })?;
+            fields.extend(base.fields.iter().cloned());
+            offset = base.size;
+            size = base.size;
+            align = base.align;
+        }
+
+        for field in &class.fields {
+            let members = match field {
+                ClassField::Named(decl) => std::slice::from_ref(decl),
+                ClassField::AnonymousUnion(members) => members.as_slice(),
+            };
+            // The members of an anonymous union share one offset, and every
+            // field of a union starts at 0
+            let group_align = members.iter().map(|m| self.align_of(&m.var_type)).max().unwrap_or(1);
+            let start = if class.is_union { 0 } else { align_to(offset, group_align) };
+
+            let mut end = start;
+            for member in members {
+                if let Type::Custom(name) = &member.var_type {
+                    if name == &class.name {
+                        return Err(anyhow!("Class {} contains itself by value", class.name));
+                    }
+                }
+                if fields.iter().any(|f: &FieldLayout| f.name == member.name) {
+                    return Err(anyhow!("Duplicate field {} in class {}", member.name, class.name));
+                }
+
+                fields.push(FieldLayout {
+                    name: member.name.clone(),
+                    offset: start,
+                    field_type: member.var_type.clone(),
+                });
+                end = end.max(start + self.size_of(&member.var_type));
+            }
+
+            offset = end;
+            size = size.max(end);
+            align = align.max(group_align);
+        }
+
+        self.classes.insert(
+            class.name.clone(),
+            ClassLayout {
+                name: class.name.clone(),
+                base: class.base.clone(),
+                size: align_to(size, align),
+                align,
+                fields,
+            },
+        );
+        Ok(())
+    }
+
+    /// Evaluate the enumerators of `enum_def`. Each value is a constant
+    /// expression, which may use earlier enumerators, and must fit the
+    /// underlying type.
+    pub fn add_enum(&mut self, enum_def: &EnumDef) -> Result<()> {
+        let label = enum_def.name.as_deref().unwrap_or("<anonymous>");
+        if let Some(name) = &enum_def.name {
+            if self.classes.contains_key(name) || self.enums.contains_key(name) {
+                return Err(anyhow!("Duplicate type definition: {}", name));
+            }
+        }
+
+        let mut values = Vec::new();
+        let mut next: Option<u64> = Some(0);
+        for enumerator in &enum_def.enumerators {
+            if self.constants.contains_key(&enumerator.name) {
+                return Err(anyhow!("Enumerator {} in enum {} is already defined", enumerator.name, label));
+            }
+            let value = match &enumerator.value {
+                Some(expr) => consteval::eval(expr, &self.constants, self).ok_or_else(|| {
+                    anyhow!("Value of enumerator {} in enum {} is not a constant expression: {}", enumerator.name, label, expr)
+                })?,
+                None => next.ok_or_else(|| anyhow!("Enumerator {} in enum {} overflows", enumerator.name, label))?,
+            };
+            if consteval::truncate(value, &enum_def.underlying) != value {
+                let shown = if enum_def.underlying.is_unsigned() { value as i128 } else { value as i64 as i128 };
+                return Err(anyhow!(
+                    "Enumerator {} = {} in enum {} does not fit in {}",
+                    enumerator.name, shown, label, enum_def.underlying
+                ));
+            }
+            next = if enum_def.underlying.is_unsigned() {
+                value.checked_add(1)
+            } else {
+                (value as i64).checked_add(1).map(|next| next as u64)
+            };
+            self.constants.insert(enumerator.name.clone(), value);
+            values.push((enumerator.name.clone(), value));
+        }
+
+        if let Some(name) = &enum_def.name {
+            let underlying = enum_def.underlying.clone();
+            self.enums.insert(name.clone(), EnumLayout { name: name.clone(), underlying, values });
+        }
+        Ok(())
+    }
+
+    pub fn enum_layout(&self, name: &str) -> Option<&EnumLayout> {
+        self.enums.get(name)
+    }
+
+    /// Values of every enumerator, by name
+    pub fn constants(&self) -> &HashMap<String, u64> {
+        &self.constants
+    }
+
+    /// `ty` with enum types replaced by their underlying integer type
+    pub fn resolve(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Custom(name) => match self.enums.get(name) {
+                Some(layout) => layout.underlying.clone(),
+                None => ty.clone(),
+            },
+            Type::Pointer(inner) => Type::Pointer(Box::new(self.resolve(inner))),
+            Type::Array(inner, len) => Type::Array(Box::new(self.resolve(inner)), *len),
+            _ => ty.clone(),
+        }
+    }
+
+    pub fn class(&self, name: &str) -> Option<&ClassLayout> {
+        self.classes.get(name)
+    }
+
+    /// True if `derived` is `base` or inherits from it, directly or not
+    pub fn derives_from(&self, derived: &str, base: &str) -> bool {
+        let mut class = Some(derived);
+        while let Some(name) = class {
+            if name == base {
+                return true;
+            }
+            class = self.classes.get(name).and_then(|layout| layout.base.as_deref());
+        }
+        false
+    }
+
+    pub fn size_of(&self, ty: &Type) -> usize {
+        match ty {
+            Type::Array(inner, Some(len)) => self.size_of(inner) * len,
+            Type::Custom(name) => match (self.classes.get(name), self.enums.get(name)) {
+                (Some(class), _) => class.size,
+                (None, Some(layout)) => layout.underlying.size_bytes(),
+                (None, None) => ty.size_bytes(),
+            },
+            _ => ty.size_bytes(),
+        }
+    }
+
+    pub fn align_of(&self, ty: &Type) -> usize {
+        match ty {
+            Type::Array(inner, Some(_)) => self.align_of(inner),
+            Type::Custom(name) => match (self.classes.get(name), self.enums.get(name)) {
+                (Some(class), _) => class.align,
+                (None, Some(layout)) => layout.underlying.size_bytes(),
+                (None, None) => 8,
+            },
+            Type::Void => 1,
+            _ => ty.size_bytes().max(1),
+        }
+    }
+}
+
+/// Element type and count of HolyC's sub-integer view `member` of an integer
+/// type: `x.u8` on a U64 is eight U8s, `x.i32` two I32s. Views are
+/// little-endian, so element 0 holds the least significant bits.
+pub fn integer_view(ty: &Type, member: &str) -> Option<(Type, usize)> {
+    if !ty.is_integer() {
+        return None;
+    }
+    let element = match member {
+        "u8" => Type::U8,
+        "i8" => Type::I8,
+        "u16" => Type::U16,
+        "i16" => Type::I16,
+        "u32" => Type::U32,
+        "i32" => Type::I32,
+        _ => return None,
+    };
+    let count = ty.size_bytes() / element.size_bytes();
+    (count > 1).then_some((element, count))
+}
+
+pub fn align_to(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decl(name: &str, var_type: Type) -> VarDecl {
+        VarDecl {
+            name: name.to_string(),
+            var_type,
+            init: None,
+        }
+    }
+
+    fn field(name: &str, var_type: Type) -> ClassField {
+        ClassField::Named(decl(name, var_type))
+    }
+
+    #[test]
+    fn test_field_alignment() {
+        let mut engine = LayoutEngine::new();
+        engine
+            .add_class(&ClassDef {
+                name: "Header".to_string(),
+                fields: vec![field("tag", Type::U8), field("amount", Type::U64), field("bump", Type::U8)],
+                base: None,
+                is_union: false,
+            })
+            .unwrap();
+
+        let layout = engine.class("Header").unwrap();
+        assert_eq!(layout.field("tag").unwrap().offset, 0);
+        assert_eq!(layout.field("amount").unwrap().offset, 8);
+        assert_eq!(layout.field("bump").unwrap().offset, 16);
+        assert_eq!(layout.size, 24);
+        assert_eq!(layout.align, 8);
+    }
+
+    #[test]
+    fn test_nested_class_size() {
+        let mut engine = LayoutEngine::new();
+        engine
+            .add_class(&ClassDef {
+                name: "Point".to_string(),
+                fields: vec![field("x", Type::U32), field("y", Type::U32)],
+                base: None,
+                is_union: false,
+            })
+            .unwrap();
+        engine
+            .add_class(&ClassDef {
+                name: "Segment".to_string(),
+                fields: vec![
+                    field("a", Type::Custom("Point".to_string())),
+                    field("b", Type::Custom("Point".to_string())),
+                ],
+                base: None,
+                is_union: false,
+            })
+            .unwrap();
+
+        assert_eq!(engine.size_of(&Type::Custom("Segment".to_string())), 16);
+        assert_eq!(engine.class("Segment").unwrap().field("b").unwrap().offset, 8);
+    }
+
+    #[test]
+    fn test_union_members_overlap() {
+        let mut engine = LayoutEngine::new();
+        engine
+            .add_class(&ClassDef {
+                name: "Word".to_string(),
+                fields: vec![field("raw", Type::U32), field("bytes", Type::Array(Box::new(Type::U8), Some(6)))],
+                base: None,
+                is_union: true,
+            })
+            .unwrap();
+        let word = engine.class("Word").unwrap();
+        assert_eq!(word.field("bytes").unwrap().offset, 0);
+        assert_eq!((word.size, word.align), (8, 4));
+
+        engine
+            .add_class(&ClassDef {
+                name: "Slot".to_string(),
+                fields: vec![
+                    field("tag", Type::U8),
+                    ClassField::AnonymousUnion(vec![decl("amount", Type::U64), decl("key", Type::Custom("Word".to_string()))]),
+                    field("bump", Type::U8),
+                ],
+                base: None,
+                is_union: false,
+            })
+            .unwrap();
+        let slot = engine.class("Slot").unwrap();
+        assert_eq!(slot.field("amount").unwrap().offset, 8);
+        assert_eq!(slot.field("key").unwrap().offset, 8);
+        assert_eq!(slot.field("bump").unwrap().offset, 16);
+        assert_eq!(slot.size, 24);
+
+        assert_eq!(integer_view(&Type::U64, "u8"), Some((Type::U8, 8)));
+        assert_eq!(integer_view(&Type::I32, "i16"), Some((Type::I16, 2)));
+        assert_eq!(integer_view(&Type::U32, "u32"), None);
+    }
+
+    #[test]
+    fn test_base_class_prefix() {
+        let mut engine = LayoutEngine::new();
+        let class = |name: &str, base: Option<&str>, fields| ClassDef {
+            name: name.to_string(),
+            base: base.map(str::to_string),
+            fields,
+            is_union: false,
+        };
+        engine.add_class(&class("Header", None, vec![field("discriminator", Type::U8), field("bump", Type::U8)])).unwrap();
+        engine.add_class(&class("Vault", Some("Header"), vec![field("amount", Type::U64)])).unwrap();
+        engine.add_class(&class("Pool", Some("Header"), vec![field("fee", Type::U16)])).unwrap();
+        engine.add_class(&class("Boosted", Some("Pool"), vec![field("boost", Type::U8)])).unwrap();
+
+        let vault = engine.class("Vault").unwrap();
+        assert_eq!(vault.field("bump").unwrap().offset, 1);
+        assert_eq!(vault.field("amount").unwrap().offset, 8);
+        assert_eq!(vault.size, 16);
+        // Derived fields start after the base's padded size
+        let boosted = engine.class("Boosted").unwrap();
+        assert_eq!(boosted.field("boost").unwrap().offset, 4);
+        assert!(engine.derives_from("Boosted", "Header"));
+        assert!(!engine.derives_from("Header", "Vault"));
+        assert!(!engine.derives_from("Pool", "Vault"));
+
+        let err = engine.add_class(&class("Shadow", Some("Header"), vec![field("bump", Type::U8)])).unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate field bump in class Shadow");
+        let err = engine.add_class(&class("Early", Some("Later"), Vec::new())).unwrap_err();
+        assert_eq!(err.to_string(), "Base class Later of Early must be defined before it");
+    }
+
+    #[test]
+    fn test_enum_values() {
+        let enumerator = |name: &str, value: Option<Expr>| Enumerator { name: name.to_string(), value };
+        let neg = |n| Expr::Unary { op: UnaryOp::Neg, expr: Box::new(Expr::IntLiteral(n)) };
+        let mut engine = LayoutEngine::new();
+        engine
+            .add_enum(&EnumDef {
+                name: Some("Status".to_string()),
+                underlying: Type::I16,
+                enumerators: vec![
+                    enumerator("FAILED", Some(neg(2))),
+                    enumerator("PENDING", None),
+                    enumerator("OK", None),
+                    enumerator("DONE", Some(Expr::Ident("OK".to_string()))),
+                    enumerator("NEXT", None),
+                ],
+            })
+            .unwrap();
+
+        let status = engine.enum_layout("Status").unwrap();
+        let values: Vec<i64> = status.values.iter().map(|(_, value)| *value as i64).collect();
+        assert_eq!(values, [-2, -1, 0, 0, 1]);
+        assert_eq!(engine.size_of(&Type::Custom("Status".to_string())), 2);
+        assert_eq!(engine.resolve(&Type::Pointer(Box::new(Type::Custom("Status".to_string())))), Type::Pointer(Box::new(Type::I16)));
+
+        let err = engine
+            .add_enum(&EnumDef {
+                name: None,
+                underlying: Type::U8,
+                enumerators: vec![enumerator("BIG", Some(Expr::IntLiteral(255))), enumerator("BIGGER", None)],
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Enumerator BIGGER = 256 in enum <anonymous> does not fit in U8");
+        let err = engine
+            .add_enum(&EnumDef { name: None, underlying: Type::I64, enumerators: vec![enumerator("OK", None)] })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Enumerator OK in enum <anonymous> is already defined");
+    }
+}
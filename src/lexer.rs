@@ -103,13 +103,16 @@ pub enum Token {
 
     #[regex(r#""([^"\\]|\\.)*""#, |lex| {
         let s = lex.slice();
-        s[1..s.len()-1].to_string()
+        unescape(&s[1..s.len()-1])
     })]
-    StringLiteral(String),
+    StringLiteral(Vec<u8>),
 
-    #[regex(r"'([^'\\]|\\.)'", |lex| {
+    #[regex(r"'([^'\\]|\\x[0-9a-fA-F]{2}|\\.)'", |lex| {
         let s = lex.slice();
-        s.chars().nth(1).unwrap() as u8
+        match unescape(&s[1..s.len()-1])?.as_slice() {
+            [byte] => Some(*byte),
+            _ => None,
+        }
     })]
     CharLiteral(u8),
 
@@ -193,6 +196,8 @@ pub enum Token {
     Arrow,
     #[token(".")]
     Dot,
+    #[token("...")]
+    Ellipsis,
 
     // Delimiters
     #[token("(")]
@@ -223,6 +228,10 @@ pub enum Token {
 
     #[regex(r#"#include\s*["<][^>"]*[">]"#, |lex| lex.slice().to_string())]
     Include(String),
+
+    // Inline assembly is kept verbatim: `asm (bindings) { body }`
+    #[regex(r"asm\s*[({]", lex_asm_block)]
+    AsmBlock(String),
 }
 
 impl std::fmt::Display for Token {
@@ -232,7 +241,7 @@ impl std::fmt::Display for Token {
             Token::IntLiteral(n) => write!(f, "Int({})", n),
             Token::HexLiteral(n) => write!(f, "Hex(0x{:x})", n),
             Token::BinLiteral(n) => write!(f, "Bin(0b{:b})", n),
-            Token::StringLiteral(s) => write!(f, "String(\"{}\")", s),
+            Token::StringLiteral(s) => write!(f, "String(\"{}\")", escape(s)),
             Token::CharLiteral(c) => write!(f, "Char('{}')", *c as char),
             Token::FloatLiteral(fl) => write!(f, "Float({})", fl),
             _ => write!(f, "{:?}", self),
@@ -240,6 +249,121 @@ impl std::fmt::Display for Token {
     }
 }
 
+/// Decode the C escapes in the body of a string or character literal:
+/// `\n`, `\t`, `\r`, `\0`, `\\`, `\"`, `\'` and `\xNN`. Other characters are
+/// kept as UTF-8. Returns None for an unknown or malformed escape.
+pub fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next()? {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            '0' => 0,
+            '\\' => b'\\',
+            '"' => b'"',
+            '\'' => b'\'',
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 {
+                    return None;
+                }
+                u8::from_str_radix(&hex, 16).ok()?
+            }
+            _ => return None,
+        });
+    }
+    Some(bytes)
+}
+
+/// Inverse of [`unescape`]: printable ASCII is kept, everything else escaped
+pub fn escape(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for byte in bytes {
+        match byte {
+            b'\n' => text.push_str("\\n"),
+            b'\t' => text.push_str("\\t"),
+            b'\r' => text.push_str("\\r"),
+            0 => text.push_str("\\0"),
+            b'\\' => text.push_str("\\\\"),
+            b'"' => text.push_str("\\\""),
+            b' ' => text.push(' '),
+            b if b.is_ascii_graphic() => text.push(*b as char),
+            b => text.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    text
+}
+
+/// Extend an `asm` token over its whole block, which the token's pattern
+/// only starts
+fn lex_asm_block(lex: &mut logos::Lexer<Token>) -> Option<String> {
+    let (_, _, len) = split_asm_block(&lex.source()[lex.span().start..])?;
+    lex.bump(len - lex.slice().len());
+    Some(lex.slice().to_string())
+}
+
+/// Split `asm (bindings) { body }` at the start of `text` into the bindings,
+/// the body and the length of the block. Parentheses and braces nest, and
+/// those in strings or `;` and `//` comments don't count.
+pub fn split_asm_block(text: &str) -> Option<(&str, &str, usize)> {
+    let skip_space = |at: usize| at + text[at..].len() - text[at..].trim_start().len();
+    let mut at = skip_space(text.strip_prefix("asm").map(|_| 3)?);
+    let mut bindings = "";
+    if text[at..].starts_with('(') {
+        let end = at + closing(&text[at..], '(', ')')?;
+        bindings = &text[at + 1..end];
+        at = skip_space(end + 1);
+    }
+    if !text[at..].starts_with('{') {
+        return None;
+    }
+    let end = at + closing(&text[at..], '{', '}')?;
+    Some((bindings, &text[at + 1..end], end + 1))
+}
+
+/// Offset of the `close` matching the `open` that starts `text`
+fn closing(text: &str, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            ';' => {
+                chars.by_ref().find(|(_, c)| *c == '\n');
+            }
+            '/' if chars.peek().is_some_and(|(_, c)| *c == '/') => {
+                chars.by_ref().find(|(_, c)| *c == '\n');
+            }
+            _ if c == open => depth += 1,
+            _ if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
 /// Lexer wrapper for easier usage
 pub struct Lexer<'source> {
     inner: logos::Lexer<'source, Token>,
@@ -253,21 +377,23 @@ impl<'source> Lexer<'source> {
     }
 
     pub fn collect_tokens(source: &'source str) -> Result<Vec<(Token, std::ops::Range<usize>)>, String> {
+        Self::tokenize(source).map_err(|span| format!(
+            "Lexical error at position {}: unexpected character '{}'",
+            span.start,
+            &source[span.clone()]
+        ))
+    }
+
+    /// Tokens and their spans, or the span of the first text that is not a
+    /// valid token
+    pub fn tokenize(source: &'source str) -> Result<Vec<(Token, std::ops::Range<usize>)>, std::ops::Range<usize>> {
         let mut lexer = Token::lexer(source);
         let mut tokens = Vec::new();
 
         while let Some(token_result) = lexer.next() {
             match token_result {
-                Ok(token) => {
-                    tokens.push((token, lexer.span()));
-                }
-                Err(_) => {
-                    return Err(format!(
-                        "Lexical error at position {}: unexpected character '{}'",
-                        lexer.span().start,
-                        &source[lexer.span()]
-                    ));
-                }
+                Ok(token) => tokens.push((token, lexer.span())),
+                Err(_) => return Err(lexer.span()),
             }
         }
 
@@ -310,6 +436,16 @@ mod tests {
         assert_eq!(tokens[8].0, Token::RightBrace);
     }
 
+    #[test]
+    fn test_escapes_are_decoded() {
+        let tokens = Lexer::collect_tokens(r#""swap ok\n\x41\0" '\n' '\x7f'"#).unwrap();
+        assert_eq!(tokens[0].0, Token::StringLiteral(b"swap ok\nA\0".to_vec()));
+        assert_eq!(tokens[1].0, Token::CharLiteral(b'\n'));
+        assert_eq!(tokens[2].0, Token::CharLiteral(0x7f));
+        assert!(Lexer::collect_tokens(r#""bad \q""#).is_err());
+        assert_eq!(escape(b"a\"\n\xff"), r#"a\"\n\xff"#);
+    }
+
     #[test]
     fn test_hex_literals() {
         let source = "0xdeadbeef";
@@ -346,6 +482,22 @@ mod tests {
         assert_eq!(tokens.len(), 6); // U64 x ; U64 y ;
     }
 
+    #[test]
+    fn test_asm_blocks_nest() {
+        let block = "asm (in r1 = f(a)) {\n    ja +1 ; skips }\n    exit // {done)\n    { exit }\n}";
+        let tokens = Lexer::collect_tokens(&format!("{} x", block)).unwrap();
+        assert_eq!(tokens[0].0, Token::AsmBlock(block.to_string()));
+        assert_eq!(tokens[1].0, Token::Ident("x".to_string()));
+
+        let (bindings, body, len) = split_asm_block(block).unwrap();
+        assert_eq!(bindings, "in r1 = f(a)");
+        assert!(body.starts_with("\n    ja +1") && body.ends_with("{ exit }\n"));
+        assert_eq!(len, block.len());
+
+        assert!(Lexer::collect_tokens("asm { exit").is_err());
+        assert!(Lexer::collect_tokens("asm (in r1 = a { exit }").is_err());
+    }
+
     #[test]
     fn test_xor_obfuscation() {
         let source = "vault_deobf = vault_slot ^ 0x6e9de2b30b19f9ea;";
@@ -1,41 +1,69 @@
-/// HolyC to Solana BPF Compiler Library
-///
-/// This library provides a complete toolchain for compiling HolyC code
-/// to Solana BPF bytecode that can be deployed on-chain.
-///
-/// # Architecture
-///
-/// The compilation pipeline consists of:
-/// 1. **Lexer** - Tokenizes HolyC source code
-/// 2. **Parser** - Builds Abstract Syntax Tree (AST)
-/// 3. **CodeGen** - Generates Solana BPF bytecode
-/// 4. **Wrapper** - Provides Solana program runtime interface
-///
-/// # Example
-///
-/// ```no_run
-/// use holyc_bpf_compiler::{compile_source, CompilerOptions};
-///
-/// let source = r#"
-///     U64 add(U64 a, U64 b) {
-///         return a + b;
-///     }
-/// "#;
-///
-/// let options = CompilerOptions::default();
-/// let bytecode = compile_source(source, options).unwrap();
-/// ```
+//! HolyC to Solana BPF Compiler Library
+//!
+//! This library provides a complete toolchain for compiling HolyC code
+//! to Solana BPF bytecode that can be deployed on-chain.
+//!
+//! # Architecture
+//!
+//! The compilation pipeline consists of:
+//! 1. **Preprocessor** - Expands macros and conditional compilation
+//! 2. **Lexer** - Tokenizes HolyC source code
+//! 3. **Parser** - Builds Abstract Syntax Tree (AST)
+//! 4. **Sema** - Fills in default arguments, checks labels, jumps over
+//!    initializations, class pointer conversions and enum switches
+//! 5. **Loop optimizer** - LICM, strength reduction and unrolling (optional)
+//! 6. **CodeGen** - Generates Solana BPF bytecode, checking stack frame
+//!    sizes and call depth against the target limits
+//! 7. **Verifier** - Rejects bytecode the Solana loader would refuse
+//! 8. **Wrapper** - Provides Solana program runtime interface
+//!
+//! # Example
+//!
+//! ```no_run
+//! use holyc_bpf_compiler::{compile_source, CompilerOptions};
+//!
+//! let source = r#"
+//!     U64 add(U64 a, U64 b) {
+//!         return a + b;
+//!     }
+//! "#;
+//!
+//! let options = CompilerOptions::default();
+//! let bytecode = compile_source(source, options).unwrap();
+//! ```
 
 pub mod lexer;
 pub mod ast;
+pub mod preprocessor;
 pub mod parser;
+pub mod sema;
 pub mod codegen;
+pub mod syscalls;
+pub mod verifier;
+pub mod elf;
+pub mod disasm;
+pub mod asm;
+pub mod layout;
+pub mod consteval;
+pub mod format;
+pub mod switch;
+pub mod rodata;
+pub mod target;
+pub mod frame;
+pub mod callgraph;
+pub mod cu;
+pub mod cfg;
+pub mod loop_opt;
 pub mod solana_wrapper;
+#[cfg(test)]
+mod test_vm;
 
 use anyhow::{anyhow, Context, Result};
+use std::ops::Range;
+use std::path::PathBuf;
 
 /// Compiler options
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CompilerOptions {
     /// Emit assembly listing
     pub emit_asm: bool,
@@ -43,50 +71,135 @@ pub struct CompilerOptions {
     pub emit_ast: bool,
     /// Optimization level (0-3)
     pub opt_level: u8,
+    /// Fully unroll loops with at most this many iterations (0 disables)
+    pub unroll_limit: u64,
+    /// Abort on out-of-range indices into fixed-size arrays
+    pub bounds_checks: bool,
+    /// Compute 32-bit arithmetic with ALU32 instructions
+    pub alu32: bool,
     /// Verbose output
     pub verbose: bool,
+    /// Macros defined before preprocessing, as `NAME` or `NAME=VALUE`
+    pub defines: Vec<String>,
+    /// Directories searched for `#include` files
+    pub include_paths: Vec<PathBuf>,
 }
 
-impl Default for CompilerOptions {
-    fn default() -> Self {
-        Self {
-            emit_asm: false,
-            emit_ast: false,
-            opt_level: 0,
-            verbose: false,
-        }
+/// Compile HolyC source code to Solana BPF bytecode (the `.text` section)
+pub fn compile_source(source: &str, options: CompilerOptions) -> Result<Vec<u8>> {
+    Ok(compile_program(source, options)?.text)
+}
+
+/// Compile HolyC source code to a deployable ELF shared object
+pub fn compile_to_elf(source: &str, options: CompilerOptions) -> Result<Vec<u8>> {
+    compile_program(source, options)?.to_bytes()
+}
+
+/// Run the preprocessor over `source`, with `defines` given as `NAME` or
+/// `NAME=VALUE`. `file_name` is used in diagnostics and to resolve quoted
+/// includes; `include_paths` are searched after it.
+pub fn preprocess_source(
+    source: &str,
+    file_name: &str,
+    defines: &[String],
+    include_paths: &[PathBuf],
+) -> Result<preprocessor::Preprocessed> {
+    let mut preprocessor = preprocessor::Preprocessor::new();
+    for define in defines {
+        preprocessor.define_arg(define)?;
+    }
+    for dir in include_paths {
+        preprocessor.add_include_path(dir);
     }
+    preprocessor.run(source, file_name).context("Preprocessing failed")
 }
 
-/// Compile HolyC source code to Solana BPF bytecode
-pub fn compile_source(source: &str, options: CompilerOptions) -> Result<Vec<u8>> {
-    // Lex
-    let token_results = lexer::Lexer::collect_tokens(source)
-        .map_err(|e| anyhow!("Lexical analysis failed: {}", e))?;
-    let tokens: Vec<_> = token_results.into_iter().map(|(t, _)| t).collect();
+/// Tokenize preprocessed source, reporting errors at their original location
+pub fn lex_preprocessed(source: &preprocessor::Preprocessed) -> Result<Vec<(lexer::Token, Range<usize>)>> {
+    lexer::Lexer::tokenize(&source.text).map_err(|span| anyhow!(
+        "Lexical analysis failed at {}: unexpected character '{}'",
+        source.map.locate(span.start),
+        &source.text[span.clone()]
+    ))
+}
+
+/// Parse the tokens of preprocessed source, reporting errors at their
+/// original location
+pub fn parse_tokens(source: &preprocessor::Preprocessed, tokens: Vec<(lexer::Token, Range<usize>)>) -> Result<ast::Program> {
+    let (tokens, spans): (Vec<_>, Vec<_>) = tokens.into_iter().unzip();
+    let mut parser = parser::Parser::new(tokens);
+    parser.parse().map_err(|err| {
+        let location = match spans.get(parser.position()) {
+            Some(span) => source.map.locate(span.start).to_string(),
+            None => "end of input".to_string(),
+        };
+        err.context(format!("Parsing failed at {}", location))
+    })
+}
+
+/// Compile HolyC source code to its code and read-only data
+pub fn compile_program(source: &str, options: CompilerOptions) -> Result<elf::ElfProgram> {
+    compile_named(source, "<input>", options)
+}
+
+/// Compile source read from `file_name`
+fn compile_named(source: &str, file_name: &str, options: CompilerOptions) -> Result<elf::ElfProgram> {
+    // Preprocess and lex
+    let source = preprocess_source(source, file_name, &options.defines, &options.include_paths)?;
+    let tokens = lex_preprocessed(&source)?;
 
     if options.verbose {
         println!("Lexed {} tokens", tokens.len());
     }
 
     // Parse
-    let mut parser = parser::Parser::new(tokens);
-    let program = parser.parse().context("Parsing failed")?;
+    let mut program = parse_tokens(&source, tokens)?;
 
     if options.verbose {
         println!("Parsed {} items", program.items.len());
     }
 
+    // Fill in default arguments and check what the parser cannot
+    sema::fill_default_args(&mut program).context("Semantic analysis failed")?;
+    for warning in sema::check_program(&program).context("Semantic analysis failed")? {
+        eprintln!("warning: {}", warning);
+    }
+
+    // Optimize loops
+    let loop_options = loop_opt::LoopOptOptions::new(options.opt_level, options.unroll_limit);
+    if loop_options.is_enabled() {
+        let reports = loop_opt::optimize_program(&mut program, &loop_options);
+        if options.verbose {
+            for report in &reports {
+                println!("Loop optimization: {}", report);
+            }
+        }
+    }
+
     // Generate bytecode
-    let mut codegen = codegen::CodeGen::new();
+    let mut codegen = codegen::CodeGen::new()
+        .with_bounds_checks(options.bounds_checks)
+        .with_alu32(options.alu32);
     let bytecode = codegen.generate(&program)
         .context("Code generation failed")?;
 
+    // Reject anything the on-chain loader would
+    verifier::verify(&bytecode).context("Generated invalid bytecode")?;
+
+    let program = codegen.elf_program(bytecode);
     if options.verbose {
-        println!("Generated {} bytes ({} instructions)", bytecode.len(), bytecode.len() / 8);
+        println!("Generated {} bytes ({} instructions)", program.text.len(), program.text.len() / 8);
+        println!("Read-only data: {} bytes", program.rodata.len());
+        for frame in codegen.frames() {
+            println!("Stack frame: {} uses {} bytes", frame.function, frame.size());
+        }
+        println!("Max call depth: {} ({})", codegen.call_depth().max_depth, codegen.call_depth().deepest_path.join(" -> "));
+    }
+    for cycle in &codegen.call_depth().cycles {
+        eprintln!("warning: recursive call chain {} has no static depth bound", cycle.join(" -> "));
     }
 
-    Ok(bytecode)
+    Ok(program)
 }
 
 /// Compile HolyC source file to bytecode
@@ -94,7 +207,7 @@ pub fn compile_file(path: &str, options: CompilerOptions) -> Result<Vec<u8>> {
     let source = std::fs::read_to_string(path)
         .with_context(|| format!("Failed to read file: {}", path))?;
 
-    compile_source(&source, options)
+    Ok(compile_named(&source, path, options)?.text)
 }
 
 #[cfg(test)]
@@ -153,7 +266,928 @@ mod tests {
         assert!(result.is_ok());
         let bytecode = result.unwrap();
 
-        // Check that XOR instruction is present (0xbf opcode for XOR64Reg)
-        assert!(bytecode.chunks(8).any(|chunk| chunk[0] == 0xbf));
+        // Check that XOR instruction is present
+        assert!(bytecode.chunks(8).any(|chunk| chunk[0] == codegen::BpfOpcode::Xor64Reg as u8));
+    }
+
+    #[test]
+    fn test_function_ending_in_if_gets_exit() {
+        let source = r#"
+            U64 sign(U64 x) {
+                if (x > 0) {
+                    return 1;
+                }
+            }
+
+            U64 twice(U64 x) {
+                return sign(x) + sign(x);
+            }
+        "#;
+
+        let bytecode = compile_source(source, CompilerOptions::default()).unwrap();
+        verifier::verify(&bytecode).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_stack_frame_is_rejected() {
+        let source = r#"
+            U64 process(U64 n) {
+                U8 buf[8192];
+                return n;
+            }
+        "#;
+
+        let err = compile_source(source, CompilerOptions::default()).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("Function process needs a"));
+        assert!(message.contains("buf"));
+    }
+
+    #[test]
+    fn test_stack_slots_are_reused() {
+        // Spilled arguments are freed after each statement, and sibling
+        // scopes share the space of their locals
+        let calls = "s += g(1, g(2, 3));\n".repeat(520);
+        let source = format!(r#"
+            U64 g(U64 a, U64 b) {{ return a + b; }}
+            U64 entrypoint(U8 *input) {{
+                U64 s = 0;
+                {}
+                {{ U8 a[3000]; a[2999] = 1; s += a[2999]; }}
+                {{ U8 b[3000]; b[0] = 2; s += b[0]; }}
+                return s;
+            }}
+        "#, calls);
+        assert_eq!(execute(&source, CompilerOptions::default(), &mut []), 520 * 6 + 3);
+    }
+
+    #[test]
+    fn test_globals_live_in_rodata() {
+        let source = r#"
+            const U64 FEE_BPS = 30;
+            U16 NET_BPS = 10000 - FEE_BPS;
+            U64 entrypoint() {
+                return NET_BPS;
+            }
+        "#;
+
+        let program = compile_program(source, CompilerOptions::default()).unwrap();
+        assert_eq!(program.rodata, [30, 0, 0, 0, 0, 0, 0, 0, 0xf2, 0x26]); // 9970
+        assert_eq!(program.rodata_symbols, [("FEE_BPS".to_string(), 0), ("NET_BPS".to_string(), 8)]);
+        assert_eq!(program.rodata_refs, [(0, 8)]);
+
+        let elf = program.to_bytes().unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        assert!(listing.contains("lddw r6, NET_BPS"));
+        assert!(listing.contains("ldxh r6, [r6+0]"));
+    }
+
+    #[test]
+    fn test_string_literals_are_interned() {
+        let source = r#"
+            U64 entrypoint() {
+                sol_log_("swap ok", 7);
+                sol_log_("swap ok", 7);
+                sol_log_("a\x41\n", 3);
+                return 0;
+            }
+        "#;
+
+        let program = compile_program(source, CompilerOptions::default()).unwrap();
+        assert_eq!(program.rodata, b"swap ok\0aA\n\0");
+        assert_eq!(program.rodata_refs.iter().filter(|(_, offset)| *offset == 0).count(), 2);
+
+        let elf = program.to_bytes().unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        assert!(listing.contains("lddw r6, .str.0"));
+        assert!(listing.contains("call sol_log_"));
+        assert!(listing.contains(".str.1:"));
+    }
+
+    #[test]
+    fn test_print_statements_are_logged() {
+        let source = r#"
+            U64 entrypoint(U8 *name, I32 delta) {
+                "swap ok\n";
+                "%x %x\n", 1, 2;
+                "0x%x, 0x%x, 0x%x, 0x%x, 0x%x\n", 1, 2, 3, 4, 5;
+                "%s moved %d\n", name, delta;
+                return 0;
+            }
+        "#;
+
+        let program = compile_program(source, CompilerOptions::default()).unwrap();
+        assert_eq!(program.rodata, b"swap ok\0");
+        let listing = disasm::disassemble(&program.text, &disasm::Symbols::from_program(&program), false).unwrap();
+        assert_eq!(listing.matches("call sol_log_\n").count(), 3);
+        assert_eq!(listing.matches("call sol_log_64_").count(), 1);
+        assert!(listing.contains("lsh64 r7, 32\n    arsh64 r7, 32"), "I32 should be sign-extended for %d");
+        assert!(listing.contains("jsge r1, 0"));
+        assert!(listing.contains("stw [r6+0], 1987013920"), "text stored four bytes at a time");
+    }
+
+    #[test]
+    fn test_print_format_is_checked() {
+        let check = |body: &str| {
+            let source = format!("U0 entrypoint(U64 n, U8 *s) {{ {} }}", body);
+            format!("{:#}", compile_source(&source, CompilerOptions::default()).unwrap_err())
+        };
+        assert!(check(r#""%d %d\n", n;"#).contains("format has 2 conversions but 1 argument was given"));
+        assert!(check(r#""%s\n", n;"#).contains("%s expects a U8* argument, got n of type U64"));
+        assert!(check(r#""%d\n", s;"#).contains("%d expects an integer argument"));
+        assert!(check(r#""%f\n", n;"#).contains("unsupported conversion %f"));
+    }
+
+    #[test]
+    fn test_macros_are_expanded_before_parsing() {
+        let source = r#"
+            #define XOR_KEY_1 0x6e9de2b30b19f9ea
+            #define MIX(slot) ((slot) ^ XOR_KEY_1)
+            U64 deobfuscate(U64 vault_slot) {
+            #ifdef DEVNET
+                "vault %x\n", vault_slot;
+            #endif
+                return MIX(vault_slot);
+            }
+        "#;
+        let listing = |defines: Vec<String>| {
+            let program = compile_program(source, CompilerOptions { defines, ..Default::default() }).unwrap();
+            disasm::disassemble(&program.text, &disasm::Symbols::from_program(&program), false).unwrap()
+        };
+        assert!(listing(Vec::new()).contains("lddw r7, 0x6e9de2b30b19f9ea"));
+        assert!(!listing(Vec::new()).contains("call sol_log_"));
+        assert!(listing(vec!["DEVNET".to_string()]).contains("call sol_log_"));
+
+        let source = "#define ZERO 0\nU64 f() {\n    return ZERO\n}\n";
+        let err = compile_source(source, CompilerOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "Parsing failed at <input>:4:1");
+    }
+
+    #[test]
+    fn test_writing_a_global_is_rejected() {
+        let source = "U64 counter = 0; U0 entrypoint() { counter += 1; }";
+        let err = compile_source(source, CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Cannot write to global counter"));
+
+        let source = "U64 limit; U0 entrypoint() { U64 x = limit * 2; }";
+        assert!(compile_source(source, CompilerOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_inline_asm_is_spliced() {
+        let source = r#"
+            U64 Max(U64 a, U64 b) {
+                U64 m;
+                asm (in r1 = a, in r2 = b, out r0 = m) {
+                    mov64 r0, r1
+                    jge r1, r2, done
+                    mov64 r0, r2
+                done:
+                }
+                return m;
+            }
+        "#;
+
+        let bytecode = compile_source(source, CompilerOptions::default()).unwrap();
+        let insts: Vec<&[u8]> = bytecode.chunks(8).collect();
+        let jge = insts.iter().position(|inst| inst[0] == codegen::BpfOpcode::JgeReg as u8).unwrap();
+        assert_eq!(insts[jge][1], 0x21); // dst=R1, src=R2
+        assert_eq!(insts[jge][2], 1);
+        assert_eq!(insts[jge + 2][0], codegen::BpfOpcode::StXDW as u8);
+        assert_eq!(insts[jge + 2][1], 0x0a); // stored from R0 into the frame
+    }
+
+    #[test]
+    fn test_inline_asm_rejects_undeclared_writes() {
+        let source = "U64 F(U64 a) { asm (in r1 = a) { mov64 r3, r1 } return a; }";
+        let err = compile_source(source, CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("writes r3 without declaring it"));
+    }
+
+    #[test]
+    fn test_switch_dispatch() {
+        let source = r#"
+            U64 entrypoint(U8* input) {
+                U64 lamports = 0;
+                switch (*input) {
+                    case 0: return 1;
+                    case 1:
+                    case 2: lamports = 20; break;
+                    case 3...5: lamports = 30;
+                    default: lamports++;
+                }
+                return lamports;
+            }
+
+            U64 classify(I64 x) {
+                switch (x) {
+                    case -1: return 1;
+                    case 1000...2000: return 2;
+                    case 0x10000000000: return 3;
+                }
+                return 0;
+            }
+        "#;
+
+        let elf = compile_to_elf(source, CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        // Dense: one bounds check, then a tree over the index
+        assert!(listing.contains("jgt r6, 5, "));
+        assert!(listing.contains("jge r6, 3, "));
+        // Sparse: signed binary search, with wide values loaded into r1
+        assert!(listing.contains("jsge r6, 1000, "));
+        assert!(listing.contains("jeq r6, -1, "));
+        assert!(listing.contains("lddw r1, 0x10000000000"));
+
+        let source = "U0 f(U64 x) { switch (x) { case 1: case 0...3: break; } }";
+        let err = compile_source(source, CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Switch in f: Duplicate case value 1 in switch"));
+        let source = "U0 f(U64 x) { switch (x) { default: continue; } }";
+        assert!(compile_source(source, CompilerOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_do_while_and_goto() {
+        let source = r#"
+            U64 drain(U64 n) {
+                U64 steps = 0;
+                do {
+                    if (n == 7) { continue; }
+                    steps++;
+                } while (--n > 0);
+                if (steps > 5) { goto done; }
+            again:
+                steps += 2;
+                if (steps < 5) { goto again; }
+            done:
+                return steps;
+            }
+        "#;
+
+        let elf = compile_to_elf(source, CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        // The do-while tests its condition at the bottom and jumps back;
+        // `continue` goes to the test
+        assert!(listing.contains("jgt r6, r7, lbb_3"));
+        assert!(listing.contains("ja lbb_11"));
+        // Forward and backward gotos
+        assert!(listing.contains("ja lbb_28"));
+        assert!(listing.contains("ja lbb_20"));
+
+        let err = compile_source("U0 f() { goto missing; }", CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("goto to undefined label missing in f"));
+    }
+
+    #[test]
+    fn test_ternary_and_comma() {
+        let source = r#"
+            U64 Pick(U64 a, U64 b) {
+                return a > b ? a - b : b ? 100 / b : 0;
+            }
+
+            U64 Steps(U64 n) {
+                U64 i;
+                U64 j;
+                U64 steps = 0;
+                for (i = 0, j = n - 1; i < j; i++, j--) {
+                    steps++;
+                }
+                return steps;
+            }
+        "#;
+
+        let elf = compile_to_elf(source, CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        // Each branch is skipped unless chosen, and both leave the result in r6
+        assert!(listing.contains("jle r6, r7, lbb_9"));
+        assert!(listing.contains("jeq r6, 0, lbb_15"));
+        assert_eq!(listing.matches("ja lbb_16").count(), 2);
+        assert!(listing.contains("lbb_15:\n    mov64 r6, 0\nlbb_16:\n    mov64 r0, r6"));
+        // Both for-header assignments run before the loop, both steps after each pass
+        assert!(listing.contains("stxdw [r10-16], r6\n    ldxdw r6, [r10-8]\n    mov64 r7, 1\n    sub64 r6, r7\n    stxdw [r10-24], r6"));
+        assert!(listing.contains("add64 r6, 1\n    stxdw [r10-16], r6"));
+        assert!(listing.contains("add64 r7, -1\n    stxdw [r10-24], r7"));
+    }
+
+    #[test]
+    fn test_unions_and_integer_views() {
+        let source = r#"
+            union Key {
+                U64 raw;
+                U8 bytes[8];
+            };
+
+            class Slot {
+                U8 tag;
+                union {
+                    U64 amount;
+                    Key key;
+                };
+            };
+
+            U64 Top(U64 x, Slot *slot) {
+                U32 word = 0x11223344;
+                word.u8[3] = 0xaa;
+                slot->key.bytes[1] = x.u8[7];
+                return word.u16[1] + x.i32[1] + slot->amount;
+            }
+
+            U64 Pick(U64 x, U64 i) {
+                return x.u8[i];
+            }
+        "#;
+
+        let elf = compile_to_elf(source, CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        // Views address the little-endian bytes of the variable's slot
+        assert!(listing.contains("stxw [r10-20], r6\n    mov64 r6, 170\n    stxb [r10-17], r6"));
+        assert!(listing.contains("ldxb r6, [r10-1]"));
+        assert!(listing.contains("ldxh r6, [r10-18]"));
+        assert!(listing.contains("ldxw r7, [r10-4]"));
+        // The anonymous union starts at offset 8, and its members overlap
+        assert!(listing.contains("stxb [r7+9], r6"));
+        assert!(listing.contains("ldxdw r7, [r7+8]"));
+        // A variable index is added to the slot address
+        assert!(listing.contains("add64 r6, -8\n    ldxdw r7, [r10-16]\n    add64 r6, r7\n    ldxb r6, [r6+0]"));
+
+        let err = compile_source("U64 f(U64 x) { return x.u8[8]; }", CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Index 8 is out of bounds for x.u8 (length 8)"));
+        let err = compile_source("U64 f(U32 x) { return x.u32[0]; }", CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("U32 has no sub-integer view u32"));
+    }
+
+    #[test]
+    fn test_enums() {
+        let source = r#"
+            enum Tag : U8 {
+                INIT = 1,
+                DEPOSIT,
+                WITHDRAW,
+                CLOSE = 0x10,
+            };
+
+            enum { MAX_USERS = 32, MAX_FEE = MAX_USERS * 2 };
+
+            class Ix {
+                Tag tag;
+                U8 bump;
+            };
+
+            Tag last = CLOSE;
+
+            U64 Dispatch(Ix *ix) {
+                Tag tag = ix->tag;
+                switch (tag) {
+                    case INIT: return 1;
+                    case DEPOSIT...WITHDRAW: return MAX_FEE;
+                    case CLOSE: return last;
+                }
+                return ix->bump;
+            }
+        "#;
+
+        let elf = compile_to_elf(source, CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        // Tag is stored as a U8, so the field and the local are single bytes
+        assert!(listing.contains("ldxb r6, [r6+0]\n    stxb [r10-9], r6"));
+        assert!(listing.contains("ldxb r6, [r6+1]"));
+        // Enumerators are constants, in case labels and in expressions
+        assert!(listing.contains("jeq r6, 1, lbb_13"));
+        assert!(listing.contains("jge r6, 4, lbb_24"));
+        assert!(listing.contains("jeq r6, 16, lbb_19"));
+        assert!(listing.contains("mov64 r6, 64"));
+        assert!(listing.contains("last:\n    .byte 0x10"));
+    }
+
+    #[test]
+    fn test_stack_arguments() {
+        let source = r#"
+            U64 Swap(U64 pool, U64 a, U64 b, U64 c, U64 d, U8 e, U64 f) {
+                return pool + e + f;
+            }
+
+            U64 entrypoint(U8 *input) {
+                return Swap(1, 2, 3, 4, 5, 6, Swap(7, 8, 9, 10, 11, 12, 13));
+            }
+        "#;
+
+        let elf = compile_to_elf(source, CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        // The callee copies its fifth and later arguments from the end of
+        // the caller's frame, which R5 points to
+        assert!(listing.contains("stxdw [r10-32], r4\n    ldxdw r0, [r5-4096]\n    stxdw [r10-40], r0"));
+        assert!(listing.contains("ldxdw r0, [r5-4088]\n    stxb [r10-41], r0"));
+        assert!(listing.contains("ldxdw r0, [r5-4080]"));
+        // The caller writes them there, parking them across the nested call
+        assert!(listing.contains("stxdw [r10-4080], r6\n    mov64 r5, r10\n    call Swap"));
+        assert!(listing.contains("ldxdw r0, [r10-48]\n    stxdw [r10-4096], r0"));
+
+        let err = compile_source("U64 f() { return sol_log_64_(1, 2, 3, 4, 5, 6); }", CompilerOptions::default())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Too many arguments to syscall sol_log_64_ (max 5)"));
+    }
+
+    #[test]
+    fn test_classes_by_value() {
+        let source = r#"
+            class Point { U64 x; U64 y; };
+            class Rgb { U8 r; U8 g; U8 b; };
+            class Big { U64 a; U64 b; U64 c; };
+
+            Point MakePoint(U64 x, U64 y) {
+                Point p;
+                p.x = x;
+                p.y = y;
+                return p;
+            }
+
+            U64 Brightness(Rgb c) { return c.r + c.g + c.b; }
+            U64 SumBig(Big b) { return b.b + b.c; }
+
+            U64 entrypoint(U8 *input) {
+                Point p = MakePoint(1, 2);
+                Rgb c;
+                Big big;
+                return p.x + MakePoint(3, 4).y + Brightness(c) + SumBig(big);
+            }
+        "#;
+
+        let elf = compile_to_elf(source, CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        // Returned classes are written through the hidden pointer in R1,
+        // which comes back in R0
+        assert!(listing.contains("MakePoint:\n    stxdw [r10-8], r1\n    stxdw [r10-16], r2"));
+        assert!(listing.contains("ldxdw r6, [r10-8]\n    ldxdw r0, [r10-40]\n    stxdw [r6+0], r0"));
+        assert!(listing.contains("stxdw [r6+8], r0\n    mov64 r0, r6\n    exit"));
+        assert!(listing.contains("mov64 r6, r10\n    add64 r6, -40\n    mov64 r1, r6"));
+        // A 3-byte class travels in one register
+        assert!(listing.contains("Brightness:\n    stxb [r10-3], r1\n    rsh64 r1, 8\n    stxb [r10-2], r1"));
+        assert!(listing.contains("ldxb r0, [r10-25]\n    lsh64 r0, 16\n    or64 r7, r0\n    mov64 r1, r7"));
+        // A 24-byte class is copied and passed by address
+        assert!(listing.contains("SumBig:\n    mov64 r6, r1\n    ldxdw r0, [r6+0]"));
+        assert!(listing.contains("stxdw [r10-80], r0\n    mov64 r7, r10\n    add64 r7, -96\n    mov64 r1, r7"));
+    }
+
+    #[test]
+    fn test_class_assignment() {
+        let source = r#"
+            class CHeader { U8 bump; U32 len; };
+            class CVault : CHeader { U64 amount; };
+
+            U64 Copy(CVault *vault) {
+                CVault copy;
+                copy = *vault;
+                CHeader header = copy;
+                return header.len;
+            }
+        "#;
+
+        let listing = disasm::disassemble_file(&compile_to_elf(source, CompilerOptions::default()).unwrap(), false)
+            .unwrap();
+        // Copies go through R0 in chunks of the class alignment
+        assert!(listing.contains("ldxdw r6, [r10-8]\n    ldxdw r0, [r6+0]\n    stxdw [r10-24], r0"));
+        assert!(listing.contains("ldxdw r0, [r6+8]\n    stxdw [r10-16], r0"));
+        // Copying into a base class copies the base's part only
+        assert!(listing.contains("ldxw r0, [r10-20]\n    stxw [r10-28], r0\n    ldxw r6, [r10-28]"));
+
+        let err = compile_source(
+            "class A { U64 x; }; class B { U64 x; }; U64 f(A a) { B b = a; return b.x; }",
+            CompilerOptions::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("Initializer of b has type A, expected B"));
+    }
+
+    #[test]
+    fn test_function_pointers() {
+        let source = r#"
+            U64 OnInit(U8 *data, U64 len) { return len; }
+            U64 OnClose(U8 *data, U64 len) { return len + 1; }
+
+            U64 Route(U8 *data, U64 len, U64 tag) {
+                U64 (*handlers[2])(U8*, U64);
+                handlers[0] = OnInit;
+                handlers[1] = &OnClose;
+                U64 (*handler)(U8*, U64) = handlers[tag];
+                return (*handler)(data, len) + handlers[1](data, len);
+            }
+        "#;
+
+        let program = compile_program(source, CompilerOptions::default()).unwrap();
+        assert_eq!(program.function_refs, [(15, 0), (18, 5)]);
+
+        let elf = program.to_bytes().unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        assert!(listing.contains("lddw r6, OnInit"));
+        assert!(listing.contains("lddw r6, OnClose"));
+        assert!(listing.contains("callx r6"));
+
+        // The listing assembles back to the same file
+        let reassembled = asm::assemble(&listing).unwrap().to_bytes().unwrap();
+        assert_eq!(reassembled, elf);
+
+        let err = compile_source("U64 f(U64 (*g)(U64)) { return g(1, 2); }", CompilerOptions::default())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Call through g expects 1 arguments, got 2"));
+    }
+
+    #[test]
+    fn test_default_arguments() {
+        let source = r#"
+            U64 Add(U64 a = 1, U64 b = 5) { return a * b; }
+            U0 Init() {}
+
+            U64 entrypoint(U8 *input) {
+                Init;
+                return Add(7) + Add(, 2);
+            }
+        "#;
+
+        let elf = compile_to_elf(source, CompilerOptions::default()).unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        assert!(listing.contains("stxdw [r10-8], r1\n    call Init"));
+        assert!(listing.contains("mov64 r6, 7\n    mov64 r1, r6\n    mov64 r6, 5\n    mov64 r2, r6\n    call Add"));
+        assert!(listing.contains("mov64 r7, 1\n    mov64 r1, r7\n    mov64 r7, 2\n    mov64 r2, r7\n    call Add"));
+
+        let err = compile_source("U0 Foo(U64 a, U64 b = 5) {} U0 f() { Foo(); }", CompilerOptions::default())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Call to Foo in f omits argument a, which has no default"));
+    }
+
+    #[test]
+    fn test_initializer_lists() {
+        let source = r#"
+            class Point { U64 x; U64 y; };
+            Point ORIGIN = {7, 8};
+
+            U64 entrypoint(U8 *input) {
+                U8 seeds[4] = {1, 2, 3, 4};
+                U64 n = *input;
+                Point ps[2] = {{5, 0x100000000}, {n}};
+                U8 name[] = "ab";
+                return seeds[3] + ps[1].x + name[1] + ORIGIN.y;
+            }
+        "#;
+
+        let program = compile_program(source, CompilerOptions::default()).unwrap();
+        assert_eq!(&program.rodata[..16], &[7, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0]);
+
+        let listing = disasm::disassemble_file(&program.to_bytes().unwrap(), false).unwrap();
+        // Four bytes in one store
+        assert!(listing.contains("stw [r10-12], 67305985"));
+        // Constants too wide for a doubleword immediate are split, and the
+        // element known only at run time is stored after the zeros
+        assert!(listing.contains("stdw [r10-56], 5\n    stw [r10-48], 0\n    stw [r10-44], 1\n    stdw [r10-32], 0\n"));
+        assert!(listing.contains("ldxdw r6, [r10-24]\n    stxdw [r10-40], r6"));
+        // The string sizes the array, with its NUL
+        assert!(listing.contains("stb [r10-59], 97\n    sth [r10-58], 98"));
+
+        let err = compile_source("U64 f() { U8 a[2] = {1, 2, 3}; return 0; }", CompilerOptions::default())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Initializer of a has 3 elements, but U8[2] holds 2"));
+        let err = compile_source("U64 f() { return 0; } U64 G[2] = {1, f()};", CompilerOptions::default())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Global G must be initialized with a constant expression, got f()"));
+    }
+
+    #[test]
+    fn test_indexing() {
+        let source = r#"
+            class Pt { U64 x; U32 y; };
+
+            U64 entrypoint(U8 *input) {
+                U64 *words = input;
+                Pt *pts = input;
+                U8 grid[3][5];
+                U64 i = input[0];
+                grid[i][1] = input[i];
+                return words[i] + pts[i].y + words[-1] + grid[2][4];
+            }
+        "#;
+
+        let listing = disasm::disassemble_file(&compile_to_elf(source, CompilerOptions::default()).unwrap(), false).unwrap();
+        // Elements are scaled by their size and loaded at their width
+        assert!(listing.contains("ldxdw r6, [r10-16]\n    ldxdw r7, [r10-48]\n    lsh64 r7, 3\n    add64 r6, r7\n    ldxdw r6, [r6+0]"));
+        assert!(listing.contains("lsh64 r8, 4\n    add64 r7, r8\n    ldxw r7, [r7+8]"));
+        assert!(listing.contains("mul64 r8, 5\n    add64 r7, r8\n    stxb [r7+1], r6"));
+        // Constant indices fold into the offset
+        assert!(listing.contains("ldxdw r7, [r7-8]"));
+        assert!(listing.contains("ldxb r7, [r10-25]"));
+        assert!(!listing.contains("abort"));
+
+        let options = CompilerOptions { bounds_checks: true, ..CompilerOptions::default() };
+        let listing = disasm::disassemble_file(&compile_to_elf(source, options).unwrap(), false).unwrap();
+        assert!(listing.contains("jlt r8, 3, lbb_17\n    call abort\nlbb_17:\n    mul64 r8, 5"));
+        assert_eq!(listing.matches("call abort").count(), 1);
+
+        let err = compile_source("U64 f() { U8 grid[3][5]; return grid[1][5]; }", CompilerOptions::default())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Index 5 is out of bounds for grid[1] (length 5)"));
+    }
+
+    #[test]
+    fn test_casts_and_signed_operations() {
+        let source = r#"
+            I8 Narrow(I64 v) { return v; }
+
+            U64 entrypoint(U8 *input) {
+                I32 a = input[0] - 100;
+                I32 b = input[1];
+                U64 n = (U8)a + b(U16);
+                if (a < 0) { n++; }
+                return n + (a >> 2) + a / b + Narrow(a);
+            }
+        "#;
+
+        let listing = disasm::disassemble_file(&compile_to_elf(source, CompilerOptions::default()).unwrap(), false).unwrap();
+        // Signed values are sign-extended when loaded and narrowed by casts
+        assert!(listing.contains("ldxw r6, [r10-12]\n    lsh64 r6, 32\n    arsh64 r6, 32\n    and64 r6, 255"));
+        assert!(listing.contains("and64 r7, 65535"));
+        assert!(listing.contains("jsge r6, r7"));
+        assert!(listing.contains("arsh64 r7, r8"));
+        // Signed division divides the magnitudes and restores the sign
+        assert!(listing.contains("xor64 r7, r0\n    sub64 r7, r0"));
+        assert!(listing.contains("div64 r7, r8\n    xor64 r7, r9\n    sub64 r7, r9"));
+        // Returns are converted to the declared type
+        assert!(listing.contains("lsh64 r6, 56\n    arsh64 r6, 56\n    mov64 r0, r6"));
+
+        let source = r#"
+            U64 entrypoint(U8 *input) {
+                I32 a = input[0];
+                U32 b = input[1];
+                a = a * 3 + 1;
+                return b / 7 + a;
+            }
+        "#;
+        let options = CompilerOptions { alu32: true, ..CompilerOptions::default() };
+        let listing = disasm::disassemble_file(&compile_to_elf(source, options).unwrap(), false).unwrap();
+        // 32-bit results are extended back to 64 bits for their type
+        assert!(listing.contains("mul32 r6, r7\n    lsh64 r6, 32\n    arsh64 r6, 32"));
+        assert!(listing.contains("div32 r6, r7\n    lsh64 r6, 32\n    rsh64 r6, 32"));
+        assert!(listing.contains("add32 r6, r7\n    lsh64 r6, 32\n    rsh64 r6, 32"));
+
+        // Constant casts fold
+        let source = "U64 entrypoint(U8 *input) { I64 x = (U8)300; I64 y = (I8)255; return x + y; }";
+        let listing = disasm::disassemble_file(&compile_to_elf(source, CompilerOptions::default()).unwrap(), false).unwrap();
+        assert!(listing.contains("mov64 r6, 44"));
+        assert!(listing.contains("lddw r6, 0xffffffffffffffff"));
+    }
+
+    /// Run the entrypoint of `source` on `input` and return R0
+    fn execute(source: &str, options: CompilerOptions, input: &mut [u8]) -> u64 {
+        let elf = compile_to_elf(source, options).unwrap();
+        test_vm::run(&elf, input).unwrap().result
+    }
+
+    #[test]
+    fn test_narrowing_executes() {
+        // Input: a U32 at 0, two I8 at 8 and 9, a selector at 10 and an I32 at 12
+        let source = r#"
+            U32 Mul(U32 a, U32 b) { return (U32)(a * b); }
+            I8 Add8(I8 a, I8 b) { return a + b; }
+            I32 Add32(I32 a, I32 b) { return a + b; }
+            I64 Widen(I8 v) { return v; }
+
+            U64 entrypoint(U8 *input) {
+                U32 *words = input;
+                I8 a = input[8];
+                I8 b = input[9];
+                I8 sum = a + b;
+                switch (input[10]) {
+                    case 0: return Mul(words[0], words[0]);
+                    case 1: return Add8(a, b);
+                    case 2: return Add32(words[3], 1);
+                    case 3: return Widen(a + b);
+                    case 4: return sum;
+                }
+                return (U8)(a + b);
+            }
+        "#;
+        let expected: [u64; 6] = [0, -56i64 as u64, i32::MIN as u64, -56i64 as u64, -56i64 as u64, 200];
+        for alu32 in [false, true] {
+            let options = CompilerOptions { alu32, ..CompilerOptions::default() };
+            for (selector, &want) in expected.iter().enumerate() {
+                let mut input = [0u8; 16];
+                input[0..4].copy_from_slice(&0x10000u32.to_le_bytes());
+                input[8..10].copy_from_slice(&[100, 100]);
+                input[10] = selector as u8;
+                input[12..16].copy_from_slice(&i32::MAX.to_le_bytes());
+                assert_eq!(execute(source, options.clone(), &mut input), want, "case {} with alu32 {}", selector, alu32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_global_dispatch_table_executes() {
+        let source = r#"
+            U64 Add(U64 a, U64 b) { return a + b; }
+            U64 Mul(U64 a, U64 b) { return a * b; }
+            U64 (*ops[2])(U64, U64) = {Add, &Mul};
+            U64 (*fallback)(U64, U64) = Add;
+
+            U64 entrypoint(U8 *input) {
+                if (input[0] > 1) {
+                    return fallback(input[1], input[2]);
+                }
+                return ops[input[0]](input[1], input[2]);
+            }
+        "#;
+        let program = compile_program(source, CompilerOptions::default()).unwrap();
+        assert_eq!(program.rodata_function_refs, [(0, 0), (8, 7), (16, 0)]);
+        for (op, want) in [(0, 13), (1, 42), (2, 13)] {
+            assert_eq!(execute(source, CompilerOptions::default(), &mut [op, 6, 7]), want, "op {}", op);
+        }
+
+        // The table survives a round trip through the listing
+        let elf = program.to_bytes().unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        assert!(listing.contains(".quad Add"));
+        assert!(listing.contains(".quad Mul"));
+        let reassembled = asm::assemble(&listing).unwrap();
+        assert_eq!(reassembled.rodata_function_refs, program.rodata_function_refs);
+        let reassembled = reassembled.to_bytes().unwrap();
+        assert_eq!(reassembled, elf);
+        for (op, want) in [(0, 13), (1, 42), (2, 13)] {
+            assert_eq!(test_vm::run(&reassembled, &mut [op, 6, 7]).unwrap().result, want, "op {}", op);
+        }
+
+        let err = compile_source("U64 Add(U64 a) { return a; } U32 narrow = Add;", CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Global narrow can not hold the address of Add in a U32"));
+        let err = compile_source("U64 Add(U64 a) { return a; } U64 sum = Add + 1;", CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("must be initialized with a constant expression"));
+    }
+
+    #[test]
+    fn test_global_string_pointers_execute() {
+        let source = r#"
+            U8 *greeting = "hello";
+            U8 *names[2] = {"ab", "cd"};
+
+            U64 entrypoint(U8 *input) {
+                U8 *name = names[input[0]];
+                sol_log_(greeting, 5);
+                return greeting[1] + name[1];
+            }
+        "#;
+        let program = compile_program(source, CompilerOptions::default()).unwrap();
+        assert_eq!(program.rodata_data_refs, [(0, 8), (16, 32), (24, 35)]);
+        assert_eq!(&program.rodata[8..14], b"hello\0");
+        assert_eq!(&program.rodata[32..], b"ab\0cd\0");
+        assert_eq!(execute(source, CompilerOptions::default(), &mut [0]), (b'e' + b'b') as u64);
+        assert_eq!(execute(source, CompilerOptions::default(), &mut [1]), (b'e' + b'd') as u64);
+
+        let elf = program.to_bytes().unwrap();
+        let listing = disasm::disassemble_file(&elf, false).unwrap();
+        assert!(listing.contains(".quad .str.0"));
+        assert_eq!(asm::assemble(&listing).unwrap().to_bytes().unwrap(), elf);
+
+        let err = compile_source(r#"U32 narrow = "hi";"#, CompilerOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains(r#"Global narrow can not hold the address of "hi" in a U32"#));
+    }
+
+    #[test]
+    fn test_defaults_and_scopes_execute() {
+        let source = r#"
+            U64 SCALE = 3;
+            U64 Mul(U64 a, U64 b = SCALE) { return a * b; }
+            U64 Seven() { return 7; }
+
+            U64 entrypoint(U8 *input) {
+                U64 SCALE = 100;
+                U64 total = Mul(input[0]);
+                {
+                    U64 Seven = 1000;
+                    total += Seven;
+                }
+                return total + Seven();
+            }
+        "#;
+        assert_eq!(execute(source, CompilerOptions::default(), &mut [5]), 15 + 1000 + 7);
+    }
+
+    #[test]
+    fn test_strength_reduction_saves_compute_units() {
+        let compute_units = |source: &str, opt_level: u8| {
+            let elf = compile_to_elf(source, CompilerOptions { opt_level, ..CompilerOptions::default() }).unwrap();
+            let mut input = [10u8; 64];
+            test_vm::run(&elf, &mut input).unwrap().cu
+        };
+
+        // A single multiply, also inside an address computation, is left alone
+        for source in [
+            "U64 entrypoint(U8 *input) { U64 s = 0; for (U64 i = 0; i < input[0]; i++) { s += i * 12; } return s; }",
+            "U64 entrypoint(U8 *input) { U64 s = 0; for (U64 i = 0; i < input[0]; i++) { s += input[i * 3]; } return s; }",
+        ] {
+            assert_eq!(compute_units(source, 1), compute_units(source, 0), "{}", source);
+        }
+
+        let source = "U64 entrypoint(U8 *input) { U64 s = 0; for (U64 i = 0; i < input[0]; i++) { s += i * 12; s ^= i * 12; s -= i * 12; } return s; }";
+        assert!(compute_units(source, 1) < compute_units(source, 0));
+    }
+
+    #[test]
+    fn test_unrolled_copies_share_stack_slots() {
+        let source = r#"
+            U64 entrypoint(U8 *input) {
+                U64 s = 0;
+                for (U64 i = 0; i < 32; i++) {
+                    U8 buf[200];
+                    buf[199] = i;
+                    s += buf[199];
+                }
+                return s;
+            }
+        "#;
+        let options = CompilerOptions { unroll_limit: 32, ..CompilerOptions::default() };
+        let program = compile_program(source, options.clone()).unwrap();
+        assert!(program.text.len() > 32 * 3 * 8, "loop was not unrolled");
+        assert_eq!(execute(source, options, &mut []), (0..32).sum::<u64>());
+    }
+
+    #[test]
+    fn test_narrow_counters_keep_their_results() {
+        let source = r#"
+            U64 entrypoint(U8 *input) {
+                U8 i = 250;
+                U64 c = 0;
+                U64 s = 0;
+                while (c < 10) { s += i * 12; s += i * 12; s += i * 12; i++; c++; }
+                return s;
+            }
+        "#;
+        for opt_level in [0, 1] {
+            let options = CompilerOptions { opt_level, ..CompilerOptions::default() };
+            assert_eq!(execute(source, options, &mut []), 54756, "at -O{}", opt_level);
+        }
+    }
+
+    #[test]
+    fn test_estimated_compute_units_bound_metered() {
+        let sources = [
+            "U64 entrypoint(U8 *input) { U64 s = 0; for (U64 i = 0; i < 10; i++) { s += input[i]; } return s; }",
+            r#"
+            U64 entrypoint(U8 *input) {
+                U8 buf[16];
+                U32 s = 0;
+                for (U8 i = 0; i < 200; i++) {
+                    buf[i % 16] = input[i % 16];
+                    s += (U32)buf[i % 16] * 3;
+                }
+                return s;
+            }
+            "#,
+            r#"
+            U64 Sq(U64 x) { return x * x; }
+            U64 Dbl(U64 x) { return x + x; }
+            U64 entrypoint(U8 *input) {
+                U64 (*f)(U64) = Sq;
+                if (input[0] & 1) { f = Dbl; }
+                U64 s = 0;
+                switch (input[1]) {
+                    case 0: s = f(input[2]); break;
+                    case 1: s = Sq(3); break;
+                    default: s = Dbl(input[3]);
+                }
+                return s;
+            }
+            "#,
+            r#"
+            U64 entrypoint(U8 *input) {
+                U64 s = 0;
+                for (U64 i = 0; i < 4; i++) {
+                    s += input[i];
+                    "%d %x\n", s, i;
+                }
+                return s;
+            }
+            "#,
+        ];
+        let options = [
+            CompilerOptions::default(),
+            CompilerOptions { opt_level: 1, ..CompilerOptions::default() },
+            CompilerOptions { unroll_limit: 16, ..CompilerOptions::default() },
+            CompilerOptions { alu32: true, ..CompilerOptions::default() },
+            CompilerOptions { bounds_checks: true, ..CompilerOptions::default() },
+        ];
+        for source in sources {
+            let preprocessed = preprocess_source(source, "<input>", &[], &[]).unwrap();
+            let tokens = lex_preprocessed(&preprocessed).unwrap();
+            let mut program = parse_tokens(&preprocessed, tokens).unwrap();
+            sema::fill_default_args(&mut program).unwrap();
+
+            for options in &options {
+                let estimate = cu::estimate_program(&program, options).unwrap()
+                    .into_iter().find(|f| f.name == "entrypoint").unwrap().cost;
+                assert!(estimate.is_bounded(), "{}", source);
+
+                let elf = compile_to_elf(source, options.clone()).unwrap();
+                for first in [0u8, 1, 2, 3] {
+                    let mut input = [first; 64];
+                    input[1] = first % 3;
+                    let metered = test_vm::run(&elf, &mut input).unwrap().cu;
+                    assert!(metered <= estimate.worst, "{:?}: metered {} > estimated {}\n{}", options, metered, estimate, source);
+                }
+            }
+        }
     }
 }
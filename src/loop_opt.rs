@@ -0,0 +1,1047 @@
+//! Loop optimizations
+//!
+//! Each `while`/`for` loop is lowered to a CFG to find its natural loop,
+//! the variables it defines and its basic induction variables. Those facts
+//! drive three rewrites of the AST:
+//!
+//! 1. **LICM** - pure invariant expressions are hoisted into temporaries
+//!    computed once before the loop
+//! 2. **Strength reduction** - `i * k` on an induction variable becomes a
+//!    temporary advanced by `step * k` each iteration, and multiplies by a
+//!    power of two become shifts. A multiply costs as much as an add on
+//!    sBPF, so a product is only replaced when it is used often enough
+//!    per iteration to pay for advancing the temporary
+//! 3. **Unrolling** - `for` loops with a constant trip count up to a limit
+//!    are fully unrolled
+use crate::ast::*;
+use crate::cfg::{BlockId, Cfg, NaturalLoop, Terminator};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Upper bound on statements produced by fully unrolling one loop
+const MAX_UNROLLED_STMTS: u64 = 512;
+
+/// Compute units spent advancing a strength-reduced temporary each
+/// iteration: load, materialize the step, add and store
+const ADVANCE_COST: usize = 4;
+
+#[derive(Debug, Clone, Default)]
+pub struct LoopOptOptions {
+    pub licm: bool,
+    pub strength_reduction: bool,
+    /// Fully unroll `for` loops running at most this many iterations (0 = off)
+    pub unroll_limit: u64,
+}
+
+impl LoopOptOptions {
+    pub fn new(opt_level: u8, unroll_limit: u64) -> Self {
+        Self {
+            licm: opt_level >= 1,
+            strength_reduction: opt_level >= 1,
+            unroll_limit,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.licm || self.strength_reduction || self.unroll_limit > 0
+    }
+}
+
+/// Optimize every loop in the program, returning one report line per
+/// transformation applied
+pub fn optimize_program(program: &mut Program, options: &LoopOptOptions) -> Vec<String> {
+    let mut optimizer = LoopOptimizer {
+        options: options.clone(),
+        reports: Vec::new(),
+        temp_counter: 0,
+        function: String::new(),
+        var_types: HashMap::new(),
+        escaped: HashSet::new(),
+    };
+
+    for item in &mut program.items {
+        if let Item::FunctionDef(func) = item {
+            optimizer.optimize_function(func);
+        }
+    }
+
+    optimizer.reports
+}
+
+/// A `for` loop of the form `for (v = a; v OP b; v += s)` with constant `a`, `b`, `s`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanonicalLoop {
+    pub var: String,
+    pub var_type: Option<Type>,
+    pub start: i128,
+    pub bound: i128,
+    pub op: BinaryOp,
+    pub step: i128,
+}
+
+impl CanonicalLoop {
+    pub fn from_stmt(stmt: &Stmt) -> Option<Self> {
+        let Stmt::For { init: Some(init), condition: Some(condition), increment: Some(increment), body } = stmt else {
+            return None;
+        };
+
+        let (var, var_type, start) = match &**init {
+            Stmt::VarDecl(VarDecl { name, var_type, init: Some(value) }) => {
+                (name.clone(), Some(var_type.clone()), int_value(value)?)
+            }
+            Stmt::Expr(Expr::Assign { target, value }) => match &**target {
+                Expr::Ident(name) => (name.clone(), None, int_value(value)?),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let (op, bound) = match condition {
+            Expr::Binary { op, left, right } if op.is_comparison() && **left == Expr::Ident(var.clone()) => {
+                (*op, int_value(right)?)
+            }
+            _ => return None,
+        };
+
+        let (step_var, step) = induction_update(increment)?;
+        if step_var != var || step == 0 {
+            return None;
+        }
+
+        // The body must leave the induction variable alone
+        let mut defs = HashMap::new();
+        let mut escaped = HashSet::new();
+        for stmt in body {
+            stmt_defs(stmt, &mut defs, &mut escaped);
+        }
+        if defs.contains_key(&var) || escaped.contains(&var) {
+            return None;
+        }
+
+        Some(Self { var, var_type, start, bound, op, step })
+    }
+
+    /// Number of iterations, or None if the loop does not terminate cleanly
+    pub fn trip_count(&self) -> Option<u64> {
+        let (start, bound, step) = (self.start, self.bound, self.step);
+        let trips = match (self.op, step > 0) {
+            (BinaryOp::Lt, true) => if start < bound { (bound - start + step - 1) / step } else { 0 },
+            (BinaryOp::Le, true) => if start <= bound { (bound - start) / step + 1 } else { 0 },
+            (BinaryOp::Gt, false) => if start > bound { (start - bound - step - 1) / -step } else { 0 },
+            (BinaryOp::Ge, false) => if start >= bound { (start - bound) / -step + 1 } else { 0 },
+            (BinaryOp::Ne, _) => {
+                let distance = bound - start;
+                if distance % step != 0 || distance / step < 0 {
+                    return None;
+                }
+                distance / step
+            }
+            _ => return None,
+        };
+
+        // Unsigned counters that would step below zero wrap instead
+        let signed = matches!(self.var_type, Some(ref ty) if ty.is_integer() && !ty.is_unsigned());
+        if !signed && start + trips * step < 0 {
+            return None;
+        }
+
+        // So do counters that would step past the range of their type,
+        // which leaves the loop running for a different count or forever
+        if let Some(ty) = &self.var_type {
+            if !ty.is_integer() {
+                return None;
+            }
+            let bits = 8 * ty.size_bytes() as u32;
+            let (min, max) = if ty.is_unsigned() { (0, (1i128 << bits) - 1) } else { (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1) };
+            let last = start + trips * step;
+            if [start, last].iter().any(|value| !(min..=max).contains(value)) {
+                return None;
+            }
+        }
+
+        u64::try_from(trips).ok()
+    }
+}
+
+/// Trip count of a canonical `for` loop, if it can be determined statically
+pub fn trip_count(stmt: &Stmt) -> Option<u64> {
+    CanonicalLoop::from_stmt(stmt)?.trip_count()
+}
+
+/// What a loop's CFG tells us about the variables it touches
+struct LoopFacts {
+    defs: HashMap<String, usize>,
+    /// Induction variable -> (defining update, step, block)
+    induction_vars: HashMap<String, (Expr, i128, BlockId)>,
+    latch: BlockId,
+    latch_preds: usize,
+}
+
+struct LoopOptimizer {
+    options: LoopOptOptions,
+    reports: Vec<String>,
+    temp_counter: usize,
+    function: String,
+    var_types: HashMap<String, Type>,
+    escaped: HashSet<String>,
+}
+
+impl LoopOptimizer {
+    fn optimize_function(&mut self, func: &mut FunctionDef) {
+        self.function = func.name.clone();
+        self.var_types = func.params.iter()
+            .map(|p| (p.name.clone(), p.param_type.clone()))
+            .collect();
+        collect_var_types(&func.body, &mut self.var_types);
+
+        // A goto can enter a loop anywhere, which breaks the single-entry
+        // shape every rewrite relies on
+        let mut has_goto = false;
+        for stmt in &func.body {
+            stmt.walk(&mut |stmt| has_goto |= matches!(stmt, Stmt::Goto(_) | Stmt::Label(_)));
+        }
+        if has_goto {
+            return;
+        }
+
+        // Locals whose address is taken may change behind our back
+        let mut defs = HashMap::new();
+        self.escaped.clear();
+        for stmt in &func.body {
+            stmt_defs(stmt, &mut defs, &mut self.escaped);
+        }
+
+        self.optimize_block(&mut func.body);
+    }
+
+    fn optimize_block(&mut self, block: &mut Block) {
+        let mut idx = 0;
+        while idx < block.len() {
+            // Inner loops first, so their hoisted code can move further out
+            match &mut block[idx] {
+                Stmt::If { then_block, else_block, .. } => {
+                    self.optimize_block(then_block);
+                    if let Some(else_block) = else_block {
+                        self.optimize_block(else_block);
+                    }
+                }
+                Stmt::While { body, .. } | Stmt::DoWhile { body, .. } | Stmt::For { body, .. } => {
+                    self.optimize_block(body)
+                }
+                Stmt::Block(inner) => self.optimize_block(inner),
+                Stmt::Switch { cases, .. } => {
+                    for case in cases {
+                        self.optimize_block(&mut case.body);
+                    }
+                }
+                _ => {}
+            }
+
+            if matches!(block[idx], Stmt::While { .. } | Stmt::For { .. }) {
+                let replacement = self.optimize_loop(block[idx].clone());
+                let len = replacement.len();
+                block.splice(idx..idx + 1, replacement);
+                idx += len;
+            } else {
+                idx += 1;
+            }
+        }
+    }
+
+    /// Returns the statements replacing `stmt`
+    fn optimize_loop(&mut self, mut stmt: Stmt) -> Vec<Stmt> {
+        if self.options.unroll_limit > 0 {
+            if let Some(unrolled) = self.unroll(&stmt) {
+                return vec![unrolled];
+            }
+        }
+
+        let cfg = Cfg::build(std::slice::from_ref(&stmt));
+        let loops = cfg.natural_loops();
+        let Some(natural_loop) = cfg.loop_headers.first()
+            .and_then(|header| loops.iter().find(|l| l.header == *header)) else {
+            return vec![stmt];
+        };
+        let facts = self.analyze(&cfg, natural_loop, &loops);
+
+        let mut preheader = Vec::new();
+        if self.options.licm {
+            self.hoist_invariants(&mut stmt, &cfg, natural_loop, &facts, &mut preheader);
+        }
+        if self.options.strength_reduction {
+            self.reduce_induction_vars(&mut stmt, &cfg, natural_loop, &facts, &mut preheader);
+            self.shifts_for_multiplies(&mut stmt);
+        }
+
+        if preheader.is_empty() {
+            vec![stmt]
+        } else {
+            preheader.push(stmt);
+            vec![Stmt::Block(preheader)]
+        }
+    }
+
+    fn analyze(&self, cfg: &Cfg, natural_loop: &NaturalLoop, loops: &[NaturalLoop]) -> LoopFacts {
+        let mut defs = HashMap::new();
+        let mut escaped = HashSet::new();
+        let mut updates: Vec<(String, Expr, i128, BlockId)> = Vec::new();
+
+        for &block in &natural_loop.blocks {
+            for stmt in &cfg.blocks[block].stmts {
+                stmt_defs(stmt, &mut defs, &mut escaped);
+                if let Stmt::Expr(expr) = stmt {
+                    if let Some((var, step)) = induction_update(expr) {
+                        updates.push((var, expr.clone(), step, block));
+                    }
+                }
+            }
+            if let Terminator::Branch { condition: expr, .. }
+                | Terminator::Switch { value: expr, .. }
+                | Terminator::Return(Some(expr)) = &cfg.blocks[block].terminator
+            {
+                expr_defs(expr, &mut defs, &mut escaped);
+            }
+        }
+
+        // A basic induction variable is updated exactly once per iteration,
+        // in a block that dominates every latch and sits in no inner loop
+        let dom = cfg.dominators();
+        let inner_blocks: BTreeSet<BlockId> = loops.iter()
+            .filter(|l| l.header != natural_loop.header && l.blocks.is_subset(&natural_loop.blocks))
+            .flat_map(|l| l.blocks.iter().copied())
+            .collect();
+        let mut induction_vars = HashMap::new();
+        for (var, update, step, block) in updates {
+            if defs.get(&var) == Some(&1)
+                && !self.escaped.contains(&var)
+                && !inner_blocks.contains(&block)
+                && natural_loop.latches.iter().all(|latch| dom[*latch].contains(&block))
+            {
+                induction_vars.insert(var, (update, step, block));
+            }
+        }
+
+        let latch = natural_loop.latches[0];
+        let latch_preds = cfg.predecessors()[latch].len();
+        LoopFacts { defs, induction_vars, latch, latch_preds }
+    }
+
+    fn hoist_invariants(
+        &mut self,
+        stmt: &mut Stmt,
+        cfg: &Cfg,
+        natural_loop: &NaturalLoop,
+        facts: &LoopFacts,
+        preheader: &mut Vec<Stmt>,
+    ) {
+        // Variables (re)defined by a `for` initializer get their loop-entry
+        // value after the preheader, so they are not invariant there
+        let mut blocked: HashSet<String> = facts.defs.keys().cloned().collect();
+        if let Stmt::For { init: Some(init), .. } = stmt {
+            let mut init_defs = HashMap::new();
+            stmt_defs(init, &mut init_defs, &mut HashSet::new());
+            blocked.extend(init_defs.into_keys());
+        }
+
+        let mut candidates: Vec<Expr> = Vec::new();
+        for expr in cfg.block_exprs(&natural_loop.blocks) {
+            self.collect_invariants(expr, &blocked, &mut candidates);
+        }
+
+        let kind = loop_kind(stmt);
+        for candidate in candidates {
+            let temp = self.new_temp("licm");
+            let replaced = replace_in_loop(stmt, &candidate, &Expr::Ident(temp.clone()));
+            if replaced == 0 {
+                continue;
+            }
+            self.reports.push(format!(
+                "{}: hoisted loop-invariant `{}` out of {} loop into {}",
+                self.function, candidate, kind, temp
+            ));
+            let temp_type = self.arith_type(&candidate);
+            preheader.push(Stmt::VarDecl(VarDecl {
+                name: temp,
+                var_type: temp_type,
+                init: Some(candidate),
+            }));
+        }
+    }
+
+    /// Collect maximal invariant subexpressions worth hoisting
+    fn collect_invariants(&self, expr: &Expr, blocked: &HashSet<String>, out: &mut Vec<Expr>) {
+        let worth_hoisting = matches!(expr, Expr::Binary { .. } | Expr::Unary { op: UnaryOp::Neg | UnaryOp::BitNot, .. })
+            && expr.any(&|e| matches!(e, Expr::Ident(_)));
+
+        if worth_hoisting && self.is_invariant(expr, blocked) {
+            if !out.contains(expr) {
+                out.push(expr.clone());
+            }
+            return;
+        }
+
+        for child in expr.children() {
+            self.collect_invariants(child, blocked, out);
+        }
+    }
+
+    fn is_invariant(&self, expr: &Expr, blocked: &HashSet<String>) -> bool {
+        match expr {
+            Expr::IntLiteral(_) | Expr::CharLiteral(_) | Expr::BoolLiteral(_) => true,
+            Expr::Ident(name) => {
+                !blocked.contains(name)
+                    && !self.escaped.contains(name)
+                    && matches!(self.var_types.get(name), Some(ty) if ty.is_integer() || *ty == Type::Bool)
+            }
+            Expr::Binary { op, left, right } => {
+                let pure = match op {
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul
+                    | BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor
+                    | BinaryOp::Shl | BinaryOp::Shr => true,
+                    // Hoisting a division could introduce a trap on a path
+                    // that never executed it
+                    BinaryOp::Div | BinaryOp::Mod => matches!(**right, Expr::IntLiteral(n) if n != 0),
+                    _ => false,
+                };
+                pure && self.is_invariant(left, blocked) && self.is_invariant(right, blocked)
+            }
+            Expr::Unary { op: UnaryOp::Neg | UnaryOp::BitNot | UnaryOp::Not, expr } => {
+                self.is_invariant(expr, blocked)
+            }
+            _ => false,
+        }
+    }
+
+    fn reduce_induction_vars(
+        &mut self,
+        stmt: &mut Stmt,
+        cfg: &Cfg,
+        natural_loop: &NaturalLoop,
+        facts: &LoopFacts,
+        preheader: &mut Vec<Stmt>,
+    ) {
+        let kind = loop_kind(stmt);
+        let mut vars: Vec<_> = facts.induction_vars.iter().collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        let dom = cfg.dominators();
+        let every_iteration: BTreeSet<BlockId> = natural_loop.blocks.iter().copied()
+            .filter(|block| natural_loop.latches.iter().all(|latch| dom[*latch].contains(block)))
+            .collect();
+
+        for (var, (update, step, block)) in vars {
+            // A 64-bit temporary only follows a counter that wraps with it
+            if !matches!(self.var_types.get(var), Some(Type::U64 | Type::I64)) {
+                continue;
+            }
+
+            // Collect the distinct `var * k` products used in the loop, and
+            // keep those whose uses on every iteration save more than
+            // advancing a temporary costs
+            let mut factors: Vec<u64> = Vec::new();
+            for expr in cfg.block_exprs(&natural_loop.blocks) {
+                collect_products(expr, var, &mut factors);
+            }
+            let every_iteration = cfg.block_exprs(&every_iteration);
+            factors.retain(|&factor| {
+                let uses: usize = every_iteration.iter().map(|expr| count_products(expr, var, factor)).sum();
+                uses * product_cost(factor) > ADVANCE_COST
+            });
+            if factors.is_empty() {
+                continue;
+            }
+
+            // Find where the update happens so the temporary can follow it
+            let in_increment = *block == facts.latch
+                && matches!(stmt, Stmt::For { increment: Some(inc), .. } if inc == update);
+            let insert_at = match stmt {
+                Stmt::For { body, .. } if in_increment => {
+                    // Appending to the body is only equivalent to updating in
+                    // the increment when no `continue` bypasses the body end
+                    if facts.latch_preds != 1 {
+                        continue;
+                    }
+                    body.len()
+                }
+                Stmt::While { body, .. } | Stmt::For { body, .. } => {
+                    match body.iter().position(|s| *s == Stmt::Expr(update.clone())) {
+                        Some(pos) => pos + 1,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            // The temporary must be initialized after the loop counter is
+            if let Stmt::For { init, .. } = stmt {
+                if let Some(init) = init.take() {
+                    preheader.push(*init);
+                }
+            }
+
+            let temp_type = self.arith_type(&Expr::Ident(var.clone()));
+            for factor in factors {
+                let temp = self.new_temp("sr");
+                let product = Expr::Binary {
+                    op: BinaryOp::Mul,
+                    left: Box::new(Expr::Ident(var.clone())),
+                    right: Box::new(Expr::IntLiteral(factor)),
+                };
+                replace_products(stmt, var, factor, &temp);
+
+                let delta = step.wrapping_mul(factor as i128);
+                let (op, magnitude) = if delta < 0 { (BinaryOp::SubAssign, -delta) } else { (BinaryOp::AddAssign, delta) };
+                let advance = Stmt::Expr(Expr::Binary {
+                    op,
+                    left: Box::new(Expr::Ident(temp.clone())),
+                    right: Box::new(Expr::IntLiteral(magnitude as u64)),
+                });
+                if let Stmt::While { body, .. } | Stmt::For { body, .. } = stmt {
+                    body.insert(insert_at, advance);
+                }
+
+                self.reports.push(format!(
+                    "{}: strength-reduced `{}` in {} loop to induction variable {} ({} {} per iteration)",
+                    self.function, product, kind, temp, op, magnitude
+                ));
+                preheader.push(Stmt::VarDecl(VarDecl {
+                    name: temp,
+                    var_type: temp_type.clone(),
+                    init: Some(product),
+                }));
+            }
+        }
+    }
+
+    /// Rewrite `x * 2^n` as `x << n` throughout the loop
+    fn shifts_for_multiplies(&mut self, stmt: &mut Stmt) {
+        let kind = loop_kind(stmt);
+        let mut rewritten = Vec::new();
+        for_each_loop_expr(stmt, &mut |expr| rewrite_multiplies(expr, &mut rewritten));
+        for (before, after) in rewritten {
+            self.reports.push(format!(
+                "{}: replaced `{}` with `{}` in {} loop",
+                self.function, before, after, kind
+            ));
+        }
+    }
+
+    fn unroll(&mut self, stmt: &Stmt) -> Option<Stmt> {
+        let mut canonical = CanonicalLoop::from_stmt(stmt)?;
+        if canonical.var_type.is_none() {
+            canonical.var_type = self.var_types.get(&canonical.var).cloned();
+        }
+        let trips = canonical.trip_count()?;
+        let Stmt::For { init: Some(init), increment: Some(increment), body, .. } = stmt else {
+            return None;
+        };
+
+        if trips > self.options.unroll_limit
+            || trips.saturating_mul(body.len() as u64 + 1) > MAX_UNROLLED_STMTS
+            || body.iter().any(breaks_loop)
+        {
+            return None;
+        }
+
+        // Each copy is a block of its own, so the copies' locals share
+        // stack slots and the frame is no larger than the loop's
+        let mut unrolled = vec![(**init).clone()];
+        for _ in 0..trips {
+            unrolled.push(Stmt::Block(body.clone()));
+            unrolled.push(Stmt::Expr(increment.clone()));
+        }
+
+        self.reports.push(format!(
+            "{}: fully unrolled for loop over `{}` ({} iterations)",
+            self.function, canonical.var, trips
+        ));
+        Some(Stmt::Block(unrolled))
+    }
+
+    fn new_temp(&mut self, prefix: &str) -> String {
+        let name = format!("__{}{}", prefix, self.temp_counter);
+        self.temp_counter += 1;
+        name
+    }
+
+    /// 64-bit type an arithmetic expression is computed in
+    fn arith_type(&self, expr: &Expr) -> Type {
+        let unsigned = expr.any(&|e| match e {
+            Expr::Ident(name) => self.var_types.get(name).is_some_and(|ty| ty.is_unsigned()),
+            _ => false,
+        });
+        if unsigned { Type::U64 } else { Type::I64 }
+    }
+}
+
+fn loop_kind(stmt: &Stmt) -> &'static str {
+    if matches!(stmt, Stmt::For { .. }) { "for" } else { "while" }
+}
+
+fn int_value(expr: &Expr) -> Option<i128> {
+    match expr {
+        Expr::IntLiteral(n) => Some(*n as i128),
+        Expr::CharLiteral(c) => Some(*c as i128),
+        Expr::Unary { op: UnaryOp::Neg, expr } => int_value(expr).map(|n| -n),
+        _ => None,
+    }
+}
+
+/// Recognize `v++`, `++v`, `v--`, `--v`, `v += c`, `v -= c` and `v = v + c`
+fn induction_update(expr: &Expr) -> Option<(String, i128)> {
+    match expr {
+        Expr::Unary { op, expr } => {
+            let Expr::Ident(var) = &**expr else { return None };
+            match op {
+                UnaryOp::PreIncrement | UnaryOp::PostIncrement => Some((var.clone(), 1)),
+                UnaryOp::PreDecrement | UnaryOp::PostDecrement => Some((var.clone(), -1)),
+                _ => None,
+            }
+        }
+        Expr::Binary { op: op @ (BinaryOp::AddAssign | BinaryOp::SubAssign), left, right } => {
+            let Expr::Ident(var) = &**left else { return None };
+            let step = int_value(right)?;
+            Some((var.clone(), if *op == BinaryOp::AddAssign { step } else { -step }))
+        }
+        Expr::Assign { target, value } => {
+            let Expr::Ident(var) = &**target else { return None };
+            match &**value {
+                Expr::Binary { op: op @ (BinaryOp::Add | BinaryOp::Sub), left, right }
+                    if **left == Expr::Ident(var.clone()) =>
+                {
+                    let step = int_value(right)?;
+                    Some((var.clone(), if *op == BinaryOp::Add { step } else { -step }))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Count definitions of each variable and record those whose address is taken
+fn expr_defs(expr: &Expr, defs: &mut HashMap<String, usize>, escaped: &mut HashSet<String>) {
+    match expr {
+        Expr::Assign { target, .. } => record_def(target, defs),
+        Expr::Binary { op, left, .. } if op.is_assignment() => record_def(left, defs),
+        Expr::Unary { op, expr: inner } => {
+            if let Some(name) = storage_root(inner) {
+                match op {
+                    UnaryOp::PreIncrement | UnaryOp::PreDecrement
+                    | UnaryOp::PostIncrement | UnaryOp::PostDecrement => {
+                        *defs.entry(name.clone()).or_default() += 1;
+                    }
+                    UnaryOp::AddressOf => {
+                        escaped.insert(name.clone());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+
+    for child in expr.children() {
+        expr_defs(child, defs, escaped);
+    }
+}
+
+fn record_def(target: &Expr, defs: &mut HashMap<String, usize>) {
+    if let Some(name) = storage_root(target) {
+        *defs.entry(name.clone()).or_default() += 1;
+    }
+}
+
+/// Variable whose own storage `target` is part of: `x` for `x`, `x.field`
+/// and `x.u8[3]`, but not for `p->field` or `*p`
+fn storage_root(target: &Expr) -> Option<&String> {
+    match target {
+        Expr::Ident(name) => Some(name),
+        Expr::Member { expr, .. } => storage_root(expr),
+        Expr::Index { expr, .. } if matches!(**expr, Expr::Member { .. } | Expr::Index { .. }) => storage_root(expr),
+        _ => None,
+    }
+}
+
+fn stmt_defs(stmt: &Stmt, defs: &mut HashMap<String, usize>, escaped: &mut HashSet<String>) {
+    match stmt {
+        Stmt::VarDecl(var) => {
+            *defs.entry(var.name.clone()).or_default() += 1;
+            if let Some(init) = &var.init {
+                expr_defs(init, defs, escaped);
+            }
+        }
+        Stmt::Expr(expr) | Stmt::Return(Some(expr)) => expr_defs(expr, defs, escaped),
+        Stmt::Print { args, .. } => {
+            for arg in args {
+                expr_defs(arg, defs, escaped);
+            }
+        }
+        Stmt::If { condition, then_block, else_block } => {
+            expr_defs(condition, defs, escaped);
+            for stmt in then_block.iter().chain(else_block.iter().flatten()) {
+                stmt_defs(stmt, defs, escaped);
+            }
+        }
+        Stmt::While { condition, body } | Stmt::DoWhile { body, condition } => {
+            expr_defs(condition, defs, escaped);
+            for stmt in body {
+                stmt_defs(stmt, defs, escaped);
+            }
+        }
+        Stmt::For { init, condition, increment, body } => {
+            if let Some(init) = init {
+                stmt_defs(init, defs, escaped);
+            }
+            for expr in condition.iter().chain(increment.iter()) {
+                expr_defs(expr, defs, escaped);
+            }
+            for stmt in body {
+                stmt_defs(stmt, defs, escaped);
+            }
+        }
+        Stmt::Block(block) => {
+            for stmt in block {
+                stmt_defs(stmt, defs, escaped);
+            }
+        }
+        Stmt::Switch { value, cases } => {
+            expr_defs(value, defs, escaped);
+            for stmt in cases.iter().flat_map(|case| &case.body) {
+                stmt_defs(stmt, defs, escaped);
+            }
+        }
+        Stmt::Asm(asm) => {
+            for output in &asm.outputs {
+                *defs.entry(output.var.clone()).or_default() += 1;
+            }
+        }
+        Stmt::Return(None) | Stmt::Break | Stmt::Continue | Stmt::Goto(_) | Stmt::Label(_) => {}
+    }
+}
+
+fn collect_var_types(block: &[Stmt], types: &mut HashMap<String, Type>) {
+    for stmt in block {
+        match stmt {
+            Stmt::VarDecl(var) => {
+                types.insert(var.name.clone(), var.var_type.clone());
+            }
+            Stmt::If { then_block, else_block, .. } => {
+                collect_var_types(then_block, types);
+                if let Some(else_block) = else_block {
+                    collect_var_types(else_block, types);
+                }
+            }
+            Stmt::While { body, .. } | Stmt::DoWhile { body, .. } => collect_var_types(body, types),
+            Stmt::For { init, body, .. } => {
+                if let Some(init) = init {
+                    collect_var_types(std::slice::from_ref(&**init), types);
+                }
+                collect_var_types(body, types);
+            }
+            Stmt::Block(inner) => collect_var_types(inner, types),
+            Stmt::Switch { cases, .. } => {
+                for case in cases {
+                    collect_var_types(&case.body, types);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// True if the statement contains a `break` or `continue` for the enclosing loop
+fn breaks_loop(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Break | Stmt::Continue => true,
+        Stmt::If { then_block, else_block, .. } => {
+            then_block.iter().chain(else_block.iter().flatten()).any(breaks_loop)
+        }
+        Stmt::Block(block) => block.iter().any(breaks_loop),
+        // A `break` in a switch only leaves the switch
+        Stmt::Switch { cases, .. } => cases.iter()
+            .flat_map(|case| &case.body)
+            .any(|stmt| !matches!(stmt, Stmt::Break) && breaks_loop(stmt)),
+        _ => false,
+    }
+}
+
+/// Apply `f` to every top-level expression inside the loop, excluding a
+/// `for` initializer which runs before the loop
+fn for_each_loop_expr(stmt: &mut Stmt, f: &mut dyn FnMut(&mut Expr)) {
+    match stmt {
+        Stmt::While { condition, body } => {
+            f(condition);
+            for stmt in body {
+                for_each_stmt_expr(stmt, f);
+            }
+        }
+        Stmt::For { condition, increment, body, .. } => {
+            for expr in condition.iter_mut().chain(increment.iter_mut()) {
+                f(expr);
+            }
+            for stmt in body {
+                for_each_stmt_expr(stmt, f);
+            }
+        }
+        _ => for_each_stmt_expr(stmt, f),
+    }
+}
+
+fn for_each_stmt_expr(stmt: &mut Stmt, f: &mut dyn FnMut(&mut Expr)) {
+    match stmt {
+        Stmt::VarDecl(VarDecl { init: Some(init), .. }) => f(init),
+        Stmt::Expr(expr) | Stmt::Return(Some(expr)) => f(expr),
+        Stmt::Print { args, .. } => args.iter_mut().for_each(f),
+        Stmt::If { condition, then_block, else_block } => {
+            f(condition);
+            for stmt in then_block.iter_mut().chain(else_block.iter_mut().flatten()) {
+                for_each_stmt_expr(stmt, f);
+            }
+        }
+        Stmt::While { condition, body } | Stmt::DoWhile { body, condition } => {
+            f(condition);
+            for stmt in body {
+                for_each_stmt_expr(stmt, f);
+            }
+        }
+        Stmt::For { init, condition, increment, body } => {
+            if let Some(init) = init {
+                for_each_stmt_expr(init, f);
+            }
+            for expr in condition.iter_mut().chain(increment.iter_mut()) {
+                f(expr);
+            }
+            for stmt in body {
+                for_each_stmt_expr(stmt, f);
+            }
+        }
+        Stmt::Block(block) => {
+            for stmt in block {
+                for_each_stmt_expr(stmt, f);
+            }
+        }
+        Stmt::Switch { value, cases } => {
+            f(value);
+            for stmt in cases.iter_mut().flat_map(|case| &mut case.body) {
+                for_each_stmt_expr(stmt, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn replace_expr(expr: &mut Expr, from: &Expr, to: &Expr) -> usize {
+    if expr == from {
+        *expr = to.clone();
+        return 1;
+    }
+    expr.children_mut().into_iter().map(|child| replace_expr(child, from, to)).sum()
+}
+
+fn replace_in_loop(stmt: &mut Stmt, from: &Expr, to: &Expr) -> usize {
+    let mut count = 0;
+    for_each_loop_expr(stmt, &mut |expr| count += replace_expr(expr, from, to));
+    count
+}
+
+/// Constant factor `k` if `expr` is `var * k` or `k * var`
+fn product_factor(expr: &Expr, var: &str) -> Option<u64> {
+    let Expr::Binary { op: BinaryOp::Mul, left, right } = expr else { return None };
+    match (&**left, &**right) {
+        (Expr::Ident(name), Expr::IntLiteral(k)) | (Expr::IntLiteral(k), Expr::Ident(name)) if name == var => Some(*k),
+        _ => None,
+    }
+}
+
+fn collect_products(expr: &Expr, var: &str, factors: &mut Vec<u64>) {
+    if let Some(factor) = product_factor(expr, var) {
+        if !factors.contains(&factor) {
+            factors.push(factor);
+        }
+        return;
+    }
+    for child in expr.children() {
+        collect_products(child, var, factors);
+    }
+}
+
+fn count_products(expr: &Expr, var: &str, factor: u64) -> usize {
+    if product_factor(expr, var) == Some(factor) {
+        return 1;
+    }
+    expr.children().into_iter().map(|child| count_products(child, var, factor)).sum()
+}
+
+/// Compute units a `var * k` costs beyond loading `var`: materializing `k`
+/// and multiplying, or a single shift for a power of two
+fn product_cost(factor: u64) -> usize {
+    if factor.is_power_of_two() { 1 } else { 2 }
+}
+
+fn replace_products(stmt: &mut Stmt, var: &str, factor: u64, temp: &str) {
+    fn replace(expr: &mut Expr, var: &str, factor: u64, temp: &str) {
+        if product_factor(expr, var) == Some(factor) {
+            *expr = Expr::Ident(temp.to_string());
+            return;
+        }
+        for child in expr.children_mut() {
+            replace(child, var, factor, temp);
+        }
+    }
+    for_each_loop_expr(stmt, &mut |expr| replace(expr, var, factor, temp));
+}
+
+fn rewrite_multiplies(expr: &mut Expr, rewritten: &mut Vec<(Expr, Expr)>) {
+    for child in expr.children_mut() {
+        rewrite_multiplies(child, rewritten);
+    }
+
+    let Expr::Binary { op, left, right } = expr else { return };
+    let shift_op = match op {
+        BinaryOp::Mul => BinaryOp::Shl,
+        BinaryOp::MulAssign => BinaryOp::ShlAssign,
+        _ => return,
+    };
+    let (value, factor) = match (&**left, &**right) {
+        (_, Expr::IntLiteral(k)) => ((**left).clone(), *k),
+        (Expr::IntLiteral(k), _) if *op == BinaryOp::Mul => ((**right).clone(), *k),
+        _ => return,
+    };
+    if factor < 2 || !factor.is_power_of_two() {
+        return;
+    }
+
+    let before = expr.clone();
+    *expr = Expr::Binary {
+        op: shift_op,
+        left: Box::new(value),
+        right: Box::new(Expr::IntLiteral(factor.trailing_zeros() as u64)),
+    };
+    rewritten.push((before, expr.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+    use crate::parser::Parser;
+
+    fn parse(source: &str) -> Program {
+        let tokens: Vec<Token> = Lexer::collect_tokens(source).unwrap()
+            .into_iter().map(|(t, _)| t).collect();
+        Parser::new(tokens).parse().unwrap()
+    }
+
+    fn body(program: &Program) -> &Block {
+        match &program.items[0] {
+            Item::FunctionDef(func) => &func.body,
+            _ => panic!("Expected function definition"),
+        }
+    }
+
+    #[test]
+    fn test_licm_hoists_invariant_product() {
+        let mut program = parse(
+            "U64 f(U64 n, U64 fee) { U64 s = 0; U64 i = 0; while (i < n) { s += i + fee * 3; i++; } return s; }",
+        );
+        let reports = optimize_program(&mut program, &LoopOptOptions::new(1, 0));
+
+        assert!(reports.iter().any(|r| r.contains("hoisted loop-invariant `fee * 3`")));
+        let Stmt::Block(stmts) = &body(&program)[2] else { panic!("Expected preheader block") };
+        assert!(matches!(&stmts[0], Stmt::VarDecl(v) if v.name == "__licm0"));
+    }
+
+    #[test]
+    fn test_licm_keeps_variant_and_trapping_exprs() {
+        let mut program = parse(
+            "U64 f(U64 n, U64 d) { U64 s = 0; U64 i = 0; while (i < n) { s = s + n / d + i * n; i++; } return s; }",
+        );
+        let reports = optimize_program(&mut program, &LoopOptOptions { licm: true, ..Default::default() });
+        assert!(reports.is_empty(), "unexpected: {:?}", reports);
+    }
+
+    #[test]
+    fn test_strength_reduction_of_induction_variable() {
+        let mut program = parse(
+            "U64 f(U64 n) { U64 s = 0; for (U64 i = 0; i < n; i++) { s += i * 12; s ^= i * 12; s -= i * 12; } return s; }",
+        );
+        let reports = optimize_program(&mut program, &LoopOptOptions::new(1, 0));
+
+        assert!(reports.iter().any(|r| r.contains("strength-reduced `i * 12`") && r.contains("+= 12")));
+        let Stmt::Block(stmts) = &body(&program)[1] else { panic!("Expected preheader block") };
+        // init moved ahead of the temporary, which is advanced at the body end
+        assert!(matches!(&stmts[0], Stmt::VarDecl(v) if v.name == "i"));
+        assert!(matches!(&stmts[1], Stmt::VarDecl(v) if v.name == "__sr0"));
+        let Stmt::For { init: None, body, .. } = &stmts[2] else { panic!("Expected for loop") };
+        assert_eq!(body.len(), 4);
+    }
+
+    #[test]
+    fn test_no_strength_reduction_that_costs_more() {
+        // One multiply per iteration costs less than advancing a temporary,
+        // and products on a conditional path do not run every iteration
+        let mut program = parse(
+            "U64 f(U64 n) { U64 s = 0; for (U64 i = 0; i < n; i++) { s += i * 12; if (s) { s ^= i * 12; s -= i * 12; } } return s; }",
+        );
+        let reports = optimize_program(&mut program, &LoopOptOptions::new(1, 0));
+        assert!(reports.iter().all(|r| !r.contains("strength-reduced")), "unexpected: {:?}", reports);
+    }
+
+    #[test]
+    fn test_no_strength_reduction_past_continue() {
+        let mut program = parse(
+            "U64 f(U64 n) { U64 s = 0; for (U64 i = 0; i < n; i++) { s += i * 12; s ^= i * 12; s -= i * 12; if (i == 2) { continue; } } return s; }",
+        );
+        let reports = optimize_program(&mut program, &LoopOptOptions { strength_reduction: true, ..Default::default() });
+        assert!(reports.iter().all(|r| !r.contains("strength-reduced")));
+    }
+
+    #[test]
+    fn test_power_of_two_multiply_becomes_shift() {
+        let mut program = parse(
+            "U64 f(U64 n) { U64 s = 0; U64 i = 0; while (i < n) { s += n * 8; s *= 4; i++; } return s; }",
+        );
+        let reports = optimize_program(&mut program, &LoopOptOptions { strength_reduction: true, ..Default::default() });
+        assert!(reports.iter().any(|r| r.contains("`n * 8` with `n << 3`")));
+        assert!(reports.iter().any(|r| r.contains("`s *= 4` with `s <<= 2`")));
+    }
+
+    #[test]
+    fn test_bounded_unrolling() {
+        let mut program = parse(
+            "U64 f() { U64 s = 0; for (U64 i = 0; i < 4; i++) { s += i; } return s; }",
+        );
+        let reports = optimize_program(&mut program, &LoopOptOptions::new(0, 8));
+        assert_eq!(reports, vec!["f: fully unrolled for loop over `i` (4 iterations)"]);
+        let Stmt::Block(stmts) = &body(&program)[1] else { panic!("Expected unrolled block") };
+        assert_eq!(stmts.len(), 1 + 4 * 2);
+
+        // Above the limit the loop is left alone
+        let mut program = parse(
+            "U64 f() { U64 s = 0; for (U64 i = 0; i < 100; i++) { s += i; } return s; }",
+        );
+        assert!(optimize_program(&mut program, &LoopOptOptions::new(0, 8)).is_empty());
+    }
+
+    #[test]
+    fn test_trip_count() {
+        let program = parse("Void f() { for (I64 i = 10; i >= 0; i -= 3) { } for (U64 j = 10; j >= 0; j--) { } }");
+        assert_eq!(trip_count(&body(&program)[0]), Some(4));
+        // Unsigned counter never goes below zero
+        assert_eq!(trip_count(&body(&program)[1]), None);
+
+        // Narrow counters wrap before reaching a bound past their range
+        let program = parse("Void f() { for (U8 i = 250; i < 255; i += 2) { } for (U8 j = 0; j < 255; j++) { } for (I8 k = 0; k <= 127; k++) { } }");
+        assert_eq!(trip_count(&body(&program)[0]), None);
+        assert_eq!(trip_count(&body(&program)[1]), Some(255));
+        assert_eq!(trip_count(&body(&program)[2]), None);
+    }
+
+    #[test]
+    fn test_no_strength_reduction_of_narrow_counters() {
+        let mut program = parse(
+            "U64 f(U64 n) { U64 s = 0; for (U8 i = 0; i < n; i++) { s += i * 12; s ^= i * 12; s -= i * 12; } return s; }",
+        );
+        let reports = optimize_program(&mut program, &LoopOptOptions::new(1, 0));
+        assert!(reports.iter().all(|r| !r.contains("strength-reduced")), "unexpected: {:?}", reports);
+    }
+}
@@ -1,17 +1,15 @@
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use std::fs;
 use std::path::PathBuf;
 
-mod lexer;
-mod ast;
-mod parser;
-mod codegen;
-mod solana_wrapper;
-
-use lexer::Lexer;
-use parser::Parser as HolyCParser;
-use codegen::CodeGen;
+use holyc_bpf_compiler::ast::Program;
+use holyc_bpf_compiler::codegen::CodeGen;
+use holyc_bpf_compiler::{asm, cu, disasm, sema};
+use holyc_bpf_compiler::{lex_preprocessed, parse_tokens, preprocess_source, CompilerOptions};
+use holyc_bpf_compiler::lexer::Lexer;
+use holyc_bpf_compiler::loop_opt::{self, LoopOptOptions};
+use holyc_bpf_compiler::verifier;
 
 #[derive(Parser)]
 #[command(name = "holycc")]
@@ -42,6 +40,20 @@ enum Commands {
         #[arg(long)]
         emit_ast: bool,
 
+        /// Optimization level (0-3); 1 and above enable LICM and strength reduction
+        #[arg(short = 'O', long, default_value_t = 0)]
+        opt_level: u8,
+
+        /// Fully unroll for loops with at most this many iterations (0 disables)
+        #[arg(long, default_value_t = 0)]
+        unroll: u64,
+
+        #[command(flatten)]
+        codegen: CodegenArgs,
+
+        #[command(flatten)]
+        preprocess: PreprocessArgs,
+
         /// Verbose output
         #[arg(short, long)]
         verbose: bool,
@@ -67,12 +79,96 @@ enum Commands {
         /// Output AST as JSON
         #[arg(short, long)]
         json: bool,
+
+        #[command(flatten)]
+        preprocess: PreprocessArgs,
+    },
+
+    /// Statically analyze a HolyC program
+    Analyze {
+        /// Input HolyC source file
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Estimate compute units per function
+        #[arg(long)]
+        cu: bool,
+
+        /// Compute-unit budget to check the estimates against
+        #[arg(long, default_value_t = cu::DEFAULT_CU_BUDGET)]
+        budget: u64,
+
+        /// Optimization level the program will be compiled with
+        #[arg(short = 'O', long, default_value_t = 0)]
+        opt_level: u8,
+
+        /// Unroll limit the program will be compiled with
+        #[arg(long, default_value_t = 0)]
+        unroll: u64,
+
+        #[command(flatten)]
+        codegen: CodegenArgs,
+
+        #[command(flatten)]
+        preprocess: PreprocessArgs,
+    },
+
+    /// Disassemble a BPF program (raw bytecode or ELF)
+    Disasm {
+        /// Program to disassemble
+        input: PathBuf,
+
+        /// Write the listing to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Show the address and raw bytes of every instruction
+        #[arg(short, long)]
+        annotate: bool,
+    },
+
+    /// Assemble an sBPF listing into an ELF shared object
+    Asm {
+        /// Assembly source, in the syntax printed by `disasm`
+        input: PathBuf,
+
+        /// Output ELF file (.so)
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Skip bytecode verification (for deliberately unusual code)
+        #[arg(long)]
+        no_verify: bool,
     },
 
     /// Show compiler information
     Info,
 }
 
+/// Code generation flags of `compile` and `analyze`
+#[derive(Args)]
+struct CodegenArgs {
+    /// Abort on out-of-range indices into fixed-size arrays
+    #[arg(long)]
+    bounds_checks: bool,
+
+    /// Compute 32-bit arithmetic with ALU32 instructions, wrapping at 32 bits
+    #[arg(long)]
+    alu32: bool,
+}
+
+/// Preprocessor flags shared by the commands that read HolyC source
+#[derive(Args)]
+struct PreprocessArgs {
+    /// Define a macro before preprocessing (NAME or NAME=VALUE)
+    #[arg(short = 'D', value_name = "NAME[=VALUE]")]
+    define: Vec<String>,
+
+    /// Add a directory to the #include search path
+    #[arg(short = 'I', value_name = "DIR")]
+    include: Vec<PathBuf>,
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
@@ -82,22 +178,48 @@ fn main() -> Result<()> {
             output,
             emit_asm,
             emit_ast,
+            opt_level,
+            unroll,
+            codegen,
+            preprocess,
             verbose,
-        } => compile(&input, &output, emit_asm, emit_ast, verbose),
+        } => {
+            let loop_options = LoopOptOptions::new(opt_level, unroll);
+            compile(&input, &output, emit_asm, emit_ast, loop_options, &codegen, &preprocess, verbose)
+        }
 
         Commands::Lex { input, json } => lex_file(&input, json),
 
-        Commands::Parse { input, json } => parse_file(&input, json),
+        Commands::Parse { input, json, preprocess } => parse_file(&input, json, &preprocess),
+
+        Commands::Analyze { input, cu, budget, opt_level, unroll, codegen, preprocess } => {
+            let options = CompilerOptions {
+                opt_level,
+                unroll_limit: unroll,
+                bounds_checks: codegen.bounds_checks,
+                alu32: codegen.alu32,
+                ..CompilerOptions::default()
+            };
+            analyze(&input, cu, budget, &options, &preprocess)
+        }
+
+        Commands::Disasm { input, output, annotate } => disasm_file(&input, output.as_ref(), annotate),
+
+        Commands::Asm { input, output, no_verify } => asm_file(&input, &output, !no_verify),
 
         Commands::Info => show_info(),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn compile(
     input: &PathBuf,
     output: &PathBuf,
     emit_asm: bool,
     emit_ast: bool,
+    loop_options: LoopOptOptions,
+    codegen_args: &CodegenArgs,
+    preprocess: &PreprocessArgs,
     verbose: bool,
 ) -> Result<()> {
     if verbose {
@@ -112,29 +234,50 @@ fn compile(
         .with_context(|| format!("Failed to read input file: {}", input.display()))?;
 
     if verbose {
-        println!("[1/4] Lexing...");
+        println!("[1/7] Preprocessing...");
+    }
+
+    let source = preprocess_source(&source, &input.display().to_string(), &preprocess.define, &preprocess.include)?;
+
+    if verbose {
+        println!("[2/7] Lexing...");
     }
 
     // Lex
-    let token_results = Lexer::collect_tokens(&source)
-        .map_err(|e| anyhow!("Lexical analysis failed: {}", e))?;
-    let tokens: Vec<_> = token_results.into_iter().map(|(t, _)| t).collect();
+    let tokens = lex_preprocessed(&source)?;
 
     if verbose {
         println!("      Found {} tokens", tokens.len());
     }
 
     if verbose {
-        println!("[2/4] Parsing...");
+        println!("[3/7] Parsing...");
     }
 
     // Parse
-    let mut parser = HolyCParser::new(tokens);
-    let program = parser.parse().context("Parsing failed")?;
+    let mut program = parse_tokens(&source, tokens)?;
 
     if verbose {
         println!("      Parsed {} top-level items", program.items.len());
     }
+    check_program(&mut program)?;
+
+    if verbose {
+        println!("[4/7] Optimizing loops...");
+    }
+
+    // Optimize loops
+    if loop_options.is_enabled() {
+        let reports = loop_opt::optimize_program(&mut program, &loop_options);
+        if verbose {
+            for report in &reports {
+                println!("      {}", report);
+            }
+            println!("      {} loop transformations", reports.len());
+        }
+    } else if verbose {
+        println!("      Skipped (use -O1 or --unroll)");
+    }
 
     // Emit AST if requested
     if emit_ast {
@@ -150,31 +293,55 @@ fn compile(
     }
 
     if verbose {
-        println!("[3/4] Generating BPF code...");
+        println!("[5/7] Generating BPF code...");
     }
 
     // Generate BPF bytecode
-    let mut codegen = CodeGen::new();
+    let mut codegen = CodeGen::new()
+        .with_bounds_checks(codegen_args.bounds_checks)
+        .with_alu32(codegen_args.alu32);
     let bytecode = codegen.generate(&program)
         .context("Code generation failed")?;
 
     if verbose {
         println!("      Generated {} bytes of BPF bytecode", bytecode.len());
         println!("      {} instructions", bytecode.len() / 8);
+        for frame in codegen.frames() {
+            println!("      Stack frame {}: {} bytes", frame.function, frame.size());
+        }
+        let depth = codegen.call_depth();
+        println!("      Max call depth: {} ({})", depth.max_depth, depth.deepest_path.join(" -> "));
+    }
+    for cycle in &codegen.call_depth().cycles {
+        eprintln!("warning: recursive call chain {} has no static depth bound", cycle.join(" -> "));
     }
 
     if verbose {
-        println!("[4/4] Writing output...");
+        println!("[6/7] Verifying bytecode...");
     }
 
-    // Write bytecode to output file
-    fs::write(output, &bytecode)
+    // Reject anything the on-chain loader would
+    verifier::verify(&bytecode).context("Generated invalid bytecode")?;
+
+    if verbose {
+        println!("      OK");
+        println!("[7/7] Writing output...");
+    }
+
+    // Write the program as an ELF shared object
+    let program = codegen.elf_program(bytecode);
+    let elf = program.to_bytes()?;
+    fs::write(output, &elf)
         .with_context(|| format!("Failed to write output to {}", output.display()))?;
 
+    if verbose {
+        println!("      Wrote {} bytes ({} bytes of .rodata)", elf.len(), program.rodata.len());
+    }
+
     // Emit assembly if requested
     if emit_asm {
         let asm_path = output.with_extension("asm");
-        let asm = disassemble_bytecode(&bytecode);
+        let asm = disasm::disassemble(&program.text, &disasm::Symbols::from_program(&program), true)?;
         fs::write(&asm_path, asm)
             .with_context(|| format!("Failed to write assembly to {}", asm_path.display()))?;
 
@@ -218,16 +385,8 @@ fn lex_file(input: &PathBuf, json: bool) -> Result<()> {
     Ok(())
 }
 
-fn parse_file(input: &PathBuf, json: bool) -> Result<()> {
-    let source = fs::read_to_string(input)
-        .with_context(|| format!("Failed to read input file: {}", input.display()))?;
-
-    let token_results = Lexer::collect_tokens(&source)
-        .map_err(|e| anyhow!("Lexical analysis failed: {}", e))?;
-    let tokens: Vec<_> = token_results.into_iter().map(|(t, _)| t).collect();
-
-    let mut parser = HolyCParser::new(tokens);
-    let program = parser.parse().context("Parsing failed")?;
+fn parse_file(input: &PathBuf, json: bool, preprocess: &PreprocessArgs) -> Result<()> {
+    let program = read_program(input, preprocess)?;
 
     if json {
         let json = serde_json::to_string_pretty(&program)
@@ -244,6 +403,95 @@ fn parse_file(input: &PathBuf, json: bool) -> Result<()> {
     Ok(())
 }
 
+/// Preprocess, lex and parse a source file
+fn read_program(input: &PathBuf, preprocess: &PreprocessArgs) -> Result<Program> {
+    let source = fs::read_to_string(input)
+        .with_context(|| format!("Failed to read input file: {}", input.display()))?;
+
+    let source = preprocess_source(&source, &input.display().to_string(), &preprocess.define, &preprocess.include)?;
+    let tokens = lex_preprocessed(&source)?;
+    let mut program = parse_tokens(&source, tokens)?;
+    check_program(&mut program)?;
+    Ok(program)
+}
+
+/// Fill in default arguments and run the semantic checks, printing their
+/// warnings
+fn check_program(program: &mut Program) -> Result<()> {
+    sema::fill_default_args(program).context("Semantic analysis failed")?;
+    for warning in sema::check_program(program).context("Semantic analysis failed")? {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(())
+}
+
+fn analyze(
+    input: &PathBuf,
+    compute_units: bool,
+    budget: u64,
+    options: &CompilerOptions,
+    preprocess: &PreprocessArgs,
+) -> Result<()> {
+    if !compute_units {
+        return Err(anyhow!("Nothing to analyze: pass --cu"));
+    }
+
+    let program = read_program(input, preprocess)?;
+
+    println!("Compute-unit estimate for {} (budget {} CU):", input.display(), budget);
+    println!("{:-<60}", "");
+    println!("{:<24} {:>10} {:>10}", "function", "best", "worst");
+    for function in cu::estimate_program(&program, options)? {
+        let cost = &function.cost;
+        println!("{:<24} {:>10} {:>10}", function.name, cost.best, cost.worst);
+        for term in &cost.terms {
+            println!("{:<24} + {} x {}", "", term.units, term.parameter);
+        }
+        if cost.worst > budget {
+            println!("  warning: worst case exceeds the {} CU budget", budget);
+        } else if !cost.is_bounded() {
+            println!("  note: worst case depends on the quantities above");
+        }
+    }
+    println!("{:-<60}", "");
+
+    Ok(())
+}
+
+fn disasm_file(input: &PathBuf, output: Option<&PathBuf>, annotate: bool) -> Result<()> {
+    let data = fs::read(input)
+        .with_context(|| format!("Failed to read input file: {}", input.display()))?;
+
+    let listing = disasm::disassemble_file(&data, annotate)
+        .with_context(|| format!("Failed to disassemble {}", input.display()))?;
+
+    match output {
+        Some(path) => fs::write(path, listing)
+            .with_context(|| format!("Failed to write listing to {}", path.display()))?,
+        None => print!("{}", listing),
+    }
+
+    Ok(())
+}
+
+fn asm_file(input: &PathBuf, output: &PathBuf, verify: bool) -> Result<()> {
+    let source = fs::read_to_string(input)
+        .with_context(|| format!("Failed to read input file: {}", input.display()))?;
+
+    let program = asm::assemble(&source)
+        .with_context(|| format!("Failed to assemble {}", input.display()))?;
+    if verify {
+        verifier::verify(&program.text).context("Verification failed")?;
+    }
+
+    let elf = program.to_bytes()?;
+    fs::write(output, &elf)
+        .with_context(|| format!("Failed to write output file: {}", output.display()))?;
+
+    println!("✓ Assembled {} instructions ({} bytes) to {}", program.text.len() / 8, elf.len(), output.display());
+    Ok(())
+}
+
 fn show_info() -> Result<()> {
     println!("HolyC → Solana BPF Compiler (holycc)");
     println!();
@@ -251,87 +499,53 @@ fn show_info() -> Result<()> {
     println!("Authors:       {}", env!("CARGO_PKG_AUTHORS"));
     println!();
     println!("Capabilities:");
+    println!("  - Preprocessing (macros, conditional compilation, #include, -D, -I)");
     println!("  - Lexical analysis (HolyC tokenization)");
     println!("  - Syntax parsing (AST generation)");
+    println!("  - Loop optimizations (LICM, strength reduction, unrolling)");
     println!("  - BPF code generation (Solana-compatible)");
+    println!("  - Disassembly of raw bytecode and ELF programs");
+    println!("  - sBPF assembler producing ELF shared objects");
+    println!("  - Bytecode verification (same checks as the Solana loader)");
     println!("  - Direct .so output for Solana deployment");
     println!();
     println!("Supported HolyC features:");
     println!("  - Integer types: U8, U16, U32, U64, I8, I16, I32, I64");
-    println!("  - Classes and structs");
-    println!("  - Functions (up to 5 parameters)");
+    println!("  - Classes, unions and anonymous union members");
+    println!("  - Class inheritance (class CDerived : CBase) with implicit upcasts");
+    println!("  - Sub-integer views: x.u8[3], x.i32[1]");
+    println!("  - Enums with explicit discriminants and underlying types");
+    println!("  - Function pointers and indirect calls (callx)");
+    println!("  - Classes passed, returned and assigned by value");
+    println!("  - Initializer lists for arrays and classes: {{1, 2}}, \"seed\"");
+    println!("  - Functions, with arguments past the fifth passed on the stack");
+    println!("  - Default arguments and omitted-argument calls: Foo(, 2), Foo;");
     println!("  - Arithmetic: +, -, *, /, %");
     println!("  - Bitwise: &, |, ^, ~, <<, >>");
     println!("  - Comparisons: ==, !=, <, <=, >, >=");
-    println!("  - Control flow: if/else, while, for");
-    println!("  - Pointers and arrays");
+    println!("  - Conditional ?: and comma operators");
+    println!("  - Control flow: if/else, while, do-while, for, switch with case ranges, goto");
+    println!("  - Pointers and multi-dimensional arrays, indexed with element scaling");
+    println!("  - Optional run-time bounds checks on fixed-size arrays (--bounds-checks)");
+    println!("  - Casts (U8)x and x(U8), with sign and zero extension");
+    println!("  - Signed division, remainder, shifts and comparisons; optional ALU32 (--alu32)");
+    println!("  - Print statements logged via sol_log_: \"Balance: %d\\n\", bal;");
     println!();
     println!("BPF Target:");
     println!("  - eBPF extended instruction set");
     println!("  - Solana BPF VM compatibility");
+    println!("  - 4 KiB stack frames, max call depth 64 (checked at compile time)");
     println!("  - Direct deployment to Solana");
     println!();
     println!("Usage examples:");
     println!("  holycc compile -i program.HC -o program.so");
     println!("  holycc compile -i program.HC -o program.so --emit-asm");
+    println!("  holycc compile -i program.HC -o program.so -O1 --unroll 8 -v");
+    println!("  holycc compile -i program.HC -o program.so -D DEVNET -D FEE_BPS=30 -I lib");
     println!("  holycc lex -i program.HC");
     println!("  holycc parse -i program.HC --json");
+    println!("  holycc analyze -i program.HC --cu");
+    println!("  holycc disasm program.so");
 
     Ok(())
 }
-
-fn disassemble_bytecode(bytecode: &[u8]) -> String {
-    use codegen::BpfInstruction;
-
-    let mut output = String::new();
-    output.push_str("; HolyC-compiled BPF assembly\n");
-    output.push_str("; Generated by holycc\n\n");
-
-    for (idx, chunk) in bytecode.chunks(8).enumerate() {
-        if chunk.len() == 8 {
-            let inst = unsafe {
-                std::ptr::read(chunk.as_ptr() as *const BpfInstruction)
-            };
-
-            output.push_str(&format!(
-                "{:04x}: {:02x} {:02x} {:04x} {:08x}  ; ",
-                idx * 8,
-                inst.opcode,
-                inst.dst_src,
-                inst.offset as u16,
-                inst.imm as u32
-            ));
-
-            // Decode instruction
-            let dst = inst.dst_src & 0x0F;
-            let src = (inst.dst_src >> 4) & 0x0F;
-
-            match inst.opcode {
-                0xd7 => output.push_str(&format!("mov r{}, {}\n", dst, inst.imm)),
-                0xdf => output.push_str(&format!("mov r{}, r{}\n", dst, src)),
-                0x07 => output.push_str(&format!("add r{}, {}\n", dst, inst.imm)),
-                0x0f => output.push_str(&format!("add r{}, r{}\n", dst, src)),
-                0x1f => output.push_str(&format!("sub r{}, r{}\n", dst, src)),
-                0x2f => output.push_str(&format!("mul r{}, r{}\n", dst, src)),
-                0x3f => output.push_str(&format!("div r{}, r{}\n", dst, src)),
-                0xaf => output.push_str(&format!("mod r{}, r{}\n", dst, src)),
-                0x5f => output.push_str(&format!("and r{}, r{}\n", dst, src)),
-                0x4f => output.push_str(&format!("or r{}, r{}\n", dst, src)),
-                0xbf => output.push_str(&format!("xor r{}, r{}\n", dst, src)),
-                0x7f => output.push_str(&format!("lsh r{}, r{}\n", dst, src)),
-                0x8f => output.push_str(&format!("rsh r{}, r{}\n", dst, src)),
-                0x79 => output.push_str(&format!("ldxdw r{}, [r{}+{}]\n", dst, src, inst.offset)),
-                0x7b => output.push_str(&format!("stxdw [r{}+{}], r{}\n", dst, inst.offset, src)),
-                0x15 => output.push_str(&format!("jeq r{}, {}, {:+}\n", dst, inst.imm, inst.offset)),
-                0x55 => output.push_str(&format!("jne r{}, {}, {:+}\n", dst, inst.imm, inst.offset)),
-                0x05 => output.push_str(&format!("ja {:+}\n", inst.offset)),
-                0x85 => output.push_str(&format!("call {}\n", inst.imm)),
-                0x95 => output.push_str("exit\n"),
-                _ => output.push_str(&format!("??? (0x{:02x})\n", inst.opcode)),
-            }
-        }
-    }
-
-    output.push_str(&format!("\nTotal: {} instructions\n", bytecode.len() / 8));
-    output
-}
@@ -1,5 +1,5 @@
 use crate::ast::*;
-use crate::lexer::Token;
+use crate::lexer::{self, Token};
 use anyhow::{anyhow, Result};
 
 pub struct Parser {
@@ -22,6 +22,20 @@ impl Parser {
         Ok(Program { items })
     }
 
+    /// Parse a single expression that spans all the tokens
+    pub fn parse_expression(&mut self) -> Result<Expr> {
+        let expr = self.parse_expr()?;
+        if let Some(token) = self.peek() {
+            return Err(anyhow!("Unexpected token after expression: {:?}", token));
+        }
+        Ok(expr)
+    }
+
+    /// Index of the token the parser stopped at
+    pub fn position(&self) -> usize {
+        self.current
+    }
+
     fn parse_item(&mut self) -> Result<Item> {
         // Handle preprocessor directives
         if let Some(Token::Define(def)) = self.peek().cloned() {
@@ -36,11 +50,30 @@ impl Parser {
 
         // Check for class definition
         if self.match_token(&Token::Class) {
-            return Ok(Item::ClassDef(self.parse_class()?));
+            return Ok(Item::ClassDef(self.parse_class(false)?));
+        }
+        if self.match_token(&Token::Union) {
+            return Ok(Item::ClassDef(self.parse_class(true)?));
+        }
+        if self.match_token(&Token::Enum) {
+            return Ok(Item::EnumDef(self.parse_enum()?));
         }
 
-        // Parse function or global variable
+        // Parse function or global variable. Globals are always read-only,
+        // so `const` and `static` need no further handling.
+        while self.match_token(&Token::Const) || self.match_token(&Token::Static) {}
         let return_type = self.parse_type()?;
+        if self.check(&Token::LeftParen) {
+            // Function pointer global, e.g. `U64 (*handler)(U8*, U64);`
+            let (name, var_type) = self.parse_declarator(return_type)?;
+            let init = if self.match_token(&Token::Assign) {
+                Some(self.parse_initializer()?)
+            } else {
+                None
+            };
+            self.expect(&Token::Semicolon)?;
+            return Ok(Item::GlobalVar(VarDecl { name, var_type, init }));
+        }
         let name = self.expect_ident()?;
 
         if self.match_token(&Token::LeftParen) {
@@ -60,8 +93,9 @@ impl Parser {
             }))
         } else {
             // Global variable
+            let return_type = self.parse_array_suffix(return_type)?;
             let init = if self.match_token(&Token::Assign) {
-                Some(self.parse_expr()?)
+                Some(self.parse_initializer()?)
             } else {
                 None
             };
@@ -88,27 +122,119 @@ impl Parser {
         })
     }
 
-    fn parse_class(&mut self) -> Result<ClassDef> {
+    fn parse_inline_asm(&self, text: &str) -> Result<InlineAsm> {
+        // Parse asm (in rN = var, out rN = var, clobber rN) { body }
+        let (bindings, body, _) = lexer::split_asm_block(text).ok_or_else(|| anyhow!("Unterminated asm block"))?;
+
+        let parse_reg = |reg: &str| -> Result<u8> {
+            reg.trim().strip_prefix('r')
+                .and_then(|n| n.parse::<u8>().ok())
+                .filter(|n| *n < 10)
+                .ok_or_else(|| anyhow!("Invalid asm register: {}", reg.trim()))
+        };
+
+        let mut asm = InlineAsm { inputs: Vec::new(), outputs: Vec::new(), clobbers: Vec::new(), body: body.to_string() };
+        for binding in bindings.split(',').map(str::trim).filter(|b| !b.is_empty()) {
+            let (kind, rest) = binding.split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("Invalid asm binding: {}", binding))?;
+            match kind {
+                "in" | "out" => {
+                    let (reg, var) = rest.split_once('=')
+                        .ok_or_else(|| anyhow!("Expected `{} rN = variable`, got: {}", kind, binding))?;
+                    let binding = AsmBinding { reg: parse_reg(reg)?, var: var.trim().to_string() };
+                    if kind == "in" {
+                        asm.inputs.push(binding);
+                    } else {
+                        asm.outputs.push(binding);
+                    }
+                }
+                "clobber" => asm.clobbers.push(parse_reg(rest)?),
+                _ => return Err(anyhow!("Unknown asm binding kind: {}", kind)),
+            }
+        }
+        Ok(asm)
+    }
+
+    fn parse_class(&mut self, is_union: bool) -> Result<ClassDef> {
         let name = self.expect_ident()?;
+        let base = if self.match_token(&Token::Colon) {
+            Some(self.expect_ident()?)
+        } else {
+            None
+        };
         self.expect(&Token::LeftBrace)?;
 
         let mut fields = Vec::new();
         while !self.check(&Token::RightBrace) && !self.is_at_end() {
-            let field_type = self.parse_type()?;
-            let field_name = self.expect_ident()?;
-            self.expect(&Token::Semicolon)?;
+            if self.match_token(&Token::Union) {
+                self.expect(&Token::LeftBrace)?;
+                let mut members = Vec::new();
+                while !self.check(&Token::RightBrace) && !self.is_at_end() {
+                    members.push(self.parse_field()?);
+                }
+                self.expect(&Token::RightBrace)?;
+                self.expect(&Token::Semicolon)?;
+                fields.push(ClassField::AnonymousUnion(members));
+            } else {
+                fields.push(ClassField::Named(self.parse_field()?));
+            }
+        }
 
-            fields.push(VarDecl {
-                name: field_name,
-                var_type: field_type,
-                init: None,
-            });
+        self.expect(&Token::RightBrace)?;
+        self.expect(&Token::Semicolon)?;
+
+        Ok(ClassDef { name, base, fields, is_union })
+    }
+
+    fn parse_enum(&mut self) -> Result<EnumDef> {
+        let name = match self.peek() {
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                self.advance();
+                Some(name)
+            }
+            _ => None,
+        };
+        let underlying = if self.match_token(&Token::Colon) {
+            self.parse_type()?
+        } else {
+            Type::I64
+        };
+        if !underlying.is_integer() {
+            return Err(anyhow!("Enum underlying type must be an integer type, got {}", underlying));
+        }
+        self.expect(&Token::LeftBrace)?;
+
+        let mut enumerators = Vec::new();
+        while !self.check(&Token::RightBrace) && !self.is_at_end() {
+            let name = self.expect_ident()?;
+            let value = if self.match_token(&Token::Assign) {
+                Some(self.parse_assignment()?)
+            } else {
+                None
+            };
+            enumerators.push(Enumerator { name, value });
+            if !self.match_token(&Token::Comma) {
+                break;
+            }
         }
 
         self.expect(&Token::RightBrace)?;
         self.expect(&Token::Semicolon)?;
 
-        Ok(ClassDef { name, fields })
+        Ok(EnumDef { name, underlying, enumerators })
+    }
+
+    fn parse_field(&mut self) -> Result<VarDecl> {
+        let field_type = self.parse_type()?;
+        let (field_name, field_type) = self.parse_declarator(field_type)?;
+        self.expect(&Token::Semicolon)?;
+
+        Ok(VarDecl {
+            name: field_name,
+            var_type: field_type,
+            init: None,
+        })
     }
 
     fn parse_type(&mut self) -> Result<Type> {
@@ -123,7 +249,7 @@ impl Parser {
             Some(Token::I64) => { self.advance(); Type::I64 }
             Some(Token::F64) => { self.advance(); Type::F64 }
             Some(Token::Bool) => { self.advance(); Type::Bool }
-            Some(Token::Void) => { self.advance(); Type::Void }
+            Some(Token::U0) | Some(Token::Void) => { self.advance(); Type::Void }
             Some(Token::Ident(name)) => {
                 let name = name.clone();
                 self.advance();
@@ -139,23 +265,73 @@ impl Parser {
         }
 
         // Handle arrays
-        if self.match_token(&Token::LeftBracket) {
-            let size = if !self.check(&Token::RightBracket) {
-                if let Some(Token::IntLiteral(n)) = self.peek() {
-                    let size = *n as usize;
-                    self.advance();
-                    Some(size)
-                } else {
-                    None
-                }
+        self.parse_array_suffix(result)
+    }
+
+    /// Optional `[N]` after a type or declarator name, as in `U8 buf[32]`
+    /// `[N]` suffixes after a declarator. `T x[3][5]` is an array of three
+    /// `T[5]`; only the first length may be left out.
+    fn parse_array_suffix(&mut self, element: Type) -> Result<Type> {
+        let mut lens = Vec::new();
+        while self.match_token(&Token::LeftBracket) {
+            let len = if let Some(Token::IntLiteral(n)) = self.peek() {
+                let len = *n as usize;
+                self.advance();
+                Some(len)
             } else {
                 None
             };
             self.expect(&Token::RightBracket)?;
-            result = Type::Array(Box::new(result), size);
+            if len.is_none() && !lens.is_empty() {
+                return Err(anyhow!("Only the first dimension of an array can be left out"));
+            }
+            lens.push(len);
         }
+        Ok(lens.into_iter().rev().fold(element, |inner, len| Type::Array(Box::new(inner), len)))
+    }
 
-        Ok(result)
+    /// Name and type after a base type: `name`, `name[N]`, or a function
+    /// pointer `(*name)(U8*, U64)` / `(*name[N])(U8*, U64)`
+    fn parse_declarator(&mut self, base: Type) -> Result<(String, Type)> {
+        if !(self.check(&Token::LeftParen)
+            && matches!(self.tokens.get(self.current + 1), Some(Token::Star)))
+        {
+            let name = self.expect_ident()?;
+            return Ok((name, self.parse_array_suffix(base)?));
+        }
+        self.advance();
+        self.advance();
+        let name = self.expect_ident()?;
+        let len = match self.parse_array_suffix(Type::Void)? {
+            Type::Array(_, len) => Some(len),
+            _ => None,
+        };
+        self.expect(&Token::RightParen)?;
+        self.expect(&Token::LeftParen)?;
+        let mut params = Vec::new();
+        if !self.check(&Token::RightParen) {
+            loop {
+                // Parameter names are optional in a signature
+                let param = self.parse_type()?;
+                if let Some(Token::Ident(_)) = self.peek() {
+                    self.advance();
+                }
+                params.push(param);
+                if !self.match_token(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(&Token::RightParen)?;
+
+        let pointer = Type::Pointer(Box::new(Type::Function {
+            return_type: Box::new(base),
+            params,
+        }));
+        Ok(match len {
+            Some(len) => (name, Type::Array(Box::new(pointer), len)),
+            None => (name, pointer),
+        })
     }
 
     fn parse_params(&mut self) -> Result<Vec<Param>> {
@@ -167,9 +343,14 @@ impl Parser {
 
         loop {
             let param_type = self.parse_type()?;
-            let name = self.expect_ident()?;
+            let (name, param_type) = self.parse_declarator(param_type)?;
+            let default = if self.match_token(&Token::Assign) {
+                Some(self.parse_assignment()?)
+            } else {
+                None
+            };
 
-            params.push(Param { name, param_type });
+            params.push(Param { name, param_type, default });
 
             if !self.match_token(&Token::Comma) {
                 break;
@@ -190,6 +371,23 @@ impl Parser {
     }
 
     fn parse_stmt(&mut self) -> Result<Stmt> {
+        // Inline assembly
+        if let Some(Token::AsmBlock(text)) = self.peek().cloned() {
+            self.advance();
+            return Ok(Stmt::Asm(self.parse_inline_asm(&text)?));
+        }
+
+        // Print statement: a format string followed by its arguments
+        if let Some(Token::StringLiteral(format)) = self.peek().cloned() {
+            self.advance();
+            let mut args = Vec::new();
+            while self.match_token(&Token::Comma) {
+                args.push(self.parse_assignment()?);
+            }
+            self.expect(&Token::Semicolon)?;
+            return Ok(Stmt::Print { format, args });
+        }
+
         // Return statement
         if self.match_token(&Token::Return) {
             let value = if !self.check(&Token::Semicolon) {
@@ -211,6 +409,18 @@ impl Parser {
             return Ok(Stmt::Continue);
         }
 
+        // Goto and labels
+        if self.match_token(&Token::Goto) {
+            let label = self.expect_ident()?;
+            self.expect(&Token::Semicolon)?;
+            return Ok(Stmt::Goto(label));
+        }
+        if let (Some(Token::Ident(name)), Some(Token::Colon)) = (self.peek(), self.tokens.get(self.current + 1)) {
+            let name = name.clone();
+            self.current += 2;
+            return Ok(Stmt::Label(name));
+        }
+
         // If statement
         if self.match_token(&Token::If) {
             self.expect(&Token::LeftParen)?;
@@ -248,6 +458,20 @@ impl Parser {
             return Ok(Stmt::While { condition, body });
         }
 
+        // Do-while loop
+        if self.match_token(&Token::Do) {
+            self.expect(&Token::LeftBrace)?;
+            let body = self.parse_block_contents()?;
+            self.expect(&Token::RightBrace)?;
+            self.expect(&Token::While)?;
+            self.expect(&Token::LeftParen)?;
+            let condition = self.parse_expr()?;
+            self.expect(&Token::RightParen)?;
+            self.expect(&Token::Semicolon)?;
+
+            return Ok(Stmt::DoWhile { body, condition });
+        }
+
         // For loop
         if self.match_token(&Token::For) {
             self.expect(&Token::LeftParen)?;
@@ -285,6 +509,40 @@ impl Parser {
             });
         }
 
+        // Switch statement
+        if self.match_token(&Token::Switch) {
+            self.expect(&Token::LeftParen)?;
+            let value = self.parse_expr()?;
+            self.expect(&Token::RightParen)?;
+            self.expect(&Token::LeftBrace)?;
+
+            let mut cases = Vec::new();
+            while !self.check(&Token::RightBrace) && !self.is_at_end() {
+                let label = if self.match_token(&Token::Case) {
+                    let low = self.parse_expr()?;
+                    if self.match_token(&Token::Ellipsis) {
+                        CaseLabel::Range(low, self.parse_expr()?)
+                    } else {
+                        CaseLabel::Value(low)
+                    }
+                } else if self.match_token(&Token::Default) {
+                    CaseLabel::Default
+                } else {
+                    return Err(anyhow!("Expected case or default in switch, got {:?}", self.peek()));
+                };
+                self.expect(&Token::Colon)?;
+
+                let mut body = Vec::new();
+                while !matches!(self.peek(), Some(Token::Case | Token::Default | Token::RightBrace) | None) {
+                    body.push(self.parse_stmt()?);
+                }
+                cases.push(SwitchCase { label, body });
+            }
+            self.expect(&Token::RightBrace)?;
+
+            return Ok(Stmt::Switch { value, cases });
+        }
+
         // Block
         if self.match_token(&Token::LeftBrace) {
             let block = self.parse_block_contents()?;
@@ -293,12 +551,12 @@ impl Parser {
         }
 
         // Variable declaration or expression
-        if self.is_type_token() {
+        if self.is_var_decl_start() {
             let var_type = self.parse_type()?;
-            let name = self.expect_ident()?;
+            let (name, var_type) = self.parse_declarator(var_type)?;
 
             let init = if self.match_token(&Token::Assign) {
-                Some(self.parse_expr()?)
+                Some(self.parse_initializer()?)
             } else {
                 None
             };
@@ -318,12 +576,40 @@ impl Parser {
         Ok(Stmt::Expr(expr))
     }
 
+    /// Full expression, including the comma operator. Initializers and call
+    /// arguments use `parse_assignment` so their commas keep separating.
     fn parse_expr(&mut self) -> Result<Expr> {
-        self.parse_assignment()
+        let expr = self.parse_assignment()?;
+        if !self.check(&Token::Comma) {
+            return Ok(expr);
+        }
+
+        let mut exprs = vec![expr];
+        while self.match_token(&Token::Comma) {
+            exprs.push(self.parse_assignment()?);
+        }
+        Ok(Expr::Comma(exprs))
+    }
+
+    /// The value after `=` in a declaration: an expression, or a braced
+    /// list of initializers, which may nest and end with a comma
+    fn parse_initializer(&mut self) -> Result<Expr> {
+        if !self.match_token(&Token::LeftBrace) {
+            return self.parse_assignment();
+        }
+        let mut items = Vec::new();
+        while !self.check(&Token::RightBrace) {
+            items.push(self.parse_initializer()?);
+            if !self.match_token(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(&Token::RightBrace)?;
+        Ok(Expr::InitList(items))
     }
 
     fn parse_assignment(&mut self) -> Result<Expr> {
-        let expr = self.parse_logical_or()?;
+        let expr = self.parse_ternary()?;
 
         if let Some(token) = self.peek() {
             let op = match token {
@@ -360,6 +646,23 @@ impl Parser {
         Ok(expr)
     }
 
+    /// `a ? b : c`, right-associative, binding looser than `||`
+    fn parse_ternary(&mut self) -> Result<Expr> {
+        let condition = self.parse_logical_or()?;
+        if !self.match_token(&Token::Question) {
+            return Ok(condition);
+        }
+
+        let then_expr = self.parse_expr()?;
+        self.expect(&Token::Colon)?;
+        let else_expr = self.parse_ternary()?;
+        Ok(Expr::Ternary {
+            condition: Box::new(condition),
+            then_expr: Box::new(then_expr),
+            else_expr: Box::new(else_expr),
+        })
+    }
+
     fn parse_logical_or(&mut self) -> Result<Expr> {
         let mut expr = self.parse_logical_and()?;
 
@@ -583,19 +886,72 @@ impl Parser {
             });
         }
 
+        // C-style cast: `(U8)x`
+        if self.is_cast() {
+            let target_type = self.parse_cast_type()?;
+            let expr = self.parse_unary()?;
+            return Ok(Expr::Cast {
+                expr: Box::new(expr),
+                target_type,
+            });
+        }
+
         self.parse_postfix()
     }
 
+    /// Whether the parenthesis at the current token holds a type: a
+    /// built-in type, or a name followed by `*`, as in `(CVault*)`
+    fn is_cast(&self) -> bool {
+        if !self.check(&Token::LeftParen) {
+            return false;
+        }
+        match self.tokens.get(self.current + 1) {
+            Some(Token::Ident(_)) => {
+                let mut ahead = self.current + 2;
+                while matches!(self.tokens.get(ahead), Some(Token::Star)) {
+                    ahead += 1;
+                }
+                ahead > self.current + 2 && matches!(self.tokens.get(ahead), Some(Token::RightParen))
+            }
+            Some(token) => matches!(
+                token,
+                Token::U0 | Token::U8 | Token::U16 | Token::U32 | Token::U64
+                    | Token::I8 | Token::I16 | Token::I32 | Token::I64
+                    | Token::F64 | Token::Bool | Token::Void
+            ),
+            None => false,
+        }
+    }
+
+    fn parse_cast_type(&mut self) -> Result<Type> {
+        self.expect(&Token::LeftParen)?;
+        let target_type = self.parse_type()?;
+        self.expect(&Token::RightParen)?;
+        Ok(target_type)
+    }
+
     fn parse_postfix(&mut self) -> Result<Expr> {
         let mut expr = self.parse_primary()?;
 
         loop {
-            if self.match_token(&Token::LeftParen) {
-                // Function call
+            if self.is_cast() {
+                // HolyC postfix cast: `x(U8)`
+                let target_type = self.parse_cast_type()?;
+                expr = Expr::Cast {
+                    expr: Box::new(expr),
+                    target_type,
+                };
+            } else if self.match_token(&Token::LeftParen) {
+                // Function call; an empty slot, as in `Foo(, 2)`, takes
+                // the parameter's default
                 let mut args = Vec::new();
                 if !self.check(&Token::RightParen) {
                     loop {
-                        args.push(self.parse_expr()?);
+                        if self.check(&Token::Comma) || self.check(&Token::RightParen) {
+                            args.push(Expr::DefaultArg);
+                        } else {
+                            args.push(self.parse_assignment()?);
+                        }
                         if !self.match_token(&Token::Comma) {
                             break;
                         }
@@ -668,10 +1024,10 @@ impl Parser {
                 self.advance();
                 Ok(Expr::FloatLiteral(f))
             }
-            Some(Token::StringLiteral(s)) => {
-                let s = s.clone();
+            Some(Token::StringLiteral(bytes)) => {
+                let bytes = bytes.clone();
                 self.advance();
-                Ok(Expr::StringLiteral(s))
+                Ok(Expr::StringLiteral(bytes))
             }
             Some(Token::CharLiteral(c)) => {
                 let c = *c;
@@ -768,10 +1124,29 @@ impl Parser {
         }
     }
 
+    /// A statement starting with an identifier is a declaration only when a
+    /// declarator follows, e.g. `CPoint p;`, `CPoint **p;` or
+    /// `CPoint *(*make)(U64);`
+    fn is_var_decl_start(&self) -> bool {
+        if !matches!(self.peek(), Some(Token::Ident(_))) {
+            return self.is_type_token();
+        }
+        let mut ahead = self.current + 1;
+        while matches!(self.tokens.get(ahead), Some(Token::Star)) {
+            ahead += 1;
+        }
+        match self.tokens.get(ahead..ahead + 5) {
+            Some([Token::LeftParen, Token::Star, Token::Ident(_), Token::RightParen, Token::LeftParen])
+            | Some([Token::LeftParen, Token::Star, Token::Ident(_), Token::LeftBracket, _]) => true,
+            _ => matches!(self.tokens.get(ahead), Some(Token::Ident(_))),
+        }
+    }
+
     fn is_type_token(&self) -> bool {
         matches!(
             self.peek(),
-            Some(Token::U8)
+            Some(Token::U0)
+                | Some(Token::U8)
                 | Some(Token::U16)
                 | Some(Token::U32)
                 | Some(Token::U64)
@@ -826,6 +1201,130 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_unions() {
+        let source = "union Key { U64 raw; U8 bytes[8]; }; class Slot { U8 tag; union { U64 amount; Key key; }; };";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::ClassDef(key) = &program.items[0] else { panic!("Expected union definition") };
+        assert!(key.is_union);
+        assert_eq!(key.fields.len(), 2);
+        let Item::ClassDef(slot) = &program.items[1] else { panic!("Expected class definition") };
+        assert!(!slot.is_union);
+        let ClassField::AnonymousUnion(members) = &slot.fields[1] else { panic!("Expected anonymous union") };
+        assert_eq!(members[1].var_type, Type::Custom("Key".to_string()));
+    }
+
+    #[test]
+    fn test_parse_enum() {
+        let source = "enum Tag : U8 { INIT = 1, DEPOSIT, CLOSE = 0x10, }; enum { MAX_USERS = 32 };";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::EnumDef(tag) = &program.items[0] else { panic!("Expected enum definition") };
+        assert_eq!(tag.name.as_deref(), Some("Tag"));
+        assert_eq!(tag.underlying, Type::U8);
+        let names: Vec<_> = tag.enumerators.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["INIT", "DEPOSIT", "CLOSE"]);
+        assert_eq!(tag.enumerators[1].value, None);
+        let Item::EnumDef(limits) = &program.items[1] else { panic!("Expected enum definition") };
+        assert_eq!((limits.name.as_ref(), &limits.underlying), (None, &Type::I64));
+
+        let tokens: Vec<Token> = Lexer::collect_tokens("enum Bad : Bool { A };").unwrap().into_iter().map(|(t, _)| t).collect();
+        assert!(Parser::new(tokens).parse().is_err());
+    }
+
+    #[test]
+    fn test_parse_function_pointers() {
+        let source = "class CRouter { U64 (*handlers[4])(U8*, U64 len); };
+                      U64 Run(CRouter *r, U0 (*log)(U8 *msg)) {
+                          CRouter *(*make)(U64);
+                          (*log)(0);
+                          return r->handlers[1](0, 2);
+                      }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::ClassDef(router) = &program.items[0] else { panic!("Expected class definition") };
+        let ClassField::Named(handlers) = &router.fields[0] else { panic!("Expected named field") };
+        assert_eq!(handlers.var_type.to_string(), "U64 (*)(U8*, U64)[4]");
+        let Item::FunctionDef(run) = &program.items[1] else { panic!("Expected function") };
+        assert_eq!(run.params[1].name, "log");
+        assert_eq!(run.params[1].param_type.to_string(), "Void (*)(U8*)");
+        let Stmt::VarDecl(make) = &run.body[0] else { panic!("Expected declaration") };
+        assert_eq!(make.var_type.to_string(), "CRouter* (*)(U64)");
+        assert!(matches!(&run.body[1], Stmt::Expr(Expr::Call { .. })));
+    }
+
+    #[test]
+    fn test_parse_default_args() {
+        let source = "U0 Foo(I64 a, I64 b = 5 + 1) { Foo(, 2); Foo(1,); }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::FunctionDef(foo) = &program.items[0] else { panic!("Expected function") };
+        assert_eq!(foo.params[0].default, None);
+        assert_eq!(foo.params[1].default.as_ref().map(|e| e.to_string()).as_deref(), Some("5 + 1"));
+        let Stmt::Expr(Expr::Call { args, .. }) = &foo.body[0] else { panic!("Expected call") };
+        assert_eq!(args, &[Expr::DefaultArg, Expr::IntLiteral(2)]);
+        let Stmt::Expr(Expr::Call { args, .. }) = &foo.body[1] else { panic!("Expected call") };
+        assert_eq!(args, &[Expr::IntLiteral(1), Expr::DefaultArg]);
+    }
+
+    #[test]
+    fn test_parse_initializer_lists() {
+        let source = "U8 SEED[] = \"vault\";
+                      U0 f() { Point ps[2] = {{1, 2}, {3,},}; U8 empty[4] = {}; }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::GlobalVar(seed) = &program.items[0] else { panic!("Expected global") };
+        assert_eq!(seed.init, Some(Expr::StringLiteral(b"vault".to_vec())));
+        let Item::FunctionDef(f) = &program.items[1] else { panic!("Expected function") };
+        let Stmt::VarDecl(ps) = &f.body[0] else { panic!("Expected declaration") };
+        assert_eq!(ps.init.as_ref().unwrap().to_string(), "{{1, 2}, {3}}");
+        let Stmt::VarDecl(empty) = &f.body[1] else { panic!("Expected declaration") };
+        assert_eq!(empty.init, Some(Expr::InitList(Vec::new())));
+    }
+
+    #[test]
+    fn test_parse_multi_dimensional_arrays() {
+        let source = "U64 Sum(U8 rows[][4]) { U8 seeds[3][32]; return seeds[1][2]; }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::FunctionDef(sum) = &program.items[0] else { panic!("Expected function") };
+        assert_eq!(sum.params[0].param_type.to_string(), "U8[][4]");
+        let Stmt::VarDecl(seeds) = &sum.body[0] else { panic!("Expected declaration") };
+        let row = Type::Array(Box::new(Type::U8), Some(32));
+        assert_eq!(seeds.var_type, Type::Array(Box::new(row), Some(3)));
+        assert_eq!(seeds.var_type.to_string(), "U8[3][32]");
+
+        let tokens: Vec<Token> = Lexer::collect_tokens("U8 bad[3][];").unwrap().into_iter().map(|(t, _)| t).collect();
+        assert!(Parser::new(tokens).parse().is_err());
+    }
+
+    #[test]
+    fn test_parse_base_class() {
+        let source = "class CHeader { U8 bump; }; class CVault : CHeader { U64 amount; };";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::ClassDef(header) = &program.items[0] else { panic!("Expected class definition") };
+        assert_eq!(header.base, None);
+        let Item::ClassDef(vault) = &program.items[1] else { panic!("Expected class definition") };
+        assert_eq!(vault.base.as_deref(), Some("CHeader"));
+        assert_eq!(vault.fields.len(), 1);
+    }
+
     #[test]
     fn test_parse_xor_expression() {
         let source = "U64 test() { return x ^ 0xdeadbeef; }";
@@ -842,4 +1341,106 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_parse_inline_asm() {
+        let source = "U64 f(U64 a) { U64 b; asm (in r1 = a, out r0 = b, clobber r3) {\n    mov64 r0, r1\n} return b; }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let mut parser = Parser::new(tokens);
+        let program = parser.parse().unwrap();
+
+        let Item::FunctionDef(func) = &program.items[0] else { panic!("Expected function definition") };
+        let Stmt::Asm(asm) = &func.body[1] else { panic!("Expected asm statement") };
+        assert_eq!(asm.inputs, [AsmBinding { reg: 1, var: "a".to_string() }]);
+        assert_eq!(asm.outputs, [AsmBinding { reg: 0, var: "b".to_string() }]);
+        assert_eq!(asm.clobbers, [3]);
+        assert_eq!(asm.body.trim(), "mov64 r0, r1");
+    }
+
+    #[test]
+    fn test_parse_print_statement() {
+        let source = r#"U0 f(U64 bal) { "Balance: %d\n", bal + 1; "done\n"; }"#;
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let mut parser = Parser::new(tokens);
+        let program = parser.parse().unwrap();
+
+        let Item::FunctionDef(func) = &program.items[0] else { panic!("Expected function definition") };
+        let Stmt::Print { format, args } = &func.body[0] else { panic!("Expected print statement") };
+        assert_eq!(format, b"Balance: %d\n");
+        assert!(matches!(args[..], [Expr::Binary { op: BinaryOp::Add, .. }]));
+        assert_eq!(func.body[1], Stmt::Print { format: b"done\n".to_vec(), args: Vec::new() });
+    }
+
+    #[test]
+    fn test_parse_switch() {
+        let source = "U0 f(U8 c) { switch (c) { case 1: case 'A'...'Z': c++; break; default: c = 0; } }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::FunctionDef(func) = &program.items[0] else { panic!("Expected function definition") };
+        let Stmt::Switch { value, cases } = &func.body[0] else { panic!("Expected switch statement") };
+        assert_eq!(*value, Expr::Ident("c".to_string()));
+        assert_eq!(cases.len(), 3);
+        assert_eq!(cases[0], SwitchCase { label: CaseLabel::Value(Expr::IntLiteral(1)), body: Vec::new() });
+        assert_eq!(cases[1].label, CaseLabel::Range(Expr::CharLiteral(b'A'), Expr::CharLiteral(b'Z')));
+        assert_eq!(cases[1].body.len(), 2);
+        assert_eq!(cases[2].label, CaseLabel::Default);
+    }
+
+    #[test]
+    fn test_parse_do_while_and_goto() {
+        let source = "U0 f(U64 n) { do { n--; } while (n > 0); retry: if (n) { goto retry; } }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::FunctionDef(func) = &program.items[0] else { panic!("Expected function definition") };
+        assert!(matches!(&func.body[0], Stmt::DoWhile { body, condition: Expr::Binary { op: BinaryOp::Gt, .. } } if body.len() == 1));
+        assert_eq!(func.body[1], Stmt::Label("retry".to_string()));
+        let Stmt::If { then_block, .. } = &func.body[2] else { panic!("Expected if statement") };
+        assert_eq!(then_block[0], Stmt::Goto("retry".to_string()));
+    }
+
+    #[test]
+    fn test_parse_ternary_and_comma() {
+        let source = "U0 f(U64 a, U64 n) { U64 i; U64 j; for (i = 0, j = n; i < j; i++, j--) { a = i < 2 || j ? a + 1 : n ? 2 : 3; Foo(a, (i, j)); } }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::FunctionDef(func) = &program.items[0] else { panic!("Expected function definition") };
+        let Stmt::For { init: Some(init), increment: Some(increment), body, .. } = &func.body[2] else {
+            panic!("Expected for loop")
+        };
+        assert_eq!(**init, Stmt::Expr(Expr::Comma(vec![
+            Expr::Assign { target: Box::new(Expr::Ident("i".to_string())), value: Box::new(Expr::IntLiteral(0)) },
+            Expr::Assign { target: Box::new(Expr::Ident("j".to_string())), value: Box::new(Expr::Ident("n".to_string())) },
+        ])));
+        assert_eq!(increment.to_string(), "i++, j--");
+        // `?:` binds looser than `||`, tighter than `=`, and nests to the right
+        let [Stmt::Expr(ternary), Stmt::Expr(call)] = &body[..] else { panic!("Expected two expressions") };
+        assert_eq!(ternary.to_string(), "a = ((i < 2) || j) ? a + 1 : (n ? 2 : 3)");
+        assert_eq!(call.to_string(), "Foo(a, (i, j))");
+    }
+
+    #[test]
+    fn test_parse_casts() {
+        let source = "U0 f(U64 a, U64 b, U8 *p) { a = (U8)a + b(I32); p = (CVault *)p; f(a * b); }";
+        let tokens = Lexer::collect_tokens(source).unwrap();
+        let tokens: Vec<Token> = tokens.into_iter().map(|(t, _)| t).collect();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let Item::FunctionDef(func) = &program.items[0] else { panic!("Expected function definition") };
+        let [Stmt::Expr(prefix), Stmt::Expr(pointer), Stmt::Expr(call)] = &func.body[..] else {
+            panic!("Expected three expressions")
+        };
+        // Both forms bind tighter than `+`
+        assert_eq!(prefix.to_string(), "a = (U8)a + (I32)b");
+        assert_eq!(pointer.to_string(), "p = (CVault*)p");
+        // A parenthesised expression after a name is still a call
+        assert!(matches!(call, Expr::Call { .. }));
+    }
 }
@@ -0,0 +1,858 @@
+//! C-style preprocessor
+//!
+//! Runs before lexing: handles `#define` and `#undef`, conditional
+//! compilation with `#ifdef`, `#ifndef`, `#if`, `#elif`, `#else` and
+//! `#endif`, expands object-like and function-like macros and splices in
+//! `#include`d files. The result is plain source text plus a `SourceMap`
+//! recording which file and offset each byte came from, so diagnostics point
+//! at the original source. Text produced by a macro maps to the macro's use.
+use crate::consteval;
+use crate::layout::LayoutEngine;
+use crate::lexer::{Lexer, Token};
+use crate::parser::Parser;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A macro definition; `params` is None for object-like macros
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub params: Option<Vec<String>>,
+    pub body: String,
+}
+
+/// A position in an original source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SourceFile {
+    name: String,
+    text: String,
+}
+
+/// Output text from `output` onwards comes from `source` in `file`. Copied
+/// text maps byte for byte; an expansion maps entirely to the macro use.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    output: usize,
+    file: usize,
+    source: usize,
+    copied: bool,
+}
+
+/// Maps offsets in preprocessed text back to the original sources
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    segments: Vec<Segment>,
+}
+
+impl SourceMap {
+    /// Original location of an offset in the preprocessed text
+    pub fn locate(&self, offset: usize) -> Location {
+        let idx = self.segments.partition_point(|s| s.output <= offset);
+        let (file, source) = match idx.checked_sub(1).map(|idx| self.segments[idx]) {
+            Some(s) if s.copied => (s.file, s.source + (offset - s.output)),
+            Some(s) => (s.file, s.source),
+            None => (0, 0),
+        };
+        self.location(file, source)
+    }
+
+    fn location(&self, file: usize, offset: usize) -> Location {
+        let Some(file) = self.files.get(file) else {
+            return Location { file: String::new(), line: 1, column: 1 };
+        };
+        let before = &file.text[..offset.min(file.text.len())];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        Location {
+            file: file.name.clone(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    fn add_file(&mut self, name: &str, text: &str) -> usize {
+        self.files.push(SourceFile { name: name.to_string(), text: text.to_string() });
+        self.files.len() - 1
+    }
+}
+
+/// Preprocessed source text and where it came from
+#[derive(Debug, Clone)]
+pub struct Preprocessed {
+    pub text: String,
+    pub map: SourceMap,
+}
+
+impl Preprocessed {
+    fn push(&mut self, text: &str, file: usize, source: usize, copied: bool) {
+        if text.is_empty() {
+            return;
+        }
+        self.map.segments.push(Segment { output: self.text.len(), file, source, copied });
+        self.text.push_str(text);
+    }
+}
+
+/// State of one `#if` ... `#endif` group
+#[derive(Debug)]
+struct Conditional {
+    /// The enclosing code is being compiled
+    parent_active: bool,
+    /// The current branch is being compiled
+    active: bool,
+    /// Some branch has been taken, so later ones are skipped
+    taken: bool,
+    seen_else: bool,
+    line: usize,
+}
+
+/// A piece of scanned text: copied as is, or a macro use and its expansion
+enum Chunk {
+    Text(std::ops::Range<usize>),
+    Expansion(std::ops::Range<usize>, String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    include_paths: Vec<PathBuf>,
+    /// Files marked `#pragma once`
+    once: HashSet<PathBuf>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define a macro from a command-line `NAME` or `NAME=VALUE`; a bare
+    /// name is defined as 1
+    pub fn define_arg(&mut self, arg: &str) -> Result<()> {
+        let (name, value) = arg.split_once('=').unwrap_or((arg, "1"));
+        if !is_ident(name) {
+            return Err(anyhow!("Invalid macro name in -D {}", arg));
+        }
+        self.macros.insert(name.to_string(), Macro { params: None, body: value.to_string() });
+        Ok(())
+    }
+
+    /// Search `dir` for `#include` files, after the including file's own
+    /// directory for `"file"` and in order of addition
+    pub fn add_include_path(&mut self, dir: impl Into<PathBuf>) {
+        self.include_paths.push(dir.into());
+    }
+
+    /// Preprocess `source`, naming it `file_name` in locations. Quoted
+    /// includes are looked up next to `file_name` first.
+    pub fn run(&mut self, source: &str, file_name: &str) -> Result<Preprocessed> {
+        let mut out = Preprocessed { text: String::new(), map: SourceMap::default() };
+        let path = Path::new(file_name);
+        let mut stack = vec![fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())];
+        self.process(source, path, &mut out, &mut stack)?;
+        Ok(out)
+    }
+
+    /// Preprocess one file into `out`. `stack` holds the files being
+    /// included, innermost last.
+    fn process(&mut self, source: &str, path: &Path, out: &mut Preprocessed, stack: &mut Vec<PathBuf>) -> Result<()> {
+        let file_name = path.display().to_string();
+        let file = out.map.add_file(&file_name, source);
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut run_start: Option<usize> = None;
+        let mut in_comment = false;
+
+        let mut lines = line_offsets(source).into_iter();
+        while let Some((start, line)) = lines.next() {
+            let active = conditionals.last().is_none_or(|c| c.active);
+            let Some(name) = (!in_comment).then(|| directive_name(line)).flatten() else {
+                in_comment = comment_state(line, in_comment);
+                if active {
+                    run_start.get_or_insert(start);
+                } else {
+                    self.flush(source, &mut run_start, start, file, out)?;
+                    push_newline(out, line, start, file);
+                }
+                continue;
+            };
+            self.flush(source, &mut run_start, start, file, out)?;
+
+            // A trailing backslash continues the directive on the next line
+            let mut text = line.trim_end_matches(['\n', '\r']).to_string();
+            let mut last = (start, line);
+            while text.ends_with('\\') {
+                let Some(next) = lines.next() else { break };
+                text.pop();
+                text.push_str(next.1.trim_end_matches(['\n', '\r']));
+                push_newline(out, last.1, last.0, file);
+                last = next;
+            }
+
+            let line_number = out.map.location(file, start).line;
+            if name == "include" && active {
+                let target = self.resolve_include(&text, path)
+                    .with_context(|| format!("{}:{}", file_name, line_number))?;
+                self.include(&target, out, stack)
+                    .with_context(|| format!("In file included from {}:{}", file_name, line_number))?;
+            } else {
+                let current = stack.last().cloned().unwrap_or_default();
+                self.directive(&text, &mut conditionals, line_number, &current)
+                    .with_context(|| format!("{}:{}", file_name, line_number))?;
+            }
+            push_newline(out, last.1, last.0, file);
+        }
+        self.flush(source, &mut run_start, source.len(), file, out)?;
+
+        if let Some(open) = conditionals.last() {
+            return Err(anyhow!("{}:{}: #if without #endif", file_name, open.line));
+        }
+        Ok(())
+    }
+
+    /// Find the file named by an `#include` line
+    fn resolve_include(&self, text: &str, including: &Path) -> Result<PathBuf> {
+        let operand = strip_comments(text);
+        let operand = operand.trim_start().trim_start_matches('#').trim_start()
+            .trim_start_matches("include").trim();
+        let (name, quoted) = if let Some(name) = operand.strip_prefix('"').and_then(|o| o.strip_suffix('"')) {
+            (name, true)
+        } else if let Some(name) = operand.strip_prefix('<').and_then(|o| o.strip_suffix('>')) {
+            (name, false)
+        } else {
+            return Err(anyhow!("Expected #include \"file\" or #include <file>, got '{}'", operand));
+        };
+
+        let local = quoted.then(|| including.parent().unwrap_or(Path::new("")).join(name));
+        local.into_iter()
+            .chain(self.include_paths.iter().map(|dir| dir.join(name)))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| anyhow!("Cannot find include file {}", name))
+    }
+
+    /// Splice in an included file, unless it is `#pragma once` and already
+    /// included. Including a file that is still being processed is a cycle,
+    /// unless its include guard is already defined.
+    fn include(&mut self, path: &Path, out: &mut Preprocessed, stack: &mut Vec<PathBuf>) -> Result<()> {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if self.once.contains(&canonical) {
+            return Ok(());
+        }
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read include file {}", path.display()))?;
+
+        if let Some(idx) = stack.iter().position(|p| *p == canonical) {
+            if include_guard(&source).is_some_and(|guard| self.macros.contains_key(guard)) {
+                return Ok(());
+            }
+            let cycle: Vec<String> = stack[idx..].iter().chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(anyhow!("Include cycle: {}", cycle.join(" -> ")));
+        }
+
+        stack.push(canonical);
+        self.process(&source, path, out, stack)?;
+        stack.pop();
+        Ok(())
+    }
+
+    /// Expand the pending run of source lines ending at `end`
+    fn flush(&self, source: &str, run_start: &mut Option<usize>, end: usize, file: usize, out: &mut Preprocessed) -> Result<()> {
+        let Some(start) = run_start.take() else {
+            return Ok(());
+        };
+        let text = &source[start..end];
+        let chunks = self.scan(text, &mut Vec::new()).map_err(|err| {
+            let location = out.map.location(file, start + err.offset);
+            anyhow!("{}: {}", location, err.message)
+        })?;
+        for chunk in chunks {
+            match chunk {
+                Chunk::Text(range) => out.push(&text[range.clone()], file, start + range.start, true),
+                Chunk::Expansion(range, expansion) => out.push(&expansion, file, start + range.start, false),
+            }
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, text: &str, conditionals: &mut Vec<Conditional>, line: usize, file: &Path) -> Result<()> {
+        let text = strip_comments(text);
+        let body = text.trim_start().trim_start_matches('#').trim_start();
+        let (name, rest) = split_ident(body);
+        let rest = rest.trim();
+        let active = conditionals.last().is_none_or(|c| c.active);
+
+        match name {
+            "ifdef" | "ifndef" | "if" => {
+                let taken = active && match name {
+                    "ifdef" => self.macros.contains_key(expect_name(rest)?),
+                    "ifndef" => !self.macros.contains_key(expect_name(rest)?),
+                    _ => self.eval_condition(rest)?,
+                };
+                conditionals.push(Conditional { parent_active: active, active: taken, taken, seen_else: false, line });
+            }
+            "elif" => {
+                let cond = conditionals.last_mut().ok_or_else(|| anyhow!("#elif without #if"))?;
+                if cond.seen_else {
+                    return Err(anyhow!("#elif after #else"));
+                }
+                let parent_active = cond.parent_active && !cond.taken;
+                let taken = parent_active && self.eval_condition(rest)?;
+                let cond = conditionals.last_mut().expect("checked above");
+                cond.active = taken;
+                cond.taken |= taken;
+            }
+            "else" => {
+                let cond = conditionals.last_mut().ok_or_else(|| anyhow!("#else without #if"))?;
+                if cond.seen_else {
+                    return Err(anyhow!("#else after #else"));
+                }
+                cond.active = cond.parent_active && !cond.taken;
+                cond.taken = true;
+                cond.seen_else = true;
+            }
+            "endif" => {
+                conditionals.pop().ok_or_else(|| anyhow!("#endif without #if"))?;
+            }
+            _ if !active => {}
+            "define" => {
+                let (name, after) = split_ident(rest);
+                if name.is_empty() {
+                    return Err(anyhow!("#define needs a macro name"));
+                }
+                // Parameters only when the parenthesis touches the name
+                let definition = match after.strip_prefix('(') {
+                    Some(after) => {
+                        let (params, body) = after.split_once(')')
+                            .ok_or_else(|| anyhow!("Unterminated parameter list in #define {}", name))?;
+                        let params: Vec<String> = params.split(',')
+                            .map(|p| p.trim().to_string())
+                            .filter(|p| !p.is_empty())
+                            .collect();
+                        if let Some(bad) = params.iter().find(|p| !is_ident(p)) {
+                            return Err(anyhow!("Invalid parameter {} in #define {}", bad, name));
+                        }
+                        Macro { params: Some(params), body: body.trim().to_string() }
+                    }
+                    None => Macro { params: None, body: after.trim().to_string() },
+                };
+                self.macros.insert(name.to_string(), definition);
+            }
+            "undef" => {
+                self.macros.remove(expect_name(rest)?);
+            }
+            "error" => return Err(anyhow!("#error {}", rest)),
+            "pragma" if rest == "once" => {
+                self.once.insert(file.to_path_buf());
+            }
+            // Unknown pragmas are ignored, as in C
+            "pragma" => {}
+            _ => return Err(anyhow!("Unknown preprocessor directive #{}", name)),
+        }
+        Ok(())
+    }
+
+    /// Evaluate an `#if` expression. `defined NAME` tests for a macro, and
+    /// identifiers left after expansion count as 0.
+    fn eval_condition(&self, expr: &str) -> Result<bool> {
+        let expr = self.replace_defined(expr)?;
+        let expanded: String = self.scan(&expr, &mut Vec::new())
+            .map_err(|err| anyhow!("{}", err.message))?
+            .into_iter()
+            .map(|chunk| match chunk {
+                Chunk::Text(range) => expr[range].to_string(),
+                Chunk::Expansion(_, text) => text,
+            })
+            .collect();
+
+        let tokens = Lexer::collect_tokens(&expanded).map_err(|e| anyhow!("Invalid #if expression: {}", e))?;
+        let tokens = tokens.into_iter()
+            .map(|(token, _)| match token {
+                Token::Ident(_) => Token::IntLiteral(0),
+                token => token,
+            })
+            .collect();
+        let parsed = Parser::new(tokens).parse_expression()
+            .with_context(|| format!("Invalid #if expression: {}", expanded.trim()))?;
+        let value = consteval::eval(&parsed, &HashMap::new(), &LayoutEngine::new())
+            .ok_or_else(|| anyhow!("#if expression is not constant: {}", expanded.trim()))?;
+        Ok(value != 0)
+    }
+
+    fn replace_defined(&self, expr: &str) -> Result<String> {
+        let mut result = String::new();
+        let mut rest = expr;
+        while let Some(idx) = find_ident(rest, "defined") {
+            result.push_str(&rest[..idx]);
+            let after = rest[idx + "defined".len()..].trim_start();
+            let (name, after) = match after.strip_prefix('(') {
+                Some(inner) => {
+                    let (name, after) = split_ident(inner.trim_start());
+                    let after = after.trim_start().strip_prefix(')')
+                        .ok_or_else(|| anyhow!("Expected ) after defined({}", name))?;
+                    (name, after)
+                }
+                None => split_ident(after),
+            };
+            if name.is_empty() {
+                return Err(anyhow!("defined needs a macro name"));
+            }
+            result.push_str(if self.macros.contains_key(name) { " 1 " } else { " 0 " });
+            rest = after;
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    /// Split `text` into copied text and macro expansions. Macros named in
+    /// `disabled` are being expanded and are not expanded again.
+    fn scan(&self, text: &str, disabled: &mut Vec<String>) -> Result<Vec<Chunk>, ScanError> {
+        let bytes = text.as_bytes();
+        let mut chunks = Vec::new();
+        let mut copied = 0;
+        let mut pos = 0;
+
+        while pos < bytes.len() {
+            let start = pos;
+            match bytes[pos] {
+                b'/' if bytes.get(pos + 1) == Some(&b'/') => {
+                    pos = text[pos..].find('\n').map_or(bytes.len(), |idx| pos + idx);
+                }
+                b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                    pos = text[pos + 2..].find("*/").map_or(bytes.len(), |idx| pos + idx + 4);
+                }
+                quote @ (b'"' | b'\'') => pos = skip_quoted(bytes, pos, quote),
+                b'0'..=b'9' => {
+                    while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_') {
+                        pos += 1;
+                    }
+                }
+                b if b.is_ascii_alphabetic() || b == b'_' => {
+                    let (name, _) = split_ident(&text[pos..]);
+                    pos += name.len();
+                    let Some(definition) = self.macros.get(name).filter(|_| !disabled.iter().any(|d| d == name)) else {
+                        continue;
+                    };
+
+                    let body = match &definition.params {
+                        None => definition.body.clone(),
+                        Some(params) => {
+                            // A function-like macro name without arguments is left alone
+                            let open = pos + (text[pos..].len() - text[pos..].trim_start().len());
+                            if bytes.get(open) != Some(&b'(') {
+                                continue;
+                            }
+                            let (args, end) = split_args(text, open)
+                                .ok_or_else(|| ScanError::new(start, format!("Unterminated arguments to macro {}", name)))?;
+                            pos = end;
+                            let args = if params.is_empty() && args.len() == 1 && args[0].trim().is_empty() {
+                                Vec::new()
+                            } else {
+                                args
+                            };
+                            if args.len() != params.len() {
+                                return Err(ScanError::new(start, format!(
+                                    "Macro {} expects {} argument{}, got {}",
+                                    name, params.len(), if params.len() == 1 { "" } else { "s" }, args.len()
+                                )));
+                            }
+                            let mut expanded_args = Vec::new();
+                            for arg in &args {
+                                expanded_args.push(self.expand(arg.trim(), disabled).map_err(|err| err.at(start))?);
+                            }
+                            substitute(&definition.body, params, &expanded_args)
+                        }
+                    };
+
+                    disabled.push(name.to_string());
+                    let expansion = self.expand(&body, disabled).map_err(|err| err.at(start));
+                    disabled.pop();
+
+                    if copied < start {
+                        chunks.push(Chunk::Text(copied..start));
+                    }
+                    // Keep the expansion from merging with neighbouring tokens
+                    chunks.push(Chunk::Expansion(start..pos, format!(" {} ", expansion?)));
+                    copied = pos;
+                    continue;
+                }
+                _ => pos += 1,
+            }
+            pos = pos.max(start + 1).min(bytes.len());
+        }
+
+        if copied < bytes.len() {
+            chunks.push(Chunk::Text(copied..bytes.len()));
+        }
+        Ok(chunks)
+    }
+
+    fn expand(&self, text: &str, disabled: &mut Vec<String>) -> Result<String, ScanError> {
+        Ok(self.scan(text, disabled)?
+            .into_iter()
+            .map(|chunk| match chunk {
+                Chunk::Text(range) => text[range].to_string(),
+                Chunk::Expansion(_, expansion) => expansion,
+            })
+            .collect())
+    }
+}
+
+/// An expansion error at an offset into the scanned text
+#[derive(Debug)]
+struct ScanError {
+    offset: usize,
+    message: String,
+}
+
+impl ScanError {
+    fn new(offset: usize, message: String) -> Self {
+        Self { offset, message }
+    }
+
+    /// Errors inside an expansion are reported at the macro use
+    fn at(self, offset: usize) -> Self {
+        Self { offset, ..self }
+    }
+}
+
+/// Macro guarding a whole file: its first directive is `#ifndef NAME`
+/// followed by `#define NAME`, and its last is `#endif`
+fn include_guard(source: &str) -> Option<&str> {
+    let mut lines = source.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//"));
+    let guard = lines.next()?.strip_prefix('#')?.trim_start().strip_prefix("ifndef")?.trim();
+    let define = lines.next()?.strip_prefix('#')?.trim_start().strip_prefix("define")?.trim();
+    let last = lines.next_back()?.strip_prefix('#')?.trim_start();
+    (define == guard && last.starts_with("endif")).then_some(guard)
+}
+
+/// Lines of `source` with their starting offsets, newlines included
+fn line_offsets(source: &str) -> Vec<(usize, &str)> {
+    let mut offset = 0;
+    source.split_inclusive('\n')
+        .map(|line| {
+            let start = offset;
+            offset += line.len();
+            (start, line)
+        })
+        .collect()
+}
+
+/// Keep line structure for skipped lines: emit the newline, if any
+fn push_newline(out: &mut Preprocessed, line: &str, start: usize, file: usize) {
+    if line.ends_with('\n') {
+        out.push("\n", file, start + line.len() - 1, true);
+    }
+}
+
+/// Name of the directive on this line, if it is one
+fn directive_name(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix('#')?;
+    Some(split_ident(rest.trim_start()).0)
+}
+
+/// Whether a block comment is still open at the end of `line`
+fn comment_state(line: &str, mut in_comment: bool) -> bool {
+    let bytes = line.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if in_comment {
+            match line[pos..].find("*/") {
+                Some(idx) => {
+                    pos += idx + 2;
+                    in_comment = false;
+                }
+                None => return true,
+            }
+            continue;
+        }
+        match bytes[pos] {
+            b'/' if bytes.get(pos + 1) == Some(&b'/') => return false,
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                in_comment = true;
+                pos += 2;
+            }
+            quote @ (b'"' | b'\'') => pos = skip_quoted(bytes, pos, quote),
+            _ => pos += 1,
+        }
+    }
+    in_comment
+}
+
+/// Remove comments from a directive line, leaving string literals intact
+fn strip_comments(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut result = String::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'/' if bytes.get(pos + 1) == Some(&b'/') => break,
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                pos = text[pos + 2..].find("*/").map_or(bytes.len(), |idx| pos + idx + 4);
+                result.push(' ');
+            }
+            quote @ (b'"' | b'\'') => {
+                let end = skip_quoted(bytes, pos, quote);
+                result.push_str(&text[pos..end]);
+                pos = end;
+            }
+            _ => {
+                let len = text[pos..].chars().next().map_or(1, char::len_utf8);
+                result.push_str(&text[pos..pos + len]);
+                pos += len;
+            }
+        }
+    }
+    result
+}
+
+/// Offset just past the string or character literal starting at `start`.
+/// Literals end at the end of the line if unterminated.
+fn skip_quoted(bytes: &[u8], start: usize, quote: u8) -> usize {
+    let mut pos = start + 1;
+    while pos < bytes.len() && bytes[pos] != quote && bytes[pos] != b'\n' {
+        pos += if bytes[pos] == b'\\' { 2 } else { 1 };
+    }
+    (pos + 1).min(bytes.len())
+}
+
+/// Arguments of a macro call whose `(` is at `open`, split at top-level
+/// commas, and the offset just past the closing `)`
+fn split_args(text: &str, open: usize) -> Option<(Vec<String>, usize)> {
+    let bytes = text.as_bytes();
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut arg_start = open + 1;
+    let mut pos = open + 1;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'(' => depth += 1,
+            b')' if depth == 0 => {
+                args.push(text[arg_start..pos].to_string());
+                return Some((args, pos + 1));
+            }
+            b')' => depth -= 1,
+            b',' if depth == 0 => {
+                args.push(text[arg_start..pos].to_string());
+                arg_start = pos + 1;
+            }
+            quote @ (b'"' | b'\'') => {
+                pos = skip_quoted(bytes, pos, quote);
+                continue;
+            }
+            _ => {}
+        }
+        pos += 1;
+    }
+    None
+}
+
+/// Replace parameter names in a macro body with their arguments
+fn substitute(body: &str, params: &[String], args: &[String]) -> String {
+    let bytes = body.as_bytes();
+    let mut result = String::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            quote @ (b'"' | b'\'') => {
+                let end = skip_quoted(bytes, pos, quote);
+                result.push_str(&body[pos..end]);
+                pos = end;
+            }
+            b if b.is_ascii_alphanumeric() || b == b'_' => {
+                let end = body[pos..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .map_or(body.len(), |idx| pos + idx);
+                let word = &body[pos..end];
+                match params.iter().position(|p| p == word) {
+                    Some(idx) => result.push_str(&args[idx]),
+                    None => result.push_str(word),
+                }
+                pos = end;
+            }
+            _ => {
+                let len = body[pos..].chars().next().map_or(1, char::len_utf8);
+                result.push_str(&body[pos..pos + len]);
+                pos += len;
+            }
+        }
+    }
+    result
+}
+
+/// Offset of `word` as a whole identifier in `text`
+fn find_ident(text: &str, word: &str) -> Option<usize> {
+    let is_ident_byte = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    text.match_indices(word).map(|(idx, _)| idx).find(|&idx| {
+        let before = idx.checked_sub(1).map(|i| text.as_bytes()[i]);
+        let after = text.as_bytes().get(idx + word.len()).copied();
+        !before.is_some_and(is_ident_byte) && !after.is_some_and(is_ident_byte)
+    })
+}
+
+/// Split a leading identifier off `text`
+fn split_ident(text: &str) -> (&str, &str) {
+    let end = text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(text.len());
+    if text.as_bytes().first().is_some_and(|b| b.is_ascii_digit()) {
+        return ("", text);
+    }
+    text.split_at(end)
+}
+
+fn expect_name(text: &str) -> Result<&str> {
+    let (name, rest) = split_ident(text);
+    if name.is_empty() || !rest.trim().is_empty() {
+        return Err(anyhow!("Expected a macro name, got '{}'", text));
+    }
+    Ok(name)
+}
+
+fn is_ident(text: &str) -> bool {
+    let (name, rest) = split_ident(text);
+    !name.is_empty() && rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess(source: &str) -> Result<String> {
+        Ok(Preprocessor::new().run(source, "test.HC")?.text)
+    }
+
+    fn words(text: &str) -> Vec<&str> {
+        text.split_whitespace().collect()
+    }
+
+    fn squash(text: &str) -> String {
+        text.split_whitespace().collect()
+    }
+
+    #[test]
+    fn test_macros_are_expanded() {
+        let source = "#define FEE 30\n#define MUL(a, b) ((a) * (b))\n#define SQ(x) MUL(x, x)\nU64 f = SQ(FEE + 1) + MUL; \"FEE\";\n";
+        assert_eq!(squash(&preprocess(source).unwrap()), "U64f=((30+1)*(30+1))+MUL;\"FEE\";");
+
+        // A macro is not expanded inside its own expansion
+        assert_eq!(squash(&preprocess("#define loop loop + 1\nloop").unwrap()), "loop+1");
+
+        let err = preprocess("#define ADD(a, b) a + b\nADD(1)").unwrap_err();
+        assert_eq!(format!("{:#}", err), "test.HC:2:1: Macro ADD expects 2 arguments, got 1");
+    }
+
+    #[test]
+    fn test_conditional_compilation() {
+        let source = "\
+#define DEVNET
+#ifdef DEVNET
+devnet
+#else
+mainnet
+#endif
+#ifndef DEVNET
+no
+#elif VERSION >= 2 && defined(DEVNET)
+v2
+#else
+old
+#endif
+#undef DEVNET
+#if defined DEVNET || 0
+no
+#endif
+";
+        assert_eq!(words(&preprocess(source).unwrap()), ["devnet", "old"]);
+
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.define_arg("VERSION=2").unwrap();
+        let text = preprocessor.run(source, "test.HC").unwrap().text;
+        assert_eq!(words(&text), ["devnet", "v2"]);
+
+        assert!(preprocess("#if 1\nx\n").unwrap_err().to_string().contains("test.HC:1: #if without #endif"));
+        assert!(preprocess("#endif\n").is_err());
+        assert!(preprocess("#error unsupported\n").is_err());
+        assert!(preprocess("#if 0\n#error skipped\n#endif\n").is_ok());
+    }
+
+    #[test]
+    fn test_locations_map_to_the_source() {
+        let source = "#define KEY \\\n    0x42\nU64 a;\n  U64 b = KEY;\n";
+        let result = Preprocessor::new().run(source, "keys.HC").unwrap();
+        let offset = |needle: &str| result.text.find(needle).unwrap();
+
+        assert_eq!(result.map.locate(offset("a;")).to_string(), "keys.HC:3:5");
+        assert_eq!(result.map.locate(offset("b =")).to_string(), "keys.HC:4:7");
+        // Expanded text maps to the macro use
+        assert_eq!(result.map.locate(offset("0x42")).to_string(), "keys.HC:4:11");
+    }
+
+    #[test]
+    fn test_includes_are_resolved() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib = dir.path().join("lib");
+        fs::create_dir(&lib).unwrap();
+        fs::write(dir.path().join("fees.HC"), "#pragma once\n#define FEE 30\nU64 fee = FEE;\n").unwrap();
+        fs::write(lib.join("token.HC"), "#ifndef TOKEN_HC\n#define TOKEN_HC\n#include \"../fees.HC\"\nU64 token;\n#endif\n").unwrap();
+        fs::write(lib.join("fees.HC"), "wrong\n").unwrap();
+        let main = dir.path().join("main.HC");
+        let source = "#include \"fees.HC\"\n#include <token.HC>\n#include \"fees.HC\"\n#include <token.HC>\nU64 x = FEE;\n";
+
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.add_include_path(&lib);
+        let result = preprocessor.run(source, main.to_str().unwrap()).unwrap();
+        // Quoted includes look next to the including file before -I, and
+        // each file is included once however it is named
+        assert_eq!(squash(&result.text), "U64fee=30;U64token;U64x=30;");
+
+        let fee = result.map.locate(result.text.find("fee").unwrap());
+        assert!(fee.file.ends_with("fees.HC") && fee.line == 3, "{}", fee);
+        let token = result.map.locate(result.text.find("token").unwrap());
+        assert!(token.file.ends_with("token.HC") && token.line == 4, "{}", token);
+        let x = result.map.locate(result.text.find("x =").unwrap());
+        assert_eq!((x.line, x.column), (5, 5));
+        assert!(x.file.ends_with("main.HC"));
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.HC"), "#include \"b.HC\"\n").unwrap();
+        fs::write(dir.path().join("b.HC"), "\n#include \"a.HC\"\n").unwrap();
+        let main = dir.path().join("main.HC");
+        let main = main.to_str().unwrap();
+
+        let err = Preprocessor::new().run("#include \"a.HC\"\n", main).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("Include cycle: "), "{}", message);
+        assert!(message.contains("a.HC -> ") && message.contains("b.HC -> "), "{}", message);
+        assert!(message.contains("b.HC:2"), "{}", message);
+
+        let err = Preprocessor::new().run("\n#include <missing.HC>\n", main).unwrap_err();
+        assert!(format!("{:#}", err).ends_with("main.HC:2: Cannot find include file missing.HC"));
+        assert!(Preprocessor::new().run("#include missing.HC\n", main).is_err());
+
+        // A guarded header may include itself indirectly
+        fs::write(dir.path().join("c.HC"), "#ifndef C_HC\n#define C_HC\n#include \"d.HC\"\nc\n#endif\n").unwrap();
+        fs::write(dir.path().join("d.HC"), "#include \"c.HC\"\nd\n").unwrap();
+        let text = Preprocessor::new().run("#include \"c.HC\"\n", main).unwrap().text;
+        assert_eq!(words(&text), ["d", "c"]);
+
+        // Includes in skipped blocks are not resolved
+        assert!(Preprocessor::new().run("#if 0\n#include \"missing.HC\"\n#endif\n", main).is_ok());
+    }
+}
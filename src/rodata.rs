@@ -0,0 +1,81 @@
+//! Read-only data section
+//!
+//! Collects the contents of `.rodata`. SBF programs have no writable static
+//! data, so everything with a static address lives here and is addressed
+//! through `lddw` relocations. String literals are interned, so each
+//! distinct string is stored once.
+use std::collections::HashMap;
+
+/// Contents of `.rodata` under construction
+#[derive(Debug, Clone, Default)]
+pub struct Rodata {
+    data: Vec<u8>,
+    symbols: Vec<(String, usize)>,
+    strings: HashMap<Vec<u8>, usize>,
+}
+
+impl Rodata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `bytes` at the next offset aligned to `align`, returning the
+    /// offset. Named items get a symbol.
+    pub fn push(&mut self, name: Option<&str>, bytes: &[u8], align: usize) -> usize {
+        let align = align.max(1);
+        let offset = self.data.len().div_ceil(align) * align;
+        self.data.resize(offset, 0);
+        self.data.extend_from_slice(bytes);
+        if let Some(name) = name {
+            self.symbols.push((name.to_string(), offset));
+        }
+        offset
+    }
+
+    /// Offset of a NUL-terminated copy of `bytes`, adding it on first use.
+    /// Each string gets a `.str.N` symbol so listings can refer to it.
+    pub fn intern(&mut self, bytes: &[u8]) -> usize {
+        if let Some(&offset) = self.strings.get(bytes) {
+            return offset;
+        }
+        let name = format!(".str.{}", self.strings.len());
+        let mut data = bytes.to_vec();
+        data.push(0);
+        let offset = self.push(Some(&name), &data, 1);
+        self.strings.insert(bytes.to_vec(), offset);
+        offset
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Named items and their offsets, in layout order
+    pub fn symbols(&self) -> &[(String, usize)] {
+        &self.symbols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_items_are_aligned() {
+        let mut rodata = Rodata::new();
+        assert_eq!(rodata.push(Some("flag"), &[1], 1), 0);
+        assert_eq!(rodata.push(Some("fee"), &30u64.to_le_bytes(), 8), 8);
+        assert_eq!(rodata.data().len(), 16);
+        assert_eq!(rodata.symbols(), [("flag".to_string(), 0), ("fee".to_string(), 8)]);
+    }
+
+    #[test]
+    fn test_strings_are_deduplicated() {
+        let mut rodata = Rodata::new();
+        assert_eq!(rodata.intern(b"swap ok"), 0);
+        assert_eq!(rodata.intern(b"fail"), 8);
+        assert_eq!(rodata.intern(b"swap ok"), 0);
+        assert_eq!(rodata.data(), b"swap ok\0fail\0");
+        assert_eq!(rodata.symbols()[1], (".str.1".to_string(), 8));
+    }
+}
@@ -0,0 +1,768 @@
+//! Semantic checks
+//!
+//! Rules the parser cannot enforce, checked on the parsed program before
+//! optimization. Errors stop compilation; warnings are returned for the
+//! driver to print. Every `goto` must name a label defined in its function,
+//! and a label nothing jumps to is reported. A jump that lands in the scope
+//! of a local past its initializer leaves the local holding whatever its
+//! stack slot last held, so that is reported too.
+//!
+//! A pointer to a derived class converts implicitly to a pointer to any of
+//! its bases, since the base layout is a prefix of the derived one. Other
+//! implicit conversions between pointers to different classes are reported,
+//! as are switches over an enum that miss enumerators and have no `default`.
+//!
+//! Before the checks, calls to functions defined in the program get their
+//! omitted arguments filled in from the parameter defaults, and a statement
+//! that is just a function's name, `Foo;`, becomes a call. Elsewhere the
+//! name of a function that needs no arguments is ambiguous and reported.
+//! Defaults are resolved where the function is defined, so they may only
+//! name globals, functions, enumerators and syscalls.
+use crate::ast::*;
+use crate::consteval;
+use crate::layout::LayoutEngine;
+use crate::syscalls;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// Position of a statement: its pre-order index and the scopes enclosing it,
+/// outermost first
+#[derive(Debug, Clone)]
+struct Point {
+    pos: usize,
+    scopes: Vec<usize>,
+}
+
+impl Point {
+    /// True if a declaration at `decl` is visible here
+    fn sees(&self, decl: &Point) -> bool {
+        decl.pos < self.pos && decl.scopes.last().is_some_and(|scope| self.scopes.contains(scope))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Scan {
+    labels: HashMap<String, Point>,
+    gotos: Vec<(String, Point)>,
+    inits: Vec<(String, Point)>,
+    pos: usize,
+    next_scope: usize,
+    duplicates: Vec<String>,
+}
+
+/// Fill in omitted arguments, `Foo(1)` or `Foo(, 2)`, from the parameter
+/// defaults, and turn `Foo;` into `Foo()`
+pub fn fill_default_args(program: &mut Program) -> Result<()> {
+    let layouts = LayoutEngine::from_program(program)?;
+    let mut constants = layouts.constants().clone();
+    let mut params = HashMap::new();
+    let mut globals = Vec::new();
+    for item in &program.items {
+        match item {
+            Item::FunctionDef(func) => {
+                params.insert(func.name.clone(), func.params.clone());
+            }
+            Item::GlobalVar(var) => {
+                let value = var.init.as_ref().and_then(|init| consteval::eval(init, &constants, &layouts));
+                if let Some(value) = value {
+                    constants.insert(var.name.clone(), consteval::truncate(value, &layouts.resolve(&var.var_type)));
+                }
+                globals.push(var.name.clone());
+            }
+            _ => {}
+        }
+    }
+
+    for item in &program.items {
+        if let Item::FunctionDef(func) = item {
+            check_defaults(func, |name| {
+                globals.iter().any(|global| global == name) || params.contains_key(name)
+                    || constants.contains_key(name) || syscalls::is_syscall(name)
+            })?;
+        }
+    }
+
+    for item in &mut program.items {
+        match item {
+            Item::FunctionDef(func) => {
+                let mut fill = Fill {
+                    params: &params,
+                    constants: &constants,
+                    layouts: &layouts,
+                    scopes: vec![globals.clone(), func.params.iter().map(|p| p.name.clone()).collect()],
+                    function: &func.name,
+                };
+                fill.block(&mut func.body)?;
+            }
+            Item::GlobalVar(VarDecl { name, init: Some(init), .. }) => {
+                let fill = Fill {
+                    params: &params,
+                    constants: &constants,
+                    layouts: &layouts,
+                    scopes: vec![globals.clone()],
+                    function: name,
+                };
+                fill.expr(init)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check that the defaults of `func` only name what `defined` accepts:
+/// a default is evaluated by the caller, where the parameters don't exist
+fn check_defaults(func: &FunctionDef, defined: impl Fn(&str) -> bool) -> Result<()> {
+    for param in &func.params {
+        let Some(default) = &param.default else {
+            continue;
+        };
+        let mut err = None;
+        default.walk(&mut |e| {
+            let Expr::Ident(id) = e else {
+                return;
+            };
+            if err.is_some() {
+                return;
+            }
+            if func.params.iter().any(|p| p.name == *id) {
+                err = Some(anyhow!("Default for parameter {} of {} refers to parameter {}", param.name, func.name, id));
+            } else if !defined(id) {
+                err = Some(anyhow!("Default for parameter {} of {} uses undefined name {}", param.name, func.name, id));
+            }
+        });
+        if let Some(err) = err {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Walks a function with the names of the locals in scope, which hide
+/// functions of the same name, filling in calls
+struct Fill<'a> {
+    params: &'a HashMap<String, Vec<Param>>,
+    constants: &'a HashMap<String, u64>,
+    layouts: &'a LayoutEngine,
+    /// Globals, then parameters, then one scope per enclosing block
+    scopes: Vec<Vec<String>>,
+    function: &'a str,
+}
+
+impl Fill<'_> {
+    fn block(&mut self, stmts: &mut [Stmt]) -> Result<()> {
+        self.scopes.push(Vec::new());
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) -> Result<()> {
+        if let Stmt::Expr(Expr::Ident(name)) = stmt {
+            if self.callable(name).is_some() {
+                let func = Box::new(Expr::Ident(name.clone()));
+                *stmt = Stmt::Expr(Expr::Call { func, args: Vec::new() });
+            }
+        }
+
+        match stmt {
+            Stmt::VarDecl(var) => {
+                if let Some(init) = &mut var.init {
+                    self.expr(init)?;
+                }
+                self.scopes.last_mut().unwrap().push(var.name.clone());
+            }
+            Stmt::If { condition, then_block, else_block } => {
+                self.expr(condition)?;
+                self.block(then_block)?;
+                if let Some(else_block) = else_block {
+                    self.block(else_block)?;
+                }
+            }
+            Stmt::While { condition, body } | Stmt::DoWhile { body, condition } => {
+                self.expr(condition)?;
+                self.block(body)?;
+            }
+            // The initializer is scoped to the loop
+            Stmt::For { init, condition, increment, body } => {
+                self.scopes.push(Vec::new());
+                if let Some(init) = init {
+                    self.stmt(init)?;
+                }
+                for expr in condition.iter_mut().chain(increment) {
+                    self.expr(expr)?;
+                }
+                self.block(body)?;
+                self.scopes.pop();
+            }
+            Stmt::Switch { value, cases } => {
+                self.expr(value)?;
+                self.scopes.push(Vec::new());
+                for case in cases {
+                    for stmt in &mut case.body {
+                        self.stmt(stmt)?;
+                    }
+                }
+                self.scopes.pop();
+            }
+            Stmt::Block(body) => self.block(body)?,
+            _ => {
+                for expr in stmt.exprs_mut() {
+                    self.expr(expr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parameters of the function `name` refers to here, unless a global,
+    /// parameter or local in scope hides it
+    fn callable(&self, name: &str) -> Option<&Vec<Param>> {
+        self.params.get(name).filter(|_| !self.scopes.iter().flatten().any(|h| h == name))
+    }
+
+    fn expr(&self, expr: &mut Expr) -> Result<()> {
+        match expr {
+            // HolyC would call a function that needs no arguments, where
+            // a function name is otherwise its address
+            Expr::Ident(name) => {
+                if self.callable(name).is_some_and(|params| params.iter().all(|p| p.default.is_some())) {
+                    return Err(anyhow!(
+                        "{} in {} could be a call or an address, as {} needs no arguments; write {}() or &{}",
+                        name, self.function, name, name, name
+                    ));
+                }
+            }
+            Expr::Unary { op: UnaryOp::AddressOf, expr: inner } if matches!(**inner, Expr::Ident(_)) => {}
+            Expr::Call { func, args } => {
+                if !matches!(**func, Expr::Ident(_)) {
+                    self.expr(func)?;
+                }
+                for arg in args {
+                    self.expr(arg)?;
+                }
+            }
+            _ => {
+                for child in expr.children_mut() {
+                    self.expr(child)?;
+                }
+            }
+        }
+
+        let Expr::Call { func, args } = expr else {
+            return Ok(());
+        };
+        let Some((name, params)) = (match &**func {
+            Expr::Ident(name) => self.callable(name).map(|params| (name, params)),
+            _ => None,
+        }) else {
+            if args.contains(&Expr::DefaultArg) {
+                return Err(anyhow!("Call to {} in {} omits an argument, but only functions defined in the program have defaults", func, self.function));
+            }
+            return Ok(());
+        };
+
+        if args.len() > params.len() {
+            return Err(anyhow!("{} takes {} arguments, but {} calls it with {}", name, params.len(), self.function, args.len()));
+        }
+        args.resize(params.len(), Expr::DefaultArg);
+        for (arg, param) in args.iter_mut().zip(params) {
+            if *arg == Expr::DefaultArg {
+                *arg = self.default_arg(name, param)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The default of `param` of `name`, as seen where `name` is defined.
+    /// A name in it that a parameter or local hides here is replaced by
+    /// its value, so the default must be constant then.
+    fn default_arg(&self, name: &str, param: &Param) -> Result<Expr> {
+        let default = param.default.clone().ok_or_else(|| anyhow!(
+            "Call to {} in {} omits argument {}, which has no default", name, self.function, param.name
+        ))?;
+        let mut hidden = None;
+        default.walk(&mut |e| {
+            if let Expr::Ident(id) = e {
+                if hidden.is_none() && self.scopes[1..].iter().flatten().any(|local| local == id) {
+                    hidden = Some(id.clone());
+                }
+            }
+        });
+        let Some(hidden) = hidden else {
+            return Ok(default);
+        };
+        consteval::eval(&default, self.constants, self.layouts)
+            .map(Expr::IntLiteral)
+            .ok_or_else(|| anyhow!(
+                "Default of {} for {} uses {}, which a local of {} hides", param.name, name, hidden, self.function
+            ))
+    }
+}
+
+/// Check every function, returning the warnings
+pub fn check_program(program: &Program) -> Result<Vec<String>> {
+    let layouts = LayoutEngine::from_program(program)?;
+    let mut functions = HashMap::new();
+    let mut globals = HashMap::new();
+    for item in &program.items {
+        match item {
+            Item::FunctionDef(func) => {
+                functions.insert(func.name.as_str(), func);
+            }
+            Item::GlobalVar(var) => {
+                globals.insert(var.name.as_str(), &var.var_type);
+            }
+            _ => {}
+        }
+    }
+
+    let mut warnings = Vec::new();
+    for item in &program.items {
+        if let Item::FunctionDef(func) = item {
+            check_labels(func, &mut warnings)?;
+            let mut check = TypeCheck {
+                layouts: &layouts,
+                functions: &functions,
+                globals: &globals,
+                scopes: vec![func.params.iter().map(|p| (p.name.as_str(), &p.param_type)).collect()],
+                function: func,
+                warnings: &mut warnings,
+            };
+            check.block(&func.body);
+        }
+    }
+    Ok(warnings)
+}
+
+fn check_labels(func: &FunctionDef, warnings: &mut Vec<String>) -> Result<()> {
+    let mut scan = Scan::default();
+    scan.block(&func.body, &mut Vec::new());
+    if let Some(label) = scan.duplicates.first() {
+        return Err(anyhow!("Label {} is defined twice in {}", label, func.name));
+    }
+
+    for (label, from) in &scan.gotos {
+        let to = scan.labels.get(label)
+            .ok_or_else(|| anyhow!("goto to undefined label {} in {}", label, func.name))?;
+        for (var, decl) in &scan.inits {
+            if to.sees(decl) && !from.sees(decl) {
+                warnings.push(format!("goto {} in {} skips the initialization of {}", label, func.name, var));
+            }
+        }
+    }
+
+    let mut unused: Vec<&String> = scan.labels.keys()
+        .filter(|label| !scan.gotos.iter().any(|(target, _)| target == *label))
+        .collect();
+    unused.sort();
+    for label in unused {
+        warnings.push(format!("label {} in {} is never used", label, func.name));
+    }
+    Ok(())
+}
+
+impl Scan {
+    /// Scan `stmts` in a new scope nested in `scopes`
+    fn block<'a>(&mut self, stmts: impl IntoIterator<Item = &'a Stmt>, scopes: &mut Vec<usize>) {
+        scopes.push(self.next_scope);
+        self.next_scope += 1;
+        for stmt in stmts {
+            self.stmt(stmt, scopes);
+        }
+        scopes.pop();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, scopes: &mut Vec<usize>) {
+        let point = Point { pos: self.pos, scopes: scopes.clone() };
+        self.pos += 1;
+
+        match stmt {
+            Stmt::VarDecl(VarDecl { name, init: Some(_), .. }) => self.inits.push((name.clone(), point)),
+            Stmt::Goto(label) => self.gotos.push((label.clone(), point)),
+            Stmt::Label(label) => {
+                let previous = self.labels.insert(label.clone(), point);
+                self.duplicates.extend(previous.map(|_| label.clone()));
+            }
+            Stmt::If { then_block, else_block, .. } => {
+                self.block(then_block, scopes);
+                if let Some(else_block) = else_block {
+                    self.block(else_block, scopes);
+                }
+            }
+            Stmt::While { body, .. } | Stmt::DoWhile { body, .. } | Stmt::Block(body) => self.block(body, scopes),
+            // The initializer is scoped to the loop
+            Stmt::For { init, body, .. } => self.block(init.iter().map(|init| &**init).chain(body), scopes),
+            Stmt::Switch { cases, .. } => self.block(cases.iter().flat_map(|case| &case.body), scopes),
+            _ => {}
+        }
+    }
+}
+
+/// Walks a function with the types of the variables in scope, checking
+/// implicit conversions between class pointers and switches over enums
+struct TypeCheck<'a> {
+    layouts: &'a LayoutEngine,
+    functions: &'a HashMap<&'a str, &'a FunctionDef>,
+    globals: &'a HashMap<&'a str, &'a Type>,
+    scopes: Vec<HashMap<&'a str, &'a Type>>,
+    function: &'a FunctionDef,
+    warnings: &'a mut Vec<String>,
+}
+
+impl<'a> TypeCheck<'a> {
+    fn block(&mut self, stmts: impl IntoIterator<Item = &'a Stmt>) {
+        self.scopes.push(HashMap::new());
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn stmt(&mut self, stmt: &'a Stmt) {
+        match stmt {
+            Stmt::VarDecl(var) => {
+                if let Some(init) = &var.init {
+                    self.expr(init);
+                    self.convert(init, &var.var_type, || format!("initializer of {}", var.name));
+                }
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(&var.name, &var.var_type);
+                }
+            }
+            Stmt::Expr(expr) => self.expr(expr),
+            Stmt::Return(Some(value)) => {
+                self.expr(value);
+                let function = self.function;
+                self.convert(value, &function.return_type, || "return value".to_string());
+            }
+            Stmt::If { condition, then_block, else_block } => {
+                self.expr(condition);
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            Stmt::While { condition, body } | Stmt::DoWhile { body, condition } => {
+                self.expr(condition);
+                self.block(body);
+            }
+            Stmt::For { init, condition, increment, body } => {
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    self.stmt(init);
+                }
+                for expr in condition.iter().chain(increment) {
+                    self.expr(expr);
+                }
+                self.block(body);
+                self.scopes.pop();
+            }
+            Stmt::Switch { value, cases } => {
+                self.expr(value);
+                self.check_enum_switch(value, cases);
+                self.block(cases.iter().flat_map(|case| &case.body));
+            }
+            Stmt::Block(body) => self.block(body),
+            Stmt::Print { args, .. } => {
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Check the assignments and calls in `expr`
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign { target, value } => {
+                if let Some(ty) = self.type_of(target) {
+                    self.convert(value, &ty, || format!("assignment to {}", target));
+                }
+            }
+            Expr::Call { func, args } => {
+                let callee = match &**func {
+                    Expr::Ident(name) => self.functions.get(name.as_str()).copied(),
+                    _ => None,
+                };
+                for (idx, (arg, param)) in args.iter().zip(callee.iter().flat_map(|f| &f.params)).enumerate() {
+                    self.convert(arg, &param.param_type, || format!("argument {} of {}", idx + 1, func));
+                }
+            }
+            _ => {}
+        }
+        for child in expr.children() {
+            self.expr(child);
+        }
+    }
+
+    /// Report the enumerators a switch over an enum without `default` misses
+    fn check_enum_switch(&mut self, value: &Expr, cases: &[SwitchCase]) {
+        let Some(Type::Custom(name)) = self.type_of(value) else { return };
+        let Some(layout) = self.layouts.enum_layout(&name) else { return };
+        if cases.iter().any(|case| case.label == CaseLabel::Default) {
+            return;
+        }
+
+        let signed = !layout.underlying.is_unsigned();
+        let key = |value: u64| if signed { value as i64 as i128 } else { value as i128 };
+        let eval = |expr: &Expr| consteval::eval(expr, self.layouts.constants(), self.layouts).map(key);
+        let covered: Vec<(i128, i128)> = cases.iter()
+            .filter_map(|case| match &case.label {
+                CaseLabel::Value(expr) => eval(expr).map(|value| (value, value)),
+                CaseLabel::Range(low, high) => Some((eval(low)?, eval(high)?)),
+                CaseLabel::Default => None,
+            })
+            .collect();
+
+        let missing: Vec<&str> = layout.values.iter()
+            .filter(|(_, value)| !covered.iter().any(|(low, high)| (*low..=*high).contains(&key(*value))))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if !missing.is_empty() {
+            self.warnings.push(format!(
+                "switch over {} in {} does not handle {}",
+                name, self.function.name, missing.join(", ")
+            ));
+        }
+    }
+
+    /// Report `value` if it is a pointer to a class that is not `to`'s
+    /// pointee or derived from it
+    fn convert(&mut self, value: &Expr, to: &Type, what: impl FnOnce() -> String) {
+        let Some(from) = self.type_of(value) else { return };
+        let (Type::Pointer(from_class), Type::Pointer(to_class)) = (&from, to) else { return };
+        let (Type::Custom(derived), Type::Custom(base)) = (&**from_class, &**to_class) else { return };
+        if !self.layouts.derives_from(derived, base) {
+            self.warnings.push(format!(
+                "{} in {} converts {} to {}, but {} is not a base class of {}",
+                what(), self.function.name, from, to, base, derived
+            ));
+        }
+    }
+
+    /// Static type of `expr`, where it is known
+    fn type_of(&self, expr: &Expr) -> Option<Type> {
+        let field = |class: &Type, member: &str| match class {
+            Type::Custom(name) => self.layouts.class(name)?.field(member).map(|f| f.field_type.clone()),
+            _ => None,
+        };
+        match expr {
+            Expr::Ident(name) => self.scopes.iter().rev()
+                .find_map(|scope| scope.get(name.as_str()))
+                .or_else(|| self.globals.get(name.as_str()))
+                .map(|ty| (*ty).clone()),
+            Expr::StringLiteral(_) => Some(Type::Pointer(Box::new(Type::U8))),
+            Expr::Unary { op: UnaryOp::AddressOf, expr } => Some(Type::Pointer(Box::new(self.type_of(expr)?))),
+            Expr::Unary { op: UnaryOp::Deref, expr } | Expr::Index { expr, .. } => match self.type_of(expr)? {
+                Type::Pointer(inner) | Type::Array(inner, _) => Some(*inner),
+                _ => None,
+            },
+            Expr::Member { expr, member } => field(&self.type_of(expr)?, member),
+            Expr::Arrow { expr, member } => match self.type_of(expr)? {
+                Type::Pointer(class) => field(&class, member),
+                _ => None,
+            },
+            Expr::Call { func, .. } => match &**func {
+                Expr::Ident(name) => self.functions.get(name.as_str()).map(|f| f.return_type.clone()),
+                _ => None,
+            },
+            Expr::Assign { target, .. } => self.type_of(target),
+            Expr::Cast { target_type, .. } => Some(target_type.clone()),
+            Expr::Comma(exprs) => self.type_of(exprs.last()?),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+    use crate::parser::Parser;
+
+    fn check(source: &str) -> Result<Vec<String>> {
+        let tokens: Vec<Token> = Lexer::collect_tokens(source).unwrap()
+            .into_iter().map(|(t, _)| t).collect();
+        check_program(&Parser::new(tokens).parse().unwrap())
+    }
+
+    fn fill(source: &str) -> Result<Program> {
+        let tokens: Vec<Token> = Lexer::collect_tokens(source).unwrap()
+            .into_iter().map(|(t, _)| t).collect();
+        let mut program = Parser::new(tokens).parse().unwrap();
+        fill_default_args(&mut program)?;
+        Ok(program)
+    }
+
+    #[test]
+    fn test_default_args_are_filled_in() {
+        let program = fill(
+            "U0 Foo(I64 a = 1, I64 b = 5) {}
+             U0 f() { Foo(7); Foo(, 2); Foo; }",
+        )
+        .unwrap();
+        let Item::FunctionDef(f) = &program.items[1] else { panic!("Expected function") };
+        let calls: Vec<String> = f.body.iter()
+            .map(|stmt| match stmt {
+                Stmt::Expr(expr) => expr.to_string(),
+                _ => panic!("Expected expression"),
+            })
+            .collect();
+        assert_eq!(calls, ["Foo(7, 5)", "Foo(1, 2)", "Foo(1, 5)"]);
+
+        let err = fill("U0 Foo(I64 a, I64 b = 5) {} U0 f() { Foo(, 2); }").unwrap_err();
+        assert_eq!(err.to_string(), "Call to Foo in f omits argument a, which has no default");
+        let err = fill("U0 Foo(I64 a) {} U0 f() { Foo(1, 2); }").unwrap_err();
+        assert_eq!(err.to_string(), "Foo takes 1 arguments, but f calls it with 2");
+
+        // A local of the same name is not a call, but only in its scope
+        let program = fill("U0 Foo() {} U0 f() { U64 Foo = 1; Foo; }").unwrap();
+        let Item::FunctionDef(f) = &program.items[1] else { panic!("Expected function") };
+        assert_eq!(f.body[1], Stmt::Expr(Expr::Ident("Foo".to_string())));
+        let program = fill("U0 Foo() {} U0 f() { { U64 Foo = 1; } Foo; }").unwrap();
+        let Item::FunctionDef(f) = &program.items[1] else { panic!("Expected function") };
+        assert_eq!(f.body[1], Stmt::Expr(Expr::Call { func: Box::new(Expr::Ident("Foo".to_string())), args: Vec::new() }));
+    }
+
+    #[test]
+    fn test_bare_names_of_functions_without_required_args() {
+        let err = fill("U64 Zero(U64 a = 0) { return a; } U0 f() { U64 x = Zero; }").unwrap_err();
+        assert_eq!(err.to_string(), "Zero in f could be a call or an address, as Zero needs no arguments; write Zero() or &Zero");
+        let err = fill("U64 Zero() { return 0; } U64 (*G)() = Zero;").unwrap_err();
+        assert!(err.to_string().starts_with("Zero in G could be a call or an address"));
+
+        // Calls, `&Zero` and names of functions that need arguments are fine
+        fill("U64 Zero() { return 0; } U64 Id(U64 a) { return a; }
+              U0 f() { U64 x = Zero() + Id(1); U64 (*p)() = &Zero; U64 (*q)(U64) = Id; }").unwrap();
+    }
+
+    #[test]
+    fn test_defaults_resolve_where_the_callee_is_defined() {
+        let source = "U64 SCALE = 3;
+             U64 Mul(U64 a, U64 b = SCALE * 2) { return a * b; }
+             U64 f(U64 SCALE) { return Mul(1) + Mul(2, SCALE); }
+             U64 g() { return Mul(1); }";
+        let program = fill(source).unwrap();
+        let returned: Vec<String> = program.items[2..].iter()
+            .map(|item| match item {
+                Item::FunctionDef(FunctionDef { body, .. }) => match &body[0] {
+                    Stmt::Return(Some(expr)) => expr.to_string(),
+                    _ => panic!("Expected return"),
+                },
+                _ => panic!("Expected function"),
+            })
+            .collect();
+        assert_eq!(returned, ["Mul(1, 6) + Mul(2, SCALE)", "Mul(1, SCALE * 2)"]);
+
+        let err = fill("U64 Base() { return 1; } U64 Add(U64 a, U64 b = Base()) { return a + b; }
+                        U64 f() { U64 Base = 5; return Add(1); }").unwrap_err();
+        assert_eq!(err.to_string(), "Default of b for Add uses Base, which a local of f hides");
+    }
+
+    #[test]
+    fn test_defaults_are_checked_where_they_are_defined() {
+        // Neither default is ever used, and both are still reported
+        let err = fill("U64 Add(U64 a, U64 b = a) { return a + b; }").unwrap_err();
+        assert_eq!(err.to_string(), "Default for parameter b of Add refers to parameter a");
+        let err = fill("U64 Add(U64 a, U64 b = missing + 1) { return a + b; }").unwrap_err();
+        assert_eq!(err.to_string(), "Default for parameter b of Add uses undefined name missing");
+
+        // A parameter hides a global of the same name
+        let err = fill("U64 a = 1; U64 Add(U64 a, U64 b = a) { return a + b; }").unwrap_err();
+        assert_eq!(err.to_string(), "Default for parameter b of Add refers to parameter a");
+
+        fill("enum Mode { FAST, SLOW }; U64 LIMIT = 9; U64 Base() { return 1; }
+              U64 Add(U64 a, U64 b = LIMIT + SLOW + Base(), U64 c = sol_remaining_compute_units()) { return a + b + c; }").unwrap();
+    }
+
+    #[test]
+    fn test_labels_must_be_defined() {
+        let err = check("U0 f() { goto out; }").unwrap_err();
+        assert_eq!(err.to_string(), "goto to undefined label out in f");
+        let err = check("U0 f() { a: a: goto a; }").unwrap_err();
+        assert_eq!(err.to_string(), "Label a is defined twice in f");
+
+        let warnings = check("U0 f() { unused: retry: goto retry; }").unwrap();
+        assert_eq!(warnings, ["label unused in f is never used"]);
+    }
+
+    #[test]
+    fn test_skipped_initializations_warn() {
+        let source = "
+            U64 f(U64 n) {
+                if (n) { goto done; }
+                U64 fee = n * 3;
+                U64 total;
+                { U64 inner = 1; n += inner; }
+            done:
+                return fee;
+            }";
+        assert_eq!(check(source).unwrap(), ["goto done in f skips the initialization of fee"]);
+
+        // Jumping back over a declaration, or within its scope, is fine
+        let source = "
+            U64 g(U64 n) {
+            again:
+                U64 step = 1;
+                n -= step;
+                if (n > 10) { goto again; }
+                for (U64 i = 0; i < n; i++) { U64 x = i; if (x) { goto next; } next: n++; }
+                return n;
+            }";
+        assert!(check(source).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_class_pointer_conversions() {
+        let source = "
+            class CHeader { U8 bump; };
+            class CVault : CHeader { U64 amount; };
+            class CLocked : CVault { U64 until; };
+            class CPool : CHeader { U64 fee; };
+            U8 Bump(CHeader *header) { return header->bump; }
+            CHeader *Upcast(CLocked *locked) {
+                CVault *vault = locked;
+                CHeader *header;
+                header = vault;
+                Bump(locked);
+                return locked;
+            }
+            U0 Mixed(CHeader *header, CPool *pool) {
+                CVault *vault = header;
+                pool = vault;
+            }";
+        assert_eq!(check(source).unwrap(), [
+            "initializer of vault in Mixed converts CHeader* to CVault*, but CVault is not a base class of CHeader",
+            "assignment to pool in Mixed converts CVault* to CPool*, but CPool is not a base class of CVault",
+        ]);
+    }
+
+    #[test]
+    fn test_enum_switches_must_be_exhaustive() {
+        let source = "
+            enum Tag : U8 { INIT = 1, DEPOSIT, WITHDRAW, CLOSE = 0x10 };
+            class Ix { Tag tag; };
+            U64 Partial(Ix *ix) {
+                switch (ix->tag) {
+                    case INIT: return 1;
+                    case DEPOSIT: return 2;
+                }
+                return 0;
+            }
+            U64 Covered(Tag tag) {
+                switch (tag) {
+                    case INIT...WITHDRAW: return 1;
+                    case CLOSE: return 2;
+                }
+                switch (tag) {
+                    case INIT: return 3;
+                    default: return 4;
+                }
+            }";
+        assert_eq!(check(source).unwrap(), ["switch over Tag in Partial does not handle WITHDRAW, CLOSE"]);
+    }
+}
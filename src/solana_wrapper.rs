@@ -1,7 +1,7 @@
-/// Solana program wrapper for HolyC-compiled code
-///
-/// This module provides the runtime integration between HolyC-compiled
-/// BPF bytecode and the Solana program interface.
+//! Solana program wrapper for HolyC-compiled code
+//!
+//! This module provides the runtime integration between HolyC-compiled
+//! BPF bytecode and the Solana program interface.
 
 use solana_program::{
     account_info::AccountInfo,
@@ -12,7 +12,7 @@ use solana_program::{
     pubkey::Pubkey,
 };
 
-/// Solana entrypoint macro generates the required program entry function
+// Solana entrypoint macro generates the required program entry function
 entrypoint!(process_instruction);
 
 /// Main entry point for the Solana program
@@ -51,7 +51,7 @@ pub fn process_instruction(
     }
 }
 
-/// External function that will be provided by the HolyC-compiled BPF code
+// External function that will be provided by the HolyC-compiled BPF code
 extern "C" {
     fn holyc_entrypoint(
         accounts: *const u8,
@@ -63,6 +63,7 @@ extern "C" {
 
 /// Helper functions for HolyC code to call Solana runtime functions
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn solana_log(message: *const u8, len: u64) {
     if message.is_null() || len == 0 {
         return;
@@ -77,6 +78,7 @@ pub extern "C" fn solana_log(message: *const u8, len: u64) {
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn solana_read_u64_le(data: *const u8, offset: u64) -> u64 {
     if data.is_null() {
         return 0;
@@ -89,6 +91,7 @@ pub extern "C" fn solana_read_u64_le(data: *const u8, offset: u64) -> u64 {
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn solana_write_u64_le(data: *mut u8, offset: u64, value: u64) {
     if data.is_null() {
         return;
@@ -101,6 +104,7 @@ pub extern "C" fn solana_write_u64_le(data: *mut u8, offset: u64, value: u64) {
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn solana_memcpy(dst: *mut u8, src: *const u8, len: u64) {
     if dst.is_null() || src.is_null() || len == 0 {
         return;
@@ -112,6 +116,7 @@ pub extern "C" fn solana_memcpy(dst: *mut u8, src: *const u8, len: u64) {
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn solana_memset(dst: *mut u8, value: u8, len: u64) {
     if dst.is_null() || len == 0 {
         return;
@@ -130,8 +135,10 @@ pub struct CAccountInfo {
     pub data_len: u64,
     pub data: *mut u8,
     pub owner: [u8; 32],       // Pubkey (32 bytes)
+    pub rent_epoch: u64,
     pub is_signer: u8,         // Bool as u8
     pub is_writable: u8,       // Bool as u8
+    pub executable: u8,        // Bool as u8
 }
 
 impl CAccountInfo {
@@ -143,8 +150,10 @@ impl CAccountInfo {
             data_len: account.data_len() as u64,
             data: account.data.borrow_mut().as_mut_ptr(),
             owner: account.owner.to_bytes(),
+            rent_epoch: account.rent_epoch,
             is_signer: account.is_signer as u8,
             is_writable: account.is_writable as u8,
+            executable: account.executable as u8,
         }
     }
 }
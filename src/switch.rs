@@ -0,0 +1,303 @@
+//! Switch dispatch planning
+//!
+//! Case labels are folded to constants and merged into sorted, disjoint
+//! ranges, which are then turned into a tree of comparisons. Dense switches
+//! subtract their lowest value and reject everything past the highest with a
+//! single unsigned compare, so the tree only ever sees a small index. SBF has
+//! no indirect jump (`callx` pushes a call frame), so this bounds-checked
+//! branch tree takes the place of a jump table. Sparse switches binary-search
+//! the original values.
+use crate::ast::*;
+use crate::consteval;
+use crate::layout::LayoutEngine;
+use anyhow::{anyhow, Result};
+
+/// At most this many single values are tested one after another rather
+/// than binary-searched
+const LINEAR_CASES: usize = 3;
+
+/// Matched values `low..=high` and the index of the case they jump to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaseRange {
+    pub low: i128,
+    pub high: i128,
+    pub target: usize,
+}
+
+/// Decision tree over the switch value. Targets are case indices; None
+/// leaves the switch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dispatch {
+    Goto(Option<usize>),
+    /// Take `target` if the value equals `key`, else continue with `other`
+    Equals {
+        key: i128,
+        target: Option<usize>,
+        other: Box<Dispatch>,
+    },
+    /// Continue with `below` if the value is less than `pivot`, else `above`
+    Split {
+        pivot: i128,
+        below: Box<Dispatch>,
+        above: Box<Dispatch>,
+    },
+}
+
+impl Dispatch {
+    /// Fewest and most jumps taken to reach a case
+    pub fn jumps(&self) -> (u64, u64) {
+        match self {
+            Dispatch::Goto(_) => (1, 1),
+            Dispatch::Equals { other, .. } => (1, other.jumps().1 + 1),
+            Dispatch::Split { below, above, .. } => {
+                let (below_best, below_worst) = below.jumps();
+                let (above_best, above_worst) = above.jumps();
+                (below_best.min(above_best) + 1, below_worst.max(above_worst) + 1)
+            }
+        }
+    }
+}
+
+/// Compiled form of a switch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwitchPlan {
+    /// Subtracted from the value before dispatch, with the largest index
+    /// still inside the table. The rebased value is compared unsigned and
+    /// anything past the limit takes the default.
+    pub rebase: Option<(i128, u64)>,
+    /// Compare signed; never set together with `rebase`
+    pub signed: bool,
+    pub tree: Dispatch,
+    /// Index of the `default:` case
+    pub default: Option<usize>,
+}
+
+impl SwitchPlan {
+    /// Plan the dispatch for `cases`, comparing signed if the switch value
+    /// has a signed type
+    pub fn new(cases: &[SwitchCase], signed: bool, layouts: &LayoutEngine) -> Result<Self> {
+        let key = |expr: &Expr| -> Result<i128> {
+            let value = consteval::eval(expr, layouts.constants(), layouts)
+                .ok_or_else(|| anyhow!("Case label {} is not a constant expression", expr))?;
+            Ok(if signed { value as i64 as i128 } else { value as i128 })
+        };
+
+        let mut default = None;
+        let mut ranges = Vec::new();
+        for (target, case) in cases.iter().enumerate() {
+            let (low, high) = match &case.label {
+                CaseLabel::Value(expr) => (key(expr)?, key(expr)?),
+                CaseLabel::Range(low, high) => (key(low)?, key(high)?),
+                CaseLabel::Default => {
+                    if default.replace(target).is_some() {
+                        return Err(anyhow!("Multiple default labels in switch"));
+                    }
+                    continue;
+                }
+            };
+            if low > high {
+                return Err(anyhow!("Empty case range {}...{}", low, high));
+            }
+            ranges.push(CaseRange { low, high, target });
+        }
+
+        ranges.sort_by_key(|range| range.low);
+        for pair in ranges.windows(2) {
+            if pair[1].low <= pair[0].high {
+                return Err(anyhow!("Duplicate case value {} in switch", pair[1].low));
+            }
+        }
+        // Neighbouring ranges that go to the same case need one test
+        ranges.dedup_by(|next, prev| {
+            let merge = prev.high + 1 == next.low && prev.target == next.target;
+            if merge {
+                prev.high = next.high;
+            }
+            merge
+        });
+
+        let (Some(first), Some(last)) = (ranges.first(), ranges.last()) else {
+            return Ok(Self { rebase: None, signed, tree: Dispatch::Goto(default), default });
+        };
+        let span = last.high - first.low;
+        let covered: i128 = ranges.iter().map(|range| range.high - range.low + 1).sum();
+        let dense = ranges.len() > 1 && span <= i32::MAX as i128 && covered * 2 > span;
+
+        if dense {
+            let base = first.low;
+            let rebased: Vec<CaseRange> = ranges.iter()
+                .map(|range| CaseRange { low: range.low - base, high: range.high - base, ..*range })
+                .collect();
+            let tree = build(&rebased, (0, span), default);
+            return Ok(Self { rebase: Some((base, span as u64)), signed: false, tree, default });
+        }
+
+        let known = if signed {
+            (i64::MIN as i128, i64::MAX as i128)
+        } else {
+            (0, u64::MAX as i128)
+        };
+        Ok(Self { rebase: None, signed, tree: build(&ranges, known, default), default })
+    }
+
+    /// Fewest and most instructions from the first compare to a case
+    pub fn dispatch_len(&self) -> (u64, u64) {
+        let (best, worst) = self.tree.jumps();
+        match self.rebase {
+            // The subtraction and the bounds check come first; an
+            // out-of-range value leaves at the check
+            Some(_) => (2, worst + 2),
+            None => (best, worst),
+        }
+    }
+}
+
+/// Tree for `ranges` when the value is known to lie in `known`. Values in
+/// no range go to `default`.
+fn build(ranges: &[CaseRange], known: (i128, i128), default: Option<usize>) -> Dispatch {
+    match ranges {
+        [] => Dispatch::Goto(default),
+
+        [range] if range.low <= known.0 && range.high >= known.1 => Dispatch::Goto(Some(range.target)),
+
+        [range] if range.low == range.high => Dispatch::Equals {
+            key: range.low,
+            target: Some(range.target),
+            other: Box::new(Dispatch::Goto(default)),
+        },
+
+        [range] => {
+            let mut tree = Dispatch::Goto(Some(range.target));
+            if range.high < known.1 {
+                tree = Dispatch::Split {
+                    pivot: range.high + 1,
+                    below: Box::new(tree),
+                    above: Box::new(Dispatch::Goto(default)),
+                };
+            }
+            if range.low > known.0 {
+                tree = Dispatch::Split {
+                    pivot: range.low,
+                    below: Box::new(Dispatch::Goto(default)),
+                    above: Box::new(tree),
+                };
+            }
+            tree
+        }
+
+        _ if ranges.len() <= LINEAR_CASES && ranges.iter().all(|range| range.low == range.high) => {
+            // When the values fill the known range the last test is implied
+            let (tests, last) = match ranges.split_last() {
+                Some((last, tests)) if known.1 - known.0 + 1 == ranges.len() as i128 => {
+                    (tests, Dispatch::Goto(Some(last.target)))
+                }
+                _ => (ranges, Dispatch::Goto(default)),
+            };
+            tests.iter().rev().fold(last, |other, range| Dispatch::Equals {
+                key: range.low,
+                target: Some(range.target),
+                other: Box::new(other),
+            })
+        }
+
+        _ => {
+            let mid = ranges.len() / 2;
+            let pivot = ranges[mid].low;
+            Dispatch::Split {
+                pivot,
+                below: Box::new(build(&ranges[..mid], (known.0, pivot - 1), default)),
+                above: Box::new(build(&ranges[mid..], (pivot, known.1), default)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(label: CaseLabel) -> SwitchCase {
+        SwitchCase { label, body: Vec::new() }
+    }
+
+    fn value(n: u64) -> CaseLabel {
+        CaseLabel::Value(Expr::IntLiteral(n))
+    }
+
+    /// Case reached by `value`, walking the tree the way the generated code does
+    fn dispatch(plan: &SwitchPlan, value: i128) -> Option<usize> {
+        let mut value = value;
+        if let Some((base, limit)) = plan.rebase {
+            value -= base;
+            if value < 0 || value > limit as i128 {
+                return plan.default;
+            }
+        }
+        let mut tree = &plan.tree;
+        loop {
+            match tree {
+                Dispatch::Goto(target) => return *target,
+                Dispatch::Equals { key, target, other } => {
+                    if value == *key {
+                        return *target;
+                    }
+                    tree = other;
+                }
+                Dispatch::Split { pivot, below, above } => {
+                    tree = if value < *pivot { below } else { above };
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dense_cases_are_rebased() {
+        let cases = [
+            case(value(10)),
+            case(CaseLabel::Range(Expr::CharLiteral(b'A'), Expr::CharLiteral(b'Z'))),
+            case(value(11)),
+            case(CaseLabel::Default),
+            case(value(12)),
+        ];
+        let plan = SwitchPlan::new(&cases, false, &LayoutEngine::new()).unwrap();
+        assert!(plan.rebase.is_none(), "10..12 and 65..90 are too far apart");
+
+        let cases = [case(value(3)), case(value(4)), case(value(6)), case(CaseLabel::Default), case(value(5))];
+        let plan = SwitchPlan::new(&cases, false, &LayoutEngine::new()).unwrap();
+        assert_eq!(plan.rebase, Some((3, 3)));
+        let targets: Vec<_> = (0..9).map(|v| dispatch(&plan, v)).collect();
+        assert_eq!(targets, [Some(3), Some(3), Some(3), Some(0), Some(1), Some(4), Some(2), Some(3), Some(3)]);
+    }
+
+    #[test]
+    fn test_sparse_cases_are_searched() {
+        let cases = [
+            case(value(0x8f5c_5b58_5ef4_5f4a)),
+            case(value(7)),
+            case(CaseLabel::Range(Expr::IntLiteral(1000), Expr::IntLiteral(2000))),
+            case(value(1 << 40)),
+            case(CaseLabel::Value(Expr::Unary { op: UnaryOp::Neg, expr: Box::new(Expr::IntLiteral(5)) })),
+        ];
+        let plan = SwitchPlan::new(&cases, true, &LayoutEngine::new()).unwrap();
+        assert!(plan.rebase.is_none());
+        assert_eq!(dispatch(&plan, 0x8f5c_5b58_5ef4_5f4a_u64 as i64 as i128), Some(0));
+        assert_eq!(dispatch(&plan, 7), Some(1));
+        assert_eq!(dispatch(&plan, 1500), Some(2));
+        assert_eq!(dispatch(&plan, 2001), None);
+        assert_eq!(dispatch(&plan, 1 << 40), Some(3));
+        assert_eq!(dispatch(&plan, -5), Some(4));
+        assert_eq!(dispatch(&plan, -4), None);
+        // Two levels of search, a check at each end of a range, then the jump
+        assert_eq!(plan.dispatch_len().1, 5);
+    }
+
+    #[test]
+    fn test_invalid_labels() {
+        let layouts = LayoutEngine::new();
+        let err = SwitchPlan::new(&[case(value(1)), case(CaseLabel::Range(Expr::IntLiteral(0), Expr::IntLiteral(3)))], false, &layouts);
+        assert_eq!(err.unwrap_err().to_string(), "Duplicate case value 1 in switch");
+        assert!(SwitchPlan::new(&[case(CaseLabel::Default), case(CaseLabel::Default)], false, &layouts).is_err());
+        let err = SwitchPlan::new(&[case(CaseLabel::Value(Expr::Ident("n".to_string())))], false, &layouts);
+        assert_eq!(err.unwrap_err().to_string(), "Case label n is not a constant expression");
+    }
+}
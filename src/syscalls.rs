@@ -0,0 +1,125 @@
+//! Solana syscalls callable from HolyC
+//!
+//! A syscall is invoked with `call imm`, where `imm` is the murmur3 hash of
+//! the syscall's name. The runtime resolves the hash when the program is
+//! loaded.
+
+/// Syscalls registered by the runtime for SBF v1 programs
+pub const SYSCALLS: &[&str] = &[
+    "abort",
+    "sol_panic_",
+    "sol_log_",
+    "sol_log_64_",
+    "sol_log_pubkey",
+    "sol_log_compute_units_",
+    "sol_log_data",
+    // Program addresses
+    "sol_create_program_address",
+    "sol_try_find_program_address",
+    // Hashing and signatures
+    "sol_sha256",
+    "sol_keccak256",
+    "sol_blake3",
+    "sol_poseidon",
+    "sol_secp256k1_recover",
+    // Elliptic curves and big integers
+    "sol_curve_validate_point",
+    "sol_curve_group_op",
+    "sol_curve_multiscalar_mul",
+    "sol_alt_bn128_group_op",
+    "sol_alt_bn128_compression",
+    "sol_big_mod_exp",
+    // Sysvars
+    "sol_get_clock_sysvar",
+    "sol_get_epoch_schedule_sysvar",
+    "sol_get_fees_sysvar",
+    "sol_get_rent_sysvar",
+    "sol_get_last_restart_slot",
+    "sol_get_epoch_rewards_sysvar",
+    "sol_get_sysvar",
+    "sol_get_epoch_stake",
+    // Memory
+    "sol_memcpy_",
+    "sol_memmove_",
+    "sol_memset_",
+    "sol_memcmp_",
+    "sol_alloc_free_",
+    // Cross-program invocation and instruction context
+    "sol_invoke_signed_c",
+    "sol_invoke_signed_rust",
+    "sol_set_return_data",
+    "sol_get_return_data",
+    "sol_get_stack_height",
+    "sol_get_processed_sibling_instruction",
+    "sol_remaining_compute_units",
+];
+
+pub fn is_syscall(name: &str) -> bool {
+    SYSCALLS.contains(&name)
+}
+
+/// Immediate used to call the named syscall
+pub fn hash(name: &str) -> u32 {
+    murmur3_32(name.as_bytes(), 0)
+}
+
+/// Name of the syscall whose hash is `hash`
+pub fn by_hash(hash: u32) -> Option<&'static str> {
+    SYSCALLS.iter().copied().find(|name| self::hash(name) == hash)
+}
+
+fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k |= (*byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^ (h >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syscall_hashes() {
+        assert_eq!(hash("sol_log_"), 0x207559bd);
+        assert_eq!(hash("sol_log_64_"), 0x5c2a3178);
+        assert_eq!(by_hash(0x207559bd), Some("sol_log_"));
+        assert_eq!(by_hash(0xffffffff), None);
+    }
+
+    #[test]
+    fn test_table_matches_runtime_hashes() {
+        for name in SYSCALLS {
+            assert_eq!(hash(name), solana_sbpf::ebpf::hash_symbol_name(name.as_bytes()), "{}", name);
+        }
+        assert_eq!(by_hash(0x3b97b73c), Some("sol_get_fees_sysvar"));
+        assert_eq!(by_hash(0xc4947c21), Some("sol_poseidon"));
+        assert_eq!(by_hash(0xdd1c41a6), Some("sol_curve_group_op"));
+        assert_eq!(by_hash(0xae0c318b), Some("sol_alt_bn128_group_op"));
+        assert_eq!(by_hash(0x780e4c15), Some("sol_big_mod_exp"));
+        assert_eq!(by_hash(0xedef5aee), Some("sol_remaining_compute_units"));
+    }
+}
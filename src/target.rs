@@ -0,0 +1,50 @@
+//! Properties of the SBF virtual machine that the compiler must respect
+//!
+//! Every function call gets a fixed-size stack frame addressed through R10,
+//! and the VM aborts the program when the call depth exceeds its limit.
+//! Indirect calls name their register differently across SBF versions.
+
+/// Where `callx` takes the register holding its target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallxEncoding {
+    /// In the immediate, as SBF v1 expects
+    Imm,
+    /// In the source register field, as SBF v2 and later expect
+    Src,
+}
+
+/// Limits of the execution target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    /// Bytes of stack available to each function
+    pub frame_size: usize,
+    /// Maximum number of nested frames, including the entrypoint
+    pub max_call_depth: usize,
+    /// Encoding of the `callx` register
+    pub callx: CallxEncoding,
+}
+
+impl Target {
+    /// Solana SBF: 4 KiB frames, 64 nested calls
+    pub fn sbf() -> Self {
+        Self {
+            frame_size: 4096,
+            max_call_depth: 64,
+            callx: CallxEncoding::Imm,
+        }
+    }
+
+    /// SBF v2: as v1, but `callx` names its register in the source field
+    pub fn sbf_v2() -> Self {
+        Self {
+            callx: CallxEncoding::Src,
+            ..Self::sbf()
+        }
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self::sbf()
+    }
+}
@@ -0,0 +1,112 @@
+//! Test harness that runs compiled programs in the sBPF interpreter
+//!
+//! Loads an ELF produced by the compiler into `solana-sbpf` with the input
+//! region mapped at its usual address, and reports the value left in R0
+//! and the compute units consumed. `abort` fails the run; the logging
+//! syscalls do nothing.
+use solana_sbpf::{
+    aligned_memory::AlignedMemory,
+    declare_builtin_function,
+    ebpf,
+    elf::Executable,
+    error::ProgramResult,
+    memory_region::{MemoryMapping, MemoryRegion},
+    program::BuiltinProgram,
+    vm::{Config, ContextObject, EbpfVm},
+};
+use std::sync::Arc;
+
+/// Compute units a single run may use
+const CU_LIMIT: u64 = 200_000;
+
+/// The instruction meter, in compute units
+struct Meter {
+    remaining: u64,
+}
+
+impl ContextObject for Meter {
+    fn trace(&mut self, _state: [u64; 12]) {}
+
+    fn consume(&mut self, amount: u64) {
+        self.remaining = self.remaining.saturating_sub(amount);
+    }
+
+    fn get_remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+declare_builtin_function!(
+    Abort,
+    fn rust(
+        _meter: &mut Meter,
+        _a: u64,
+        _b: u64,
+        _c: u64,
+        _d: u64,
+        _e: u64,
+        _memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        Err("abort".into())
+    }
+);
+
+declare_builtin_function!(
+    Log,
+    fn rust(
+        _meter: &mut Meter,
+        _a: u64,
+        _b: u64,
+        _c: u64,
+        _d: u64,
+        _e: u64,
+        _memory_mapping: &mut MemoryMapping,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(0)
+    }
+);
+
+/// Outcome of a successful run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Run {
+    /// R0 when the entrypoint returned
+    pub result: u64,
+    /// Compute units consumed
+    pub cu: u64,
+}
+
+/// Run the entrypoint of `elf` on `input`
+pub fn run(elf: &[u8], input: &mut [u8]) -> Result<Run, String> {
+    let mut loader = BuiltinProgram::new_loader(Config::default());
+    loader.register_function("abort", Abort::vm).map_err(|e| e.to_string())?;
+    for name in ["sol_log_", "sol_log_64_", "sol_log_pubkey", "sol_log_compute_units_"] {
+        loader.register_function(name, Log::vm).map_err(|e| e.to_string())?;
+    }
+    let executable = Executable::<Meter>::from_elf(elf, Arc::new(loader)).map_err(|e| e.to_string())?;
+
+    let config = executable.get_config();
+    let version = executable.get_sbpf_version();
+    let mut stack = AlignedMemory::<{ ebpf::HOST_ALIGN }>::zero_filled(config.stack_size());
+    let stack_len = stack.len();
+    let stack_gap = if !version.dynamic_stack_frames() && config.enable_stack_frame_gaps {
+        config.stack_frame_size as u64
+    } else {
+        0
+    };
+    let mut heap = vec![0u8; 32 * 1024];
+    let regions = vec![
+        executable.get_ro_region(),
+        MemoryRegion::new_writable_gapped(stack.as_slice_mut(), ebpf::MM_STACK_START, stack_gap),
+        MemoryRegion::new_writable(&mut heap, ebpf::MM_HEAP_START),
+        MemoryRegion::new_writable(input, ebpf::MM_INPUT_START),
+    ];
+    let mapping = MemoryMapping::new(regions, config, version).map_err(|e| e.to_string())?;
+
+    let mut meter = Meter { remaining: CU_LIMIT };
+    let mut vm = EbpfVm::new(executable.get_loader().clone(), version, &mut meter, mapping, stack_len);
+    let (cu, result) = vm.execute_program(&executable, true);
+    match result {
+        ProgramResult::Ok(result) => Ok(Run { result, cu }),
+        ProgramResult::Err(err) => Err(err.to_string()),
+    }
+}
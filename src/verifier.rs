@@ -0,0 +1,197 @@
+//! Bytecode verifier
+//!
+//! Mirrors the checks the Solana loader runs when a program is deployed, so
+//! that invalid code is rejected at compile time instead of on chain.
+use crate::codegen::BpfOpcode;
+use crate::syscalls;
+use anyhow::{anyhow, Result};
+
+const INSN_SIZE: usize = 8;
+
+/// A decoded instruction slot
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    opcode: u8,
+    dst: u8,
+    src: u8,
+    offset: i16,
+    imm: i32,
+}
+
+impl Slot {
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            opcode: bytes[0],
+            dst: bytes[1] & 0x0f,
+            src: bytes[1] >> 4,
+            offset: i16::from_le_bytes([bytes[2], bytes[3]]),
+            imm: i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+}
+
+/// Check a program's instruction stream, reporting every problem found
+pub fn verify(bytecode: &[u8]) -> Result<()> {
+    if bytecode.is_empty() {
+        return Err(anyhow!("Program contains no instructions"));
+    }
+    if !bytecode.len().is_multiple_of(INSN_SIZE) {
+        return Err(anyhow!("Program length {} is not a multiple of {}", bytecode.len(), INSN_SIZE));
+    }
+
+    let slots: Vec<Slot> = bytecode.chunks(INSN_SIZE).map(Slot::decode).collect();
+
+    // Second halves of lddw, which are not instructions of their own
+    let mut lddw_tail = vec![false; slots.len()];
+    let mut pc = 0;
+    while pc < slots.len() {
+        if slots[pc].opcode == BpfOpcode::Lddw as u8 && pc + 1 < slots.len() {
+            lddw_tail[pc + 1] = true;
+            pc += 1;
+        }
+        pc += 1;
+    }
+
+    let mut errors = Vec::new();
+    for (pc, slot) in slots.iter().enumerate() {
+        if lddw_tail[pc] {
+            if slot.opcode != 0 || slot.dst != 0 || slot.src != 0 || slot.offset != 0 {
+                errors.push(format!("pc {}: malformed second half of lddw", pc));
+            }
+            continue;
+        }
+        if let Err(err) = check_slot(pc, slot, &slots, &lddw_tail) {
+            errors.push(format!("pc {}: {}", pc, err));
+        }
+    }
+
+    // The last instruction must not let control fall off the end
+    let last = slots.len() - 1;
+    let last_pc = if lddw_tail[last] { last - 1 } else { last };
+    let last_opcode = slots[last_pc].opcode;
+    if last_opcode != BpfOpcode::Exit as u8 && last_opcode != BpfOpcode::Ja as u8 {
+        errors.push(format!("pc {}: execution falls through past the last instruction", last_pc));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Bytecode verification failed:\n  {}", errors.join("\n  ")))
+    }
+}
+
+fn check_slot(pc: usize, slot: &Slot, slots: &[Slot], lddw_tail: &[bool]) -> Result<()> {
+    let opcode = BpfOpcode::from_u8(slot.opcode)
+        .ok_or_else(|| anyhow!("unknown opcode 0x{:02x}", slot.opcode))?;
+
+    if slot.dst > 10 || slot.src > 10 {
+        return Err(anyhow!("invalid register r{}", slot.dst.max(slot.src)));
+    }
+    if slot.dst == 10 && opcode.writes_dst() {
+        return Err(anyhow!("write to read-only frame pointer r10"));
+    }
+
+    let branch_target = |offset: i64| -> Result<()> {
+        let target = pc as i64 + 1 + offset;
+        if target < 0 || target as usize >= slots.len() {
+            return Err(anyhow!("jump to {} is out of bounds", target));
+        }
+        if lddw_tail[target as usize] {
+            return Err(anyhow!("jump to {} lands in the middle of lddw", target));
+        }
+        Ok(())
+    };
+
+    match opcode {
+        BpfOpcode::Lddw if pc + 1 >= slots.len() => Err(anyhow!("lddw is missing its second half")),
+
+        BpfOpcode::Div64Imm | BpfOpcode::Div32Imm | BpfOpcode::Mod64Imm | BpfOpcode::Mod32Imm
+            if slot.imm == 0 => Err(anyhow!("division by zero")),
+
+        BpfOpcode::Lsh64Imm | BpfOpcode::Rsh64Imm | BpfOpcode::Arsh64Imm
+            if !(0..64).contains(&slot.imm) => Err(anyhow!("shift by {} overflows 64 bits", slot.imm)),
+        BpfOpcode::Lsh32Imm | BpfOpcode::Rsh32Imm | BpfOpcode::Arsh32Imm
+            if !(0..32).contains(&slot.imm) => Err(anyhow!("shift by {} overflows 32 bits", slot.imm)),
+
+        BpfOpcode::Le | BpfOpcode::Be if !matches!(slot.imm, 16 | 32 | 64) => {
+            Err(anyhow!("invalid endianness conversion width {}", slot.imm))
+        }
+
+        BpfOpcode::Call if slot.src == 1 => branch_target(slot.imm as i64)
+            .map_err(|err| anyhow!("call target unresolved: {}", err)),
+        BpfOpcode::Call => match syscalls::by_hash(slot.imm as u32) {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("call to unresolved target 0x{:08x}", slot.imm as u32)),
+        },
+        BpfOpcode::Callx if !(0..10).contains(&slot.imm) => {
+            Err(anyhow!("callx through invalid register r{}", slot.imm))
+        }
+
+        op if op.is_jump() => branch_target(slot.offset as i64),
+
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::{BpfInstruction, BpfReg};
+
+    fn bytes(insts: &[BpfInstruction]) -> Vec<u8> {
+        insts.iter().flat_map(|inst| inst.to_bytes()).collect()
+    }
+
+    fn error(insts: &[BpfInstruction]) -> String {
+        verify(&bytes(insts)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_valid_program() {
+        let [lo, hi] = BpfInstruction::lddw(BpfReg::R1, 1 << 40);
+        let program = bytes(&[
+            lo,
+            hi,
+            BpfInstruction::jeq_imm(BpfReg::R1, 0, 1),
+            BpfInstruction::syscall(syscalls::hash("sol_log_64_")),
+            BpfInstruction::mov_reg(BpfReg::R0, BpfReg::R1),
+            BpfInstruction::exit(),
+        ]);
+        verify(&program).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_bad_jumps() {
+        assert!(error(&[BpfInstruction::ja(5), BpfInstruction::exit()]).contains("out of bounds"));
+
+        let [lo, hi] = BpfInstruction::lddw(BpfReg::R1, 1 << 40);
+        let err = error(&[BpfInstruction::ja(1), lo, hi, BpfInstruction::exit()]);
+        assert!(err.contains("middle of lddw"));
+    }
+
+    #[test]
+    fn test_rejects_r10_write_and_zero_division() {
+        let err = error(&[
+            BpfInstruction::mov_imm(BpfReg::R10, 0),
+            BpfInstruction::new(BpfOpcode::Div64Imm, BpfReg::R1, BpfReg::R0, 0, 0),
+            BpfInstruction::exit(),
+        ]);
+        assert!(err.contains("pc 0: write to read-only frame pointer r10"));
+        assert!(err.contains("pc 1: division by zero"));
+    }
+
+    #[test]
+    fn test_rejects_unknown_opcode_and_fall_through() {
+        let mut program = bytes(&[BpfInstruction::mov_imm(BpfReg::R0, 0)]);
+        program[0] = 0xd7;
+        let err = verify(&program).unwrap_err().to_string();
+        assert!(err.contains("unknown opcode 0xd7"));
+        assert!(err.contains("falls through"));
+    }
+
+    #[test]
+    fn test_rejects_unresolved_calls() {
+        assert!(error(&[BpfInstruction::syscall(0x1234), BpfInstruction::exit()]).contains("unresolved"));
+        assert!(error(&[BpfInstruction::call_internal(7), BpfInstruction::exit()]).contains("unresolved"));
+    }
+}